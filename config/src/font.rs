@@ -2,6 +2,7 @@ use crate::*;
 use bitflags::*;
 use enum_display_derive::Display;
 use luahelper::impl_lua_conversion;
+use rangeset::RangeSet;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::fmt::Display;
 use termwiz::color::RgbColor;
@@ -164,6 +165,67 @@ impl Default for FreeTypeLoadTarget {
     }
 }
 
+/// The format of a font file, as reported by freetype
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FontFormat {
+    /// TrueType, including OpenType fonts with TrueType outlines
+    TrueType,
+    /// CFF, including OpenType fonts with CFF outlines
+    Cff,
+    Type1,
+    CidType1,
+    Type42,
+    Pfr,
+    /// Bitmap-only fonts in the X11 BDF format
+    Bdf,
+    /// Bitmap-only fonts in the X11 PCF format
+    Pcf,
+    /// Bitmap-only Windows FNT/FON fonts
+    WinFnt,
+    /// Bitmap-only OpenType fonts, which have strikes but no outlines.
+    /// Some distributions ship these in place of PCF fonts.
+    Otb,
+}
+
+impl FontFormat {
+    /// Returns true if `allowed`, the value of font_allowed_formats,
+    /// permits a font of `format`.  A font whose format is unknown
+    /// is always permitted.
+    pub fn is_allowed(format: Option<Self>, allowed: Option<&[Self]>) -> bool {
+        match (format, allowed) {
+            (Some(format), Some(allowed)) => allowed.contains(&format),
+            _ => true,
+        }
+    }
+}
+
+impl std::fmt::Display for FontFormat {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{:?}", self)
+    }
+}
+
+/// Selects the filter that freetype applies to reduce color fringes
+/// in glyphs that are rendered for LCD displays
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FreeTypeLcdFilter {
+    /// Don't filter
+    None,
+    /// The default filter, which is a good balance of sharpness
+    /// and color fringing
+    Default,
+    /// A lighter filter that is sharper, with more color fringing
+    Light,
+    /// The filter used by older versions of freetype
+    Legacy,
+}
+
+impl Default for FreeTypeLcdFilter {
+    fn default() -> Self {
+        Self::Default
+    }
+}
+
 bitflags! {
     // Note that these are strongly coupled with deps/freetype/src/lib.rs,
     // but we can't directly reference that from here without making config
@@ -183,6 +245,9 @@ bitflags! {
         const MONOCHROME = 4096;
         /// Disable auto-hinter.
         const NO_AUTOHINT = 32768;
+        /// Compute the glyph metrics from the outline rather than
+        /// trusting the metrics tables in the font.
+        const COMPUTE_METRICS = 2097152;
     }
 }
 
@@ -203,6 +268,7 @@ impl FreeTypeLoadFlags {
                 "FORCE_AUTOHINT" => flags |= Self::FORCE_AUTOHINT,
                 "MONOCHROME" => flags |= Self::MONOCHROME,
                 "NO_AUTOHINT" => flags |= Self::NO_AUTOHINT,
+                "COMPUTE_METRICS" => flags |= Self::COMPUTE_METRICS,
                 _ => {
                     return Err(serde::de::Error::custom(format!(
                         "invalid FreeTypeLoadFlags {} in {}",
@@ -251,6 +317,185 @@ impl Default for FontAntiAliasing {
     }
 }
 
+/// The unit in which a `BaselineOffset` is expressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BaselineOffsetUnit {
+    /// Device pixels, which don't change with the font size or dpi
+    Pixels,
+    /// A fraction of the font size, which scales with the size and dpi
+    Em,
+}
+
+/// How far to move the glyphs of a font up (or down, if negative)
+/// from the baseline, written in the config as a string such as
+/// `"2px"` or `"-0.05em"`.  A number without a unit is in pixels.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BaselineOffset {
+    pub amount: f64,
+    pub unit: BaselineOffsetUnit,
+}
+
+impl BaselineOffset {
+    /// Returns the offset in pixels for a font that is `font_size`
+    /// points in size at `dpi`
+    pub fn pixels(&self, font_size: f64, dpi: u32) -> f64 {
+        match self.unit {
+            BaselineOffsetUnit::Pixels => self.amount,
+            BaselineOffsetUnit::Em => self.amount * font_size * dpi as f64 / 72.0,
+        }
+    }
+}
+
+// The amount is always finite, so comparing the bits is equivalent
+// to comparing the values, and allows FontAttributes to remain Eq
+// and Hash
+impl PartialEq for BaselineOffset {
+    fn eq(&self, other: &Self) -> bool {
+        self.amount.to_bits() == other.amount.to_bits() && self.unit == other.unit
+    }
+}
+impl Eq for BaselineOffset {}
+
+impl std::hash::Hash for BaselineOffset {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.amount.to_bits().hash(state);
+        self.unit.hash(state);
+    }
+}
+
+impl std::fmt::Display for BaselineOffset {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.unit {
+            BaselineOffsetUnit::Pixels => write!(fmt, "{}px", self.amount),
+            BaselineOffsetUnit::Em => write!(fmt, "{}em", self.amount),
+        }
+    }
+}
+
+impl std::str::FromStr for BaselineOffset {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, unit) = if let Some(number) = s.strip_suffix("px") {
+            (number, BaselineOffsetUnit::Pixels)
+        } else if let Some(number) = s.strip_suffix("em") {
+            (number, BaselineOffsetUnit::Em)
+        } else {
+            (s, BaselineOffsetUnit::Pixels)
+        };
+        let amount: f64 = number
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid baseline_offset {}", s))?;
+        if !amount.is_finite() {
+            bail!("invalid baseline_offset {}: it must be finite", s);
+        }
+        // Adding zero turns negative zero into zero, so that the two
+        // compare equal
+        Ok(Self {
+            amount: amount + 0.0,
+            unit,
+        })
+    }
+}
+
+impl std::convert::TryFrom<String> for BaselineOffset {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, String> {
+        s.parse().map_err(|err| format!("{:#}", err))
+    }
+}
+
+impl From<BaselineOffset> for String {
+    fn from(offset: BaselineOffset) -> String {
+        offset.to_string()
+    }
+}
+
+/// Implements PartialEq, Eq and Hash for a wrapper around an f64 that
+/// is always finite, by comparing the bits, so that FontAttributes can
+/// remain Eq and Hash
+macro_rules! impl_finite_f64_eq {
+    ($name:ident) => {
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.0.to_bits() == other.0.to_bits()
+            }
+        }
+        impl Eq for $name {}
+
+        impl std::hash::Hash for $name {
+            fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+                self.0.to_bits().hash(state);
+            }
+        }
+
+        impl From<$name> for f64 {
+            fn from(value: $name) -> f64 {
+                value.0
+            }
+        }
+    };
+}
+
+/// How much to scale the glyphs of a font horizontally; 0.9 condenses
+/// them to 90% of their width.  Must be greater than 0 and no more
+/// than 4.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(try_from = "f64", into = "f64")]
+pub struct HorizontalScale(f64);
+impl_finite_f64_eq!(HorizontalScale);
+
+impl HorizontalScale {
+    pub fn get(self) -> f64 {
+        self.0
+    }
+}
+
+impl std::convert::TryFrom<f64> for HorizontalScale {
+    type Error = String;
+    fn try_from(scale: f64) -> Result<Self, String> {
+        if scale.is_finite() && scale > 0. && scale <= 4. {
+            Ok(Self(scale))
+        } else {
+            Err(format!(
+                "invalid horizontal_scale {}: it must be greater than 0 and no more than 4",
+                scale
+            ))
+        }
+    }
+}
+
+/// How many degrees to slant the glyphs of a font; positive values
+/// lean them to the right.  Must be between -45 and 45.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(try_from = "f64", into = "f64")]
+pub struct SlantDegrees(f64);
+impl_finite_f64_eq!(SlantDegrees);
+
+impl SlantDegrees {
+    pub fn get(self) -> f64 {
+        self.0
+    }
+}
+
+impl std::convert::TryFrom<f64> for SlantDegrees {
+    type Error = String;
+    fn try_from(degrees: f64) -> Result<Self, String> {
+        if degrees.is_finite() && degrees.abs() <= 45. {
+            // Adding zero turns negative zero into zero, so that the
+            // two compare equal
+            Ok(Self(degrees + 0.0))
+        } else {
+            Err(format!(
+                "invalid slant_degrees {}: it must be between -45 and 45",
+                degrees
+            ))
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub struct FontAttributes {
     /// The font family name
@@ -265,6 +510,30 @@ pub struct FontAttributes {
     pub italic: bool,
     pub is_fallback: bool,
     pub is_synthetic: bool,
+    /// Overrides the heuristic that decides whether this font is
+    /// an emoji font that should be preferred for codepoints that
+    /// default to emoji presentation
+    #[serde(default)]
+    pub assume_emoji_presentation: Option<bool>,
+    /// When false, bold is not synthesized for this font if it lacks
+    /// a bold face; the next font in the fallback list is used instead
+    #[serde(default = "crate::default_true")]
+    pub allow_synthetic_bold: bool,
+    /// When false, italic is not synthesized for this font if it lacks
+    /// an italic face; the next font in the fallback list is used instead
+    #[serde(default = "crate::default_true")]
+    pub allow_synthetic_italic: bool,
+    /// Moves the glyphs of this font up, or down if negative, without
+    /// changing the size of the cell
+    #[serde(default)]
+    pub baseline_offset: Option<BaselineOffset>,
+    /// Scales the glyphs of this font horizontally, along with the
+    /// width of the cell when it is the primary font
+    #[serde(default)]
+    pub horizontal_scale: Option<HorizontalScale>,
+    /// Slants the glyphs of this font without changing their advance
+    #[serde(default)]
+    pub slant_degrees: Option<SlantDegrees>,
 }
 impl_lua_conversion!(FontAttributes);
 
@@ -287,6 +556,12 @@ impl FontAttributes {
             italic: false,
             is_fallback: false,
             is_synthetic: false,
+            assume_emoji_presentation: None,
+            allow_synthetic_bold: true,
+            allow_synthetic_italic: true,
+            baseline_offset: None,
+            horizontal_scale: None,
+            slant_degrees: None,
         }
     }
 
@@ -298,6 +573,12 @@ impl FontAttributes {
             italic: false,
             is_fallback: true,
             is_synthetic: false,
+            assume_emoji_presentation: None,
+            allow_synthetic_bold: true,
+            allow_synthetic_italic: true,
+            baseline_offset: None,
+            horizontal_scale: None,
+            slant_degrees: None,
         }
     }
 }
@@ -311,6 +592,12 @@ impl Default for FontAttributes {
             italic: false,
             is_fallback: false,
             is_synthetic: false,
+            assume_emoji_presentation: None,
+            allow_synthetic_bold: true,
+            allow_synthetic_italic: true,
+            baseline_offset: None,
+            horizontal_scale: None,
+            slant_degrees: None,
         }
     }
 }
@@ -449,27 +736,98 @@ impl TextStyle {
         }
     }
 
-    #[cfg_attr(feature = "cargo-clippy", allow(clippy::let_and_return))]
-    pub fn font_with_fallback(&self) -> Vec<FontAttributes> {
+    /// Returns the fonts of this style followed by the fallback fonts
+    pub fn font_with_fallback(&self, config: &Config) -> Vec<FontAttributes> {
         let mut font = self.font.clone();
+        font.extend(
+            self.fallback_fonts(config)
+                .into_iter()
+                .map(|(attr, _)| attr),
+        );
+        font
+    }
+
+    /// Returns the fallback fonts that follow the fonts of this style,
+    /// along with where each of them came from.
+    /// The built-in fallback fonts are bracketed by those from the
+    /// font_fallback_prepend and font_fallback_append options, and
+    /// any built-in fallback named by font_fallback_remove is omitted.
+    pub fn fallback_fonts(&self, config: &Config) -> Vec<(FontAttributes, FallbackSource)> {
+        let mut fallback = vec![];
 
-        let mut default_font = FontAttributes::default();
+        let mut push = |mut attr: FontAttributes, source: FallbackSource| {
+            attr.is_fallback = true;
+            fallback.push((attr, source));
+        };
+
+        for attr in &config.font_fallback_prepend {
+            push(attr.clone(), FallbackSource::Prepend);
+        }
+
+        let mut built_in = vec![];
 
         // Insert our bundled default JetBrainsMono as a fallback
         // in case their preference doesn't match anything.
         // But don't add it if it is already their preference.
-        if font.iter().position(|f| *f == default_font).is_none() {
-            default_font.is_fallback = true;
-            font.push(default_font);
+        let default_font = FontAttributes::default();
+        if self.font.iter().position(|f| *f == default_font).is_none() {
+            built_in.push(default_font);
         }
 
         // We bundle this emoji font as an in-memory fallback
-        font.push(FontAttributes::new_fallback("Noto Color Emoji"));
+        built_in.push(FontAttributes::new_fallback("Noto Color Emoji"));
 
-        // And finally, a last resort fallback font
-        font.push(FontAttributes::new_fallback("Last Resort High-Efficiency"));
+        let removed = |attr: &FontAttributes| {
+            config
+                .font_fallback_remove
+                .iter()
+                .any(|family| family.eq_ignore_ascii_case(&attr.family))
+        };
 
-        font
+        for attr in built_in {
+            if removed(&attr) {
+                continue;
+            }
+            push(attr, FallbackSource::BuiltIn);
+        }
+
+        for attr in &config.font_fallback_append {
+            push(attr.clone(), FallbackSource::Append);
+        }
+
+        // And finally, the last resort font.  It has a glyph for every
+        // codepoint, so nothing after it would ever be used.
+        let last_resort = FontAttributes::new_fallback(LAST_RESORT_FAMILY);
+        if config.use_last_resort_font && !removed(&last_resort) {
+            push(last_resort, FallbackSource::BuiltIn);
+        }
+
+        fallback
+    }
+}
+
+/// The family name of the built-in Last Resort font, which has a
+/// placeholder glyph for every codepoint
+pub const LAST_RESORT_FAMILY: &str = "Last Resort High-Efficiency";
+
+/// Describes where an entry in the list of fallback fonts came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackSource {
+    /// The font_fallback_prepend option
+    Prepend,
+    /// The fallback fonts that are built into wezterm
+    BuiltIn,
+    /// The font_fallback_append option
+    Append,
+}
+
+impl std::fmt::Display for FallbackSource {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Prepend => write!(fmt, "font_fallback_prepend"),
+            Self::BuiltIn => write!(fmt, "built-in fallback"),
+            Self::Append => write!(fmt, "font_fallback_append"),
+        }
     }
 }
 
@@ -518,6 +876,285 @@ pub struct StyleRule {
 }
 impl_lua_conversion!(StyleRule);
 
+/// A set of unicode codepoint ranges, written in the config as a list
+/// of strings such as `"U+E000-U+F8FF"` or `"U+2B58"`.
+/// The ranges are compiled into a RangeSet when the config is parsed.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct UnicodeRanges {
+    ranges: Vec<String>,
+    set: RangeSet<u32>,
+}
+
+impl UnicodeRanges {
+    pub fn range_set(&self) -> &RangeSet<u32> {
+        &self.set
+    }
+}
+
+/// Parse a codepoint in `U+XXXX` notation; the `U+` prefix is optional
+fn parse_codepoint(s: &str) -> anyhow::Result<u32> {
+    let s = s.trim();
+    let hex = s
+        .strip_prefix("U+")
+        .or_else(|| s.strip_prefix("u+"))
+        .unwrap_or(s);
+    let value =
+        u32::from_str_radix(hex, 16).with_context(|| format!("{} is not a valid codepoint", s))?;
+    if value > 0x10ffff {
+        bail!("{} is outside the unicode codepoint space", s);
+    }
+    Ok(value)
+}
+
+/// Format `set` as a comma separated list of ranges, such as
+/// `U+0000-00FF, U+0131, U+0152-0153`, which is the notation used by
+/// CSS and accepted by `parse_unicode_ranges`.
+pub fn format_unicode_ranges(set: &RangeSet<u32>) -> String {
+    set.iter()
+        .map(|range| {
+            if range.end - range.start == 1 {
+                format!("U+{:04X}", range.start)
+            } else {
+                format!("U+{:04X}-{:04X}", range.start, range.end - 1)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Parse a comma separated list of ranges, such as that produced
+/// by `format_unicode_ranges`
+pub fn parse_unicode_ranges(s: &str) -> anyhow::Result<RangeSet<u32>> {
+    let ranges: UnicodeRanges = s.parse()?;
+    Ok(ranges.set)
+}
+
+impl std::str::FromStr for UnicodeRanges {
+    type Err = anyhow::Error;
+    /// Parses a comma separated list of ranges
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(vec![s.to_string()]).map_err(|err| anyhow!("{}", err))
+    }
+}
+
+impl std::convert::TryFrom<Vec<String>> for UnicodeRanges {
+    type Error = String;
+    /// Each entry may itself be a comma separated list of ranges,
+    /// so that the output of `wezterm ls-fonts --coverage` can be
+    /// pasted into the config
+    fn try_from(ranges: Vec<String>) -> Result<Self, String> {
+        let mut set = RangeSet::new();
+        for range in ranges.iter().flat_map(|r| r.split(',')) {
+            if range.trim().is_empty() {
+                continue;
+            }
+            let (start, end) = match range.find('-') {
+                Some(idx) => (
+                    parse_codepoint(&range[..idx]),
+                    parse_codepoint(&range[idx + 1..]),
+                ),
+                None => (parse_codepoint(range), parse_codepoint(range)),
+            };
+            let (start, end) = match (start, end) {
+                (Ok(start), Ok(end)) => (start, end),
+                (Err(err), _) | (_, Err(err)) => {
+                    return Err(format!("invalid unicode range {}: {:#}", range, err))
+                }
+            };
+            if start > end {
+                return Err(format!(
+                    "invalid unicode range {}: the start is after the end",
+                    range
+                ));
+            }
+            set.add_range(start..end + 1);
+        }
+        Ok(Self { ranges, set })
+    }
+}
+
+impl From<UnicodeRanges> for Vec<String> {
+    fn from(ranges: UnicodeRanges) -> Vec<String> {
+        ranges.ranges
+    }
+}
+
+/// Maps ranges of codepoints to a particular font.
+/// Text in those ranges uses that font in preference to the
+/// fonts that would normally be selected, provided that the
+/// font has glyphs for the text.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct UnicodeRangeRule {
+    /// The codepoints to which this rule applies
+    pub ranges: UnicodeRanges,
+    /// The font to use for text in `ranges`
+    pub font: TextStyle,
+}
+impl_lua_conversion!(UnicodeRangeRule);
+
+/// Returns the set of codepoints claimed by each rule.
+/// Where rules overlap, the earlier rule takes precedence.
+pub fn compile_unicode_range_rules(rules: &[UnicodeRangeRule]) -> Vec<RangeSet<u32>> {
+    let mut claimed = RangeSet::new();
+    let mut result = vec![];
+    for rule in rules {
+        let set = rule.ranges.range_set();
+        result.push(set.difference(&claimed));
+        claimed.add_set(set);
+    }
+    result
+}
+
+/// Substitutes the glyph used to render specific codepoints.
+/// Exactly one of `replacement` and `font` must be specified.
+#[derive(Debug, Default, Deserialize, Serialize, Clone)]
+pub struct GlyphSubstitution {
+    /// The codepoints to which this substitution applies
+    pub codepoints: UnicodeRanges,
+    /// Render the codepoints as this character instead.
+    /// This is either the character itself or its codepoint in
+    /// `U+XXXX` notation.
+    #[serde(default)]
+    pub replacement: Option<String>,
+    /// Render the codepoints using this font, in preference to
+    /// any other, provided that it has glyphs for them
+    #[serde(default)]
+    pub font: Option<TextStyle>,
+}
+impl_lua_conversion!(GlyphSubstitution);
+
+impl GlyphSubstitution {
+    fn target(&self, idx: usize) -> anyhow::Result<GlyphSubstitutionTarget> {
+        match (&self.replacement, &self.font) {
+            (Some(replacement), None) => {
+                let mut chars = replacement.chars();
+                let c = match (chars.next(), chars.next()) {
+                    (Some(c), None) => c,
+                    _ => std::char::from_u32(parse_codepoint(replacement)?)
+                        .ok_or_else(|| anyhow!("{} is not a valid character", replacement))?,
+                };
+                Ok(GlyphSubstitutionTarget::Codepoint(c))
+            }
+            (None, Some(_)) => Ok(GlyphSubstitutionTarget::Font(idx)),
+            (Some(_), Some(_)) => bail!("only one of replacement and font may be specified"),
+            (None, None) => bail!("one of replacement or font must be specified"),
+        }
+    }
+}
+
+/// What a codepoint is substituted with by `glyph_substitutions`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GlyphSubstitutionTarget {
+    /// Render this character instead
+    Codepoint(char),
+    /// Render using the font of the `glyph_substitutions` entry
+    /// with this index
+    Font(usize),
+}
+
+/// The compiled form of the `glyph_substitutions` configuration,
+/// which is a sorted list of non-overlapping ranges that can be
+/// searched efficiently while shaping
+#[derive(Debug, Default, Clone)]
+pub struct GlyphSubstitutionMap {
+    entries: Vec<(std::ops::Range<u32>, GlyphSubstitutionTarget)>,
+}
+
+impl GlyphSubstitutionMap {
+    /// Compile `rules` into a map.
+    /// Where rules overlap, the earlier rule takes precedence.
+    /// Invalid rules are reported and ignored.
+    pub fn compile(rules: &[GlyphSubstitution]) -> Self {
+        let mut claimed = RangeSet::new();
+        let mut entries = vec![];
+        for (idx, rule) in rules.iter().enumerate() {
+            let target = match rule.target(idx) {
+                Ok(target) => target,
+                Err(err) => {
+                    log::error!("glyph_substitutions[{}]: {:#}", idx, err);
+                    continue;
+                }
+            };
+            let set = rule.codepoints.range_set();
+            let unclaimed = set.difference(&claimed);
+            if unclaimed != *set {
+                log::warn!(
+                    "glyph_substitutions[{}] overlaps with an earlier rule; \
+                     the earlier rule takes precedence for the overlapping codepoints",
+                    idx
+                );
+            }
+            for range in unclaimed.iter() {
+                entries.push((range.clone(), target));
+            }
+            claimed.add_set(set);
+        }
+        entries.sort_by_key(|(range, _)| range.start);
+        Self { entries }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the substitution for `c`, if any
+    pub fn lookup(&self, c: char) -> Option<GlyphSubstitutionTarget> {
+        let c = c as u32;
+        let idx = match self
+            .entries
+            .binary_search_by_key(&c, |(range, _)| range.start)
+        {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let (range, target) = &self.entries[idx];
+        if range.contains(&c) {
+            Some(*target)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the character that `c` is rendered as
+    pub fn replacement_char(&self, c: char) -> Option<char> {
+        match self.lookup(c) {
+            Some(GlyphSubstitutionTarget::Codepoint(replacement)) => Some(replacement),
+            _ => None,
+        }
+    }
+
+    /// Returns the set of codepoints that are to be rendered using
+    /// the font of the `glyph_substitutions` entry with index `idx`
+    pub fn font_ranges(&self, idx: usize) -> RangeSet<u32> {
+        let mut set = RangeSet::new();
+        for (range, target) in &self.entries {
+            if *target == GlyphSubstitutionTarget::Font(idx) {
+                set.add_range(range.clone());
+            }
+        }
+        set
+    }
+}
+
+/// Controls how bold is synthesized for fonts that lack a bold face
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyntheticBoldMode {
+    /// Embolden the outline, but keep the glyph centered within its
+    /// original advance so that bold text stays on the monospace grid
+    PreserveAdvance,
+    /// Embolden the outline, allowing the ink to grow to the right
+    /// of the original advance
+    Widen,
+}
+
+impl Default for SyntheticBoldMode {
+    fn default() -> Self {
+        Self::PreserveAdvance
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 pub enum AllowSquareGlyphOverflow {
     Never,
@@ -531,6 +1168,72 @@ impl Default for AllowSquareGlyphOverflow {
     }
 }
 
+/// Controls what happens to glyphs whose ink is wider than the
+/// cells that they occupy
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum WideGlyphPolicy {
+    /// Crop the glyph to the boundaries of its cells
+    Clip,
+    /// Shrink the glyph so that it fits within its cells
+    ScaleToFit,
+    /// Render the glyph at its natural size, overlapping the
+    /// neighboring cells
+    Overflow,
+    /// Scale the outline of the glyph down before it is rendered, so
+    /// that its ink fits within both the width and the height of its
+    /// cells while remaining on the baseline.  Bitmap glyphs are
+    /// scaled as for ScaleToFit.
+    FitToCells,
+}
+
+impl Default for WideGlyphPolicy {
+    fn default() -> Self {
+        Self::ScaleToFit
+    }
+}
+
+/// Controls how the fractional cell dimensions computed from the
+/// font metrics are converted to whole pixels
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum CellDimensionRounding {
+    /// Round down to the nearest pixel
+    Floor,
+    /// Round to the nearest pixel
+    Round,
+    /// Round up to the nearest pixel
+    Ceil,
+}
+
+impl Default for CellDimensionRounding {
+    fn default() -> Self {
+        Self::Ceil
+    }
+}
+
+impl CellDimensionRounding {
+    /// Convert `value` to a whole number of pixels.
+    /// The result is never smaller than a single pixel.
+    pub fn apply(self, value: f64) -> usize {
+        let value = match self {
+            Self::Floor => value.floor(),
+            Self::Round => value.round(),
+            Self::Ceil => value.ceil(),
+        };
+        value.max(1.) as usize
+    }
+}
+
+impl std::fmt::Display for CellDimensionRounding {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let label = match self {
+            Self::Floor => "Floor",
+            Self::Round => "Round",
+            Self::Ceil => "Ceil",
+        };
+        write!(fmt, "{}", label)
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 pub enum FontLocatorSelection {
     /// Use fontconfig APIs to resolve fonts (!macos, posix systems)
@@ -645,6 +1348,7 @@ impl std::str::FromStr for FontShaperSelection {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::convert::TryFrom;
 
     #[test]
     fn test_reduce() {
@@ -662,4 +1366,220 @@ mod test {
             assert_eq!(style.font[0].family, "Inconsolata");
         }
     }
+
+    #[test]
+    fn test_unicode_ranges() {
+        let ranges: UnicodeRanges = "U+E000-U+F8FF,U+2B58, u+1f600-1F64F".parse().unwrap();
+        let set = ranges.range_set();
+        assert!(set.contains(0xe000));
+        assert!(set.contains(0xf8ff));
+        assert!(!set.contains(0xf900));
+        assert!(set.contains(0x2b58));
+        assert!(!set.contains(0x2b59));
+        assert!(set.contains(0x1f610));
+
+        assert!("U+F8FF-U+E000".parse::<UnicodeRanges>().is_err());
+        assert!("U+110000".parse::<UnicodeRanges>().is_err());
+        assert!("bogus".parse::<UnicodeRanges>().is_err());
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn test_baseline_offset() {
+        let px: BaselineOffset = "2px".parse().unwrap();
+        assert_eq!(px.unit, BaselineOffsetUnit::Pixels);
+        assert_eq!(px.pixels(12.0, 96), 2.0);
+        assert_eq!(px.pixels(24.0, 192), 2.0);
+
+        let em: BaselineOffset = " -0.25em ".parse().unwrap();
+        assert_eq!(em.unit, BaselineOffsetUnit::Em);
+        assert_eq!(em.pixels(12.0, 96), -4.0);
+        assert_eq!(em.pixels(12.0, 192), -8.0);
+        assert_eq!(em.to_string(), "-0.25em");
+
+        assert_eq!(
+            "1.5".parse::<BaselineOffset>().unwrap().to_string(),
+            "1.5px"
+        );
+        assert_eq!(
+            "-0px".parse::<BaselineOffset>().unwrap(),
+            "0px".parse::<BaselineOffset>().unwrap()
+        );
+        assert!("1pt".parse::<BaselineOffset>().is_err());
+        assert!("em".parse::<BaselineOffset>().is_err());
+        assert!("infpx".parse::<BaselineOffset>().is_err());
+        assert!(BaselineOffset::try_from("bogus".to_string()).is_err());
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn test_glyph_transform_attributes() {
+        assert_eq!(HorizontalScale::try_from(0.9).unwrap().get(), 0.9);
+        assert!(HorizontalScale::try_from(0.).is_err());
+        assert!(HorizontalScale::try_from(-1.).is_err());
+        assert!(HorizontalScale::try_from(f64::NAN).is_err());
+        assert!(HorizontalScale::try_from(5.).is_err());
+
+        assert_eq!(SlantDegrees::try_from(-12.).unwrap().get(), -12.);
+        assert_eq!(
+            SlantDegrees::try_from(-0.).unwrap(),
+            SlantDegrees::try_from(0.).unwrap()
+        );
+        assert!(SlantDegrees::try_from(60.).is_err());
+        assert!(SlantDegrees::try_from(f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn test_format_unicode_ranges() {
+        let mut set = RangeSet::new();
+        set.add_range(0..0x100);
+        set.add(0x131);
+        set.add_range(0x152..0x154);
+        set.add(0x1f600);
+        let formatted = format_unicode_ranges(&set);
+        assert_eq!(formatted, "U+0000-00FF, U+0131, U+0152-0153, U+1F600");
+        assert_eq!(parse_unicode_ranges(&formatted).unwrap(), set);
+        assert_eq!(format_unicode_ranges(&RangeSet::new()), "");
+
+        // A formatted list can also be used as an entry in the config
+        let ranges = UnicodeRanges::try_from(vec![formatted, "U+E000".to_string()]).unwrap();
+        assert!(ranges.range_set().contains(0x152));
+        assert!(ranges.range_set().contains(0xe000));
+    }
+
+    #[test]
+    fn test_unicode_ranges_round_trip() {
+        // A simple xorshift generator, so that the sets are arbitrary
+        // but the test is reproducible
+        let mut state = 0x2545_f491_u32;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+        for _ in 0..500 {
+            let mut set = RangeSet::new();
+            for _ in 0..next() % 20 {
+                let start = next() % 0x110000;
+                let len = match next() % 3 {
+                    0 => 1,
+                    1 => next() % 16 + 1,
+                    _ => next() % 0x1000 + 1,
+                };
+                set.add_range(start..(start + len).min(0x110000));
+            }
+            let formatted = format_unicode_ranges(&set);
+            assert_eq!(
+                parse_unicode_ranges(&formatted).unwrap(),
+                set,
+                "{}",
+                formatted
+            );
+        }
+    }
+
+    #[test]
+    fn test_unicode_range_rule_overlap() {
+        let rule = |ranges: &str| UnicodeRangeRule {
+            ranges: ranges.parse().unwrap(),
+            font: TextStyle::default(),
+        };
+        let compiled = compile_unicode_range_rules(&[rule("U+E000-U+E0FF"), rule("U+E080-U+E1FF")]);
+        assert!(compiled[0].contains(0xe080));
+        assert!(!compiled[1].contains(0xe080));
+        assert!(compiled[1].contains(0xe100));
+    }
+
+    #[test]
+    fn test_glyph_substitutions() {
+        let rule = |codepoints: &str, replacement: Option<&str>, font: bool| GlyphSubstitution {
+            codepoints: codepoints.parse().unwrap(),
+            replacement: replacement.map(|s| s.to_string()),
+            font: if font {
+                Some(TextStyle::default())
+            } else {
+                None
+            },
+        };
+        let map = GlyphSubstitutionMap::compile(&[
+            rule("U+2014", Some("-"), false),
+            rule("U+2010-U+2015", None, true),
+            rule("U+E0A0", None, false),
+            rule("U+E0B0", Some("U+E0B2"), false),
+        ]);
+        assert_eq!(
+            map.lookup('\u{2014}'),
+            Some(GlyphSubstitutionTarget::Codepoint('-'))
+        );
+        assert_eq!(
+            map.lookup('\u{2010}'),
+            Some(GlyphSubstitutionTarget::Font(1))
+        );
+        assert_eq!(
+            map.lookup('\u{2015}'),
+            Some(GlyphSubstitutionTarget::Font(1))
+        );
+        assert_eq!(map.lookup('\u{2016}'), None);
+        assert_eq!(map.lookup('a'), None);
+        // The rule with neither a replacement nor a font is ignored
+        assert_eq!(map.lookup('\u{e0a0}'), None);
+        assert_eq!(map.replacement_char('\u{e0b0}'), Some('\u{e0b2}'));
+
+        let fonts = map.font_ranges(1);
+        assert!(fonts.contains(0x2013));
+        assert!(!fonts.contains(0x2014));
+    }
+
+    #[test]
+    fn test_fallback_fonts() {
+        let mut config = Config::default();
+        config.font_fallback_prepend = vec![FontAttributes::new("Symbols Nerd Font")];
+        config.font_fallback_append = vec![FontAttributes::new("Unifont")];
+        config.font_fallback_remove = vec!["noto color emoji".to_string()];
+
+        let style = TextStyle {
+            font: vec![FontAttributes::new("Fira Code")],
+            foreground: None,
+        };
+        let fallback = style
+            .fallback_fonts(&config)
+            .into_iter()
+            .map(|(attr, source)| {
+                assert!(attr.is_fallback);
+                (attr.family, source)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fallback,
+            vec![
+                ("Symbols Nerd Font".to_string(), FallbackSource::Prepend),
+                ("JetBrains Mono".to_string(), FallbackSource::BuiltIn),
+                ("Unifont".to_string(), FallbackSource::Append),
+                (LAST_RESORT_FAMILY.to_string(), FallbackSource::BuiltIn),
+            ]
+        );
+
+        config.use_last_resort_font = false;
+        assert!(style
+            .fallback_fonts(&config)
+            .iter()
+            .all(|(attr, _)| attr.family != LAST_RESORT_FAMILY));
+    }
+
+    #[test]
+    fn allowed_formats() {
+        let scalable: &[FontFormat] = &[FontFormat::TrueType, FontFormat::Cff];
+        assert!(FontFormat::is_allowed(
+            Some(FontFormat::Cff),
+            Some(scalable)
+        ));
+        assert!(!FontFormat::is_allowed(
+            Some(FontFormat::Pcf),
+            Some(scalable)
+        ));
+        // When not configured, or the format is unknown, a font is allowed
+        assert!(FontFormat::is_allowed(Some(FontFormat::Pcf), None));
+        assert!(FontFormat::is_allowed(None, Some(scalable)));
+    }
 }
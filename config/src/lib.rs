@@ -700,9 +700,18 @@ pub struct Config {
     #[serde(default = "default_one_point_oh_f64")]
     pub line_height: f64,
 
+    /// How the fractional cell width and height are rounded
+    /// to whole pixels
+    #[serde(default)]
+    pub cell_dimension_rounding: CellDimensionRounding,
+
     #[serde(default)]
     pub allow_square_glyphs_to_overflow_width: AllowSquareGlyphOverflow,
 
+    /// What to do with glyphs that are too wide to fit in their cells
+    #[serde(default)]
+    pub wide_glyph_policy: WideGlyphPolicy,
+
     #[serde(default)]
     pub window_decorations: WindowDecorations,
 
@@ -728,6 +737,22 @@ pub struct Config {
     #[serde(default)]
     pub font_rules: Vec<StyleRule>,
 
+    /// An optional set of rules that map ranges of codepoints to
+    /// a specific font, which is used in preference to the normal
+    /// font selection for text in those ranges
+    #[serde(default)]
+    pub unicode_range_rules: Vec<UnicodeRangeRule>,
+
+    /// Substitutes the glyphs used to render specific codepoints,
+    /// either by rendering a different codepoint or by using a
+    /// specific font
+    #[serde(default)]
+    pub glyph_substitutions: Vec<GlyphSubstitution>,
+
+    /// The compiled form of glyph_substitutions
+    #[serde(skip)]
+    pub glyph_substitution_map: GlyphSubstitutionMap,
+
     /// When true (the default), PaletteIndex 0-7 are shifted to
     /// bright when the font intensity is bold.  The brightening
     /// doesn't apply to text that is the default color.
@@ -809,6 +834,42 @@ pub struct Config {
     #[serde(default)]
     pub font_antialias: FontAntiAliasing,
 
+    /// Fonts to search before the built-in fallback fonts
+    #[serde(default)]
+    pub font_fallback_prepend: Vec<FontAttributes>,
+    /// Fonts to search after the built-in fallback fonts
+    #[serde(default)]
+    pub font_fallback_append: Vec<FontAttributes>,
+    /// Family names of built-in fallback fonts that should not
+    /// be searched
+    #[serde(default)]
+    pub font_fallback_remove: Vec<String>,
+
+    /// Whether the built-in Last Resort font is searched after all of
+    /// the other fallback fonts, to show a placeholder that describes
+    /// the missing codepoint
+    #[serde(default = "default_true")]
+    pub use_last_resort_font: bool,
+
+    /// The formats of fonts that may be used as fallback fonts.
+    /// When not specified, fonts of any format may be used.
+    /// Fonts that are named explicitly by the configuration are
+    /// used regardless of their format.
+    #[serde(default)]
+    pub font_allowed_formats: Option<Vec<FontFormat>>,
+
+    /// The largest font file, in bytes, that will be loaded.  Compressed
+    /// fonts are also limited to this size once they are decompressed.
+    /// Larger files are skipped when enumerating font_dirs, and are an
+    /// error when they are the font that the configuration selects.
+    #[serde(default = "default_font_max_file_size")]
+    pub font_max_file_size: u64,
+
+    /// Controls how bold is synthesized for fonts that don't have
+    /// a bold face of their own
+    #[serde(default)]
+    pub synthetic_bold_mode: SyntheticBoldMode,
+
     #[serde(default)]
     pub freetype_load_target: FreeTypeLoadTarget,
     #[serde(default)]
@@ -822,6 +883,43 @@ pub struct Config {
     /// See https://freetype.org/freetype2/docs/subpixel-hinting.html
     pub freetype_interpreter_version: Option<u32>,
 
+    /// Selects the filter that freetype applies to glyphs that
+    /// are rendered for LCD displays
+    #[serde(default)]
+    pub freetype_lcd_filter: FreeTypeLcdFilter,
+
+    /// When true, wezterm never sets the LCD filter and leaves
+    /// freetype's built-in default in effect, so freetype_lcd_filter
+    /// is ignored
+    #[serde(default)]
+    pub freetype_skip_lcd_filter: bool,
+
+    /// Adjusts the per-channel coverage of glyphs that are rendered
+    /// for LCD displays before it is blended, with each coverage value
+    /// c becoming c^(1/gamma).  Blending in linear space makes partial
+    /// coverage look lighter than it does when blending in sRGB space;
+    /// values above 1.0 compensate for that.  1.0 leaves the coverage
+    /// as freetype rendered it.
+    #[serde(default = "default_one_point_oh_f64")]
+    pub freetype_lcd_coverage_gamma: f64,
+
+    /// Whether freetype darkens the stems of glyphs to improve
+    /// their legibility at small sizes.  When not specified,
+    /// freetype's default behavior is used.
+    pub freetype_stem_darkening: Option<bool>,
+
+    /// When true, glyphs are rendered without hinting, and in grayscale
+    /// rather than monochrome, at sizes that are smaller than the
+    /// lowest recommended size that is specified by the font
+    #[serde(default)]
+    pub freetype_unhinted_below_lowest_rec_ppem: bool,
+
+    /// When true, glyphs that are in color, such as emoji, are
+    /// rendered in grayscale mode whatever the freetype_render_target,
+    /// as the Mono and LCD targets can't represent their colors
+    #[serde(default = "default_true")]
+    pub freetype_normal_render_for_color_glyphs: bool,
+
     /// Specify the features to enable when using harfbuzz for font shaping.
     /// There is some light documentation here:
     /// <https://harfbuzz.github.io/shaping-opentype-features.html>
@@ -1143,6 +1241,15 @@ pub struct Config {
     #[serde(default = "default_true")]
     pub warn_about_missing_glyphs: bool,
 
+    #[serde(default)]
+    pub warn_about_strike_sizes: bool,
+
+    /// A warning is shown when a fallback font whose cells are more
+    /// than this many times wider, narrower, taller or shorter than
+    /// those of the primary font is used.  0 disables the warning.
+    #[serde(default = "default_fallback_metrics_warning_ratio")]
+    pub fallback_metrics_warning_ratio: f64,
+
     #[serde(default)]
     pub sort_fallback_fonts_by_coverage: bool,
 
@@ -1183,6 +1290,14 @@ fn default_word_boundary() -> String {
     " \t\n{[}]()\"'`".to_string()
 }
 
+fn default_fallback_metrics_warning_ratio() -> f64 {
+    1.5
+}
+
+fn default_font_max_file_size() -> u64 {
+    256 * 1024 * 1024
+}
+
 fn default_one_point_oh_f64() -> f64 {
     1.0
 }
@@ -1482,6 +1597,23 @@ impl Config {
             }
         }
 
+        for (idx, (rule, claimed)) in self
+            .unicode_range_rules
+            .iter()
+            .zip(compile_unicode_range_rules(&self.unicode_range_rules))
+            .enumerate()
+        {
+            if claimed != *rule.ranges.range_set() {
+                log::warn!(
+                    "unicode_range_rules[{}] overlaps with an earlier rule; \
+                     the earlier rule takes precedence for the overlapping codepoints",
+                    idx
+                );
+            }
+        }
+
+        cfg.glyph_substitution_map = GlyphSubstitutionMap::compile(&cfg.glyph_substitutions);
+
         // Add some reasonable default font rules
         let reduced = self.font.reduce_first_font_to_family();
 
@@ -1730,7 +1862,7 @@ fn default_hyperlink_rules() -> Vec<hyperlink::Rule> {
     ]
 }
 
-fn default_harfbuzz_features() -> Vec<String> {
+pub fn default_harfbuzz_features() -> Vec<String> {
     ["kern", "liga", "clig"]
         .iter()
         .map(|&s| s.to_string())
@@ -1,4 +1,7 @@
-use crate::{FontAttributes, FontStretch, FontWeight, TextStyle};
+use crate::{
+    BaselineOffset, FontAttributes, FontStretch, FontWeight, HorizontalScale, SlantDegrees,
+    TextStyle,
+};
 use anyhow::anyhow;
 use bstr::BString;
 pub use luahelper::*;
@@ -450,6 +453,18 @@ struct TextStyleAttributes {
     /// useful in a `[[font_rules]]` section to implement changing
     /// the text color for eg: bold text.
     pub foreground: Option<termwiz::color::RgbColor>,
+    #[serde(default)]
+    pub assume_emoji_presentation: Option<bool>,
+    #[serde(default)]
+    pub allow_synthetic_bold: Option<bool>,
+    #[serde(default)]
+    pub allow_synthetic_italic: Option<bool>,
+    #[serde(default)]
+    pub baseline_offset: Option<BaselineOffset>,
+    #[serde(default)]
+    pub horizontal_scale: Option<HorizontalScale>,
+    #[serde(default)]
+    pub slant_degrees: Option<SlantDegrees>,
 }
 impl_lua_conversion!(TextStyleAttributes);
 
@@ -465,6 +480,18 @@ struct LuaFontAttributes {
     /// Whether the font should be an italic variant
     #[serde(default)]
     pub italic: bool,
+    #[serde(default)]
+    pub assume_emoji_presentation: Option<bool>,
+    #[serde(default)]
+    pub allow_synthetic_bold: Option<bool>,
+    #[serde(default)]
+    pub allow_synthetic_italic: Option<bool>,
+    #[serde(default)]
+    pub baseline_offset: Option<BaselineOffset>,
+    #[serde(default)]
+    pub horizontal_scale: Option<HorizontalScale>,
+    #[serde(default)]
+    pub slant_degrees: Option<SlantDegrees>,
 }
 impl<'lua> FromLua<'lua> for LuaFontAttributes {
     fn from_lua(value: Value<'lua>, _lua: &'lua Lua) -> Result<Self, mlua::Error> {
@@ -504,6 +531,24 @@ fn font<'lua>(
         attrs.stretch = map_defaults.stretch;
         attrs.italic = map_defaults.italic;
         text_style.foreground = map_defaults.foreground;
+        if map_defaults.assume_emoji_presentation.is_some() {
+            attrs.assume_emoji_presentation = map_defaults.assume_emoji_presentation;
+        }
+        if map_defaults.allow_synthetic_bold.is_some() {
+            attrs.allow_synthetic_bold = map_defaults.allow_synthetic_bold;
+        }
+        if map_defaults.allow_synthetic_italic.is_some() {
+            attrs.allow_synthetic_italic = map_defaults.allow_synthetic_italic;
+        }
+        if map_defaults.baseline_offset.is_some() {
+            attrs.baseline_offset = map_defaults.baseline_offset;
+        }
+        if map_defaults.horizontal_scale.is_some() {
+            attrs.horizontal_scale = map_defaults.horizontal_scale;
+        }
+        if map_defaults.slant_degrees.is_some() {
+            attrs.slant_degrees = map_defaults.slant_degrees;
+        }
     }
 
     text_style.font.push(FontAttributes {
@@ -513,6 +558,12 @@ fn font<'lua>(
         italic: attrs.italic,
         is_fallback: false,
         is_synthetic: false,
+        assume_emoji_presentation: attrs.assume_emoji_presentation,
+        allow_synthetic_bold: attrs.allow_synthetic_bold.unwrap_or(true),
+        allow_synthetic_italic: attrs.allow_synthetic_italic.unwrap_or(true),
+        baseline_offset: attrs.baseline_offset,
+        horizontal_scale: attrs.horizontal_scale,
+        slant_degrees: attrs.slant_degrees,
     });
 
     Ok(text_style)
@@ -542,6 +593,24 @@ fn font_with_fallback<'lua>(
             attrs.stretch = map_defaults.stretch;
             attrs.italic = map_defaults.italic;
             text_style.foreground = map_defaults.foreground;
+            if map_defaults.assume_emoji_presentation.is_some() {
+                attrs.assume_emoji_presentation = map_defaults.assume_emoji_presentation;
+            }
+            if map_defaults.allow_synthetic_bold.is_some() {
+                attrs.allow_synthetic_bold = map_defaults.allow_synthetic_bold;
+            }
+            if map_defaults.allow_synthetic_italic.is_some() {
+                attrs.allow_synthetic_italic = map_defaults.allow_synthetic_italic;
+            }
+            if map_defaults.baseline_offset.is_some() {
+                attrs.baseline_offset = map_defaults.baseline_offset;
+            }
+            if map_defaults.horizontal_scale.is_some() {
+                attrs.horizontal_scale = map_defaults.horizontal_scale;
+            }
+            if map_defaults.slant_degrees.is_some() {
+                attrs.slant_degrees = map_defaults.slant_degrees;
+            }
         }
 
         text_style.font.push(FontAttributes {
@@ -551,6 +620,12 @@ fn font_with_fallback<'lua>(
             italic: attrs.italic,
             is_fallback: idx != 0,
             is_synthetic: false,
+            assume_emoji_presentation: attrs.assume_emoji_presentation,
+            allow_synthetic_bold: attrs.allow_synthetic_bold.unwrap_or(true),
+            allow_synthetic_italic: attrs.allow_synthetic_italic.unwrap_or(true),
+            baseline_offset: attrs.baseline_offset,
+            horizontal_scale: attrs.horizontal_scale,
+            slant_degrees: attrs.slant_degrees,
         });
     }
 
@@ -5,3 +5,5 @@
 #include <freetype/ftmodapi.h>
 #include <freetype/ftoutln.h>
 #include <freetype/ftmm.h>
+#include <freetype/ftfntfmt.h>
+#include <freetype/ftadvanc.h>
@@ -1804,3 +1804,15 @@ extern "C" {
 extern "C" {
     pub fn FT_Set_Named_Instance(face: FT_Face, instance_index: FT_UInt) -> FT_Error;
 }
+extern "C" {
+    pub fn FT_Get_Font_Format(face: FT_Face) -> *const ::std::os::raw::c_char;
+}
+pub const FT_ADVANCE_FLAG_FAST_ONLY: u32 = 536870912;
+extern "C" {
+    pub fn FT_Get_Advance(
+        face: FT_Face,
+        gindex: FT_UInt,
+        load_flags: FT_Int32,
+        padvance: *mut FT_Fixed,
+    ) -> FT_Error;
+}
@@ -0,0 +1,166 @@
+//! Support for font data that has been compressed with a general
+//! purpose compression algorithm, such as when transferring fonts
+//! over a bandwidth constrained connection.
+//!
+//! Compressed font data is wrapped in a small header that allows
+//! us to detect it and decompress it before passing it to freetype.
+//! All multi-byte values are little-endian.
+//!
+//! | offset | size | meaning                                        |
+//! |--------|------|------------------------------------------------|
+//! | 0      | 4    | magic: the bytes `WZFC`                        |
+//! | 4      | 1    | format version; currently 1                    |
+//! | 5      | 1    | algorithm: 1 for zstd, 2 for brotli            |
+//! | 6      | 2    | reserved; must be zero                         |
+//! | 8      | 8    | length of the decompressed data                |
+//! | 16     | ...  | the compressed font data                       |
+use crate::locator::FontTooLarge;
+use anyhow::{anyhow, bail, Context};
+use std::convert::TryInto;
+use std::io::Read;
+
+pub const MAGIC: &[u8; 4] = b"WZFC";
+pub const HEADER_LEN: usize = 16;
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Zstd = 1,
+    Brotli = 2,
+}
+
+/// Returns true if `data` starts with the compression wrapper magic
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Produce the header for `decompressed_len` bytes of font data
+/// compressed using `algorithm`
+pub fn make_header(algorithm: Algorithm, decompressed_len: u64) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[0..4].copy_from_slice(MAGIC);
+    header[4] = VERSION;
+    header[5] = algorithm as u8;
+    header[8..16].copy_from_slice(&decompressed_len.to_le_bytes());
+    header
+}
+
+/// If `data` is wrapped in the compression header, returns the
+/// decompressed font data.  Returns None if `data` is not compressed.
+/// `name` identifies the source of the data in any error messages.
+/// Decompression fails with `FontTooLarge` rather than producing
+/// more than `limit` bytes, even if the header understates the length.
+pub fn decompress(data: &[u8], name: &str, limit: u64) -> anyhow::Result<Option<Vec<u8>>> {
+    if !is_compressed(data) {
+        return Ok(None);
+    }
+    if data.len() < HEADER_LEN {
+        bail!("{}: compressed font header is truncated", name);
+    }
+    if data[4] != VERSION {
+        bail!("{}: unsupported compressed font version {}", name, data[4]);
+    }
+    let algorithm = match data[5] {
+        1 => Algorithm::Zstd,
+        2 => Algorithm::Brotli,
+        n => bail!("{}: unknown compression algorithm {}", name, n),
+    };
+    let expected_len: usize = u64::from_le_bytes(data[8..16].try_into().unwrap())
+        .try_into()
+        .map_err(|_| anyhow!("{}: decompressed font is too large", name))?;
+    FontTooLarge::check(name, expected_len as u64, limit)?;
+    let payload = &data[HEADER_LEN..];
+
+    let decoder: Box<dyn Read> = match algorithm {
+        Algorithm::Zstd => {
+            Box::new(zstd::stream::read::Decoder::new(payload).with_context(|| {
+                format!("decompressing {:?} font data from {}", algorithm, name)
+            })?)
+        }
+        Algorithm::Brotli => Box::new(brotli_decompressor::Decompressor::new(payload, 4096)),
+    };
+    // Read one byte more than the limit, so that we can tell whether
+    // the data would have exceeded it
+    let mut decompressed = Vec::with_capacity(expected_len);
+    decoder
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut decompressed)
+        .with_context(|| format!("decompressing {:?} font data from {}", algorithm, name))?;
+
+    if decompressed.len() as u64 > limit {
+        return Err(FontTooLarge {
+            name: name.to_string(),
+            len: None,
+            limit,
+        }
+        .into());
+    }
+
+    if decompressed.len() != expected_len {
+        bail!(
+            "{}: decompressed font length {} doesn't match the header length {}",
+            name,
+            decompressed.len(),
+            expected_len
+        );
+    }
+
+    Ok(Some(decompressed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn zstd_round_trip() {
+        let font = b"not really a font, but it will do".to_vec();
+        let mut data = make_header(Algorithm::Zstd, font.len() as u64).to_vec();
+        data.append(&mut zstd::stream::encode_all(&font[..], 0).unwrap());
+
+        assert_eq!(decompress(&data, "test", 1024).unwrap(), Some(font.clone()));
+        assert_eq!(decompress(&font, "test", 1024).unwrap(), None);
+
+        // Corrupt the payload and verify that the source is named
+        let len = data.len();
+        data.truncate(len - 4);
+        let err = decompress(&data, "my-font.ttf.zst", 1024).unwrap_err();
+        assert!(format!("{:#}", err).contains("my-font.ttf.zst"));
+    }
+
+    #[test]
+    fn decompression_limit() {
+        // A megabyte of zeroes compresses to a few dozen bytes
+        let zeroes = vec![0u8; 1024 * 1024];
+        let payload = zstd::stream::encode_all(&zeroes[..], 19).unwrap();
+        assert!(payload.len() < 1024, "{}", payload.len());
+        let bomb = |claimed_len: u64| {
+            let mut data = make_header(Algorithm::Zstd, claimed_len).to_vec();
+            data.extend_from_slice(&payload);
+            data
+        };
+
+        let limit = 64 * 1024;
+        assert!(
+            decompress(&bomb(zeroes.len() as u64), "bomb", 2 * 1024 * 1024)
+                .unwrap()
+                .is_some()
+        );
+
+        // The header announces that the data is too large
+        let err = decompress(&bomb(zeroes.len() as u64), "bomb.zst", limit).unwrap_err();
+        let too_large = err.downcast_ref::<FontTooLarge>().unwrap();
+        assert_eq!(too_large.len, Some(zeroes.len() as u64));
+        assert_eq!(
+            err.to_string(),
+            "bomb.zst is larger than the font_max_file_size limit of 65536 bytes (1048576 bytes)"
+        );
+
+        // The header understates the length, so decompression has to
+        // be stopped at the limit
+        let err = decompress(&bomb(16), "bomb.zst", limit).unwrap_err();
+        let too_large = err.downcast_ref::<FontTooLarge>().unwrap();
+        assert_eq!(too_large.len, None);
+        assert_eq!(too_large.limit, limit);
+    }
+}
@@ -0,0 +1,216 @@
+//! Helpers for comparing the codepoint coverage of fonts
+use rangeset::RangeSet;
+
+/// The bucket that codepoints outside of `SCRIPT_BUCKETS` belong to
+pub const OTHER_BUCKET: &str = "Other";
+
+/// Broad groupings of the Unicode blocks, used to summarize coverage.
+/// Each entry is an inclusive range; the entries are sorted and
+/// don't overlap.
+const SCRIPT_BUCKETS: &[(u32, u32, &str)] = &[
+    // Basic Latin through Combining Diacritical Marks
+    (0x0000, 0x036f, "Latin"),
+    (0x0370, 0x03ff, "Greek"),
+    (0x0400, 0x052f, "Cyrillic"),
+    (0x0530, 0x058f, "Armenian"),
+    (0x0590, 0x05ff, "Hebrew"),
+    (0x0600, 0x06ff, "Arabic"),
+    (0x0750, 0x077f, "Arabic"),
+    (0x08a0, 0x08ff, "Arabic"),
+    // Devanagari through Malayalam
+    (0x0900, 0x0dff, "Indic"),
+    // Thai, Lao
+    (0x0e00, 0x0eff, "Southeast Asian"),
+    // Myanmar
+    (0x1000, 0x109f, "Southeast Asian"),
+    (0x10a0, 0x10ff, "Georgian"),
+    // Hangul Jamo
+    (0x1100, 0x11ff, "Hangul"),
+    // Khmer
+    (0x1780, 0x17ff, "Southeast Asian"),
+    (0x1c80, 0x1c8f, "Cyrillic"),
+    // Latin Extended Additional
+    (0x1e00, 0x1eff, "Latin"),
+    // Greek Extended
+    (0x1f00, 0x1fff, "Greek"),
+    // General Punctuation through Enclosed Alphanumerics
+    (0x2000, 0x24ff, "Symbols"),
+    // Box Drawing, Block Elements
+    (0x2500, 0x259f, "Box Drawing"),
+    // Geometric Shapes
+    (0x25a0, 0x25ff, "Symbols"),
+    // Miscellaneous Symbols, Dingbats
+    (0x2600, 0x27bf, "Emoji"),
+    // Miscellaneous Mathematical Symbols-A through
+    // Miscellaneous Symbols and Arrows
+    (0x27c0, 0x2bff, "Symbols"),
+    // Latin Extended-C
+    (0x2c60, 0x2c7f, "Latin"),
+    (0x2de0, 0x2dff, "Cyrillic"),
+    // CJK Radicals Supplement through Bopomofo
+    (0x2e80, 0x312f, "CJK"),
+    // Hangul Compatibility Jamo
+    (0x3130, 0x318f, "Hangul"),
+    // Kanbun through CJK Unified Ideographs
+    (0x3190, 0x9fff, "CJK"),
+    (0xa640, 0xa69f, "Cyrillic"),
+    // Latin Extended-D
+    (0xa720, 0xa7ff, "Latin"),
+    // Hangul Syllables, Hangul Jamo Extended-B
+    (0xac00, 0xd7ff, "Hangul"),
+    // The Private Use Area, which is where icon fonts such as
+    // the Nerd Fonts and Powerline symbols live
+    (0xe000, 0xf8ff, "Private Use"),
+    // CJK Compatibility Ideographs
+    (0xf900, 0xfaff, "CJK"),
+    // Arabic Presentation Forms-A
+    (0xfb50, 0xfdff, "Arabic"),
+    // CJK Compatibility Forms
+    (0xfe30, 0xfe4f, "CJK"),
+    // Arabic Presentation Forms-B
+    (0xfe70, 0xfeff, "Arabic"),
+    // Halfwidth and Fullwidth Forms
+    (0xff00, 0xffef, "CJK"),
+    // Mahjong Tiles through Symbols and Pictographs Extended-A
+    (0x1f000, 0x1faff, "Emoji"),
+    // CJK Unified Ideographs Extension B and later
+    (0x20000, 0x3ffff, "CJK"),
+    // Supplementary Private Use Areas
+    (0xf0000, 0x10ffff, "Private Use"),
+];
+
+/// Returns the names of the script buckets, in the order in which
+/// they are first listed, followed by `OTHER_BUCKET`
+pub fn script_bucket_names() -> Vec<&'static str> {
+    let mut names = vec![];
+    for &(_, _, name) in SCRIPT_BUCKETS {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names.push(OTHER_BUCKET);
+    names
+}
+
+/// Returns the name of the script bucket that `c` belongs to
+pub fn script_bucket(c: u32) -> &'static str {
+    match SCRIPT_BUCKETS.binary_search_by(|&(start, end, _)| {
+        if end < c {
+            std::cmp::Ordering::Less
+        } else if start > c {
+            std::cmp::Ordering::Greater
+        } else {
+            std::cmp::Ordering::Equal
+        }
+    }) {
+        Ok(idx) => SCRIPT_BUCKETS[idx].2,
+        Err(_) => OTHER_BUCKET,
+    }
+}
+
+/// Counts the codepoints of `set` that belong to each script bucket,
+/// returning an entry for every bucket in the order given by
+/// `script_bucket_names`, including those with no codepoints
+pub fn count_by_script(set: &RangeSet<u32>) -> Vec<(&'static str, u32)> {
+    let mut counts: Vec<(&'static str, u32)> = script_bucket_names()
+        .into_iter()
+        .map(|name| (name, 0))
+        .collect();
+    let mut bucketed = 0;
+    for &(start, end, name) in SCRIPT_BUCKETS {
+        let count = set.intersection_with_range(start..end + 1).len();
+        if let Some(entry) = counts.iter_mut().find(|(n, _)| *n == name) {
+            entry.1 += count;
+        }
+        bucketed += count;
+    }
+    if let Some(other) = counts.last_mut() {
+        other.1 = set.len() - bucketed;
+    }
+    counts
+}
+
+/// The result of comparing the coverage of two fonts
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverageDiff {
+    /// The codepoints that only the first font covers
+    pub only_in_a: RangeSet<u32>,
+    /// The codepoints that only the second font covers
+    pub only_in_b: RangeSet<u32>,
+    /// The codepoints that both fonts cover
+    pub common: RangeSet<u32>,
+}
+
+impl CoverageDiff {
+    pub fn new(a: &RangeSet<u32>, b: &RangeSet<u32>) -> Self {
+        Self {
+            only_in_a: a.difference(b),
+            only_in_b: b.difference(a),
+            common: a.intersection(b),
+        }
+    }
+
+    /// Returns the number of codepoints that are only in the first
+    /// font, only in the second font, and in both fonts, for each
+    /// of the script buckets in which either one has coverage
+    pub fn summarize_by_script(&self) -> Vec<(&'static str, u32, u32, u32)> {
+        count_by_script(&self.only_in_a)
+            .into_iter()
+            .zip(count_by_script(&self.only_in_b))
+            .zip(count_by_script(&self.common))
+            .map(|(((name, a), (_, b)), (_, common))| (name, a, b, common))
+            .filter(|&(_, a, b, common)| a + b + common > 0)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets_are_sorted() {
+        for pair in SCRIPT_BUCKETS.windows(2) {
+            assert!(pair[0].0 <= pair[0].1);
+            assert!(
+                pair[0].1 < pair[1].0,
+                "{:x?} overlaps {:x?}",
+                pair[0],
+                pair[1]
+            );
+        }
+        assert_eq!(script_bucket('A' as u32), "Latin");
+        assert_eq!(script_bucket(0x2502), "Box Drawing");
+        assert_eq!(script_bucket(0xe0b0), "Private Use");
+        assert_eq!(script_bucket(0x1f600), "Emoji");
+        assert_eq!(script_bucket(0x0780), OTHER_BUCKET);
+    }
+
+    #[test]
+    fn diff() {
+        let set = |ranges: &[std::ops::Range<u32>]| {
+            let mut set = RangeSet::new();
+            for range in ranges {
+                set.add_range(range.clone());
+            }
+            set
+        };
+        let a = set(&[0x20..0x7f, 0x391..0x3a2]);
+        let b = set(&[0x41..0x5b, 0x2500..0x2580, 0x780..0x782]);
+
+        let diff = CoverageDiff::new(&a, &b);
+        assert_eq!(diff.common, set(&[0x41..0x5b]));
+        assert_eq!(diff.only_in_a, set(&[0x20..0x41, 0x5b..0x7f, 0x391..0x3a2]));
+        assert_eq!(diff.only_in_b, set(&[0x2500..0x2580, 0x780..0x782]));
+
+        assert_eq!(
+            diff.summarize_by_script(),
+            vec![
+                ("Latin", 69, 0, 26),
+                ("Greek", 17, 0, 0),
+                ("Box Drawing", 0, 128, 0),
+                (OTHER_BUCKET, 0, 2, 0),
+            ]
+        );
+    }
+}
@@ -1,28 +1,49 @@
 //! A font-database to keep track of fonts that we've located
 
-use crate::locator::{FontDataSource, FontOrigin};
-use crate::parser::{load_built_in_fonts, parse_and_collect_font_info, ParsedFont};
+use crate::locator::{FontDataSource, FontOrigin, FontTooLarge};
+use crate::parser::{
+    load_built_in_fonts, parse_and_collect_font_info, ParsedFont, ResourceForkSuitcase,
+};
 use anyhow::Context;
-use config::{Config, FontAttributes};
+use config::{Config, FontAttributes, FontFormat};
 use rangeset::RangeSet;
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 
 pub struct FontDatabase {
     by_full_name: HashMap<String, ParsedFont>,
+    /// The font_allowed_formats from the configuration, which
+    /// limits the fonts that are considered as fallbacks
+    allowed_formats: Option<Vec<FontFormat>>,
+    /// The files that were skipped because they are larger than
+    /// font_max_file_size
+    too_large: Vec<FontTooLarge>,
 }
 
 impl FontDatabase {
     pub fn new() -> Self {
         Self {
             by_full_name: HashMap::new(),
+            allowed_formats: None,
+            too_large: vec![],
         }
     }
 
     fn load_font_info(&mut self, font_info: Vec<ParsedFont>) {
         for parsed in font_info {
-            self.by_full_name
-                .entry(parsed.names().full_name.clone())
-                .or_insert(parsed);
+            match self.by_full_name.get_mut(&parsed.names().full_name) {
+                // When there are duplicate copies of a font, keep the
+                // same one regardless of the order that we found them
+                Some(existing) => {
+                    if ParsedFont::tie_break(&parsed, existing) == Ordering::Less {
+                        *existing = parsed;
+                    }
+                }
+                None => {
+                    self.by_full_name
+                        .insert(parsed.names().full_name.clone(), parsed);
+                }
+            }
         }
     }
 
@@ -30,6 +51,7 @@ impl FontDatabase {
     /// and from the built-in selection of fonts
     pub fn with_font_dirs(config: &Config) -> anyhow::Result<Self> {
         let mut font_info = vec![];
+        let mut too_large = vec![];
         for path in &config.font_dirs {
             for entry in walkdir::WalkDir::new(path).into_iter() {
                 let entry = match entry {
@@ -38,26 +60,44 @@ impl FontDatabase {
                 };
 
                 let source = FontDataSource::OnDisk(entry.path().to_path_buf());
-                parse_and_collect_font_info(&source, &mut font_info, FontOrigin::FontDirs)
-                    .map_err(|err| {
-                        log::trace!("failed to read {:?}: {:#}", source, err);
-                        err
-                    })
-                    .ok();
+                if let Err(err) =
+                    parse_and_collect_font_info(&source, &mut font_info, FontOrigin::FontDirs)
+                {
+                    // Most of the failures are for files that aren't
+                    // fonts, but a suitcase or an oversized file is a
+                    // font that the user probably expected to be able
+                    // to use
+                    match err.downcast::<FontTooLarge>() {
+                        Ok(err) => {
+                            log::warn!("Skipping {}", err);
+                            too_large.push(err);
+                        }
+                        Err(err) if err.downcast_ref::<ResourceForkSuitcase>().is_some() => {
+                            log::warn!("{:#}", err);
+                        }
+                        Err(err) => log::trace!("failed to read {:?}: {:#}", source, err),
+                    }
+                }
             }
         }
 
         let mut db = Self::new();
+        db.allowed_formats = config.font_allowed_formats.clone();
+        db.too_large = too_large;
         db.load_font_info(font_info);
         log::debug!("Available fonts from font_dirs:");
         db.print_available();
         Ok(db)
     }
 
+    /// Returns the files in font_dirs that were skipped because they
+    /// are larger than font_max_file_size
+    pub fn too_large(&self) -> &[FontTooLarge] {
+        &self.too_large
+    }
+
     pub fn print_available(&self) {
-        let mut fonts = self.by_full_name.values().collect::<Vec<_>>();
-        fonts.sort();
-        for font in fonts {
+        for font in self.fonts() {
             log::debug!("available font: {}", font.lua_name());
         }
     }
@@ -82,8 +122,9 @@ impl FontDatabase {
             if loaded.contains(attr) {
                 continue;
             }
-            if let Some(handle) = self.resolve(attr) {
-                handles.push(handle.clone());
+            let candidates = self.candidates(attr).into_iter().cloned().collect();
+            if let Some(parsed) = ParsedFont::best_match(attr, candidates) {
+                handles.push(parsed);
                 loaded.insert(attr.clone());
             }
         }
@@ -102,7 +143,12 @@ impl FontDatabase {
         let mut matches = vec![];
 
         for parsed in self.by_full_name.values() {
-            if parsed.names().family == "Last Resort High-Efficiency" {
+            // It covers everything, and is always searched last anyway
+            if parsed.is_last_resort() {
+                continue;
+            }
+            // Check this first, as computing the coverage is expensive
+            if !self.format_allowed(parsed) {
                 continue;
             }
             let covered = parsed
@@ -116,18 +162,28 @@ impl FontDatabase {
         Ok(matches)
     }
 
-    pub fn resolve(&self, font_attr: &FontAttributes) -> Option<&ParsedFont> {
-        let candidates: Vec<&ParsedFont> = self
-            .by_full_name
+    /// Returns true if font_allowed_formats permits `parsed` to be
+    /// used as a fallback font
+    fn format_allowed(&self, parsed: &ParsedFont) -> bool {
+        FontFormat::is_allowed(parsed.format(), self.allowed_formats.as_deref())
+    }
+
+    /// Returns all of the fonts in the database, sorted by name
+    pub fn fonts(&self) -> Vec<&ParsedFont> {
+        let mut fonts = self.by_full_name.values().collect::<Vec<_>>();
+        fonts.sort();
+        fonts
+    }
+
+    fn candidates(&self, font_attr: &FontAttributes) -> Vec<&ParsedFont> {
+        self.by_full_name
             .values()
-            .filter_map(|parsed| {
-                if parsed.matches_name(font_attr) {
-                    Some(parsed)
-                } else {
-                    None
-                }
-            })
-            .collect();
+            .filter(|parsed| parsed.matches_name(font_attr))
+            .collect()
+    }
+
+    pub fn resolve(&self, font_attr: &FontAttributes) -> Option<&ParsedFont> {
+        let candidates = self.candidates(font_attr);
 
         if let Some(idx) = ParsedFont::best_matching_index(font_attr, &candidates) {
             return candidates.get(idx).map(|&p| p);
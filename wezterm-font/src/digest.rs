@@ -0,0 +1,197 @@
+//! Produces a stable digest of a set of rendered glyphs, so that
+//! changes to the rendering of a font can be detected by tests
+//! rather than by eye.
+//!
+//! The digest covers the pixels and metrics of each glyph, normalized
+//! so that it doesn't depend upon row padding or the addresses of any
+//! buffers.  The version string of the font is included too, so that
+//! the digest identifies the build of the font that was rendered.
+//! It is reproducible across runs on the same build of freetype, but
+//! will legitimately change when freetype is upgraded, so the freetype
+//! version is reported alongside it.
+use crate::ftwrap::{self, FT_Int32, FT_Pixel_Mode, FT_Render_Mode};
+use crate::locator::FontDataHandle;
+use anyhow::Context;
+
+/// The text rendered when no other sample is specified
+pub const DEFAULT_SAMPLE_TEXT: &str =
+    "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789!@#$%&*()[]{}<>/\\|=-+_~";
+
+/// Describes what to render when computing a digest
+#[derive(Debug, Clone)]
+pub struct DigestOptions {
+    /// The glyphs to render
+    pub text: String,
+    /// Point size
+    pub size: f64,
+    pub dpi: u32,
+    pub load_flags: FT_Int32,
+    pub render_mode: FT_Render_Mode,
+}
+
+impl DigestOptions {
+    /// Render `DEFAULT_SAMPLE_TEXT` at the specified size, using the
+    /// load flags and render mode from the configuration
+    pub fn new(size: f64, dpi: u32) -> Self {
+        let (load_flags, render_mode) = ftwrap::compute_load_flags_from_config();
+        Self {
+            text: DEFAULT_SAMPLE_TEXT.to_string(),
+            size,
+            dpi,
+            load_flags,
+            render_mode,
+        }
+    }
+}
+
+/// The result of `render_digest`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderDigest {
+    pub digest: u64,
+    /// The number of glyphs that were rendered
+    pub num_glyphs: usize,
+    /// The (major, minor, patch) version of freetype that
+    /// rendered the glyphs
+    pub freetype_version: (i32, i32, i32),
+}
+
+impl std::fmt::Display for RenderDigest {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (major, minor, patch) = self.freetype_version;
+        write!(
+            fmt,
+            "{:016x} ({} glyphs, FreeType {}.{}.{})",
+            self.digest, self.num_glyphs, major, minor, patch
+        )
+    }
+}
+
+/// A 64-bit FNV-1a hash.  We use this rather than the hashers from
+/// std because their output isn't guaranteed to be stable.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn int(&mut self, value: i64) {
+        self.bytes(&value.to_le_bytes());
+    }
+}
+
+/// Render the glyphs described by `options` from the font identified
+/// by `handle` and compute their digest
+pub fn render_digest(
+    handle: &FontDataHandle,
+    options: &DigestOptions,
+) -> anyhow::Result<RenderDigest> {
+    let lib = ftwrap::Library::new()?;
+    let mut face = lib.face_from_locator(handle)?;
+    face.set_font_size(options.size, options.dpi)?;
+
+    let mut hash = Fnv1a::new();
+    unsafe {
+        let metrics = &(*(*face.face).size).metrics;
+        hash.int(metrics.ascender as i64);
+        hash.int(metrics.descender as i64);
+        hash.int(metrics.height as i64);
+    }
+    // Fold in the version of the font, so that upgrading it in
+    // place changes the digest even if the sample renders the same
+    if let Some(version) = face.version_string() {
+        hash.bytes(version.as_bytes());
+    }
+
+    let mut num_glyphs = 0;
+    for c in options.text.chars() {
+        hash.int(c as i64);
+        let glyph_index = unsafe { ftwrap::FT_Get_Char_Index(face.face, c as _) };
+        hash.int(glyph_index as i64);
+        if glyph_index == 0 {
+            continue;
+        }
+
+        let slot = face
+            .load_and_render_glyph(glyph_index, options.load_flags, options.render_mode, None)
+            .with_context(|| format!("render_digest: rendering {:?}", c))?;
+        num_glyphs += 1;
+
+        let bitmap = &slot.bitmap;
+        hash.int(slot.advance.x as i64);
+        hash.int(slot.advance.y as i64);
+        hash.int(slot.bitmap_left as i64);
+        hash.int(slot.bitmap_top as i64);
+        hash.int(bitmap.width as i64);
+        hash.int(bitmap.rows as i64);
+        hash.int(bitmap.pixel_mode as i64);
+
+        // Only hash the meaningful bytes of each row, so that the
+        // digest doesn't depend on how the rows were padded
+        let mode: FT_Pixel_Mode = unsafe { std::mem::transmute(u32::from(bitmap.pixel_mode)) };
+        let width = bitmap.width as usize;
+        let row_bytes = match mode {
+            FT_Pixel_Mode::FT_PIXEL_MODE_MONO => (width + 7) / 8,
+            FT_Pixel_Mode::FT_PIXEL_MODE_GRAY2 => (width + 3) / 4,
+            FT_Pixel_Mode::FT_PIXEL_MODE_GRAY4 => (width + 1) / 2,
+            FT_Pixel_Mode::FT_PIXEL_MODE_BGRA => width * 4,
+            _ => width,
+        };
+        let pitch = bitmap.pitch.abs() as usize;
+        if bitmap.buffer.is_null() || row_bytes > pitch {
+            continue;
+        }
+        let data = ftwrap::bitmap_rows_top_down(bitmap);
+        for row in data.chunks(pitch) {
+            hash.bytes(&row[..row_bytes]);
+        }
+    }
+
+    Ok(RenderDigest {
+        digest: hash.0,
+        num_glyphs,
+        freetype_version: lib.version(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FontDatabase;
+    use config::{FontAttributes, FontWeight};
+
+    fn digests(attr: &FontAttributes) -> (RenderDigest, RenderDigest) {
+        let db = FontDatabase::with_built_in().unwrap();
+        let handle = db.resolve(attr).unwrap().handle.clone();
+        (
+            render_digest(&handle, &DigestOptions::new(10., 96)).unwrap(),
+            render_digest(&handle, &DigestOptions::new(16., 96)).unwrap(),
+        )
+    }
+
+    #[test]
+    fn bundled_fonts() {
+        config::use_test_configuration();
+
+        let regular = FontAttributes::new("JetBrains Mono");
+        let mut bold = regular.clone();
+        bold.weight = FontWeight::Bold;
+
+        for attr in &[regular, bold] {
+            let (small, large) = digests(attr);
+            assert_eq!(small.num_glyphs, DEFAULT_SAMPLE_TEXT.chars().count());
+            assert_eq!(large.num_glyphs, small.num_glyphs);
+            assert_ne!(small.digest, large.digest);
+
+            // Rendering again must produce exactly the same digests
+            assert_eq!(digests(attr), (small, large));
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Helpers for reasoning about emoji presentation
+
+/// Blocks whose codepoints are predominantly emoji
+const EMOJI_BLOCKS: &[(u32, u32)] = &[
+    // Miscellaneous Symbols, Dingbats
+    (0x2600, 0x27bf),
+    // Mahjong Tiles through Symbols and Pictographs Extended-A
+    (0x1f000, 0x1faff),
+    // Tags, used for emoji tag sequences
+    (0xe0000, 0xe007f),
+];
+
+/// Codepoints with the Emoji_Presentation property, derived from
+/// the Unicode emoji-data.txt file.  Each entry is an inclusive range.
+const EMOJI_PRESENTATION: &[(u32, u32)] = &[
+    (0x231a, 0x231b),
+    (0x23e9, 0x23ec),
+    (0x23f0, 0x23f0),
+    (0x23f3, 0x23f3),
+    (0x25fd, 0x25fe),
+    (0x2614, 0x2615),
+    (0x2648, 0x2653),
+    (0x267f, 0x267f),
+    (0x2693, 0x2693),
+    (0x26a1, 0x26a1),
+    (0x26aa, 0x26ab),
+    (0x26bd, 0x26be),
+    (0x26c4, 0x26c5),
+    (0x26ce, 0x26ce),
+    (0x26d4, 0x26d4),
+    (0x26ea, 0x26ea),
+    (0x26f2, 0x26f3),
+    (0x26f5, 0x26f5),
+    (0x26fa, 0x26fa),
+    (0x26fd, 0x26fd),
+    (0x2705, 0x2705),
+    (0x270a, 0x270b),
+    (0x2728, 0x2728),
+    (0x274c, 0x274c),
+    (0x274e, 0x274e),
+    (0x2753, 0x2755),
+    (0x2757, 0x2757),
+    (0x2795, 0x2797),
+    (0x27b0, 0x27b0),
+    (0x27bf, 0x27bf),
+    (0x2b1b, 0x2b1c),
+    (0x2b50, 0x2b50),
+    (0x2b55, 0x2b55),
+    (0x1f004, 0x1f004),
+    (0x1f0cf, 0x1f0cf),
+    (0x1f18e, 0x1f18e),
+    (0x1f191, 0x1f19a),
+    (0x1f1e6, 0x1f1ff),
+    (0x1f201, 0x1f201),
+    (0x1f21a, 0x1f21a),
+    (0x1f22f, 0x1f22f),
+    (0x1f232, 0x1f236),
+    (0x1f238, 0x1f23a),
+    (0x1f250, 0x1f251),
+    (0x1f300, 0x1f320),
+    (0x1f32d, 0x1f335),
+    (0x1f337, 0x1f37c),
+    (0x1f37e, 0x1f393),
+    (0x1f3a0, 0x1f3ca),
+    (0x1f3cf, 0x1f3d3),
+    (0x1f3e0, 0x1f3f0),
+    (0x1f3f4, 0x1f3f4),
+    (0x1f3f8, 0x1f43e),
+    (0x1f440, 0x1f440),
+    (0x1f442, 0x1f4fc),
+    (0x1f4ff, 0x1f53d),
+    (0x1f54b, 0x1f54e),
+    (0x1f550, 0x1f567),
+    (0x1f57a, 0x1f57a),
+    (0x1f595, 0x1f596),
+    (0x1f5a4, 0x1f5a4),
+    (0x1f5fb, 0x1f64f),
+    (0x1f680, 0x1f6c5),
+    (0x1f6cc, 0x1f6cc),
+    (0x1f6d0, 0x1f6d2),
+    (0x1f6d5, 0x1f6d7),
+    (0x1f6eb, 0x1f6ec),
+    (0x1f6f4, 0x1f6fc),
+    (0x1f7e0, 0x1f7eb),
+    (0x1f90c, 0x1f93a),
+    (0x1f93c, 0x1f945),
+    (0x1f947, 0x1f978),
+    (0x1f97a, 0x1f9cb),
+    (0x1f9cd, 0x1f9ff),
+    (0x1fa70, 0x1fa74),
+    (0x1fa78, 0x1fa7a),
+    (0x1fa80, 0x1fa86),
+    (0x1fa90, 0x1faa8),
+    (0x1fab0, 0x1fab6),
+    (0x1fac0, 0x1fac2),
+    (0x1fad0, 0x1fad6),
+];
+
+fn in_ranges(ranges: &[(u32, u32)], c: u32) -> bool {
+    ranges
+        .binary_search_by(|&(start, end)| {
+            if c < start {
+                std::cmp::Ordering::Greater
+            } else if c > end {
+                std::cmp::Ordering::Less
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+/// Returns true if `c` defaults to emoji presentation
+pub fn is_emoji_presentation(c: char) -> bool {
+    in_ranges(EMOJI_PRESENTATION, c as u32)
+}
+
+/// Selects text presentation for the preceding codepoint
+const VS15: char = '\u{fe0e}';
+/// Selects emoji presentation for the preceding codepoint
+const VS16: char = '\u{fe0f}';
+
+/// Returns true if `c` is one of the variation selectors, which select
+/// a variant of the preceding codepoint and have no appearance of
+/// their own
+pub fn is_variation_selector(c: char) -> bool {
+    matches!(c, '\u{fe00}'..='\u{fe0f}' | '\u{e0100}'..='\u{e01ef}')
+}
+
+/// Returns true if most of the codepoints in `text` are presented as
+/// emoji, either by default or because they are followed by VS16.
+/// A codepoint that defaults to emoji presentation but is followed by
+/// VS15 is presented as text.  The selectors themselves aren't counted.
+pub fn prefers_emoji_presentation(text: &[char]) -> bool {
+    let mut num_emoji = 0;
+    let mut num_chars = 0;
+    let mut iter = text.iter().peekable();
+    while let Some(&c) = iter.next() {
+        if c == VS15 || c == VS16 {
+            continue;
+        }
+        num_chars += 1;
+        let emoji = match iter.peek() {
+            Some(&&VS16) => true,
+            Some(&&VS15) => false,
+            _ => is_emoji_presentation(c),
+        };
+        if emoji {
+            num_emoji += 1;
+        }
+    }
+    num_emoji * 2 > num_chars
+}
+
+/// Returns true if `c` lies within one of the emoji blocks
+pub fn is_in_emoji_block(c: u32) -> bool {
+    in_ranges(EMOJI_BLOCKS, c)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn presentation() {
+        assert!(is_emoji_presentation('\u{1f600}'));
+        assert!(is_emoji_presentation('\u{231a}'));
+        assert!(is_emoji_presentation('\u{1fad6}'));
+        // Heavy black heart defaults to text presentation
+        assert!(!is_emoji_presentation('\u{2764}'));
+        assert!(!is_emoji_presentation('A'));
+        assert!(is_in_emoji_block(0x2764));
+    }
+
+    #[test]
+    fn variation_selectors() {
+        assert!(!prefers_emoji_presentation(&['\u{2764}']));
+        assert!(prefers_emoji_presentation(&['\u{2764}', VS16]));
+        assert!(prefers_emoji_presentation(&['\u{1f600}']));
+        assert!(!prefers_emoji_presentation(&['\u{1f600}', VS15]));
+        // The selectors aren't counted as text
+        assert!(prefers_emoji_presentation(&[
+            '\u{2764}',
+            VS16,
+            '\u{1f600}',
+            'A'
+        ]));
+        assert!(!prefers_emoji_presentation(&[VS16]));
+
+        assert!(is_variation_selector(VS15) && is_variation_selector(VS16));
+        assert!(is_variation_selector('\u{e0100}'));
+        assert!(!is_variation_selector('\u{2764}'));
+    }
+}
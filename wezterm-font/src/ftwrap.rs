@@ -1,12 +1,19 @@
 //! Higher level freetype bindings
 
-use crate::locator::{FontDataHandle, FontDataSource};
+use crate::compression;
+use crate::locator::{FontDataHandle, FontDataSource, FontOrigin, FontTooLarge};
 use crate::parser::ParsedFont;
+use crate::rasterizer::{DisplayInfo, SubpixelOrder};
+use crate::sfnt;
 use anyhow::{anyhow, Context};
-use config::{configuration, FreeTypeLoadTarget};
+use bitflags::bitflags;
+use config::{configuration, Config, FontFormat, FreeTypeLcdFilter, FreeTypeLoadTarget};
 pub use freetype::*;
 use memmap2::{Mmap, MmapOptions};
 use rangeset::RangeSet;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::ffi::CStr;
 use std::fs::File;
@@ -14,7 +21,8 @@ use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::os::raw::{c_uchar, c_ulong};
 use std::path::Path;
 use std::ptr;
-use std::sync::Arc;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 #[inline]
 pub fn succeeded(error: FT_Error) -> bool {
@@ -43,6 +51,42 @@ fn ft_result<T>(err: FT_Error, t: T) -> anyhow::Result<T> {
     }
 }
 
+/// Explain, in terms of what the user can do about it, the errors
+/// that FT_Open_Face most commonly produces for a font that can be
+/// read but not used.
+/// Returns None for the other errors, which are less actionable.
+fn describe_open_error(err: FT_Error, source: &FontDataSource) -> Option<String> {
+    // The low byte holds the error; the rest identifies the module
+    let code = (err & 0xff) as u32;
+    let is_any = |errors: &[freetype::_bindgen_ty_2]| errors.iter().any(|&e| e as u32 == code);
+    let name = source.name_or_path_str();
+    if is_any(&[freetype::FT_Err_Unknown_File_Format]) {
+        Some(format!(
+            "{} exists but is not a recognized font format",
+            name
+        ))
+    } else if is_any(&[
+        freetype::FT_Err_Invalid_File_Format,
+        freetype::FT_Err_Invalid_Table,
+        freetype::FT_Err_Invalid_Offset,
+        freetype::FT_Err_Table_Missing,
+        freetype::FT_Err_Invalid_Stream_Read,
+        freetype::FT_Err_Invalid_Stream_Operation,
+    ]) {
+        Some(format!(
+            "{} looks like a font but is malformed; it may be truncated or corrupt",
+            name
+        ))
+    } else if is_any(&[freetype::FT_Err_Cannot_Open_Resource]) {
+        Some(format!(
+            "{} could not be opened; check that it exists and is readable",
+            name
+        ))
+    } else {
+        None
+    }
+}
+
 fn render_mode_to_load_target(render_mode: FT_Render_Mode) -> u32 {
     // enable FT_LOAD_TARGET bits.  There are no flags defined
     // for these in the bindings so we do some bit magic for
@@ -51,728 +95,4615 @@ fn render_mode_to_load_target(render_mode: FT_Render_Mode) -> u32 {
     (render_mode as u32) & 15 << 16
 }
 
-pub fn compute_load_flags_from_config() -> (i32, FT_Render_Mode) {
-    let config = configuration();
+/// The names of the individual FT_LOAD_XXX bits, in the order that
+/// they are defined in freetype.h
+const LOAD_FLAG_NAMES: &[(u32, &str)] = &[
+    (FT_LOAD_NO_SCALE, "NO_SCALE"),
+    (FT_LOAD_NO_HINTING, "NO_HINTING"),
+    (FT_LOAD_RENDER, "RENDER"),
+    (FT_LOAD_NO_BITMAP, "NO_BITMAP"),
+    (FT_LOAD_VERTICAL_LAYOUT, "VERTICAL_LAYOUT"),
+    (FT_LOAD_FORCE_AUTOHINT, "FORCE_AUTOHINT"),
+    (FT_LOAD_CROP_BITMAP, "CROP_BITMAP"),
+    (FT_LOAD_PEDANTIC, "PEDANTIC"),
+    (FT_LOAD_ADVANCE_ONLY, "ADVANCE_ONLY"),
+    (
+        FT_LOAD_IGNORE_GLOBAL_ADVANCE_WIDTH,
+        "IGNORE_GLOBAL_ADVANCE_WIDTH",
+    ),
+    (FT_LOAD_NO_RECURSE, "NO_RECURSE"),
+    (FT_LOAD_IGNORE_TRANSFORM, "IGNORE_TRANSFORM"),
+    (FT_LOAD_MONOCHROME, "MONOCHROME"),
+    (FT_LOAD_LINEAR_DESIGN, "LINEAR_DESIGN"),
+    (FT_LOAD_SBITS_ONLY, "SBITS_ONLY"),
+    (FT_LOAD_NO_AUTOHINT, "NO_AUTOHINT"),
+    (FT_LOAD_COLOR, "COLOR"),
+    (FT_LOAD_COMPUTE_METRICS, "COMPUTE_METRICS"),
+    (FT_LOAD_BITMAP_METRICS_ONLY, "BITMAP_METRICS_ONLY"),
+];
 
-    let load_flags = config.freetype_load_flags.bits() | FT_LOAD_COLOR;
+/// Bits 16-19 hold the FT_LOAD_TARGET_XXX value
+const LOAD_TARGET_MASK: u32 = 15 << 16;
+
+/// Decode `load_flags` into a human readable list of the FT_LOAD_XXX
+/// names that it is composed of, such as `COLOR|NO_HINTING|TARGET_LIGHT`.
+/// Any bits that we don't know the name of are shown in hex at the end.
+pub fn describe_load_flags(load_flags: FT_Int32) -> String {
+    let flags = load_flags as u32;
+    let mut names = vec![];
+    let mut unknown = flags & !LOAD_TARGET_MASK;
+
+    for (bit, name) in LOAD_FLAG_NAMES {
+        if flags & bit != 0 {
+            names.push(name.to_string());
+            unknown &= !bit;
+        }
+    }
+
+    let target = match (flags & LOAD_TARGET_MASK) >> 16 {
+        0 => "TARGET_NORMAL".to_string(),
+        1 => "TARGET_LIGHT".to_string(),
+        2 => "TARGET_MONO".to_string(),
+        3 => "TARGET_LCD".to_string(),
+        4 => "TARGET_LCD_V".to_string(),
+        n => format!("TARGET_0x{:x}", n),
+    };
+    names.push(target);
+
+    if unknown != 0 {
+        names.push(format!("0x{:x}", unknown));
+    }
+
+    names.join("|")
+}
+
+lazy_static::lazy_static! {
+    /// When set in the environment, load_and_render_glyph will log
+    /// the load flags and render mode used for each glyph.
+    static ref TRACE_LOAD_FLAGS: bool =
+        std::env::var_os("WEZTERM_FONT_TRACE_LOAD_FLAGS").is_some();
+}
+
+/// The load flags and render mode used to rasterize glyphs for
+/// a particular display
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LoadFlags {
+    pub load_flags: FT_Int32,
+    pub render_mode: FT_Render_Mode,
+    /// The order of the subpixels that LCD glyphs are rendered for.
+    /// freetype always renders LCD coverage in RGB order, so the
+    /// rasterizer swaps the channels for BGR displays.
+    pub subpixel_order: SubpixelOrder,
+    /// Whether glyphs that turn out to be in color are rendered with
+    /// FT_RENDER_MODE_NORMAL rather than `render_mode`
+    pub normal_render_for_color_glyphs: bool,
+}
+
+impl LoadFlags {
+    /// Returns true if LCD coverage must have its red and blue
+    /// channels swapped to match the display
+    pub fn is_bgr(&self) -> bool {
+        self.subpixel_order.is_bgr()
+    }
+}
+
+fn target_to_render(t: FreeTypeLoadTarget) -> FT_Render_Mode {
+    match t {
+        FreeTypeLoadTarget::Mono => FT_Render_Mode::FT_RENDER_MODE_MONO,
+        FreeTypeLoadTarget::Normal => FT_Render_Mode::FT_RENDER_MODE_NORMAL,
+        FreeTypeLoadTarget::Light => FT_Render_Mode::FT_RENDER_MODE_LIGHT,
+        FreeTypeLoadTarget::HorizontalLcd => FT_Render_Mode::FT_RENDER_MODE_LCD,
+        FreeTypeLoadTarget::VerticalLcd => FT_Render_Mode::FT_RENDER_MODE_LCD_V,
+    }
+}
 
-    fn target_to_render(t: FreeTypeLoadTarget) -> FT_Render_Mode {
-        match t {
-            FreeTypeLoadTarget::Mono => FT_Render_Mode::FT_RENDER_MODE_MONO,
-            FreeTypeLoadTarget::Normal => FT_Render_Mode::FT_RENDER_MODE_NORMAL,
-            FreeTypeLoadTarget::Light => FT_Render_Mode::FT_RENDER_MODE_LIGHT,
-            FreeTypeLoadTarget::HorizontalLcd => FT_Render_Mode::FT_RENDER_MODE_LCD,
-            FreeTypeLoadTarget::VerticalLcd => FT_Render_Mode::FT_RENDER_MODE_LCD_V,
+/// Orient an LCD render mode to match the subpixels of the display.
+/// Other modes, and displays whose geometry is unknown, are left
+/// as configured.
+pub(crate) fn orient_lcd_mode(mode: FT_Render_Mode, order: SubpixelOrder) -> FT_Render_Mode {
+    match (mode, order) {
+        (FT_Render_Mode::FT_RENDER_MODE_LCD, SubpixelOrder::VerticalRgb)
+        | (FT_Render_Mode::FT_RENDER_MODE_LCD, SubpixelOrder::VerticalBgr) => {
+            FT_Render_Mode::FT_RENDER_MODE_LCD_V
+        }
+        (FT_Render_Mode::FT_RENDER_MODE_LCD_V, SubpixelOrder::Rgb)
+        | (FT_Render_Mode::FT_RENDER_MODE_LCD_V, SubpixelOrder::Bgr) => {
+            FT_Render_Mode::FT_RENDER_MODE_LCD
         }
+        (mode, _) => mode,
     }
+}
+
+/// Compute the load flags and render mode from the configuration,
+/// for rendering to `display`.
+/// When the configuration selects an LCD target, its orientation is
+/// adjusted to match the subpixel geometry of the display, if known.
+/// The dpi of the display doesn't influence the flags.
+pub fn compute_load_flags(config: &Config, display: &DisplayInfo) -> LoadFlags {
+    let load_flags = config.freetype_load_flags.bits() | FT_LOAD_COLOR;
+    let order = display.subpixel_order;
 
-    let load_target = target_to_render(config.freetype_load_target);
-    let render = target_to_render(
-        config
-            .freetype_render_target
-            .unwrap_or(config.freetype_load_target),
+    let load_target = orient_lcd_mode(target_to_render(config.freetype_load_target), order);
+    let render_mode = orient_lcd_mode(
+        target_to_render(
+            config
+                .freetype_render_target
+                .unwrap_or(config.freetype_load_target),
+        ),
+        order,
     );
 
     let load_flags = load_flags | render_mode_to_load_target(load_target);
 
-    (load_flags as i32, render)
+    LoadFlags {
+        load_flags: load_flags as i32,
+        render_mode,
+        subpixel_order: order,
+        normal_render_for_color_glyphs: config.freetype_normal_render_for_color_glyphs,
+    }
+}
+
+/// Compute the load flags and render mode from the configuration for
+/// a display whose geometry is unknown.  This is appropriate for
+/// uses that don't render to a particular window.
+pub fn compute_load_flags_from_config() -> (i32, FT_Render_Mode) {
+    let flags = compute_load_flags(&configuration(), &DisplayInfo::default());
+    (flags.load_flags, flags.render_mode)
 }
 
 pub struct Face {
     pub face: FT_Face,
     source: FontDataHandle,
     size: Option<FaceSize>,
+    /// Sizes that have previously been selected, keyed by
+    /// the bits of the point size, the dpi and the transform
+    size_cache: HashMap<SizeKey, FaceSize>,
+    /// The advances, in 26.6, of glyphs that rendered without any
+    /// pixels, keyed by glyph index, the effective load flags and
+    /// the `size_key` that they were rendered at.  Cleared when the
+    /// variation coordinates change.
+    blank_glyphs: HashMap<(FT_UInt, FT_Int32, SizeKey), FT_Pos>,
+    /// The transform that is applied to the glyphs as they are loaded
+    transform: FaceTransform,
+    /// The horizontal offset, in pixels, that was requested via
+    /// `set_subpixel_offset`
+    subpixel_offset: f64,
+    /// The number of phases per pixel that the subpixel offset is
+    /// rounded to; 0 applies it as precisely as 26.6 allows
+    subpixel_granularity: u8,
+    /// The parameters that the face was opened with, which are also
+    /// used to open its duplicates and siblings
+    open_params: Vec<OpenParam>,
     lib: FT_Library,
+    /// The GSUB/GPOS feature tags; computed on first use.
+    /// The inner value is None if the tables are malformed.
+    features: RefCell<Option<Option<HashSet<u32>>>>,
+    /// The glyph substitutions of the `rtlm` feature; computed on
+    /// first use.  Empty if the font doesn't have the feature or its
+    /// GSUB table is malformed.
+    rtl_mirrors: RefCell<Option<HashMap<u16, u16>>>,
+    /// The lowest codepoint that the unicode charmap maps to each
+    /// glyph; computed on first use by `codepoint_for_glyph`.
+    glyph_codepoints: RefCell<Option<HashMap<FT_UInt, u32>>>,
+    /// The bitmap strikes and their pixel formats; computed on first use.
+    strikes: Option<Vec<StrikeInfo>>,
+    flags: FaceFlags,
+    /// Whether FT_LOAD_COMPUTE_METRICS is forced on when loading glyphs
+    compute_metrics: bool,
+    /// The LCD filter selected for this face, overriding that of
+    /// the library
+    lcd_filter: Option<FT_LcdFilter>,
+    /// Coverage below this value is zeroed after rendering
+    alpha_floor: u8,
+    /// Whether glyphs are rendered unhinted, and in grayscale rather
+    /// than monochrome, at sizes below the lowest_rec_ppem of the font
+    unhinted_below_lowest_rec_ppem: bool,
+    /// Whether color glyphs are rendered with FT_RENDER_MODE_NORMAL
+    /// whatever the requested render mode
+    normal_render_for_color_glyphs: bool,
+    /// Whether the face was opened by a library that was created by
+    /// `Library::new_metrics_only`
+    metrics_only: bool,
+    /// Whether the glyph that was most recently rendered came from a
+    /// bitmap strike that doesn't match the selected size
+    rendered_scaled_strike: bool,
+    lcd_filter_state: Rc<LcdFilterState>,
+    subpixel_order: Rc<SubpixelOrderProvider>,
 }
 
-impl Drop for Face {
-    fn drop(&mut self) {
-        unsafe {
-            FT_Done_Face(self.face);
-        }
-    }
+/// Holds the callback registered via `Library::set_subpixel_order_provider`.
+/// It is shared by the library and its faces so that faces opened before
+/// the provider was registered also consult it.
+#[derive(Default)]
+struct SubpixelOrderProvider {
+    provider: RefCell<Option<Box<dyn Fn() -> SubpixelOrder>>>,
 }
 
-struct FaceSize {
-    size: f64,
-    dpi: u32,
-    cell_width: f64,
-    cell_height: f64,
-    is_scaled: bool,
+impl SubpixelOrderProvider {
+    fn get(&self) -> SubpixelOrder {
+        match self.provider.borrow().as_ref() {
+            Some(provider) => provider(),
+            None => SubpixelOrder::Rgb,
+        }
+    }
 }
 
-pub struct SelectedFontSize {
-    pub width: f64,
-    pub height: f64,
-    pub is_scaled: bool,
+/// Tracks the LCD filter of a library.  freetype only has a single
+/// library-wide filter, which it applies when rendering LCD glyphs,
+/// so the filter that each face wants is applied just before it
+/// renders an LCD glyph.  Rather than restoring the prior filter
+/// afterwards, the filter that was last applied is remembered, which
+/// both avoids redundant calls when consecutive glyphs want the same
+/// filter and ensures that the next LCD render applies its own.
+/// Renders in other modes don't use the filter and so don't touch it.
+struct LcdFilterState {
+    /// The filter for faces that haven't selected their own
+    default: Cell<FT_LcdFilter>,
+    /// The filter that was most recently applied to the library
+    applied: Cell<Option<FT_LcdFilter>>,
+    /// When true, the filter is never set and freetype's own
+    /// default is used
+    skip: Cell<bool>,
+    /// Whether the failure to set a filter has been reported
+    warned: Cell<bool>,
 }
 
-impl Face {
-    pub fn family_name(&self) -> String {
-        unsafe {
-            if (*self.face).family_name.is_null() {
-                "".to_string()
-            } else {
-                let c = CStr::from_ptr((*self.face).family_name);
-                c.to_string_lossy().to_string()
-            }
+impl LcdFilterState {
+    fn new() -> Self {
+        Self {
+            default: Cell::new(FT_LcdFilter::FT_LCD_FILTER_NONE),
+            applied: Cell::new(None),
+            skip: Cell::new(false),
+            warned: Cell::new(false),
         }
     }
 
-    pub fn style_name(&self) -> String {
-        unsafe {
-            if (*self.face).style_name.is_null() {
-                "".to_string()
-            } else {
-                let c = CStr::from_ptr((*self.face).style_name);
-                c.to_string_lossy().to_string()
-            }
+    fn apply(&self, lib: FT_Library, filter: FT_LcdFilter) {
+        if self.skip.get() || self.applied.get() == Some(filter) {
+            return;
         }
-    }
-
-    pub fn postscript_name(&self) -> String {
-        unsafe {
-            let c = FT_Get_Postscript_Name(self.face);
-            if c.is_null() {
-                "".to_string()
+        // Our own build of freetype enables the LCD filtering feature,
+        // so this only fails if freetype was built without it, in
+        // which case it still renders LCD glyphs, but without the
+        // filter that was asked for.  Not filtering at all is what
+        // such a build does anyway, so that isn't worth mentioning.
+        let res = unsafe { FT_Library_SetLcdFilter(lib, filter) };
+        if !succeeded(res) {
+            if filter != FT_LcdFilter::FT_LCD_FILTER_NONE && !self.warned.replace(true) {
+                log::warn!(
+                    "Subpixel rendering was requested, but the LCD filter {:?} \
+                     could not be set (error {}); this build of freetype may \
+                     lack FT_CONFIG_OPTION_SUBPIXEL_RENDERING, so LCD glyphs \
+                     are rendered without filtering and may show color fringes",
+                    filter,
+                    res
+                );
             } else {
-                let c = CStr::from_ptr(c);
-                c.to_string_lossy().to_string()
+                log::trace!("FT_Library_SetLcdFilter({:?}) failed: {}", filter, res);
             }
         }
+        self.applied.set(Some(filter));
     }
+}
 
-    pub fn variations(&self) -> anyhow::Result<Vec<ParsedFont>> {
-        let mut mm = std::ptr::null_mut();
+bitflags! {
+    /// A snapshot of the `FT_FACE_FLAG_XXX` bits of a face
+    pub struct FaceFlags: u32 {
+        const SCALABLE = FT_FACE_FLAG_SCALABLE;
+        const FIXED_SIZES = FT_FACE_FLAG_FIXED_SIZES;
+        const FIXED_WIDTH = FT_FACE_FLAG_FIXED_WIDTH;
+        const SFNT = FT_FACE_FLAG_SFNT;
+        const HORIZONTAL = FT_FACE_FLAG_HORIZONTAL;
+        const VERTICAL = FT_FACE_FLAG_VERTICAL;
+        const KERNING = FT_FACE_FLAG_KERNING;
+        const FAST_GLYPHS = FT_FACE_FLAG_FAST_GLYPHS;
+        const MULTIPLE_MASTERS = FT_FACE_FLAG_MULTIPLE_MASTERS;
+        const GLYPH_NAMES = FT_FACE_FLAG_GLYPH_NAMES;
+        const EXTERNAL_STREAM = FT_FACE_FLAG_EXTERNAL_STREAM;
+        const HINTER = FT_FACE_FLAG_HINTER;
+        const CID_KEYED = FT_FACE_FLAG_CID_KEYED;
+        const TRICKY = FT_FACE_FLAG_TRICKY;
+        const COLOR = FT_FACE_FLAG_COLOR;
+        const VARIATION = FT_FACE_FLAG_VARIATION;
+    }
+}
 
-        unsafe {
-            ft_result(FT_Get_MM_Var(self.face, &mut mm), ()).context("FT_Get_MM_Var")?;
+impl FaceFlags {
+    fn from_face(face: FT_Face) -> Self {
+        Self::from_bits_truncate(unsafe { (*face).face_flags } as u32)
+    }
 
-            let mut res = vec![];
-            let num_styles = (*mm).num_namedstyles;
-            for i in 1..=num_styles {
-                FT_Set_Named_Instance(self.face, i);
-                let source = FontDataHandle {
-                    source: self.source.source.clone(),
-                    index: self.source.index,
-                    variation: i,
-                    origin: self.source.origin,
-                };
-                res.push(ParsedFont::from_face(&self, source)?);
-            }
+    pub fn has_glyph_names(self) -> bool {
+        self.contains(Self::GLYPH_NAMES)
+    }
 
-            FT_Done_MM_Var(self.lib, mm);
-            FT_Set_Named_Instance(self.face, 0);
+    pub fn has_kerning(self) -> bool {
+        self.contains(Self::KERNING)
+    }
 
-            log::debug!("Variations: {:#?}", res);
+    pub fn is_scalable(self) -> bool {
+        self.contains(Self::SCALABLE)
+    }
 
-            Ok(res)
-        }
+    /// Returns true if the face claims that all of its glyphs have
+    /// the same advance
+    pub fn is_fixed_width(self) -> bool {
+        self.contains(Self::FIXED_WIDTH)
     }
 
-    pub fn get_os2_table(&self) -> Option<&TT_OS2> {
-        unsafe {
-            let os2: *const TT_OS2 = FT_Get_Sfnt_Table(self.face, FT_Sfnt_Tag::FT_SFNT_OS2) as _;
-            if os2.is_null() {
-                None
-            } else {
-                Some(&*os2)
-            }
-        }
+    pub fn has_fixed_sizes(self) -> bool {
+        self.contains(Self::FIXED_SIZES)
     }
 
-    /// Returns the cap_height/units_per_EM ratio if known
-    pub fn cap_height(&self) -> Option<f64> {
-        unsafe {
-            let os2 = self.get_os2_table()?;
-            let units_per_em = (*self.face).units_per_EM;
-            if units_per_em == 0 || os2.sCapHeight == 0 {
-                return None;
-            }
-            Some(os2.sCapHeight as f64 / units_per_em as f64)
-        }
+    pub fn has_color(self) -> bool {
+        self.contains(Self::COLOR)
     }
 
-    pub fn weight_and_width(&self) -> (u16, u16) {
-        let (mut weight, mut width) = self
-            .get_os2_table()
-            .map(|os2| (os2.usWeightClass as f64, os2.usWidthClass as f64))
-            .unwrap_or((400., 5.));
+    pub fn is_sfnt(self) -> bool {
+        self.contains(Self::SFNT)
+    }
 
-        unsafe {
-            let index = (*self.face).face_index;
-            let variation = index >> 16;
-            if variation > 0 {
-                let vidx = (variation - 1) as usize;
+    /// Returns true if the face has variation axes
+    pub fn has_variations(self) -> bool {
+        self.contains(Self::MULTIPLE_MASTERS)
+    }
+}
 
-                let mut mm = std::ptr::null_mut();
+bitflags! {
+    /// The tables that a face uses to provide color glyphs
+    pub struct ColorFormats: u8 {
+        /// Layered glyphs from a version 0 COLR table
+        const COLR_V0 = 1;
+        /// Gradients and compositing from a version 1 COLR table
+        const COLR_V1 = 2;
+        /// The palettes used by the COLR table
+        const CPAL = 4;
+        /// Embedded PNG bitmaps in the Google CBDT/CBLC tables
+        const CBDT = 8;
+        /// Embedded bitmaps in the Apple sbix table
+        const SBIX = 16;
+        /// SVG documents in the OpenType SVG table
+        const SVG = 32;
+    }
+}
 
-                ft_result(FT_Get_MM_Var(self.face, &mut mm), ())
-                    .context("FT_Get_MM_Var")
-                    .unwrap();
-                {
-                    let mm = &*mm;
-
-                    let styles =
-                        std::slice::from_raw_parts(mm.namedstyle, mm.num_namedstyles as usize);
-                    let instance = &styles[vidx];
-                    let axes = std::slice::from_raw_parts(mm.axis, mm.num_axis as usize);
-
-                    fn ft_make_tag(a: u8, b: u8, c: u8, d: u8) -> FT_ULong {
-                        (a as FT_ULong) << 24
-                            | (b as FT_ULong) << 16
-                            | (c as FT_ULong) << 8
-                            | (d as FT_ULong)
-                    }
+impl ColorFormats {
+    const NAMES: &'static [(ColorFormats, &'static str)] = &[
+        (ColorFormats::COLR_V0, "COLRv0"),
+        (ColorFormats::COLR_V1, "COLRv1"),
+        (ColorFormats::CPAL, "CPAL"),
+        (ColorFormats::CBDT, "CBDT"),
+        (ColorFormats::SBIX, "sbix"),
+        (ColorFormats::SVG, "OT-SVG"),
+    ];
 
-                    for (i, axis) in axes.iter().enumerate() {
-                        let coords =
-                            std::slice::from_raw_parts(instance.coords, mm.num_axis as usize);
-                        let value = coords[i] as f64 / (1 << 16) as f64;
-                        let default_value = axis.def as f64 / (1 << 16) as f64;
-                        let scale = if default_value != 0. {
-                            value / default_value
-                        } else {
-                            1.
-                        };
-
-                        if axis.tag == ft_make_tag(b'w', b'g', b'h', b't') {
-                            weight = weight * scale;
-                        }
+    /// Returns true if the face has COLR layers along with the
+    /// palettes needed to color them
+    pub fn has_colr(self) -> bool {
+        self.intersects(Self::COLR_V0 | Self::COLR_V1) && self.contains(Self::CPAL)
+    }
 
-                        if axis.tag == ft_make_tag(b'w', b'd', b't', b'h') {
-                            width = width * scale;
-                        }
-                    }
-                }
+    /// Returns true if the color glyphs are embedded bitmaps
+    pub fn has_bitmaps(self) -> bool {
+        self.intersects(Self::CBDT | Self::SBIX)
+    }
+}
 
-                FT_Done_MM_Var(self.lib, mm);
-            }
+impl std::fmt::Display for ColorFormats {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.is_empty() {
+            return fmt.write_str("none");
         }
-
-        (weight.round() as u16, width.round() as u16)
+        let names = Self::NAMES
+            .iter()
+            .filter(|(format, _)| self.contains(*format))
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+        fmt.write_str(&names.join(", "))
     }
+}
 
-    pub fn italic(&self) -> bool {
-        unsafe { ((*self.face).style_flags & FT_STYLE_FLAG_ITALIC as FT_Long) != 0 }
+bitflags! {
+    /// The paletteTypes flags of a CPAL palette, which are the same
+    /// as those that freetype reports via FT_Palette_Data
+    pub struct PaletteUsability: u32 {
+        /// The palette is suitable for use on a light background
+        const LIGHT_BACKGROUND = 1;
+        /// The palette is suitable for use on a dark background
+        const DARK_BACKGROUND = 2;
     }
+}
 
-    pub fn compute_coverage(&self) -> RangeSet<u32> {
-        let mut coverage = RangeSet::new();
+impl std::fmt::Debug for Face {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(&self.identity())
+    }
+}
 
-        for encoding in &[
-            FT_Encoding::FT_ENCODING_UNICODE,
-            FT_Encoding::FT_ENCODING_MS_SYMBOL,
-        ] {
-            if unsafe { FT_Select_Charmap(self.face, *encoding) } != 0 {
-                continue;
-            }
+impl Drop for Face {
+    fn drop(&mut self) {
+        unsafe {
+            FT_Done_Face(self.face);
+        }
+    }
+}
 
-            let mut glyph = 0;
-            let mut ucs4 = unsafe { FT_Get_First_Char(self.face, &mut glyph) };
-            while glyph != 0 {
-                coverage.add(ucs4 as u32);
-                ucs4 = unsafe { FT_Get_Next_Char(self.face, ucs4, &mut glyph) };
-            }
+/// A scale and slant that is applied to the outlines of a face as its
+/// glyphs are loaded, and to the advances of the glyphs along with them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaceTransform {
+    pub x_scale: f64,
+    pub y_scale: f64,
+    /// Positive values lean the glyphs to the right
+    pub slant_degrees: f64,
+}
 
-            if *encoding == FT_Encoding::FT_ENCODING_MS_SYMBOL {
-                // Fontconfig duplicates F000..F0FF to 0000..00FF
-                for ucs4 in 0xf00..0xf100 {
-                    if coverage.contains(ucs4) {
-                        coverage.add(ucs4 as u32 - 0xf000);
-                    }
-                }
-            }
-        }
+impl FaceTransform {
+    pub const IDENTITY: Self = Self {
+        x_scale: 1.0,
+        y_scale: 1.0,
+        slant_degrees: 0.0,
+    };
 
-        coverage
+    pub fn is_identity(&self) -> bool {
+        *self == Self::IDENTITY
     }
 
-    /// This is a wrapper around set_char_size and select_size
-    /// that accounts for some weirdness with eg: color emoji
-    pub fn set_font_size(&mut self, point_size: f64, dpi: u32) -> anyhow::Result<SelectedFontSize> {
-        if let Some(face_size) = self.size.as_ref() {
-            if face_size.size == point_size && face_size.dpi == dpi {
-                return Ok(SelectedFontSize {
-                    width: face_size.cell_width,
-                    height: face_size.cell_height,
-                    is_scaled: face_size.is_scaled,
-                });
-            }
+    /// Returns the transform in the form that FT_Set_Transform uses.
+    /// The slant is applied after scaling.
+    fn matrix(&self) -> FT_Matrix {
+        let fixed = |value: f64| (value * 65536.0).round() as FT_Fixed;
+        FT_Matrix {
+            xx: fixed(self.x_scale),
+            xy: fixed(self.slant_degrees.to_radians().tan() * self.y_scale),
+            yx: 0,
+            yy: fixed(self.y_scale),
         }
+    }
 
-        let pixel_height = point_size * dpi as f64 / 72.0;
-        log::debug!(
-            "set_char_size computing {} dpi={} (pixel height={})",
-            point_size,
-            dpi,
-            pixel_height
-        );
+    fn to_bits(&self) -> [u64; 3] {
+        [
+            self.x_scale.to_bits(),
+            self.y_scale.to_bits(),
+            self.slant_degrees.to_bits(),
+        ]
+    }
+}
 
-        // Scaling before truncating to integer minimizes the chances of hitting
-        // the fallback code for set_pixel_sizes below.
-        let size = (point_size * 64.0) as FT_F26Dot6;
+impl Default for FaceTransform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
 
-        let selected_size = match self.set_char_size(size, size, dpi, dpi) {
-            Ok(_) => {
-                // Compute metrics for the nominal monospace cell
-                let (width, height) = self.cell_metrics();
-                SelectedFontSize {
-                    width,
-                    height,
-                    is_scaled: true,
-                }
-            }
-            Err(err) => {
-                log::debug!("set_char_size: {:?}, will inspect strikes", err);
+/// The number of subpixel phases that a face rounds the offset passed
+/// to `Face::set_subpixel_offset` to by default
+pub const DEFAULT_SUBPIXEL_GRANULARITY: u8 = 4;
 
-                let sizes = unsafe {
-                    let rec = &(*self.face);
-                    std::slice::from_raw_parts(rec.available_sizes, rec.num_fixed_sizes as usize)
-                };
-                if sizes.is_empty() {
-                    return Err(err);
-                }
-                // Find the best matching size; we look for the strike whose height
-                // is closest to the desired size.
-                struct Best {
-                    idx: usize,
-                    distance: usize,
-                    height: i16,
-                    width: i16,
-                }
-                let mut best: Option<Best> = None;
-
-                for (idx, info) in sizes.iter().enumerate() {
-                    log::debug!("idx={} info={:?}", idx, info);
-                    let distance = (info.height - (pixel_height as i16)).abs() as usize;
-                    let candidate = Best {
-                        idx,
-                        distance,
-                        height: info.height,
-                        width: info.width,
-                    };
+/// Round `offset`, in pixels, to the nearest of `levels` evenly spaced
+/// phases per pixel, returning it in 26.6.  With 0 levels, the offset
+/// is only rounded to the nearest 1/64th of a pixel.
+fn quantize_subpixel_offset(offset: f64, levels: u8) -> FT_Pos {
+    let units = offset * 64.0;
+    if levels == 0 {
+        return units.round() as FT_Pos;
+    }
+    let step = 64.0 / f64::from(levels);
+    ((units / step).round() * step).round() as FT_Pos
+}
 
-                    match best.take() {
-                        Some(existing) => {
-                            best.replace(if candidate.distance < existing.distance {
-                                candidate
-                            } else {
-                                existing
-                            });
-                        }
-                        None => {
-                            best.replace(candidate);
-                        }
-                    }
-                }
-                let best = best.unwrap();
-                self.select_size(best.idx)?;
-                SelectedFontSize {
-                    width: f64::from(best.width),
-                    height: f64::from(best.height),
-                    is_scaled: false,
-                }
-            }
+/// A parameter that is passed to freetype as a face is opened,
+/// altering how the face is loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenParam {
+    /// Report the legacy family name (name ID 1) of the font rather
+    /// than its typographic family name (name ID 16)
+    IgnoreTypographicFamily,
+    /// Report the legacy style name (name ID 2) of the font rather
+    /// than its typographic subfamily name (name ID 17)
+    IgnoreTypographicSubfamily,
+    /// Ignore the `sbix` table, so that the outlines of a font that
+    /// has both are used rather than its color bitmaps
+    IgnoreSbix,
+}
+
+impl OpenParam {
+    /// Returns the FT_PARAM_TAG_* value for this parameter
+    pub fn tag(self) -> FT_ULong {
+        let tag = match self {
+            Self::IgnoreTypographicFamily => b"igpf",
+            Self::IgnoreTypographicSubfamily => b"igps",
+            Self::IgnoreSbix => b"isbx",
         };
+        sfnt::make_tag(tag) as FT_ULong
+    }
 
-        self.size.replace(FaceSize {
-            size: point_size,
-            dpi,
-            cell_width: selected_size.width,
-            cell_height: selected_size.height,
-            is_scaled: selected_size.is_scaled,
-        });
+    /// Returns the parameter in the form that FT_Open_Face uses.
+    /// The payload of the result points into `self`, or is null for
+    /// the parameters that don't have one, so `self` must outlive
+    /// the call that it is passed to.
+    fn to_ft_parameter(&self) -> FT_Parameter {
+        FT_Parameter {
+            tag: self.tag(),
+            data: ptr::null_mut(),
+        }
+    }
+}
 
-        Ok(selected_size)
+/// Assemble the arguments for opening a face from `stream`, passing
+/// `params` along to freetype.  The result points to `params`, which
+/// must outlive the FT_Open_Face call that it is used for.
+fn stream_open_args(stream: FT_Stream, params: &mut [FT_Parameter]) -> FT_Open_Args {
+    let mut flags = FT_OPEN_STREAM;
+    if !params.is_empty() {
+        flags |= FT_OPEN_PARAMS;
     }
+    FT_Open_Args {
+        flags,
+        memory_base: ptr::null(),
+        memory_size: 0,
+        pathname: ptr::null_mut(),
+        stream,
+        driver: ptr::null_mut(),
+        num_params: params.len() as FT_Int,
+        params: if params.is_empty() {
+            ptr::null_mut()
+        } else {
+            params.as_mut_ptr()
+        },
+    }
+}
 
-    fn set_char_size(
-        &mut self,
-        char_width: FT_F26Dot6,
-        char_height: FT_F26Dot6,
-        horz_resolution: FT_UInt,
-        vert_resolution: FT_UInt,
-    ) -> anyhow::Result<()> {
-        ft_result(
-            unsafe {
-                FT_Set_Char_Size(
-                    self.face,
-                    char_width,
-                    char_height,
-                    horz_resolution,
-                    vert_resolution,
-                )
-            },
-            (),
-        )
-        .context("FT_Set_Char_Size")?;
+/// Identifies a size selection by the bits of the point size,
+/// the dpi and the bits of the transform
+type SizeKey = (u64, u32, [u64; 3]);
 
-        unsafe {
-            if (*self.face).height == 0 {
-                anyhow::bail!("font has 0 height, fallback to bitmaps");
-            }
+#[derive(Clone, Copy, PartialEq)]
+struct FaceSize {
+    size: f64,
+    dpi: u32,
+    /// The transform that the cell metrics were computed for
+    transform: FaceTransform,
+    cell_width: f64,
+    cell_height: f64,
+    is_scaled: bool,
+    /// The index of the selected bitmap strike, if any
+    strike: Option<usize>,
+}
+
+impl FaceSize {
+    fn selected(&self) -> SelectedFontSize {
+        SelectedFontSize {
+            width: self.cell_width,
+            height: self.cell_height,
+            is_scaled: self.is_scaled,
         }
+    }
+}
 
-        Ok(())
+pub struct SelectedFontSize {
+    pub width: f64,
+    pub height: f64,
+    pub is_scaled: bool,
+}
+
+/// Returned by `Face::with_size`; restores the size that was
+/// previously selected on the face when dropped
+pub struct SizeGuard<'a> {
+    face: &'a mut Face,
+    prior: Option<FaceSize>,
+}
+
+impl<'a> std::ops::Deref for SizeGuard<'a> {
+    type Target = Face;
+    fn deref(&self) -> &Face {
+        self.face
     }
+}
 
-    fn select_size(&mut self, idx: usize) -> anyhow::Result<()> {
-        ft_result(unsafe { FT_Select_Size(self.face, idx as i32) }, ()).context("FT_Select_Size")
+impl<'a> std::ops::DerefMut for SizeGuard<'a> {
+    fn deref_mut(&mut self) -> &mut Face {
+        self.face
     }
+}
 
-    pub fn load_and_render_glyph(
-        &mut self,
-        glyph_index: FT_UInt,
-        load_flags: FT_Int32,
-        render_mode: FT_Render_Mode,
-    ) -> anyhow::Result<&FT_GlyphSlotRec_> {
-        unsafe {
-            ft_result(FT_Load_Glyph(self.face, glyph_index, load_flags), ()).with_context(
-                || {
-                    anyhow!(
-                        "load_and_render_glyph: FT_Load_Glyph glyph_index:{}",
-                        glyph_index
-                    )
-                },
-            )?;
-            let slot = &mut *(*self.face).glyph;
-            ft_result(FT_Render_Glyph(slot, render_mode), ())
-                .context("load_and_render_glyph: FT_Render_Glyph")?;
-            Ok(slot)
+impl<'a> Drop for SizeGuard<'a> {
+    fn drop(&mut self) {
+        if let Err(err) = self.face.restore_size(self.prior) {
+            log::error!("SizeGuard: failed to restore font size: {:#}", err);
         }
     }
+}
 
-    pub fn cell_metrics(&mut self) -> (f64, f64) {
-        unsafe {
-            let metrics = &(*(*self.face).size).metrics;
-            let height = (metrics.y_scale as f64 * f64::from((*self.face).height))
-                / (f64::from(0x1_0000) * 64.0);
+/// The pixel format of the glyphs in a bitmap strike
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrikeFormat {
+    /// 1 bit per pixel
+    Mono,
+    /// 8 bit (or less) grayscale coverage
+    Gray,
+    /// 32 bit pre-multiplied color
+    Bgra,
+}
 
-            let mut width = 0.0;
-            for i in 32..128 {
-                let glyph_pos = FT_Get_Char_Index(self.face, i);
-                if glyph_pos == 0 {
-                    continue;
+impl StrikeFormat {
+    fn from_pixel_mode(mode: FT_Pixel_Mode) -> Option<Self> {
+        match mode {
+            FT_Pixel_Mode::FT_PIXEL_MODE_MONO => Some(Self::Mono),
+            FT_Pixel_Mode::FT_PIXEL_MODE_GRAY
+            | FT_Pixel_Mode::FT_PIXEL_MODE_GRAY2
+            | FT_Pixel_Mode::FT_PIXEL_MODE_GRAY4 => Some(Self::Gray),
+            FT_Pixel_Mode::FT_PIXEL_MODE_BGRA => Some(Self::Bgra),
+            _ => None,
+        }
+    }
+}
+
+/// Describes one of the bitmap strikes of a face
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StrikeInfo {
+    pub width: i16,
+    pub height: i16,
+    /// The pixel format of the strike, or None if it could
+    /// not be determined
+    pub format: Option<StrikeFormat>,
+}
+
+/// Zero the coverage of the pixels of `bitmap` that is less than
+/// `floor`.  Only grayscale bitmaps, and the alpha of BGRA bitmaps, are
+/// affected; as BGRA is premultiplied, the colors of such a pixel are
+/// zeroed along with its alpha.
+fn apply_alpha_floor(bitmap: &mut FT_Bitmap, floor: u8) {
+    let bytes_per_pixel = match bitmap.pixel_mode as u32 {
+        mode if mode == FT_Pixel_Mode::FT_PIXEL_MODE_GRAY as u32 => 1,
+        mode if mode == FT_Pixel_Mode::FT_PIXEL_MODE_BGRA as u32 => 4,
+        _ => return,
+    };
+    let stride = bitmap.pitch.abs() as usize;
+    let len = bitmap.rows as usize * stride;
+    if floor == 0 || bitmap.buffer.is_null() || len == 0 {
+        return;
+    }
+    // The row order doesn't matter here, so a bitmap with a negative
+    // pitch is processed in the order that it is stored
+    let data = unsafe { std::slice::from_raw_parts_mut(bitmap.buffer, len) };
+    let row_len = bitmap.width as usize * bytes_per_pixel;
+    for row in data.chunks_mut(stride) {
+        for pixel in row[..row_len].chunks_mut(bytes_per_pixel) {
+            if pixel[bytes_per_pixel - 1] < floor {
+                for value in pixel {
+                    *value = 0;
                 }
-                let res = FT_Load_Glyph(self.face, glyph_pos, FT_LOAD_COLOR as i32);
-                if succeeded(res) {
-                    let glyph = &(*(*self.face).glyph);
-                    if glyph.metrics.horiAdvance as f64 > width {
-                        width = glyph.metrics.horiAdvance as f64;
-                    }
+            }
+        }
+    }
+}
+
+/// Returns the pixel data of `bitmap` with its rows in top to bottom
+/// order, `abs(pitch)` bytes apart.
+/// freetype stores bitmaps with a "down" flow, which have a positive
+/// pitch, top row first, but those with an "up" flow, which have a
+/// negative pitch, bottom row first.  Copying the data of the latter
+/// without taking that into account produces glyphs that are upside
+/// down, so all code that reads bitmap rows should do so via this
+/// function.
+pub fn bitmap_rows_top_down(bitmap: &FT_Bitmap) -> Cow<[u8]> {
+    let stride = bitmap.pitch.abs() as usize;
+    let len = bitmap.rows as usize * stride;
+    if bitmap.buffer.is_null() || len == 0 {
+        return Cow::Borrowed(&[]);
+    }
+    let data = unsafe { std::slice::from_raw_parts(bitmap.buffer, len) };
+    if bitmap.pitch >= 0 {
+        Cow::Borrowed(data)
+    } else {
+        Cow::Owned(data.chunks(stride).rev().flatten().copied().collect())
+    }
+}
+
+/// Returns the number of bytes at the start of each row of a bitmap
+/// that hold its `width` pixels, and for mono bitmaps, the mask of the
+/// bits of the last of those bytes that are used
+fn used_row_bytes(pixel_mode: FT_Pixel_Mode, width: u32) -> (usize, u8) {
+    let width = width as usize;
+    match pixel_mode {
+        FT_Pixel_Mode::FT_PIXEL_MODE_MONO => {
+            let mask = match width % 8 {
+                0 => 0xff,
+                used => 0xffu8 << (8 - used),
+            };
+            ((width + 7) / 8, mask)
+        }
+        FT_Pixel_Mode::FT_PIXEL_MODE_GRAY2 => ((width + 3) / 4, 0xff),
+        FT_Pixel_Mode::FT_PIXEL_MODE_GRAY4 => ((width + 1) / 2, 0xff),
+        FT_Pixel_Mode::FT_PIXEL_MODE_BGRA => (width * 4, 0xff),
+        _ => (width, 0xff),
+    }
+}
+
+/// Zero the bytes of each `pitch` byte row of `data` beyond those that
+/// hold its `width` pixels
+fn clear_row_padding(data: &mut [u8], pitch: usize, pixel_mode: FT_Pixel_Mode, width: u32) {
+    if pitch == 0 {
+        return;
+    }
+    let (used, mask) = used_row_bytes(pixel_mode, width);
+    let used = used.min(pitch);
+    for row in data.chunks_mut(pitch) {
+        if used > 0 && used <= row.len() {
+            row[used - 1] &= mask;
+        }
+        for byte in row.iter_mut().skip(used) {
+            *byte = 0;
+        }
+    }
+}
+
+/// An owned copy of the bitmap from a glyph slot.  freetype replaces
+/// the contents of the slot each time that a glyph is loaded, so this
+/// is useful when the bitmap needs to outlive the next load.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedGlyph {
+    pub pixel_mode: FT_Pixel_Mode,
+    /// The number of entries in each row, as reported by freetype
+    pub width: u32,
+    pub rows: u32,
+    /// The number of bytes per row in `data`
+    pub pitch: usize,
+    pub data: Vec<u8>,
+    /// The distance from the origin to the leftmost column
+    pub left: i32,
+    /// The distance from the baseline to the top row
+    pub top: i32,
+}
+
+impl OwnedGlyph {
+    /// Convert to normalized f32 components, or None if the pixel mode
+    /// isn't one that we know how to interpret.
+    /// Gray and mono coverage is already linear, so it is simply scaled
+    /// to 0.0-1.0 and used for each of the four components, as white.
+    /// LCD coverage is treated in the same way, per subpixel, using the
+    /// greatest of the three as the alpha, in the order that freetype
+    /// rendered the subpixels.
+    /// Color (BGRA) bitmaps hold premultiplied sRGB, so each color is
+    /// divided by its alpha, sRGB decoded, and then multiplied by the
+    /// alpha again; alpha is always linear.
+    pub fn to_f32(&self) -> Option<OwnedGlyphF32> {
+        let norm = |value: u8| value as f32 / 255.;
+        let (width, height, data) = match self.pixel_mode {
+            FT_Pixel_Mode::FT_PIXEL_MODE_LCD | FT_Pixel_Mode::FT_PIXEL_MODE_LCD_V => {
+                let lcd = self.lcd()?;
+                let mut data = Vec::with_capacity(lcd.width * lcd.height * 4);
+                for &[red, green, blue] in lcd.pixels() {
+                    let alpha = red.max(green).max(blue);
+                    data.extend_from_slice(&[norm(red), norm(green), norm(blue), norm(alpha)]);
                 }
+                (lcd.width, lcd.height, data)
             }
-            if width == 0.0 {
-                // Most likely we're looking at a symbol font with no latin
-                // glyphs at all. Let's just pick a selection of glyphs
-                for glyph_pos in 1..8 {
-                    let res = FT_Load_Glyph(self.face, glyph_pos, FT_LOAD_COLOR as i32);
-                    if succeeded(res) {
-                        let glyph = &(*(*self.face).glyph);
-                        if glyph.metrics.horiAdvance as f64 > width {
-                            width = glyph.metrics.horiAdvance as f64;
+            FT_Pixel_Mode::FT_PIXEL_MODE_BGRA => {
+                let (width, height) = (self.width as usize, self.rows as usize);
+                let mut data = Vec::with_capacity(width * height * 4);
+                for y in 0..height {
+                    for x in 0..width {
+                        let [blue, green, red, alpha] = self.bgra_pixel(x, y)?;
+                        if alpha == 0 {
+                            data.extend_from_slice(&[0.; 4]);
+                            continue;
                         }
+                        let alpha = norm(alpha);
+                        let linear =
+                            |value: u8| srgb_to_linear((norm(value) / alpha).min(1.)) * alpha;
+                        data.extend_from_slice(&[linear(red), linear(green), linear(blue), alpha]);
                     }
                 }
-                if width == 0.0 {
-                    log::error!(
-                        "Couldn't find any glyphs for metrics, so guessing width == height"
-                    );
-                    width = height * 64.;
+                (width, height, data)
+            }
+            _ => {
+                let (width, height) = (self.width as usize, self.rows as usize);
+                let mut data = Vec::with_capacity(width * height * 4);
+                for y in 0..height {
+                    for x in 0..width {
+                        let coverage = norm(self.bgra_pixel(x, y)?[3]);
+                        data.extend_from_slice(&[coverage; 4]);
+                    }
                 }
+                (width, height, data)
             }
-            (width / 64.0, height)
+        };
+        Some(OwnedGlyphF32 {
+            width,
+            height,
+            data,
+            left: self.left,
+            top: self.top,
+        })
+    }
+
+    /// Copy the bitmap from `slot`.  The rows of the copy are always
+    /// stored top row first, even if freetype rendered them bottom up.
+    /// Any bytes, or for mono bitmaps bits, at the end of each row
+    /// beyond the width of the bitmap are cleared, as freetype doesn't
+    /// guarantee what they hold, so that rendering the same glyph in
+    /// the same way always produces an identical copy.
+    pub fn from_slot(slot: &FT_GlyphSlotRec_) -> Self {
+        let pitch = slot.bitmap.pitch.abs() as usize;
+        let pixel_mode: FT_Pixel_Mode =
+            unsafe { std::mem::transmute(u32::from(slot.bitmap.pixel_mode)) };
+        let mut data = bitmap_rows_top_down(&slot.bitmap).into_owned();
+        clear_row_padding(&mut data, pitch, pixel_mode, slot.bitmap.width);
+        Self {
+            pixel_mode,
+            width: slot.bitmap.width,
+            rows: slot.bitmap.rows,
+            pitch,
+            data,
+            left: slot.bitmap_left,
+            top: slot.bitmap_top,
         }
     }
-}
 
-pub struct Library {
-    lib: FT_Library,
-}
+    /// Returns the pre-multiplied BGRA value of the pixel at x, y,
+    /// or None if the pixel mode isn't one that we know how to
+    /// interpret as a simple image
+    pub fn bgra_pixel(&self, x: usize, y: usize) -> Option<[u8; 4]> {
+        let row = &self.data[y * self.pitch..];
+        match self.pixel_mode {
+            FT_Pixel_Mode::FT_PIXEL_MODE_MONO => {
+                if row[x / 8] & (0x80 >> (x % 8)) != 0 {
+                    Some([0xff; 4])
+                } else {
+                    Some([0; 4])
+                }
+            }
+            FT_Pixel_Mode::FT_PIXEL_MODE_GRAY => Some([row[x]; 4]),
+            FT_Pixel_Mode::FT_PIXEL_MODE_BGRA => {
+                let mut pixel = [0u8; 4];
+                pixel.copy_from_slice(&row[x * 4..x * 4 + 4]);
+                Some(pixel)
+            }
+            _ => None,
+        }
+    }
 
-impl Drop for Library {
-    fn drop(&mut self) {
-        unsafe {
-            FT_Done_FreeType(self.lib);
+    /// If this is an LCD or LCD_V bitmap, returns a copy of it with
+    /// the three subpixel samples of each pixel grouped together.
+    /// freetype produces LCD bitmaps that are 3 times wider (or, for
+    /// LCD_V, 3 times taller) than the glyph in pixels, which makes
+    /// the raw `width` and `rows` easy to misinterpret.
+    pub fn lcd(&self) -> Option<LcdGlyph> {
+        let (width, height) = match self.pixel_mode {
+            FT_Pixel_Mode::FT_PIXEL_MODE_LCD => (self.width as usize / 3, self.rows as usize),
+            FT_Pixel_Mode::FT_PIXEL_MODE_LCD_V => (self.width as usize, self.rows as usize / 3),
+            _ => return None,
+        };
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let sample = |i: usize| match self.pixel_mode {
+                    FT_Pixel_Mode::FT_PIXEL_MODE_LCD => self.data[y * self.pitch + x * 3 + i],
+                    _ => self.data[(y * 3 + i) * self.pitch + x],
+                };
+                pixels.push([sample(0), sample(1), sample(2)]);
+            }
         }
+        Some(LcdGlyph {
+            width,
+            height,
+            vertical: self.pixel_mode == FT_Pixel_Mode::FT_PIXEL_MODE_LCD_V,
+            pixels,
+        })
     }
-}
 
-impl Library {
-    pub fn new() -> anyhow::Result<Library> {
-        let mut lib = ptr::null_mut();
-        let res = unsafe { FT_Init_FreeType(&mut lib as *mut _) };
-        let lib = ft_result(res, lib).context("FT_Init_FreeType")?;
-        let mut lib = Library { lib };
+    /// Returns a copy of this bitmap that is cropped to its ink, by
+    /// removing the rows and columns at its edges that are entirely
+    /// blank, with `left` and `top` adjusted so that the remaining
+    /// pixels stay where they were relative to the origin.
+    /// LCD bitmaps are cropped by whole pixels, so that the three
+    /// subpixel samples of each pixel stay together.  A bitmap with no
+    /// ink at all is reduced to an empty one with the same bearings;
+    /// the advance of a glyph isn't part of its bitmap, so is
+    /// unaffected either way.  Bitmaps in pixel modes that we don't
+    /// know how to interpret are copied unchanged.
+    pub fn trimmed(&self) -> OwnedGlyph {
+        // The number of bytes per pixel horizontally and rows per
+        // pixel vertically, or None for mono bitmaps
+        let (bytes_per_pixel, rows_per_pixel) = match self.pixel_mode {
+            FT_Pixel_Mode::FT_PIXEL_MODE_MONO => (None, 1),
+            FT_Pixel_Mode::FT_PIXEL_MODE_GRAY => (Some(1), 1),
+            FT_Pixel_Mode::FT_PIXEL_MODE_LCD => (Some(3), 1),
+            FT_Pixel_Mode::FT_PIXEL_MODE_LCD_V => (Some(1), 3),
+            FT_Pixel_Mode::FT_PIXEL_MODE_BGRA => (Some(4), 1),
+            _ => return self.clone(),
+        };
+        // LCD bitmaps have 3 samples per pixel horizontally
+        let samples = if self.pixel_mode == FT_Pixel_Mode::FT_PIXEL_MODE_LCD {
+            3
+        } else {
+            1
+        };
+        let width = self.width as usize / samples;
+        let height = self.rows as usize / rows_per_pixel;
+        let byte = |x: usize, y: usize| self.data[y * self.pitch + x];
+        let inked = |x: usize, y: usize| match bytes_per_pixel {
+            None => byte(x / 8, y) & (0x80 >> (x % 8)) != 0,
+            Some(bytes) => (0..rows_per_pixel)
+                .any(|row| (0..bytes).any(|i| byte(x * bytes + i, y * rows_per_pixel + row) != 0)),
+        };
 
-        let config = configuration();
-        if let Some(vers) = config.freetype_interpreter_version {
-            let interpreter_version: FT_UInt = vers;
-            unsafe {
-                FT_Property_Set(
-                    lib.lib,
-                    b"truetype\0" as *const u8 as *const FT_String,
-                    b"interpreter-version\0" as *const u8 as *const FT_String,
-                    &interpreter_version as *const FT_UInt as *const _,
-                );
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
+        for y in 0..height {
+            for x in 0..width {
+                if inked(x, y) {
+                    bounds = Some(match bounds {
+                        None => (x, y, x + 1, y + 1),
+                        Some((x0, y0, x1, y1)) => (x0.min(x), y0, x1.max(x + 1), y1.max(y + 1)),
+                    });
+                }
+            }
+        }
+        let (x0, y0, x1, y1) = match bounds {
+            Some(bounds) => bounds,
+            None => {
+                return OwnedGlyph {
+                    pixel_mode: self.pixel_mode,
+                    width: 0,
+                    rows: 0,
+                    pitch: 0,
+                    data: vec![],
+                    left: self.left,
+                    top: self.top,
+                }
             }
+        };
+        if (x0, y0, x1, y1) == (0, 0, width, height) {
+            return self.clone();
         }
 
-        // Due to patent concerns, the freetype library disables the LCD
-        // filtering feature by default, and since we always build our
-        // own copy of freetype, it is likewise disabled by default for
-        // us too.  As a result, this call will generally fail.
-        // Freetype is still able to render a decent result without it!
-        lib.set_lcd_filter(FT_LcdFilter::FT_LCD_FILTER_DEFAULT).ok();
+        let (new_width, pitch) = match bytes_per_pixel {
+            None => (x1 - x0, (x1 - x0 + 7) / 8),
+            Some(bytes) => ((x1 - x0) * samples, (x1 - x0) * bytes),
+        };
+        let rows = (y1 - y0) * rows_per_pixel;
+        let mut data = vec![0u8; rows * pitch];
+        for row in 0..rows {
+            let src_row = y0 * rows_per_pixel + row;
+            match bytes_per_pixel {
+                None => {
+                    for x in x0..x1 {
+                        if byte(x / 8, src_row) & (0x80 >> (x % 8)) != 0 {
+                            let dest = x - x0;
+                            data[row * pitch + dest / 8] |= 0x80 >> (dest % 8);
+                        }
+                    }
+                }
+                Some(bytes) => {
+                    let start = src_row * self.pitch + x0 * bytes;
+                    data[row * pitch..(row + 1) * pitch]
+                        .copy_from_slice(&self.data[start..start + pitch]);
+                }
+            }
+        }
 
-        Ok(lib)
+        OwnedGlyph {
+            pixel_mode: self.pixel_mode,
+            width: new_width as u32,
+            rows: rows as u32,
+            pitch,
+            data,
+            left: self.left + x0 as i32,
+            top: self.top - y0 as i32,
+        }
     }
 
-    /// Returns the number of faces in a given font.
-    /// For a TTF this will be 1.
-    /// For a TTC, it will be the number of contained fonts
-    pub fn query_num_faces(&self, source: &FontDataSource) -> anyhow::Result<u32> {
-        let face = self.new_face(source, -1).context("query_num_faces")?;
-        let num_faces = unsafe { (*face).num_faces }.try_into();
+    /// If this is an LCD or LCD_V bitmap, returns the opaque result of
+    /// drawing it in the sRGB `foreground` color over the sRGB
+    /// `background` color.
+    /// Each subpixel coverage value blends its own color channel, in
+    /// linear space, so that the first subpixel only affects red, the
+    /// second green and the third blue; this assumes the red, green,
+    /// blue order that freetype renders by default.  A single alpha
+    /// value per pixel cannot express that, which is why LCD glyphs
+    /// need to know the background that they are drawn over.
+    pub fn blend_lcd(&self, foreground: [u8; 3], background: [u8; 3]) -> Option<RgbGlyph> {
+        let lcd = self.lcd()?;
+        let linear = |value: u8| srgb_to_linear(value as f32 / 255.);
+        let pixels = lcd
+            .pixels()
+            .iter()
+            .map(|coverage| {
+                let mut pixel = [0u8; 3];
+                for (i, value) in pixel.iter_mut().enumerate() {
+                    let alpha = coverage[i] as f32 / 255.;
+                    let blended =
+                        linear(foreground[i]) * alpha + linear(background[i]) * (1. - alpha);
+                    *value = (linear_to_srgb(blended) * 255.).round() as u8;
+                }
+                pixel
+            })
+            .collect();
+        Some(RgbGlyph {
+            width: lcd.width,
+            height: lcd.height,
+            pixels,
+            left: self.left,
+            top: self.top,
+        })
+    }
+}
 
-        unsafe {
-            FT_Done_Face(face);
-        }
+/// An LCD glyph bitmap with one entry per pixel.
+/// Each pixel holds three subpixel coverage values in the order that
+/// freetype renders them, which is red, green, blue unless the LCD
+/// geometry of the library has been changed.  For horizontal LCD
+/// bitmaps that is left to right, and for LCD_V bitmaps top to bottom.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LcdGlyph {
+    /// The width in pixels
+    pub width: usize,
+    /// The height in pixels
+    pub height: usize,
+    /// true if the subpixels are arranged vertically (LCD_V)
+    pub vertical: bool,
+    pixels: Vec<[u8; 3]>,
+}
 
-        Ok(num_faces?)
+impl LcdGlyph {
+    /// Returns the subpixel coverage of the pixel at x, y
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        self.pixels[y * self.width + x]
     }
 
-    pub fn face_from_locator(&self, handle: &FontDataHandle) -> anyhow::Result<Face> {
-        let source = handle.clone();
+    pub fn pixels(&self) -> &[[u8; 3]] {
+        &self.pixels
+    }
+}
 
-        let mut index = handle.index;
-        if handle.variation != 0 {
-            index |= handle.variation << 16;
-        }
+/// Returned by `Face::render_fitted_glyph`
+pub struct FittedGlyph<'a> {
+    pub slot: &'a FT_GlyphSlotRec_,
+    /// The factor by which the outline was scaled before it was
+    /// rendered.  This is 1.0 if the glyph already fit, or if it
+    /// is a bitmap glyph.
+    pub outline_scale: f64,
+    /// The factor by which the caller should scale the bitmap of a
+    /// bitmap glyph so that it fits.  This is always 1.0 for glyphs
+    /// that were rendered from an outline.
+    pub bitmap_scale: f64,
+    /// See `Face::rendered_scaled_strike`
+    pub was_scaled_strike: bool,
+}
 
-        let face = self
-            .new_face(&source.source, index as _)
-            .with_context(|| format!("face_from_locator({:?})", handle))?;
+/// Returns the uniform scale that shrinks ink that is `ink_width`
+/// by `ink_height` pixels to fit within `cell_width` by `cell_height`,
+/// or 1.0 if it already fits
+pub fn fit_scale(ink_width: f64, ink_height: f64, cell_width: f64, cell_height: f64) -> f64 {
+    let mut scale: f64 = 1.0;
+    if ink_width > cell_width && ink_width > 0. {
+        scale = scale.min(cell_width / ink_width);
+    }
+    if ink_height > cell_height && ink_height > 0. {
+        scale = scale.min(cell_height / ink_height);
+    }
+    scale
+}
 
-        Ok(Face {
-            face,
-            lib: self.lib,
-            source,
-            size: None,
-        })
+/// An opaque sRGB glyph bitmap, produced by blending an LCD glyph
+/// over a known background; see `OwnedGlyph::blend_lcd`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RgbGlyph {
+    /// The width in pixels
+    pub width: usize,
+    /// The height in pixels
+    pub height: usize,
+    pixels: Vec<[u8; 3]>,
+    /// The distance from the origin to the leftmost column
+    pub left: i32,
+    /// The distance from the baseline to the top row
+    pub top: i32,
+}
+
+impl RgbGlyph {
+    /// Returns the RGB color of the pixel at x, y
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        self.pixels[y * self.width + x]
     }
 
-    fn new_face(&self, source: &FontDataSource, face_index: FT_Long) -> anyhow::Result<FT_Face> {
-        let mut face = ptr::null_mut();
+    pub fn pixels(&self) -> &[[u8; 3]] {
+        &self.pixels
+    }
+}
 
-        // FT_Open_Face will take ownership of this and closes it in both
-        // the error case and the success case (although the latter is when
-        // the face is dropped).
-        let stream = FreeTypeStream::from_source(source)?;
+/// A glyph bitmap with normalized f32 components, for renderers that
+/// composite in linear space and want to avoid quantizing the glyph
+/// to 8 bits along the way.  See `OwnedGlyph::to_f32` for how the
+/// components are derived from freetype's bitmap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedGlyphF32 {
+    /// The width in pixels
+    pub width: usize,
+    /// The height in pixels
+    pub height: usize,
+    /// Premultiplied linear RGBA, 4 components per pixel, with each
+    /// component in the range 0.0-1.0
+    pub data: Vec<f32>,
+    /// The distance from the origin to the leftmost column
+    pub left: i32,
+    /// The distance from the baseline to the top row
+    pub top: i32,
+}
 
-        let args = FT_Open_Args {
-            flags: FT_OPEN_STREAM,
-            memory_base: ptr::null(),
-            memory_size: 0,
-            pathname: ptr::null_mut(),
-            stream,
-            driver: ptr::null_mut(),
-            num_params: 0,
-            params: ptr::null_mut(),
-        };
+impl OwnedGlyphF32 {
+    /// Returns the RGBA components of the pixel at x, y
+    pub fn pixel(&self, x: usize, y: usize) -> [f32; 4] {
+        let offset = (y * self.width + x) * 4;
+        let mut pixel = [0f32; 4];
+        pixel.copy_from_slice(&self.data[offset..offset + 4]);
+        pixel
+    }
+}
 
-        let res = unsafe { FT_Open_Face(self.lib, &args, face_index, &mut face as *mut _) };
+/// Map the name returned by FT_Get_Font_Format to a FontFormat
+fn font_format_from_name(name: &[u8]) -> Option<FontFormat> {
+    Some(match name {
+        b"TrueType" => FontFormat::TrueType,
+        b"CFF" => FontFormat::Cff,
+        b"Type 1" => FontFormat::Type1,
+        b"CID Type 1" => FontFormat::CidType1,
+        b"Type 42" => FontFormat::Type42,
+        b"PFR" => FontFormat::Pfr,
+        b"BDF" => FontFormat::Bdf,
+        b"PCF" => FontFormat::Pcf,
+        b"Windows FNT" => FontFormat::WinFnt,
+        _ => return None,
+    })
+}
 
-        ft_result(res, face)
-            .with_context(|| format!("FT_Open_Face(\"{:?}\", face_index={})", source, face_index))
+/// Decode an sRGB encoded component in the range 0.0-1.0
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
     }
+}
 
-    pub fn set_lcd_filter(&mut self, filter: FT_LcdFilter) -> anyhow::Result<()> {
-        unsafe {
-            ft_result(FT_Library_SetLcdFilter(self.lib, filter), ())
-                .context("FT_Library_SetLcdFilter")
+/// Encode a linear component in the range 0.0-1.0 as sRGB
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// Computes the `wght` and `wdth` scales of the named style `vidx`
+/// described by `mm`; see `Face::named_style_scales`
+unsafe fn scales_from_mm_var(mm: &FT_MM_Var, vidx: usize) -> anyhow::Result<(f64, f64)> {
+    let num_styles = mm.num_namedstyles as usize;
+    if vidx >= num_styles {
+        anyhow::bail!(
+            "named style {} is out of range; the font has {} named styles",
+            vidx + 1,
+            num_styles
+        );
+    }
+    if mm.namedstyle.is_null() || (mm.num_axis > 0 && mm.axis.is_null()) {
+        anyhow::bail!("FT_Get_MM_Var returned incomplete variation information");
+    }
+
+    let styles = std::slice::from_raw_parts(mm.namedstyle, num_styles);
+    let instance = &styles[vidx];
+    let axes = std::slice::from_raw_parts(mm.axis, mm.num_axis as usize);
+    if instance.coords.is_null() && !axes.is_empty() {
+        anyhow::bail!("named style {} has no coordinates", vidx + 1);
+    }
+
+    let coords = std::slice::from_raw_parts(instance.coords, axes.len());
+    let mut weight_scale = 1.;
+    let mut width_scale = 1.;
+    for (axis, coord) in axes.iter().zip(coords.iter()) {
+        let value = *coord as f64 / (1 << 16) as f64;
+        let default_value = axis.def as f64 / (1 << 16) as f64;
+        let scale = if default_value != 0. {
+            value / default_value
+        } else {
+            1.
+        };
+
+        if axis.tag == sfnt::make_tag(b"wght") as FT_ULong {
+            weight_scale = scale;
+        }
+        if axis.tag == sfnt::make_tag(b"wdth") as FT_ULong {
+            width_scale = scale;
         }
     }
+    Ok((weight_scale, width_scale))
 }
 
-/// Our own stream implementation.
-/// This is present because we cannot guarantee to be able to convert
-/// Path -> c-string on Windows systems, but also because we've seen
-/// mysterious errors about not being able to open a resource.
-/// The intent is to avoid a potential problem and to help reveal
-/// more context on problems opening files as/when that happens.
-struct FreeTypeStream {
-    stream: FT_StreamRec_,
-    backing: StreamBacking,
-    name: String,
+/// Returns the nominal pixel size of a strike.  The `height` of a
+/// strike is the distance between the baselines of its rows, which
+/// can include leading and so doesn't reliably indicate which strike
+/// is closest to a requested size; `y_ppem` is the actual ppem, in
+/// 26.6 format.  Some fonts leave it unset, in which case the height
+/// is the best that we have.
+fn strike_ppem(size: &FT_Bitmap_Size) -> f64 {
+    if size.y_ppem > 0 {
+        size.y_ppem as f64 / 64.0
+    } else {
+        f64::from(size.height)
+    }
 }
 
-enum StreamBacking {
-    File(BufReader<File>),
-    Map(Mmap),
-    Static(&'static [u8]),
-    Memory(Arc<Box<[u8]>>),
+/// Returns the number of pixels per em that result from `point_size`
+/// at `dpi`
+pub fn point_size_to_pixels(point_size: f64, dpi: u32) -> f64 {
+    point_size * dpi as f64 / 72.0
 }
 
-impl FreeTypeStream {
-    pub fn from_source(source: &FontDataSource) -> anyhow::Result<FT_Stream> {
-        let (backing, base, len) = match source {
-            FontDataSource::OnDisk(path) => return Self::open_path(path),
-            FontDataSource::BuiltIn { data, .. } => {
-                let base = data.as_ptr();
-                let len = data.len();
-                (StreamBacking::Static(data), base, len)
-            }
-            FontDataSource::Memory { data, .. } => {
-                let base = data.as_ptr();
-                let len = data.len();
-                (StreamBacking::Memory(Arc::clone(data)), base, len)
-            }
-        };
+/// The inverse of `point_size_to_pixels`: returns the point size that
+/// results in `pixels` pixels per em at `dpi`
+pub fn pixels_to_point_size(pixels: f64, dpi: u32) -> f64 {
+    pixels * 72.0 / dpi as f64
+}
 
-        let name = source.name_or_path_str().to_string();
+/// Returns the index of the strike in `sizes` whose ppem is closest
+/// to `pixel_height`, preferring the first of equally close strikes.
+/// If `pixel_height` is larger than all of the strikes, the largest
+/// is selected.
+fn best_strike(sizes: &[FT_Bitmap_Size], pixel_height: f64) -> Option<usize> {
+    let ppems = sizes.iter().map(strike_ppem).collect::<Vec<_>>();
+    best_strike_ppem(&ppems, pixel_height)
+}
 
-        if len > c_ulong::MAX as usize {
-            anyhow::bail!("{} is too large to pass to freetype! (len={})", name, len);
+/// Like `best_strike`, but for a list of strike ppems
+fn best_strike_ppem(ppems: &[f64], pixel_height: f64) -> Option<usize> {
+    let largest = ppems.iter().enumerate().fold(
+        None,
+        |best: Option<(usize, f64)>, (idx, &ppem)| match best {
+            Some((_, best_ppem)) if best_ppem >= ppem => best,
+            _ => Some((idx, ppem)),
+        },
+    )?;
+    if pixel_height >= largest.1 {
+        return Some(largest.0);
+    }
+
+    let mut best: Option<(usize, f64)> = None;
+    for (idx, ppem) in ppems.iter().enumerate() {
+        log::debug!("idx={} ppem={}", idx, ppem);
+        let distance = (ppem - pixel_height).abs();
+        if best.map_or(true, |(_, best_distance)| distance < best_distance) {
+            best.replace((idx, distance));
         }
+    }
+    best.map(|(idx, _)| idx)
+}
 
-        let stream = Box::new(Self {
-            stream: FT_StreamRec_ {
-                base: base as *mut _,
-                size: len as c_ulong,
-                pos: 0,
-                descriptor: FT_StreamDesc_ {
-                    pointer: ptr::null_mut(),
-                },
-                pathname: FT_StreamDesc_ {
-                    pointer: ptr::null_mut(),
-                },
-                read: None,
-                close: Some(Self::close),
-                memory: ptr::null_mut(),
-                cursor: ptr::null_mut(),
-                limit: ptr::null_mut(),
-            },
-            backing,
-            name,
+/// Returns the index of the strike with the same ppem as the strike at
+/// `idx` that has the greatest bit depth in `depths`, which are the
+/// CBLC bit depths of `sizes`.  A font may provide the same size at
+/// several depths, and the deeper strike is the better quality one;
+/// the order of preference is 32 bit BGRA, then 8, 4, 2 and 1 bit
+/// grayscale.  Of equally deep strikes, the first is preferred.
+/// `idx` is returned if `depths` doesn't describe `sizes`.
+fn deepest_strike(sizes: &[FT_Bitmap_Size], depths: &[u8], idx: usize) -> usize {
+    if depths.len() != sizes.len() {
+        return idx;
+    }
+    let ppem = (sizes[idx].x_ppem, sizes[idx].y_ppem);
+    let mut best: Option<usize> = None;
+    for (candidate, size) in sizes.iter().enumerate() {
+        if (size.x_ppem, size.y_ppem) != ppem {
+            continue;
+        }
+        if best.map_or(true, |best| depths[candidate] > depths[best]) {
+            best = Some(candidate);
+        }
+    }
+    best.unwrap_or(idx)
+}
+
+/// A strike that a font size could be adjusted to use exactly
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StrikeSuggestion {
+    /// The ppem of the strike
+    pub ppem: f64,
+    /// The point size that results in exactly `ppem` at the dpi
+    /// for which the advice was computed
+    pub point_size: f64,
+}
+
+/// Explains how a point size relates to the bitmap strikes of a
+/// font that has no outlines.  A size between strikes causes the
+/// selected strike to be scaled, which blurs it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrikeAdvice {
+    /// The ppem that the point size and dpi result in
+    pub pixel_size: f64,
+    /// The ppem of the strike that `set_font_size` selects
+    pub selected: f64,
+    /// The closest strikes below and above `pixel_size`
+    pub nearest: Vec<StrikeSuggestion>,
+}
+
+/// Computes the advice for `point_size` at `dpi` given the ppem of
+/// each of the strikes of a font.
+/// Returns None if there are no strikes, or if `point_size` already
+/// hits one of them exactly.
+pub fn strike_advice(ppems: &[f64], point_size: f64, dpi: u32) -> Option<StrikeAdvice> {
+    let pixel_size = point_size_to_pixels(point_size, dpi);
+    let selected = ppems[best_strike_ppem(ppems, pixel_size)?];
+    if ppems.iter().any(|ppem| (ppem - pixel_size).abs() < 0.01) {
+        return None;
+    }
+
+    let suggest = |ppem: f64| StrikeSuggestion {
+        ppem,
+        point_size: pixels_to_point_size(ppem, dpi),
+    };
+    let below = ppems
+        .iter()
+        .copied()
+        .filter(|&ppem| ppem < pixel_size)
+        .fold(None, |best: Option<f64>, ppem| {
+            Some(best.map_or(ppem, |best| best.max(ppem)))
         });
-        let stream = Box::into_raw(stream);
-        unsafe {
-            (*stream).stream.descriptor.pointer = stream as *mut _;
-            Ok(&mut (*stream).stream)
+    let above = ppems
+        .iter()
+        .copied()
+        .filter(|&ppem| ppem > pixel_size)
+        .fold(None, |best: Option<f64>, ppem| {
+            Some(best.map_or(ppem, |best| best.min(ppem)))
+        });
+
+    Some(StrikeAdvice {
+        pixel_size,
+        selected,
+        nearest: below.into_iter().chain(above).map(suggest).collect(),
+    })
+}
+
+/// Returns true if the data of `stream` starts with `prefix`
+unsafe fn stream_starts_with(stream: FT_Stream, prefix: &[u8]) -> bool {
+    if ((*stream).size as usize) < prefix.len() {
+        return false;
+    }
+    let mut header = vec![0u8; prefix.len()];
+    match (*stream).read {
+        Some(read) => {
+            let len = read(stream, 0, header.as_mut_ptr(), header.len() as c_ulong);
+            if len as usize != header.len() {
+                return false;
+            }
         }
+        None => header.copy_from_slice(std::slice::from_raw_parts((*stream).base, prefix.len())),
     }
+    header == prefix
+}
 
-    fn open_path(p: &Path) -> anyhow::Result<FT_Stream> {
-        let file = File::open(p).with_context(|| format!("opening file {}", p.display()))?;
+/// Returns the render mode to use in place of `mode` when producing
+/// a plain image rather than subpixel coverage for a particular panel
+fn plain_render_mode(mode: FT_Render_Mode) -> FT_Render_Mode {
+    match mode {
+        FT_Render_Mode::FT_RENDER_MODE_LCD | FT_Render_Mode::FT_RENDER_MODE_LCD_V => {
+            FT_Render_Mode::FT_RENDER_MODE_NORMAL
+        }
+        mode => mode,
+    }
+}
 
-        let meta = file
-            .metadata()
-            .with_context(|| format!("querying metadata for {}", p.display()))?;
+/// Describes how to embolden an outline glyph
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Embolden {
+    /// The horizontal strength, in 26.6 pixel units
+    pub x: FT_Pos,
+    /// The vertical strength, in 26.6 pixel units
+    pub y: FT_Pos,
+    /// Keep the emboldened glyph centered within its original advance
+    pub preserve_advance: bool,
+}
 
-        if !meta.is_file() {
-            anyhow::bail!("{} is not a file", p.display());
+impl Face {
+    pub fn family_name(&self) -> String {
+        unsafe {
+            if (*self.face).family_name.is_null() {
+                "".to_string()
+            } else {
+                let c = CStr::from_ptr((*self.face).family_name);
+                c.to_string_lossy().to_string()
+            }
         }
+    }
 
-        let len = meta.len();
-        if len as usize > c_ulong::MAX as usize {
-            anyhow::bail!(
-                "{} is too large to pass to freetype! (len={})",
-                p.display(),
-                len
-            );
+    pub fn style_name(&self) -> String {
+        unsafe {
+            if (*self.face).style_name.is_null() {
+                "".to_string()
+            } else {
+                let c = CStr::from_ptr((*self.face).style_name);
+                c.to_string_lossy().to_string()
+            }
         }
+    }
 
-        let (backing, base) = match unsafe { MmapOptions::new().map(&file) } {
-            Ok(map) => {
-                let base = map.as_ptr() as *mut _;
-                (StreamBacking::Map(map), base)
+    pub fn postscript_name(&self) -> String {
+        unsafe {
+            let c = FT_Get_Postscript_Name(self.face);
+            if c.is_null() {
+                "".to_string()
+            } else {
+                let c = CStr::from_ptr(c);
+                c.to_string_lossy().to_string()
             }
-            Err(err) => {
-                log::warn!(
-                    "Unable to memory map {}: {}, will use regular file IO instead",
-                    p.display(),
-                    err
-                );
-                (StreamBacking::File(BufReader::new(file)), ptr::null_mut())
+        }
+    }
+
+    /// Returns the format of the font file, or None if freetype
+    /// reports a format that we don't know about
+    pub fn font_format(&self) -> Option<FontFormat> {
+        let name = unsafe {
+            let c = FT_Get_Font_Format(self.face);
+            if c.is_null() {
+                return None;
             }
+            CStr::from_ptr(c).to_bytes()
         };
+        match font_format_from_name(name)? {
+            // freetype reports the sfnt wrapper rather than what is in it
+            FontFormat::TrueType if self.is_bitmap_only_sfnt() && !self.flags.has_color() => {
+                Some(FontFormat::Otb)
+            }
+            format => Some(format),
+        }
+    }
 
-        let stream = Box::new(Self {
-            stream: FT_StreamRec_ {
-                base,
-                size: len as c_ulong,
-                pos: 0,
-                descriptor: FT_StreamDesc_ {
-                    pointer: ptr::null_mut(),
-                },
-                pathname: FT_StreamDesc_ {
-                    pointer: ptr::null_mut(),
-                },
-                read: if base.is_null() {
-                    Some(Self::read)
-                } else {
-                    // when backing is mmap, a null read routine causes
-                    // freetype to simply resolve data from `base`
+    /// Returns true if this is an sfnt face that has bitmap strikes but
+    /// no outlines, such as an OTB font or a color emoji font.
+    /// freetype leaves the scalable metrics of such a face, such as its
+    /// height and units per em, set to zero.
+    pub fn is_bitmap_only_sfnt(&self) -> bool {
+        self.flags.is_sfnt() && !self.flags.is_scalable()
+    }
+
+    pub fn variations(&self) -> anyhow::Result<Vec<ParsedFont>> {
+        let mut mm = std::ptr::null_mut();
+
+        unsafe {
+            ft_result(FT_Get_MM_Var(self.face, &mut mm), ()).context("FT_Get_MM_Var")?;
+
+            let mut res = vec![];
+            let num_styles = (*mm).num_namedstyles;
+            for i in 1..=num_styles {
+                FT_Set_Named_Instance(self.face, i);
+                let source = FontDataHandle {
+                    source: self.source.source.clone(),
+                    index: self.source.index,
+                    variation: i,
+                    origin: self.source.origin,
+                };
+                res.push(ParsedFont::from_face(&self, source)?);
+            }
+
+            FT_Done_MM_Var(self.lib, mm);
+            FT_Set_Named_Instance(self.face, 0);
+
+            log::debug!("Variations: {:#?}", res);
+
+            Ok(res)
+        }
+    }
+
+    /// Returns the index of the named instance whose coordinates are
+    /// the defaults of each of the variation axes, which is typically
+    /// the "Regular" preset of a variable font.
+    /// The index is 1-based, for use with `FT_Set_Named_Instance`
+    /// and `FontDataHandle::variation`.
+    /// Returns None if this isn't a variable font, or if none of
+    /// its named instances matches the axis defaults.
+    pub fn default_instance_index(&self) -> Option<u32> {
+        /// How far, in design units, a coordinate may differ from
+        /// the axis default while still being considered equal to it
+        const EPSILON: f64 = 0.001;
+
+        unsafe {
+            if !self.flags.has_variations() {
+                return None;
+            }
+
+            let mut mm = std::ptr::null_mut();
+            if ft_result(FT_Get_MM_Var(self.face, &mut mm), ()).is_err() {
+                return None;
+            }
+
+            let result = {
+                let mm = &*mm;
+                let num_axis = mm.num_axis as usize;
+                let num_styles = mm.num_namedstyles as usize;
+                // Fonts without named instances may leave these null
+                if num_axis == 0 || num_styles == 0 || mm.axis.is_null() || mm.namedstyle.is_null()
+                {
                     None
-                },
-                close: Some(Self::close),
-                memory: ptr::null_mut(),
-                cursor: ptr::null_mut(),
-                limit: ptr::null_mut(),
-            },
-            backing,
-            name: p.to_string_lossy().to_string(),
-        });
-        let stream = Box::into_raw(stream);
+                } else {
+                    let axes = std::slice::from_raw_parts(mm.axis, num_axis);
+                    let styles = std::slice::from_raw_parts(mm.namedstyle, num_styles);
+
+                    styles
+                        .iter()
+                        .position(|style| {
+                            if style.coords.is_null() {
+                                return false;
+                            }
+                            let coords = std::slice::from_raw_parts(style.coords, num_axis);
+                            axes.iter().zip(coords.iter()).all(|(axis, &coord)| {
+                                let value = coord as f64 / (1 << 16) as f64;
+                                let default_value = axis.def as f64 / (1 << 16) as f64;
+                                (value - default_value).abs() < EPSILON
+                            })
+                        })
+                        .map(|idx| idx as u32 + 1)
+                }
+            };
+
+            FT_Done_MM_Var(self.lib, mm);
+            result
+        }
+    }
+
+    /// Returns true if the face has color glyphs
+    pub fn has_color(&self) -> bool {
+        self.flags.has_color()
+    }
+
+    /// Returns true if the face has kerning data that can be retrieved
+    /// using FT_Get_Kerning, which for sfnt fonts means a legacy kern table.
+    /// Kerning that is defined only by GPOS is not reported here.
+    pub fn has_legacy_kern(&self) -> bool {
+        self.flags.has_kerning()
+    }
+
+    /// Returns true if the GPOS table defines the kern feature.
+    /// Unlike `supports_feature`, this ignores GSUB, and a malformed
+    /// GPOS table is treated as having no kerning.
+    pub fn has_gpos_kern(&self) -> bool {
+        self.sfnt_table(b"GPOS")
+            .and_then(|data| sfnt::parse_feature_tags(&data))
+            .map(|tags| tags.contains(&sfnt::make_tag(b"kern")))
+            .unwrap_or(false)
+    }
+
+    /// Returns all of the pairs in the legacy kern table, as the left
+    /// glyph, right glyph and x-kerning in font units, so that a layout
+    /// engine can build its own kerning map rather than calling
+    /// FT_Get_Kerning for each pair of adjacent glyphs.
+    /// Only the common format 0 horizontal subtables of Windows style
+    /// kern tables are understood; see `sfnt::parse_kern_pairs`.
+    /// Returns an empty list if there is no kern table, or if it
+    /// can't be parsed.
+    pub fn all_kerning_pairs(&self) -> Vec<(FT_UInt, FT_UInt, f64)> {
+        let data = match self.sfnt_table(b"kern") {
+            Some(data) => data,
+            None => return vec![],
+        };
+        match sfnt::parse_kern_pairs(&data) {
+            Some(pairs) => pairs
+                .into_iter()
+                .map(|(left, right, value)| (left.into(), right.into(), value.into()))
+                .collect(),
+            None => {
+                log::debug!("unable to parse the kern table of {}", self.identity());
+                vec![]
+            }
+        }
+    }
+
+    /// Returns the face flags, which were captured when the
+    /// face was opened
+    pub fn flags(&self) -> FaceFlags {
+        self.flags
+    }
+
+    pub fn get_os2_table(&self) -> Option<&TT_OS2> {
         unsafe {
-            (*stream).stream.descriptor.pointer = stream as *mut _;
-            Ok(&mut (*stream).stream)
+            let os2: *const TT_OS2 = FT_Get_Sfnt_Table(self.face, FT_Sfnt_Tag::FT_SFNT_OS2) as _;
+            if os2.is_null() {
+                None
+            } else {
+                Some(&*os2)
+            }
         }
     }
 
-    /// Called by freetype when it wants to read data from the file
-    unsafe extern "C" fn read(
-        stream: FT_Stream,
-        offset: c_ulong,
-        buffer: *mut c_uchar,
-        count: c_ulong,
-    ) -> c_ulong {
-        if count == 0 {
-            return 0;
+    /// Returns the fontRevision field of the `head` table, which is
+    /// a 16.16 fixed point number, if the font has one
+    pub fn font_revision(&self) -> Option<i64> {
+        unsafe {
+            let head: *const TT_Header =
+                FT_Get_Sfnt_Table(self.face, FT_Sfnt_Tag::FT_SFNT_HEAD) as _;
+            if head.is_null() {
+                None
+            } else {
+                Some((*head).Font_Revision as i64)
+            }
+        }
+    }
+
+    /// Returns the lowestRecPPEM field of the `head` table, which is
+    /// the smallest size, in pixels per em, at which the designer
+    /// considers the font to be readable.  Fonts that don't specify
+    /// it, by leaving it as 0, and fonts without a `head` table
+    /// return None.
+    pub fn lowest_rec_ppem(&self) -> Option<u16> {
+        unsafe {
+            let head: *const TT_Header =
+                FT_Get_Sfnt_Table(self.face, FT_Sfnt_Tag::FT_SFNT_HEAD) as _;
+            if head.is_null() || (*head).Lowest_Rec_PPEM == 0 {
+                None
+            } else {
+                Some((*head).Lowest_Rec_PPEM)
+            }
         }
+    }
 
-        let myself = &mut *((*stream).descriptor.pointer as *mut Self);
-        match &mut myself.backing {
-            StreamBacking::Map(_) | StreamBacking::Static(_) | StreamBacking::Memory(_) => {
-                log::error!("read called on memory data {} !?", myself.name);
-                0
+    /// Returns true if the selected size is smaller than the
+    /// lowest_rec_ppem of the font
+    pub fn is_below_lowest_rec_ppem(&self) -> bool {
+        match (self.lowest_rec_ppem(), self.size.is_some()) {
+            (Some(lowest), true) => unsafe { (*(*self.face).size).metrics.y_ppem < lowest },
+            _ => false,
+        }
+    }
+
+    /// Returns the string for `name_id` from the `name` table, such
+    /// as `sfnt::NAME_ID_VERSION`, or None if the face has no such
+    /// record
+    pub fn name_string(&self, name_id: u16) -> Option<String> {
+        let table = self.sfnt_table(b"name")?;
+        sfnt::parse_name(&table, name_id)
+    }
+
+    /// Returns the unique font identifier from the `name` table
+    pub fn unique_id(&self) -> Option<String> {
+        self.name_string(sfnt::NAME_ID_UNIQUE_ID)
+    }
+
+    /// Returns the version string from the `name` table, which
+    /// identifies the build of the font, such as "Version 5.002"
+    pub fn version_string(&self) -> Option<String> {
+        self.name_string(sfnt::NAME_ID_VERSION)
+    }
+
+    /// Returns the license description from the `name` table
+    pub fn license(&self) -> Option<String> {
+        self.name_string(sfnt::NAME_ID_LICENSE)
+    }
+
+    /// Returns the license information URL from the `name` table
+    pub fn license_url(&self) -> Option<String> {
+        self.name_string(sfnt::NAME_ID_LICENSE_URL)
+    }
+
+    /// Returns the cap_height/units_per_EM ratio if known
+    pub fn cap_height(&self) -> Option<f64> {
+        unsafe {
+            let os2 = self.get_os2_table()?;
+            let units_per_em = (*self.face).units_per_EM;
+            if units_per_em == 0 || os2.sCapHeight == 0 {
+                return None;
             }
-            StreamBacking::File(file) => {
-                if let Err(err) = file.seek(SeekFrom::Start(offset.into())) {
-                    log::error!(
-                        "failed to seek {} to offset {}: {:#}",
-                        myself.name,
-                        offset,
-                        err
-                    );
-                    return 0;
-                }
+            Some(os2.sCapHeight as f64 / units_per_em as f64)
+        }
+    }
 
-                let buf = std::slice::from_raw_parts_mut(buffer, count as usize);
-                match file.read(buf) {
-                    Ok(len) => len as c_ulong,
-                    Err(err) => {
-                        log::error!(
-                            "failed to read {} bytes @ offset {} of {}: {:#}",
-                            count,
-                            offset,
-                            myself.name,
-                            err
-                        );
-                        0
-                    }
+    pub fn weight_and_width(&self) -> (u16, u16) {
+        let (mut weight, mut width) = self
+            .get_os2_table()
+            .map(|os2| (os2.usWeightClass as f64, os2.usWidthClass as f64))
+            .unwrap_or((400., 5.));
+
+        let variation = unsafe { (*self.face).face_index } >> 16;
+        if variation > 0 {
+            match self.named_style_scales((variation - 1) as usize) {
+                Ok((weight_scale, width_scale)) => {
+                    weight *= weight_scale;
+                    width *= width_scale;
+                }
+                Err(err) => log::warn!(
+                    "{:#}; using the weight and width from the OS/2 table of {}",
+                    err,
+                    self.identity()
+                ),
+            }
+        }
+
+        (weight.round() as u16, width.round() as u16)
+    }
+
+    /// Returns the factors by which the `wght` and `wdth` axis values
+    /// of the named style `vidx` differ from the defaults of those
+    /// axes.  A broken `fvar` table, or a style index that is beyond
+    /// the named styles of the font, produces an error.
+    fn named_style_scales(&self, vidx: usize) -> anyhow::Result<(f64, f64)> {
+        unsafe {
+            let mut mm = std::ptr::null_mut();
+            ft_result(FT_Get_MM_Var(self.face, &mut mm), ()).context("FT_Get_MM_Var")?;
+            let result = scales_from_mm_var(&*mm, vidx);
+            FT_Done_MM_Var(self.lib, mm);
+            result
+        }
+    }
+
+    pub fn italic(&self) -> bool {
+        unsafe { ((*self.face).style_flags & FT_STYLE_FLAG_ITALIC as FT_Long) != 0 }
+    }
+
+    /// Returns true if the face is already bold, in which case
+    /// synthesizing bold would make it too heavy.
+    /// Some fonts set the bold style flag without an appropriate
+    /// weight class, and others only have the weight class, so
+    /// either signal is sufficient.
+    pub fn is_already_bold(&self) -> bool {
+        let style_bold = unsafe { ((*self.face).style_flags & FT_STYLE_FLAG_BOLD as FT_Long) != 0 };
+        let (weight, _width) = self.weight_and_width();
+        style_bold || weight >= 600
+    }
+
+    /// Look up the glyph for `c` in every charmap of the face, rather
+    /// than only the one that freetype selected when the face was opened,
+    /// and log which charmap it was found in.
+    /// This is slow, and is intended for troubleshooting fonts with
+    /// unusual charmaps that appear to be missing glyphs that other
+    /// applications are able to render, not for use while rendering.
+    pub fn find_glyph_by_unicode_scan(&self, c: char) -> Option<FT_UInt> {
+        unsafe {
+            let face = &*self.face;
+            if face.charmaps.is_null() {
+                return None;
+            }
+            let charmaps = std::slice::from_raw_parts(face.charmaps, face.num_charmaps as usize);
+            let prior = face.charmap;
+
+            let mut result = None;
+            for (idx, &charmap) in charmaps.iter().enumerate() {
+                if !succeeded(FT_Set_Charmap(self.face, charmap)) {
+                    continue;
+                }
+                let cmap = &*charmap;
+                let mut codes = vec![c as FT_ULong];
+                // Symbol fonts conventionally place their glyphs in
+                // the private use area, at U+F000-U+F0FF
+                if cmap.encoding == FT_Encoding::FT_ENCODING_MS_SYMBOL && (c as u32) < 0x100 {
+                    codes.push(0xf000 + c as FT_ULong);
+                }
+                if let Some((code, glyph)) = codes
+                    .into_iter()
+                    .map(|code| (code, FT_Get_Char_Index(self.face, code)))
+                    .find(|&(_, glyph)| glyph != 0)
+                {
+                    log::info!(
+                        "find_glyph_by_unicode_scan: {} is glyph {} via code 0x{:x} \
+                         in charmap {} (platform_id={} encoding_id={} encoding={:?}) of {}",
+                        c.escape_unicode(),
+                        glyph,
+                        code,
+                        idx,
+                        cmap.platform_id,
+                        cmap.encoding_id,
+                        cmap.encoding,
+                        self.source.diagnostic_string()
+                    );
+                    result.replace(glyph);
+                    break;
+                }
+            }
+
+            if !prior.is_null() {
+                FT_Set_Charmap(self.face, prior);
+            }
+            result
+        }
+    }
+
+    /// Returns the length of the sfnt table identified by `tag`,
+    /// or None if the face has no such table
+    fn sfnt_table_len(&self, tag: u32) -> Option<usize> {
+        let mut len: FT_ULong = 0;
+        if succeeded(unsafe {
+            FT_Load_Sfnt_Table(self.face, tag as FT_ULong, 0, ptr::null_mut(), &mut len)
+        }) {
+            Some(len as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the face has the sfnt table identified by `tag`
+    /// (as produced by `sfnt::make_tag`), without loading its data
+    pub fn has_table(&self, tag: u32) -> bool {
+        self.sfnt_table_len(tag).is_some()
+    }
+
+    /// Load the first `len` bytes of the sfnt table identified by `tag`,
+    /// which is cheaper than loading the whole of a large table when
+    /// only its header is needed
+    fn load_sfnt_table_prefix(&self, tag: u32, len: usize) -> Option<Vec<u8>> {
+        let len = self.sfnt_table_len(tag)?.min(len);
+        let mut data = vec![0u8; len];
+        let mut len = len as FT_ULong;
+        if succeeded(unsafe {
+            FT_Load_Sfnt_Table(self.face, tag as FT_ULong, 0, data.as_mut_ptr(), &mut len)
+        }) {
+            Some(data)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the usability flags of each of the CPAL palettes of the
+    /// face, in palette index order.  A face without a CPAL table has
+    /// no palettes, and one whose palettes aren't flagged reports them
+    /// as empty.
+    pub fn palette_flags(&self) -> Vec<PaletteUsability> {
+        self.sfnt_table(b"CPAL")
+            .and_then(|table| sfnt::parse_palette_types(&table))
+            .unwrap_or_default()
+            .into_iter()
+            .map(PaletteUsability::from_bits_truncate)
+            .collect()
+    }
+
+    /// Returns the index of the first palette that is flagged as being
+    /// suitable for a dark background, if `dark` is true, or for a
+    /// light background otherwise.  Returns None if no palette has the
+    /// flag, in which case the default palette (index 0) is the best
+    /// choice.
+    pub fn palette_for_background(&self, dark: bool) -> Option<usize> {
+        let wanted = if dark {
+            PaletteUsability::DARK_BACKGROUND
+        } else {
+            PaletteUsability::LIGHT_BACKGROUND
+        };
+        self.palette_flags()
+            .iter()
+            .position(|flags| flags.contains(wanted))
+    }
+
+    /// Returns the bitDepth of each of the strikes in the CBLC table
+    /// of the face, in the same order as its bitmap strikes, for
+    /// diagnostics.  Color bitmap fonts such as Noto Color Emoji use
+    /// 32 bit BGRA strikes, but the format also allows 1, 2, 4 and 8
+    /// bit grayscale strikes.  The EBLC table of a monochrome bitmap
+    /// font has the same layout and is used if there is no CBLC table.
+    /// Returns an empty list if the face has neither table.
+    pub fn cbdt_bit_depths(&self) -> Vec<u8> {
+        self.sfnt_table(b"CBLC")
+            .or_else(|| self.sfnt_table(b"EBLC"))
+            .and_then(|table| sfnt::parse_strike_bit_depths(&table))
+            .unwrap_or_default()
+    }
+
+    /// Returns the color glyph formats that the face provides.
+    /// A COLR table is reported as COLR_V0 or COLR_V1 based on its
+    /// version; a font that has both kinds of glyphs uses a version 1
+    /// table, so only COLR_V1 is reported for it.
+    pub fn color_formats(&self) -> ColorFormats {
+        let mut formats = ColorFormats::empty();
+        if let Some(header) = self.load_sfnt_table_prefix(sfnt::make_tag(b"COLR"), 2) {
+            match sfnt::read_u16(&header, 0) {
+                Some(0) => formats |= ColorFormats::COLR_V0,
+                Some(_) => formats |= ColorFormats::COLR_V1,
+                None => {}
+            }
+        }
+        for (tag, format) in &[
+            (b"CPAL", ColorFormats::CPAL),
+            (b"CBDT", ColorFormats::CBDT),
+            (b"sbix", ColorFormats::SBIX),
+            (b"SVG ", ColorFormats::SVG),
+        ] {
+            if self.has_table(sfnt::make_tag(tag)) {
+                formats |= *format;
+            }
+        }
+        formats
+    }
+
+    /// Load the raw data for the sfnt table identified by `tag`, such
+    /// as `b"head"`, for parsing with the helpers in `sfnt`.
+    /// Returns None if the face has no such table, or isn't an sfnt
+    /// (TrueType or OpenType) face at all.
+    pub fn sfnt_table(&self, tag: &[u8; 4]) -> Option<Vec<u8>> {
+        if !self.flags.is_sfnt() {
+            return None;
+        }
+        self.load_sfnt_table_prefix(sfnt::make_tag(tag), usize::MAX)
+    }
+
+    /// Returns the tag and length of each of the tables of the face,
+    /// in the order in which they are listed in the font, or None if
+    /// the face isn't an sfnt face
+    pub fn sfnt_table_directory(&self) -> Option<Vec<([u8; 4], usize)>> {
+        if !self.flags.is_sfnt() {
+            return None;
+        }
+        let mut num_tables: FT_ULong = 0;
+        // With a null tag, freetype reports the number of tables
+        // via the length
+        if !succeeded(unsafe { FT_Sfnt_Table_Info(self.face, 0, ptr::null_mut(), &mut num_tables) })
+        {
+            return None;
+        }
+        let mut tables = vec![];
+        for index in 0..num_tables as FT_UInt {
+            let mut tag: FT_ULong = 0;
+            let mut len: FT_ULong = 0;
+            if succeeded(unsafe { FT_Sfnt_Table_Info(self.face, index, &mut tag, &mut len) }) {
+                tables.push(((tag as u32).to_be_bytes(), len as usize));
+            }
+        }
+        Some(tables)
+    }
+
+    /// Returns the set of feature tags defined by the GSUB and GPOS
+    /// tables, or None if either of those tables is malformed.
+    /// A face with neither table has an empty set of features.
+    /// The feature set is parsed on first use and cached for the
+    /// lifetime of the face.
+    pub fn feature_tags(&self) -> Option<HashSet<u32>> {
+        self.features
+            .borrow_mut()
+            .get_or_insert_with(|| {
+                let mut features = HashSet::new();
+                for table in &[*b"GSUB", *b"GPOS"] {
+                    if let Some(data) = self.sfnt_table(table) {
+                        features.extend(sfnt::parse_feature_tags(&data)?);
+                    }
+                }
+                Some(features)
+            })
+            .clone()
+    }
+
+    /// Returns true if the GSUB or GPOS table defines the feature `tag`
+    /// (as produced by `sfnt::make_tag`).
+    /// The feature set is cached, so this is cheap to call repeatedly.
+    pub fn supports_feature(&self, tag: u32) -> bool {
+        if let Some(features) = self.features.borrow().as_ref() {
+            return features.as_ref().map(|f| f.contains(&tag)).unwrap_or(false);
+        }
+        self.feature_tags()
+            .map(|f| f.contains(&tag))
+            .unwrap_or(false)
+    }
+
+    /// Returns the glyph that the `rtlm` feature of the font substitutes
+    /// for `glyph_index` in right-to-left text, such as the mirror image
+    /// of a bracket.  Returns None if the font doesn't provide a mirrored
+    /// form of the glyph, in which case the caller should fall back to
+    /// the Unicode Bidi_Mirroring_Glyph of the character instead.
+    /// The substitutions are parsed once and cached on the face.
+    pub fn mirrored_glyph(&self, glyph_index: FT_UInt) -> Option<FT_UInt> {
+        let mut mirrors = self.rtl_mirrors.borrow_mut();
+        let mirrors = mirrors.get_or_insert_with(|| {
+            self.sfnt_table(b"GSUB")
+                .and_then(|gsub| sfnt::parse_single_substitutions(&gsub, sfnt::make_tag(b"rtlm")))
+                .unwrap_or_default()
+                .into_iter()
+                .collect()
+        });
+        let glyph_index: u16 = glyph_index.try_into().ok()?;
+        mirrors
+            .get(&glyph_index)
+            .copied()
+            .filter(|&mirrored| mirrored != glyph_index)
+            .map(FT_UInt::from)
+    }
+
+    pub fn compute_coverage(&self) -> RangeSet<u32> {
+        let mut coverage = RangeSet::new();
+
+        for encoding in &[
+            FT_Encoding::FT_ENCODING_UNICODE,
+            FT_Encoding::FT_ENCODING_MS_SYMBOL,
+        ] {
+            if unsafe { FT_Select_Charmap(self.face, *encoding) } != 0 {
+                continue;
+            }
+
+            let mut glyph = 0;
+            let mut ucs4 = unsafe { FT_Get_First_Char(self.face, &mut glyph) };
+            while glyph != 0 {
+                coverage.add(ucs4 as u32);
+                ucs4 = unsafe { FT_Get_Next_Char(self.face, ucs4, &mut glyph) };
+            }
+
+            if *encoding == FT_Encoding::FT_ENCODING_MS_SYMBOL {
+                // Fontconfig duplicates F000..F0FF to 0000..00FF
+                for ucs4 in 0xf000..0xf100 {
+                    if coverage.contains(ucs4) {
+                        coverage.add(ucs4 as u32 - 0xf000);
+                    }
+                }
+            }
+        }
+
+        coverage
+    }
+
+    /// Returns true if the face has a glyph for every char of `s`.
+    /// This is equivalent to checking that `compute_coverage` contains
+    /// each of them, but only probes the charmaps for those chars.
+    pub fn covers_str(&self, s: &str) -> bool {
+        self.uncovered_chars(s).is_empty()
+    }
+
+    /// Returns the chars of `s` that the face has no glyph for, in the
+    /// order in which they first appear and without duplicates.
+    /// The chars are looked up in the unicode charmap and then in the
+    /// symbol charmap, which is consulted at U+F000-U+F0FF for those
+    /// in U+0000-U+00FF in the same way as in `compute_coverage`.
+    /// The selected charmap, which harfbuzz uses to find the glyphs
+    /// that it shapes, is restored afterwards.
+    pub fn uncovered_chars(&self, s: &str) -> Vec<char> {
+        let mut uncovered: Vec<char> = vec![];
+        for c in s.chars() {
+            if !uncovered.contains(&c) {
+                uncovered.push(c);
+            }
+        }
+
+        self.with_charmap_restored(|| {
+            for encoding in &[
+                FT_Encoding::FT_ENCODING_UNICODE,
+                FT_Encoding::FT_ENCODING_MS_SYMBOL,
+            ] {
+                if uncovered.is_empty() {
+                    break;
+                }
+                if unsafe { FT_Select_Charmap(self.face, *encoding) } != 0 {
+                    continue;
+                }
+                let symbol = *encoding == FT_Encoding::FT_ENCODING_MS_SYMBOL;
+                let mapped = |code: u32| unsafe { FT_Get_Char_Index(self.face, code as _) } != 0;
+                uncovered.retain(|&c| {
+                    let c = c as u32;
+                    !(mapped(c) || (symbol && c <= 0xff && mapped(0xf000 + c)))
+                });
+            }
+        });
+
+        uncovered
+    }
+
+    /// Call `func`, which may select a different charmap, and then
+    /// restore the charmap that was selected beforehand, which
+    /// harfbuzz uses to find the glyphs that it shapes
+    fn with_charmap_restored<R, F: FnOnce() -> R>(&self, func: F) -> R {
+        let prior = unsafe { (*self.face).charmap };
+        let result = func();
+        if !prior.is_null() {
+            unsafe {
+                FT_Set_Charmap(self.face, prior);
+            }
+        }
+        result
+    }
+
+    /// Computes the coverage of the codepoints from `start` through
+    /// `end` inclusive.  This is equivalent to intersecting the result
+    /// of `compute_coverage` with that range, but only visits the
+    /// portion of the charmaps within it, which is much faster when
+    /// checking whether a font covers a particular block.
+    /// The selected charmap is restored afterwards.
+    pub fn coverage_in_range(&self, start: u32, end: u32) -> RangeSet<u32> {
+        let mut coverage = RangeSet::new();
+        if start > end {
+            return coverage;
+        }
+
+        self.with_charmap_restored(|| {
+            for encoding in &[
+                FT_Encoding::FT_ENCODING_UNICODE,
+                FT_Encoding::FT_ENCODING_MS_SYMBOL,
+            ] {
+                if unsafe { FT_Select_Charmap(self.face, *encoding) } != 0 {
+                    continue;
+                }
+
+                self.walk_charmap(start, end, |ucs4, _| coverage.add(ucs4));
+
+                if *encoding == FT_Encoding::FT_ENCODING_MS_SYMBOL && start <= 0xff {
+                    // As in compute_coverage, F000..F0FF is also
+                    // reported as 0000..00FF
+                    self.walk_charmap(0xf000 + start, 0xf000 + end.min(0xff), |ucs4, _| {
+                        coverage.add(ucs4 - 0xf000)
+                    });
+                }
+            }
+        });
+
+        coverage
+    }
+
+    /// Returns the lowest codepoint that the unicode charmap maps to
+    /// `glyph_index`.  Glyphs that are only reachable through
+    /// substitutions, such as ligatures, have no codepoint.
+    /// The charmap is walked once, on first use, and the reverse
+    /// mapping is retained for subsequent calls.
+    pub fn codepoint_for_glyph(&self, glyph_index: FT_UInt) -> Option<u32> {
+        let mut codepoints = self.glyph_codepoints.borrow_mut();
+        let codepoints = codepoints.get_or_insert_with(|| {
+            let mut codepoints = HashMap::new();
+            self.with_charmap_restored(|| {
+                if unsafe { FT_Select_Charmap(self.face, FT_Encoding::FT_ENCODING_UNICODE) } != 0 {
+                    return;
+                }
+                self.walk_charmap(0, u32::max_value(), |ucs4, glyph| {
+                    codepoints.entry(glyph).or_insert(ucs4);
+                });
+            });
+            codepoints
+        });
+        codepoints.get(&glyph_index).copied()
+    }
+
+    /// Call `func` with each codepoint from `start` through `end`
+    /// inclusive that is mapped by the selected charmap, along with
+    /// the glyph that it maps to
+    fn walk_charmap<F: FnMut(u32, FT_UInt)>(&self, start: u32, end: u32, mut func: F) {
+        let mut glyph = 0;
+        let mut ucs4 = unsafe {
+            if start == 0 {
+                FT_Get_First_Char(self.face, &mut glyph)
+            } else {
+                FT_Get_Next_Char(self.face, (start - 1) as FT_ULong, &mut glyph)
+            }
+        };
+        while glyph != 0 && ucs4 as u32 <= end {
+            func(ucs4 as u32, glyph);
+            ucs4 = unsafe { FT_Get_Next_Char(self.face, ucs4, &mut glyph) };
+        }
+    }
+
+    /// Describes this face, for use in error messages and logs, so
+    /// that a problem can be attributed to a specific font, face
+    /// index, variation and size
+    pub fn identity(&self) -> String {
+        let name = format!("{} {}", self.family_name(), self.style_name());
+        let size = match self.size.as_ref() {
+            Some(FaceSize {
+                size,
+                dpi,
+                strike: Some(strike),
+                ..
+            }) => format!("{}pt at {}dpi using strike {}", size, dpi, strike),
+            Some(FaceSize { size, dpi, .. }) => format!("{}pt at {}dpi", size, dpi),
+            None => "no size selected".to_string(),
+        };
+        format!(
+            "{} ({}; {})",
+            name.trim(),
+            self.source.diagnostic_string(),
+            size
+        )
+    }
+
+    /// Returns the point size and dpi that were most recently
+    /// selected via `set_font_size`, or None if no size has been
+    /// selected yet
+    pub fn current_size(&self) -> Option<(f64, u32)> {
+        self.size.as_ref().map(|size| (size.size, size.dpi))
+    }
+
+    /// Returns the pixel height that results from the currently
+    /// selected point size and dpi, or None if no size has been
+    /// selected yet.
+    /// For bitmap fonts this is the height of the selected strike.
+    pub fn effective_pixel_size(&self) -> Option<f64> {
+        self.size.as_ref().map(|size| {
+            if size.is_scaled {
+                point_size_to_pixels(size.size, size.dpi)
+            } else {
+                size.cell_height
+            }
+        })
+    }
+
+    /// This is a wrapper around set_char_size and select_size
+    /// that accounts for some weirdness with eg: color emoji
+    pub fn set_font_size(&mut self, point_size: f64, dpi: u32) -> anyhow::Result<SelectedFontSize> {
+        self.select_font_size(point_size, dpi).with_context(|| {
+            format!(
+                "set_font_size({}, dpi={}) of {}",
+                point_size,
+                dpi,
+                self.identity()
+            )
+        })
+    }
+
+    fn select_font_size(&mut self, point_size: f64, dpi: u32) -> anyhow::Result<SelectedFontSize> {
+        if let Some(face_size) = self.size.as_ref() {
+            if face_size.size == point_size
+                && face_size.dpi == dpi
+                && face_size.transform == self.transform
+            {
+                return Ok(face_size.selected());
+            }
+        }
+
+        // If we've used this size before, we can re-select it without
+        // searching the strikes or recomputing the cell metrics
+        let key: SizeKey = (point_size.to_bits(), dpi, self.transform.to_bits());
+        if let Some(face_size) = self.size_cache.get(&key).copied() {
+            self.apply_size(&face_size)?;
+            self.size.replace(face_size);
+            return Ok(face_size.selected());
+        }
+
+        let pixel_height = point_size_to_pixels(point_size, dpi);
+        log::debug!(
+            "set_char_size computing {} dpi={} (pixel height={})",
+            point_size,
+            dpi,
+            pixel_height
+        );
+
+        // Scaling before truncating to integer minimizes the chances of hitting
+        // the fallback code for set_pixel_sizes below.
+        let size = (point_size * 64.0) as FT_F26Dot6;
+
+        let (selected_size, strike) = if self.is_bitmap_only_sfnt() {
+            // set_char_size succeeds for a size that exactly matches one
+            // of the strikes of such a face, but cell_metrics would then
+            // compute a zero height from its empty scalable metrics.
+            // Always go through the strikes instead.
+            match self.select_best_strike(pixel_height)? {
+                Some((selected_size, strike)) => (selected_size, Some(strike)),
+                None => anyhow::bail!("bitmap-only face has no strikes"),
+            }
+        } else {
+            match self.set_char_size(size, size, dpi, dpi) {
+                Ok(_) => {
+                    // Compute metrics for the nominal monospace cell.
+                    // The transform doesn't apply to the metrics that
+                    // freetype reports, so it is applied to the cell
+                    // here, keeping it consistent with the advances of
+                    // the transformed glyphs.
+                    let (width, height) = self.cell_metrics();
+                    (
+                        SelectedFontSize {
+                            width: width * self.transform.x_scale,
+                            height: height * self.transform.y_scale,
+                            is_scaled: true,
+                        },
+                        None,
+                    )
+                }
+                Err(err) => {
+                    log::debug!("set_char_size: {:?}, will inspect strikes", err);
+                    match self.select_best_strike(pixel_height)? {
+                        Some((selected_size, strike)) => (selected_size, Some(strike)),
+                        None => return Err(err),
+                    }
+                }
+            }
+        };
+
+        let face_size = FaceSize {
+            size: point_size,
+            dpi,
+            transform: self.transform,
+            cell_width: selected_size.width,
+            cell_height: selected_size.height,
+            is_scaled: selected_size.is_scaled,
+            strike,
+        };
+        self.size.replace(face_size);
+        self.size_cache.insert(key, face_size);
+
+        Ok(selected_size)
+    }
+
+    /// Select the strike that is closest to `pixel_height`.
+    /// The cell size is that of the strike, and the ascender and
+    /// descender of the size are those that freetype derives from the
+    /// line metrics of the strike.
+    /// Returns None if the face has no strikes.
+    fn select_best_strike(
+        &mut self,
+        pixel_height: f64,
+    ) -> anyhow::Result<Option<(SelectedFontSize, usize)>> {
+        let sizes = unsafe {
+            let rec = &(*self.face);
+            if rec.num_fixed_sizes <= 0 {
+                return Ok(None);
+            }
+            std::slice::from_raw_parts(rec.available_sizes, rec.num_fixed_sizes as usize)
+        };
+        let idx = match best_strike(sizes, pixel_height) {
+            Some(idx) => idx,
+            None => return Ok(None),
+        };
+        let idx = deepest_strike(sizes, &self.cbdt_bit_depths(), idx);
+        let best = sizes[idx];
+        self.select_size(idx)?;
+        Ok(Some((
+            SelectedFontSize {
+                width: f64::from(best.width),
+                height: f64::from(best.height),
+                is_scaled: false,
+            },
+            idx,
+        )))
+    }
+
+    /// Apply a previously computed size selection to the face,
+    /// re-selecting the same bitmap strike if it used one
+    fn apply_size(&mut self, face_size: &FaceSize) -> anyhow::Result<()> {
+        match face_size.strike {
+            Some(idx) => self.select_size(idx),
+            None => {
+                let size = (face_size.size * 64.0) as FT_F26Dot6;
+                self.set_char_size(size, size, face_size.dpi, face_size.dpi)
+            }
+        }
+    }
+
+    /// Restore the size state to `prior`, as captured from `self.size`.
+    /// If no size had been selected, then none is selected afterwards
+    /// either and `set_font_size` must be called before rendering.
+    fn restore_size(&mut self, prior: Option<FaceSize>) -> anyhow::Result<()> {
+        if self.size == prior {
+            return Ok(());
+        }
+        // Clear the size first, so that if re-applying the prior size
+        // fails we don't claim a size that isn't selected
+        self.size.take();
+        if let Some(prior) = prior.as_ref() {
+            self.apply_size(prior)?;
+        }
+        self.size = prior;
+        Ok(())
+    }
+
+    /// Temporarily select `point_size` at `dpi`.  The returned guard
+    /// dereferences to the face, so that it can be used to render at
+    /// the temporary size, and the previously selected size, including
+    /// the bitmap strike of fonts that don't scale, is restored when
+    /// the guard is dropped, even if that is due to an early return
+    /// or a panic.
+    pub fn with_size(&mut self, point_size: f64, dpi: u32) -> anyhow::Result<SizeGuard> {
+        let prior = self.size;
+        let mut guard = SizeGuard { face: self, prior };
+        // If this fails, dropping the guard puts back the prior size
+        guard.face.set_font_size(point_size, dpi)?;
+        Ok(guard)
+    }
+
+    fn set_char_size(
+        &mut self,
+        char_width: FT_F26Dot6,
+        char_height: FT_F26Dot6,
+        horz_resolution: FT_UInt,
+        vert_resolution: FT_UInt,
+    ) -> anyhow::Result<()> {
+        ft_result(
+            unsafe {
+                FT_Set_Char_Size(
+                    self.face,
+                    char_width,
+                    char_height,
+                    horz_resolution,
+                    vert_resolution,
+                )
+            },
+            (),
+        )
+        .context("FT_Set_Char_Size")?;
+
+        unsafe {
+            if (*self.face).height == 0 {
+                anyhow::bail!("font has 0 height, fallback to bitmaps");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the bitmap strikes of the face along with their pixel
+    /// formats.  The format is determined by loading a probe glyph at
+    /// each strike, so the result is cached on the face.
+    /// The currently selected size is preserved.
+    pub fn strikes(&mut self) -> &[StrikeInfo] {
+        if self.strikes.is_none() {
+            let strikes = self.probe_strikes();
+            self.strikes.replace(strikes);
+        }
+        self.strikes.as_deref().unwrap_or(&[])
+    }
+
+    /// Returns the pixel format of the currently selected strike,
+    /// or None if the face is scalable or no strike has been selected
+    pub fn selected_strike_format(&mut self) -> Option<StrikeFormat> {
+        let idx = self.size.as_ref()?.strike?;
+        self.strikes().get(idx)?.format
+    }
+
+    /// Returns the ppem of each of the bitmap strikes of the face,
+    /// or an empty list if the face has outlines and can therefore
+    /// be rendered at any size
+    pub fn strike_ppems(&self) -> Vec<f64> {
+        if self.flags.contains(FaceFlags::SCALABLE) {
+            return vec![];
+        }
+        unsafe {
+            let rec = &(*self.face);
+            if rec.num_fixed_sizes <= 0 {
+                return vec![];
+            }
+            std::slice::from_raw_parts(rec.available_sizes, rec.num_fixed_sizes as usize)
+                .iter()
+                .map(strike_ppem)
+                .collect()
+        }
+    }
+
+    /// Returns the ppem of the strike at `idx`, if the face has one
+    fn strike_ppem_at(&self, idx: usize) -> Option<f64> {
+        unsafe {
+            let rec = &*self.face;
+            if idx >= rec.num_fixed_sizes.max(0) as usize {
+                return None;
+            }
+            Some(strike_ppem(&*rec.available_sizes.add(idx)))
+        }
+    }
+
+    fn probe_strikes(&mut self) -> Vec<StrikeInfo> {
+        let sizes = unsafe {
+            let rec = &(*self.face);
+            if rec.num_fixed_sizes <= 0 {
+                return vec![];
+            }
+            std::slice::from_raw_parts(rec.available_sizes, rec.num_fixed_sizes as usize).to_vec()
+        };
+
+        // Gather some glyphs to probe; not every glyph in the cmap
+        // is guaranteed to have a bitmap in every strike
+        const MAX_PROBES: usize = 16;
+        let mut probes = vec![];
+        unsafe {
+            let mut glyph_index = 0;
+            let mut charcode = FT_Get_First_Char(self.face, &mut glyph_index);
+            while glyph_index != 0 && probes.len() < MAX_PROBES {
+                probes.push(glyph_index);
+                charcode = FT_Get_Next_Char(self.face, charcode, &mut glyph_index);
+            }
+        }
+
+        let strikes = sizes
+            .iter()
+            .enumerate()
+            .map(|(idx, info)| {
+                let format = if self.select_size(idx).is_ok() {
+                    probes.iter().find_map(|&glyph_index| unsafe {
+                        if FT_Load_Glyph(self.face, glyph_index, FT_LOAD_COLOR as i32) != 0 {
+                            return None;
+                        }
+                        let slot = &*(*self.face).glyph;
+                        if slot.format != FT_Glyph_Format::FT_GLYPH_FORMAT_BITMAP {
+                            return None;
+                        }
+                        StrikeFormat::from_pixel_mode(std::mem::transmute(u32::from(
+                            slot.bitmap.pixel_mode,
+                        )))
+                    })
+                } else {
+                    None
+                };
+                StrikeInfo {
+                    width: info.width,
+                    height: info.height,
+                    format,
+                }
+            })
+            .collect();
+
+        // Restore the prior size selection.
+        // Probing may have selected strikes without updating self.size,
+        // so clear it to ensure that the prior size is re-applied.
+        let prior = self.size.take();
+        if let Err(err) = self.restore_size(prior) {
+            log::error!("probe_strikes: failed to restore font size: {:#}", err);
+        }
+
+        strikes
+    }
+
+    fn select_size(&mut self, idx: usize) -> anyhow::Result<()> {
+        ft_result(unsafe { FT_Select_Size(self.face, idx as i32) }, ()).context("FT_Select_Size")
+    }
+
+    /// Set the scale and slant that are applied to the outlines of
+    /// glyphs as they are loaded, which also scales their advances.
+    /// The cell metrics that `set_font_size` reports are scaled to
+    /// match.  Bitmap strikes are not transformed.
+    /// The transform only affects this face; other faces, even those
+    /// of the same font, are unaffected.
+    pub fn set_transform(&mut self, x_scale: f64, y_scale: f64, slant_degrees: f64) {
+        self.transform = FaceTransform {
+            x_scale,
+            y_scale,
+            slant_degrees,
+        };
+        self.apply_transform();
+    }
+
+    /// Returns the transform that was set by `set_transform`
+    pub fn transform(&self) -> FaceTransform {
+        self.transform
+    }
+
+    /// Shift the outlines of glyphs that are subsequently loaded to the
+    /// right by `offset` pixels, so that text can be positioned with
+    /// more precision than whole pixels.  Only the fractional part of
+    /// the offset matters; the caller places the bitmap at the whole
+    /// pixel.  Bitmap glyphs are not shifted.
+    /// The offset is rounded to the granularity that was set via
+    /// `set_subpixel_granularity`, which may round it up to a whole
+    /// pixel, and the offset that is applied is returned.
+    /// The rendered bitmap depends on that applied offset, so it,
+    /// rather than the requested one, must be part of the key under
+    /// which the bitmap is cached, and the granularity bounds the
+    /// number of bitmaps that are cached per glyph.
+    pub fn set_subpixel_offset(&mut self, offset: f64) -> f64 {
+        self.subpixel_offset = offset.fract();
+        self.apply_transform();
+        self.subpixel_phase()
+    }
+
+    /// Set the number of phases per pixel that the offset passed to
+    /// `set_subpixel_offset` is rounded to.  The default of 4 rounds
+    /// it to the nearest quarter of a pixel, 1 rounds it to whole
+    /// pixels, which is the same as not offsetting at all, and 0
+    /// applies it as precisely as freetype's 1/64th of a pixel allows,
+    /// at the cost of potentially caching a bitmap for each of those
+    /// 64 phases.  The current offset is rounded again.
+    pub fn set_subpixel_granularity(&mut self, levels: u8) {
+        self.subpixel_granularity = levels;
+        self.apply_transform();
+    }
+
+    /// Returns the offset, in pixels, that `set_subpixel_offset` has
+    /// applied to the glyphs, after rounding
+    pub fn subpixel_phase(&self) -> f64 {
+        quantize_subpixel_offset(self.subpixel_offset, self.subpixel_granularity) as f64 / 64.0
+    }
+
+    fn apply_transform(&self) {
+        let mut delta = FT_Vector {
+            x: quantize_subpixel_offset(self.subpixel_offset, self.subpixel_granularity),
+            y: 0,
+        };
+        let delta: *mut FT_Vector = if delta.x == 0 {
+            ptr::null_mut()
+        } else {
+            &mut delta
+        };
+        unsafe {
+            if self.transform.is_identity() {
+                FT_Set_Transform(self.face, ptr::null_mut(), delta);
+            } else {
+                let mut matrix = self.transform.matrix();
+                FT_Set_Transform(self.face, &mut matrix, delta);
+            }
+        }
+    }
+
+    /// Open another handle to this face, with its own size state, for
+    /// use when the face is needed for more than one purpose at a time.
+    /// The font data is shared with this face rather than reopening the
+    /// file where possible, so the cost is mostly that of freetype
+    /// parsing the face.  The selected named instance, variation
+    /// coordinates and transform are carried over, but no size is
+    /// selected in the duplicate.
+    /// `lib` must outlive the returned face.
+    pub fn duplicate(&self, lib: &Library) -> anyhow::Result<Face> {
+        let face_index = unsafe { (*self.face).face_index };
+        let face = self
+            .open_shared(lib, face_index)
+            .with_context(|| format!("duplicate({:?})", self.source))?;
+
+        let mut dup = Face {
+            face,
+            lib: lib.lib,
+            source: self.source.clone(),
+            size: None,
+            size_cache: HashMap::new(),
+            blank_glyphs: HashMap::new(),
+            transform: self.transform,
+            subpixel_offset: self.subpixel_offset,
+            subpixel_granularity: self.subpixel_granularity,
+            open_params: self.open_params.clone(),
+            features: RefCell::new(None),
+            rtl_mirrors: RefCell::new(None),
+            glyph_codepoints: RefCell::new(None),
+            strikes: self.strikes.clone(),
+            flags: self.flags,
+            compute_metrics: self.compute_metrics,
+            lcd_filter: self.lcd_filter,
+            alpha_floor: self.alpha_floor,
+            unhinted_below_lowest_rec_ppem: self.unhinted_below_lowest_rec_ppem,
+            normal_render_for_color_glyphs: self.normal_render_for_color_glyphs,
+            metrics_only: lib.metrics_only,
+            rendered_scaled_strike: false,
+            lcd_filter_state: Rc::clone(&lib.lcd_filter),
+            subpixel_order: Rc::clone(&lib.subpixel_order),
+        };
+        if self.flags.has_variations() {
+            dup.copy_design_coordinates(self)?;
+        }
+        dup.apply_transform();
+        Ok(dup)
+    }
+
+    /// Open face `index` of the collection that this face was loaded
+    /// from, sharing the data of this face where possible rather than
+    /// opening the source again.
+    /// `lib` must outlive the returned face.
+    pub(crate) fn sibling(&self, lib: &Library, index: u32) -> anyhow::Result<Face> {
+        let handle = FontDataHandle {
+            source: self.source.source.clone(),
+            index,
+            variation: 0,
+            origin: self.source.origin,
+        };
+        let face = self
+            .open_shared(lib, index as FT_Long)
+            .with_context(|| format!("sibling({:?})", handle))?;
+        Ok(lib.wrap_face(face, handle, &self.open_params))
+    }
+
+    /// Open the face at `face_index` of the source of this face from a
+    /// stream that shares the data of this one, falling back to opening
+    /// the source again if the data can't be shared
+    fn open_shared(&self, lib: &Library, face_index: FT_Long) -> anyhow::Result<FT_Face> {
+        match unsafe { FreeTypeStream::share((*self.face).stream) } {
+            Some(stream) => {
+                lib.open_stream(stream, &self.source.source, face_index, &self.open_params)
+            }
+            None => lib.new_face(&self.source.source, face_index, &self.open_params),
+        }
+    }
+
+    /// Apply the variation design coordinates of `other`, which must
+    /// be a face of the same font, to this face
+    fn copy_design_coordinates(&mut self, other: &Face) -> anyhow::Result<()> {
+        unsafe {
+            let mut mm = ptr::null_mut();
+            ft_result(FT_Get_MM_Var(other.face, &mut mm), ()).context("FT_Get_MM_Var")?;
+            let num_axis = (*mm).num_axis;
+            FT_Done_MM_Var(other.lib, mm);
+
+            let get = |face: FT_Face| -> anyhow::Result<Vec<FT_Fixed>> {
+                let mut coords = vec![0 as FT_Fixed; num_axis as usize];
+                ft_result(
+                    FT_Get_Var_Design_Coordinates(face, num_axis, coords.as_mut_ptr()),
+                    (),
+                )
+                .context("FT_Get_Var_Design_Coordinates")?;
+                Ok(coords)
+            };
+            let mut coords = get(other.face)?;
+            // Setting the coordinates discards the named instance,
+            // so only do so if they were changed from those of the
+            // named instance, which is selected by the face index
+            if coords == get(self.face)? {
+                return Ok(());
+            }
+            ft_result(
+                FT_Set_Var_Design_Coordinates(self.face, num_axis, coords.as_mut_ptr()),
+                (),
+            )
+            .context("FT_Set_Var_Design_Coordinates")
+        }
+    }
+
+    /// Returns the number of variation axes of the face
+    fn num_axis(&self) -> anyhow::Result<FT_UInt> {
+        if !self.flags.has_variations() {
+            anyhow::bail!("{} is not a variable font", self.identity());
+        }
+        unsafe {
+            let mut mm = ptr::null_mut();
+            ft_result(FT_Get_MM_Var(self.face, &mut mm), ()).context("FT_Get_MM_Var")?;
+            let num_axis = (*mm).num_axis;
+            FT_Done_MM_Var(self.lib, mm);
+            Ok(num_axis)
+        }
+    }
+
+    /// Returns the design coordinates of the face along each of its
+    /// variation axes, in the order in which the font lists the axes
+    pub fn variation_coords(&self) -> anyhow::Result<Vec<f64>> {
+        let num_axis = self.num_axis()?;
+        let mut coords = vec![0 as FT_Fixed; num_axis as usize];
+        unsafe {
+            ft_result(
+                FT_Get_Var_Design_Coordinates(self.face, num_axis, coords.as_mut_ptr()),
+                (),
+            )
+            .context("FT_Get_Var_Design_Coordinates")?;
+        }
+        Ok(coords
+            .into_iter()
+            .map(|coord| coord as f64 / 65536.0)
+            .collect())
+    }
+
+    /// Set the design coordinates of the face along its variation
+    /// axes, in the order in which the font lists them.  Axes beyond
+    /// the end of `coords` are set to their defaults, and freetype
+    /// clamps each coordinate to the range of its axis.
+    /// Glyphs that are subsequently loaded, including the layers of
+    /// COLR color glyphs, reflect the new coordinates.  The paint
+    /// tables of COLRv1 glyphs can also vary with the coordinates;
+    /// freetype only applies those variations from version 2.13, see
+    /// `Library::supports_variable_color_glyphs`.
+    pub fn set_variation_coords(&mut self, coords: &[f64]) -> anyhow::Result<()> {
+        let num_axis = self.num_axis()?;
+        if coords.len() > num_axis as usize {
+            anyhow::bail!(
+                "{} coordinates were specified for {}, which has {} variation axes",
+                coords.len(),
+                self.identity(),
+                num_axis
+            );
+        }
+        let mut coords: Vec<FT_Fixed> = coords
+            .iter()
+            .map(|&coord| (coord * 65536.0).round() as FT_Fixed)
+            .collect();
+        unsafe {
+            ft_result(
+                FT_Set_Var_Design_Coordinates(
+                    self.face,
+                    coords.len() as FT_UInt,
+                    coords.as_mut_ptr(),
+                ),
+                (),
+            )
+            .context("FT_Set_Var_Design_Coordinates")?;
+        }
+        // Whether a glyph has any ink can depend on the coordinates
+        self.blank_glyphs.clear();
+        if self.color_formats().contains(ColorFormats::COLR_V1) {
+            let (major, minor, patch) = library_version(self.lib);
+            if (major, minor, patch) < MIN_VARIABLE_COLR_VERSION {
+                log::debug!(
+                    "FreeType {}.{}.{} doesn't apply the variations of the \
+                     COLRv1 paint tables of {}; its color glyphs only vary in shape",
+                    major,
+                    minor,
+                    patch,
+                    self.identity()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Some fonts have incorrect advances in their metrics tables,
+    /// which can cause the cursor position to drift relative to the
+    /// text.  When enabled, FT_LOAD_COMPUTE_METRICS is added to the
+    /// load flags of every glyph loaded via this face so that freetype
+    /// recomputes the metrics from the outline instead.
+    /// This costs a little more for each glyph that is loaded.
+    pub fn set_compute_metrics(&mut self, enable: bool) {
+        self.compute_metrics = enable;
+    }
+
+    /// Returns the subpixel order reported by the provider registered
+    /// with the library that opened this face, or RGB if there is none
+    pub fn subpixel_order(&self) -> SubpixelOrder {
+        self.subpixel_order.get()
+    }
+
+    /// Select the LCD filter used when rendering LCD glyphs from
+    /// this face, or None to use that of the library
+    pub fn set_lcd_filter(&mut self, filter: Option<FT_LcdFilter>) {
+        self.lcd_filter = filter;
+    }
+
+    /// Set the coverage below which the pixels of grayscale glyphs,
+    /// and the alpha of color glyphs, are zeroed after rendering.
+    /// This removes the faint haze that antialiasing can leave around
+    /// text on some backgrounds, but too high a value erodes the edges
+    /// of thin strokes, or removes them altogether.
+    /// The default of 0 leaves the rendered glyphs unchanged.
+    pub fn set_alpha_floor(&mut self, floor: u8) {
+        self.alpha_floor = floor;
+    }
+
+    /// Hinting a font at sizes below those that its designer hinted
+    /// it for tends to distort the glyphs rather than sharpen them.
+    /// When enabled, glyphs that are loaded while the selected size
+    /// is below the lowest_rec_ppem of the font are loaded without
+    /// hinting, and those that would be rendered in monochrome are
+    /// rendered in grayscale instead.
+    pub fn set_unhinted_below_lowest_rec_ppem(&mut self, enable: bool) {
+        self.unhinted_below_lowest_rec_ppem = enable;
+    }
+
+    /// The MONO and LCD render modes can't represent color, so
+    /// rendering a color glyph with them produces a monochrome or
+    /// subpixel mask rather than the colored glyph.
+    /// When enabled, which is the default, glyphs whose slot holds a
+    /// BGRA bitmap, or whose COLR layers are loaded along with them,
+    /// are rendered with FT_RENDER_MODE_NORMAL instead.
+    pub fn set_normal_render_for_color_glyphs(&mut self, enable: bool) {
+        self.normal_render_for_color_glyphs = enable;
+    }
+
+    /// Returns the render mode to use for the glyph that was most
+    /// recently loaded into the glyph slot, which is `render_mode`
+    /// unless it is a color glyph that `set_normal_render_for_color_glyphs`
+    /// applies to
+    unsafe fn render_mode_for_loaded_glyph(
+        &self,
+        glyph_index: FT_UInt,
+        load_flags: FT_Int32,
+        render_mode: FT_Render_Mode,
+    ) -> FT_Render_Mode {
+        if !self.normal_render_for_color_glyphs
+            || render_mode == FT_Render_Mode::FT_RENDER_MODE_NORMAL
+        {
+            return render_mode;
+        }
+        let slot = &*(*self.face).glyph;
+        let is_bgra = slot.format == FT_Glyph_Format::FT_GLYPH_FORMAT_BITMAP
+            && u32::from(slot.bitmap.pixel_mode) == FT_Pixel_Mode::FT_PIXEL_MODE_BGRA as u32;
+        let has_layers = load_flags & FT_LOAD_COLOR as FT_Int32 != 0 && {
+            let mut layer_glyph = 0;
+            let mut color_index = 0;
+            let mut iter: FT_LayerIterator = std::mem::zeroed();
+            FT_Get_Color_Glyph_Layer(
+                self.face,
+                glyph_index,
+                &mut layer_glyph,
+                &mut color_index,
+                &mut iter,
+            ) != 0
+        };
+        if is_bgra || has_layers {
+            FT_Render_Mode::FT_RENDER_MODE_NORMAL
+        } else {
+            render_mode
+        }
+    }
+
+    /// Returns true if the hinting of glyphs is being suppressed
+    /// because of `set_unhinted_below_lowest_rec_ppem`
+    fn suppress_hinting(&self) -> bool {
+        self.unhinted_below_lowest_rec_ppem && self.is_below_lowest_rec_ppem()
+    }
+
+    /// Returns the load flags that will actually be used when loading
+    /// a glyph with `load_flags`, taking into account the toggles that
+    /// have been set on this face.
+    pub fn effective_load_flags(&self, load_flags: FT_Int32) -> FT_Int32 {
+        let mut load_flags = load_flags;
+        if self.compute_metrics {
+            load_flags |= FT_LOAD_COMPUTE_METRICS as FT_Int32;
+        }
+        if self.suppress_hinting() {
+            load_flags |= FT_LOAD_NO_HINTING as FT_Int32;
+        }
+        load_flags
+    }
+
+    /// Returns the render mode that will actually be used when
+    /// rendering a glyph with `render_mode`, taking into account the
+    /// toggles that have been set on this face.
+    pub fn effective_render_mode(&self, render_mode: FT_Render_Mode) -> FT_Render_Mode {
+        match render_mode {
+            FT_Render_Mode::FT_RENDER_MODE_MONO if self.suppress_hinting() => {
+                FT_Render_Mode::FT_RENDER_MODE_NORMAL
+            }
+            mode => mode,
+        }
+    }
+
+    /// Load and render the specified glyph.
+    /// If `embolden` is specified, outline glyphs are emboldened
+    /// prior to rendering; bitmap glyphs are left untouched.
+    pub fn load_and_render_glyph(
+        &mut self,
+        glyph_index: FT_UInt,
+        load_flags: FT_Int32,
+        render_mode: FT_Render_Mode,
+        embolden: Option<Embolden>,
+    ) -> anyhow::Result<&FT_GlyphSlotRec_> {
+        let load_flags = self.effective_load_flags(load_flags);
+        let render_mode = self.effective_render_mode(render_mode);
+        if *TRACE_LOAD_FLAGS {
+            log::info!(
+                "load_and_render_glyph: glyph_index:{} load_flags:0x{:x} ({}) render_mode:{:?}",
+                glyph_index,
+                load_flags,
+                describe_load_flags(load_flags),
+                render_mode
+            );
+        }
+        unsafe {
+            ft_result(FT_Load_Glyph(self.face, glyph_index, load_flags), ()).with_context(
+                || {
+                    anyhow!(
+                        "load_and_render_glyph: FT_Load_Glyph glyph_index:{} of {}",
+                        glyph_index,
+                        self.identity()
+                    )
+                },
+            )?;
+            let slot = &mut *(*self.face).glyph;
+            if let Some(embolden) = embolden {
+                if slot.format == FT_Glyph_Format::FT_GLYPH_FORMAT_OUTLINE {
+                    ft_result(
+                        FT_Outline_EmboldenXY(&mut slot.outline, embolden.x, embolden.y),
+                        (),
+                    )
+                    .with_context(|| {
+                        format!(
+                            "load_and_render_glyph: FT_Outline_EmboldenXY glyph_index:{} of {}",
+                            glyph_index,
+                            self.identity()
+                        )
+                    })?;
+                    if embolden.preserve_advance {
+                        // Emboldening grows the outline to the right;
+                        // shift it back by half of that growth so that
+                        // the extra width is split between the left and
+                        // right bearings and the glyph remains centered
+                        // within its original advance.
+                        FT_Outline_Translate(&slot.outline, -embolden.x / 2, 0);
+                    }
+                }
+            }
+            let render_mode =
+                self.render_mode_for_loaded_glyph(glyph_index, load_flags, render_mode);
+            self.render_loaded_glyph(glyph_index, render_mode)
+                .context("load_and_render_glyph")?;
+            let slot = &*(*self.face).glyph;
+            if slot.bitmap.width == 0 || slot.bitmap.rows == 0 {
+                if let Some(size) = self.size_key() {
+                    self.blank_glyphs
+                        .insert((glyph_index, load_flags, size), slot.advance.x);
+                }
+            }
+            Ok(slot)
+        }
+    }
+
+    /// Returns the advance, in pixels, of `glyph_index` if an earlier
+    /// call to `load_and_render_glyph` with the same `load_flags` found
+    /// that it has no ink at the selected size, dpi and transform.
+    /// Spaces, and the notdef glyph of fonts whose notdef is empty, are
+    /// requested over and over, particularly from the fonts of a
+    /// fallback chain, and rendering them again would only produce the
+    /// same empty bitmap.
+    pub fn blank_glyph_advance(&self, glyph_index: FT_UInt, load_flags: FT_Int32) -> Option<f64> {
+        let load_flags = self.effective_load_flags(load_flags);
+        let size = self.size_key()?;
+        self.blank_glyphs
+            .get(&(glyph_index, load_flags, size))
+            .map(|&advance| advance as f64 / 64.0)
+    }
+
+    /// Identifies the selected size and the current transform, which
+    /// determine whether a glyph has any ink
+    fn size_key(&self) -> Option<SizeKey> {
+        self.size
+            .as_ref()
+            .map(|size| (size.size.to_bits(), size.dpi, self.transform.to_bits()))
+    }
+
+    /// Render the glyph that was most recently loaded into the glyph
+    /// slot, applying the LCD filter for the LCD render modes
+    unsafe fn render_loaded_glyph(
+        &mut self,
+        glyph_index: FT_UInt,
+        render_mode: FT_Render_Mode,
+    ) -> anyhow::Result<&FT_GlyphSlotRec_> {
+        if self.metrics_only {
+            anyhow::bail!(
+                "glyph_index:{} of {} can't be rendered, as it was opened for its metrics only",
+                glyph_index,
+                self.identity()
+            );
+        }
+        let slot = &mut *(*self.face).glyph;
+        let is_embedded_bitmap = slot.format == FT_Glyph_Format::FT_GLYPH_FORMAT_BITMAP;
+        self.rendered_scaled_strike = is_embedded_bitmap && self.is_mismatched_strike();
+        if matches!(
+            render_mode,
+            FT_Render_Mode::FT_RENDER_MODE_LCD | FT_Render_Mode::FT_RENDER_MODE_LCD_V
+        ) {
+            self.lcd_filter_state.apply(
+                self.lib,
+                self.lcd_filter
+                    .unwrap_or_else(|| self.lcd_filter_state.default.get()),
+            );
+        }
+        ft_result(FT_Render_Glyph(slot, render_mode), ()).with_context(|| {
+            format!(
+                "FT_Render_Glyph glyph_index:{} of {}",
+                glyph_index,
+                self.identity()
+            )
+        })?;
+        apply_alpha_floor(&mut slot.bitmap, self.alpha_floor);
+        if is_embedded_bitmap {
+            if let Some(scale) = self.embedded_bitmap_scale() {
+                slot.bitmap_left = (f64::from(slot.bitmap_left) * scale).round() as FT_Int;
+                slot.bitmap_top = (f64::from(slot.bitmap_top) * scale).round() as FT_Int;
+            }
+        }
+        Ok(slot)
+    }
+
+    /// Returns true if the glyph that was most recently rendered came
+    /// from a bitmap strike whose size doesn't match the size that was
+    /// requested via `set_font_size`, as happens when a bitmap font
+    /// has no strike of that size.  Such a glyph is larger or smaller
+    /// than the surrounding text, so the caller should expect to scale
+    /// it, and to get lower quality results than it would from a
+    /// matching strike.
+    /// This is false for glyphs that were rendered from an outline.
+    pub fn rendered_scaled_strike(&self) -> bool {
+        self.rendered_scaled_strike
+    }
+
+    /// Returns true if the bitmap strike that is used at the selected
+    /// size doesn't match that size
+    fn is_mismatched_strike(&self) -> bool {
+        /// How far, in pixels, the ppem of a strike may be from the
+        /// requested size while still being considered to match it
+        const EPSILON: f64 = 1e-3;
+
+        let size = match self.size.as_ref() {
+            Some(size) => size,
+            None => return false,
+        };
+        if size.is_scaled {
+            return self.embedded_bitmap_scale().is_some();
+        }
+        match size.strike.and_then(|idx| self.strike_ppem_at(idx)) {
+            Some(ppem) => (ppem - point_size_to_pixels(size.size, size.dpi)).abs() > EPSILON,
+            None => false,
+        }
+    }
+
+    /// Returns the ratio of the requested pixel size to the ppem of the
+    /// embedded bitmap strike that freetype uses for it, if they differ.
+    /// freetype uses the embedded bitmaps of a scalable face when the
+    /// requested size rounds to the ppem of one of its strikes, so a
+    /// request for 15.6px uses a 16px strike, whose bearings are too
+    /// large for the surrounding 15.6px text.
+    /// This is an approximation: the bearings are scaled by this ratio
+    /// and rounded to whole pixels, but the bitmap itself is not scaled.
+    /// Faces that only have bitmap strikes aren't affected, as the
+    /// whole glyph, including its bearings, is scaled to fit its cell
+    /// by the caller.
+    fn embedded_bitmap_scale(&self) -> Option<f64> {
+        let size = self.size.as_ref()?;
+        if !size.is_scaled {
+            return None;
+        }
+        let strike_ppem = unsafe {
+            let rec = &*self.face;
+            if rec.num_fixed_sizes <= 0 || rec.size.is_null() {
+                return None;
+            }
+            let y_ppem = (*rec.size).metrics.y_ppem;
+            std::slice::from_raw_parts(rec.available_sizes, rec.num_fixed_sizes as usize)
+                .iter()
+                .map(strike_ppem)
+                .find(|ppem| ppem.round() as u16 == y_ppem)?
+        };
+        let scale = point_size_to_pixels(size.size, size.dpi) / strike_ppem;
+        if (scale - 1.0).abs() < 1e-6 {
+            None
+        } else {
+            Some(scale)
+        }
+    }
+
+    /// Load and render the specified glyph, scaling it down first if
+    /// its ink doesn't fit within a box that is `cell_width` by
+    /// `cell_height` pixels.
+    /// The outline of a scalable glyph is scaled uniformly about its
+    /// origin, so that it remains on the baseline, and, if that leaves
+    /// ink to the left of the origin, it is shifted to the right so
+    /// that the ink starts at the origin.  The advance of the glyph is
+    /// not changed.
+    /// A bitmap glyph can't be transformed before it is rendered, so
+    /// it is rendered at its natural size and the scale that the caller
+    /// should apply to the bitmap is returned instead.
+    pub fn render_fitted_glyph(
+        &mut self,
+        glyph_index: FT_UInt,
+        cell_width: f64,
+        cell_height: f64,
+        load_flags: FT_Int32,
+        render_mode: FT_Render_Mode,
+    ) -> anyhow::Result<FittedGlyph> {
+        let load_flags = self.effective_load_flags(load_flags);
+        let render_mode = self.effective_render_mode(render_mode);
+        unsafe {
+            ft_result(FT_Load_Glyph(self.face, glyph_index, load_flags), ()).with_context(
+                || {
+                    anyhow!(
+                        "render_fitted_glyph: FT_Load_Glyph glyph_index:{} of {}",
+                        glyph_index,
+                        self.identity()
+                    )
+                },
+            )?;
+            let slot = &mut *(*self.face).glyph;
+            let is_outline = slot.format == FT_Glyph_Format::FT_GLYPH_FORMAT_OUTLINE;
+            let mut outline_scale = 1.0;
+            if is_outline {
+                let mut cbox: FT_BBox = std::mem::zeroed();
+                FT_Outline_Get_CBox(&slot.outline, &mut cbox);
+                outline_scale = fit_scale(
+                    (cbox.xMax - cbox.xMin) as f64 / 64.,
+                    (cbox.yMax - cbox.yMin) as f64 / 64.,
+                    cell_width,
+                    cell_height,
+                );
+                if outline_scale < 1.0 {
+                    let scale = (outline_scale * 65536.) as FT_Fixed;
+                    let matrix = FT_Matrix {
+                        xx: scale,
+                        xy: 0,
+                        yx: 0,
+                        yy: scale,
+                    };
+                    FT_Outline_Transform(&slot.outline, &matrix);
+                    FT_Outline_Get_CBox(&slot.outline, &mut cbox);
+                    if cbox.xMin < 0 {
+                        FT_Outline_Translate(&slot.outline, -cbox.xMin, 0);
+                    }
+                }
+            }
+            let render_mode =
+                self.render_mode_for_loaded_glyph(glyph_index, load_flags, render_mode);
+            self.render_loaded_glyph(glyph_index, render_mode)
+                .context("render_fitted_glyph")?;
+            let was_scaled_strike = self.rendered_scaled_strike;
+            let slot = &*(*self.face).glyph;
+            let bitmap_scale = if is_outline {
+                1.0
+            } else {
+                fit_scale(
+                    f64::from(slot.bitmap.width),
+                    f64::from(slot.bitmap.rows),
+                    cell_width,
+                    cell_height,
+                )
+            };
+            Ok(FittedGlyph {
+                slot,
+                outline_scale,
+                bitmap_scale,
+                was_scaled_strike,
+            })
+        }
+    }
+
+    /// Render the specified glyph at the current size both without
+    /// and with `FT_LOAD_COLOR`, so that the caller can decide whether
+    /// to show the monochrome version tinted with a color of its
+    /// choosing, or the color version as-is.
+    /// The color version is None if the glyph has no color
+    /// representation.  For a glyph that only has a color bitmap, the
+    /// monochrome version is the grayscale one that freetype derives
+    /// from it.  `FT_LOAD_COLOR` in `load_flags` is ignored, as are the
+    /// subpixel modes: both versions are rendered with plain coverage.
+    pub fn render_glyph_both(
+        &mut self,
+        glyph_index: FT_UInt,
+        load_flags: FT_Int32,
+        render_mode: FT_Render_Mode,
+    ) -> anyhow::Result<(OwnedGlyph, Option<OwnedGlyph>)> {
+        let render_mode = plain_render_mode(render_mode);
+        let load_color = FT_LOAD_COLOR as FT_Int32;
+
+        let color = if self.has_color() {
+            let glyph = OwnedGlyph::from_slot(
+                self.load_and_render_glyph(glyph_index, load_flags | load_color, render_mode, None)
+                    .context("render_glyph_both: color")?,
+            );
+            if glyph.pixel_mode == FT_Pixel_Mode::FT_PIXEL_MODE_BGRA {
+                Some(glyph)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+
+        let mono = OwnedGlyph::from_slot(
+            self.load_and_render_glyph(glyph_index, load_flags & !load_color, render_mode, None)
+                .context("render_glyph_both: monochrome")?,
+        );
+
+        Ok((mono, color))
+    }
+
+    /// Load and render the specified glyph, returning its bitmap with
+    /// normalized f32 components for compositing in linear space.
+    /// See `OwnedGlyph::to_f32` for the conversion; the bitmap is still
+    /// rendered by freetype at 8 bits, so this avoids further loss of
+    /// precision rather than adding any.
+    pub fn render_glyph_f32(
+        &mut self,
+        glyph_index: FT_UInt,
+        load_flags: FT_Int32,
+        render_mode: FT_Render_Mode,
+    ) -> anyhow::Result<OwnedGlyphF32> {
+        let glyph = OwnedGlyph::from_slot(self.load_and_render_glyph(
+            glyph_index,
+            load_flags,
+            render_mode,
+            None,
+        )?);
+        glyph.to_f32().ok_or_else(|| {
+            anyhow!(
+                "render_glyph_f32: unsupported pixel mode {:?} for glyph_index:{} of {}",
+                glyph.pixel_mode,
+                glyph_index,
+                self.identity()
+            )
+        })
+    }
+
+    /// Load and render the specified glyph for an LCD display, then
+    /// blend it in the `foreground` color over the `background` color,
+    /// returning opaque RGB pixels.  This suits renderers that know
+    /// the color behind the text and can't blend each subpixel
+    /// separately themselves.
+    /// `render_mode` must be `FT_RENDER_MODE_LCD` or `FT_RENDER_MODE_LCD_V`.
+    pub fn render_glyph_over_background(
+        &mut self,
+        glyph_index: FT_UInt,
+        load_flags: FT_Int32,
+        render_mode: FT_Render_Mode,
+        foreground: [u8; 3],
+        background: [u8; 3],
+    ) -> anyhow::Result<RgbGlyph> {
+        let glyph = OwnedGlyph::from_slot(self.load_and_render_glyph(
+            glyph_index,
+            load_flags,
+            render_mode,
+            None,
+        )?);
+        glyph.blend_lcd(foreground, background).ok_or_else(|| {
+            anyhow!(
+                "render_glyph_over_background: pixel mode {:?} of glyph_index:{} of {} \
+                 is not an LCD mode",
+                glyph.pixel_mode,
+                glyph_index,
+                self.identity()
+            )
+        })
+    }
+
+    /// Load and render the specified glyph at the currently selected
+    /// point size, but at the specified `dpi`.
+    /// The prior size selection is restored afterwards, so that spans
+    /// that are drawn at different DPIs within the same frame don't
+    /// disturb the size state that is shared with the shaper.
+    /// Sizes are cached per DPI, so alternating between DPIs doesn't
+    /// need to re-compute the size selection.
+    pub fn render_glyph_at_dpi(
+        &mut self,
+        glyph_index: FT_UInt,
+        dpi: u32,
+        load_flags: FT_Int32,
+        render_mode: FT_Render_Mode,
+    ) -> anyhow::Result<&FT_GlyphSlotRec_> {
+        let point_size = self
+            .size
+            .ok_or_else(|| anyhow!("render_glyph_at_dpi: no font size has been selected"))?
+            .size;
+
+        let slot = {
+            let mut face = self.with_size(point_size, dpi)?;
+            face.load_and_render_glyph(glyph_index, load_flags, render_mode, None)?
+                as *const FT_GlyphSlotRec_
+        };
+
+        // The glyph slot isn't affected by changing the size; its
+        // contents are only replaced when the next glyph is loaded.
+        Ok(unsafe { &*slot })
+    }
+
+    /// Render `codepoints` at the current size into a grid of cells,
+    /// each of which is `cell` (width, height) pixels in size, to
+    /// produce a preview of the font.  The grid has as many columns as
+    /// are needed to make it roughly square.  Each glyph is placed on
+    /// a baseline at the ascender of the font, and clipped to its cell.
+    /// Codepoints that the font doesn't cover leave their cell empty.
+    /// The result is a pre-multiplied BGRA bitmap.
+    pub fn render_preview_sheet(
+        &mut self,
+        codepoints: &[char],
+        cell: (u32, u32),
+        load_flags: FT_Int32,
+        render_mode: FT_Render_Mode,
+    ) -> anyhow::Result<OwnedGlyph> {
+        if self.size.is_none() {
+            anyhow::bail!("render_preview_sheet: no font size has been selected");
+        }
+        let (cell_width, cell_height) = (cell.0 as usize, cell.1 as usize);
+        let cols = (codepoints.len() as f64).sqrt().ceil().max(1.) as usize;
+        let rows = (codepoints.len() + cols - 1) / cols;
+        let width = cols * cell_width;
+        let height = rows * cell_height;
+        let pitch = width * 4;
+        let mut data = vec![0u8; pitch * height];
+
+        let ascender = unsafe { (*(*self.face).size).metrics.ascender as f64 / 64.0 };
+        // The preview is a plain image, so there's no sense in
+        // rendering subpixel coverage
+        let render_mode = plain_render_mode(render_mode);
+
+        for (i, &c) in codepoints.iter().enumerate() {
+            let glyph_index = unsafe { FT_Get_Char_Index(self.face, c as _) };
+            if glyph_index == 0 {
+                continue;
+            }
+            let glyph = OwnedGlyph::from_slot(
+                self.load_and_render_glyph(glyph_index, load_flags, render_mode, None)
+                    .with_context(|| format!("render_preview_sheet: {}", c.escape_unicode()))?,
+            );
+
+            let cell_x = (i % cols) * cell_width;
+            let cell_y = (i / cols) * cell_height;
+            let origin_x = cell_x as isize + glyph.left as isize;
+            let origin_y = cell_y as isize + ascender.round() as isize - glyph.top as isize;
+
+            for y in 0..glyph.rows as usize {
+                let dest_y = origin_y + y as isize;
+                if dest_y < cell_y as isize || dest_y >= (cell_y + cell_height) as isize {
+                    continue;
+                }
+                for x in 0..glyph.width as usize {
+                    let dest_x = origin_x + x as isize;
+                    if dest_x < cell_x as isize || dest_x >= (cell_x + cell_width) as isize {
+                        continue;
+                    }
+                    if let Some(pixel) = glyph.bgra_pixel(x, y) {
+                        let offset = dest_y as usize * pitch + dest_x as usize * 4;
+                        data[offset..offset + 4].copy_from_slice(&pixel);
+                    }
+                }
+            }
+        }
+
+        Ok(OwnedGlyph {
+            pixel_mode: FT_Pixel_Mode::FT_PIXEL_MODE_BGRA,
+            width: width as u32,
+            rows: height as u32,
+            pitch,
+            data,
+            left: 0,
+            top: 0,
+        })
+    }
+
+    /// Returns the largest advance of the glyphs in the face, in
+    /// pixels at the selected size.  For a monospace font this is the
+    /// width of a cell, but proportional fonts, and some that claim to
+    /// be monospace, have a few glyphs that are much wider than the rest.
+    pub fn max_advance(&self) -> f64 {
+        unsafe { (*(*self.face).size).metrics.max_advance as f64 / 64.0 }
+    }
+
+    /// Returns an estimate of the width of `s`, in pixels at the
+    /// selected size, as the sum of the advances of the glyphs that
+    /// the charmap maps its chars to.  This is much cheaper than
+    /// shaping, and is intended for deciding where to truncate text,
+    /// but it is only an approximation: there is no kerning, and
+    /// ligatures and combining marks aren't accounted for.
+    /// Variation selectors are skipped, and chars that the face has
+    /// no glyph for are measured using the advance of the notdef glyph.
+    pub fn measure_string(&mut self, s: &str, load_flags: FT_Int32) -> f64 {
+        let mut total: FT_Fixed = 0;
+        for c in s.chars() {
+            if crate::emoji::is_variation_selector(c) {
+                continue;
+            }
+            // Glyph 0 is notdef, which is what we want for a char
+            // that isn't in the charmap
+            let glyph_index = unsafe { FT_Get_Char_Index(self.face, c as _) };
+            let mut advance: FT_Fixed = 0;
+            let res = unsafe { FT_Get_Advance(self.face, glyph_index, load_flags, &mut advance) };
+            if succeeded(res) {
+                total += advance;
+            }
+        }
+        // The advances are 16.16 fixed point pixels
+        total as f64 / 65536.0
+    }
+
+    /// Returns the load flags for loading glyphs to measure them.
+    /// Only their advances are needed, so faces that are opened for
+    /// their metrics only don't decode color bitmaps.
+    fn metrics_probe_flags(&self) -> FT_Int32 {
+        if self.metrics_only {
+            FT_LOAD_BITMAP_METRICS_ONLY as FT_Int32
+        } else {
+            FT_LOAD_COLOR as FT_Int32
+        }
+    }
+
+    /// For a fixed width face, returns `max_advance` provided that it
+    /// is consistent with the advance of a typical glyph, so that the
+    /// cell width can be determined without measuring every glyph.
+    fn monospace_cell_width(&mut self) -> Option<f64> {
+        /// How many pixels wider than a digit the max advance may be;
+        /// this allows for it being rounded differently
+        const MONOSPACE_ADVANCE_TOLERANCE: f64 = 1.0;
+
+        if !self.flags.is_fixed_width() {
+            return None;
+        }
+        let max_advance = self.max_advance();
+        let typical = unsafe {
+            let glyph_pos = FT_Get_Char_Index(self.face, '0' as _);
+            if glyph_pos == 0
+                || !succeeded(FT_Load_Glyph(
+                    self.face,
+                    glyph_pos,
+                    self.metrics_probe_flags(),
+                ))
+            {
+                return None;
+            }
+            (*(*self.face).glyph).metrics.horiAdvance as f64 / 64.0
+        };
+        if typical > 0.
+            && max_advance >= typical
+            && max_advance <= typical + MONOSPACE_ADVANCE_TOLERANCE
+        {
+            Some(max_advance)
+        } else {
+            log::debug!(
+                "{} is fixed width, but max_advance={} and the advance of 0 is {}; \
+                 measuring the cell width",
+                self.identity(),
+                max_advance,
+                typical
+            );
+            None
+        }
+    }
+
+    pub fn cell_metrics(&mut self) -> (f64, f64) {
+        let monospace_width = self.monospace_cell_width();
+        unsafe {
+            let metrics = &(*(*self.face).size).metrics;
+            let height = (metrics.y_scale as f64 * f64::from((*self.face).height))
+                / (f64::from(0x1_0000) * 64.0);
+
+            if let Some(width) = monospace_width {
+                return (width, height);
+            }
+
+            let mut width = 0.0;
+            for i in 32..128 {
+                let glyph_pos = FT_Get_Char_Index(self.face, i);
+                if glyph_pos == 0 {
+                    continue;
+                }
+                let res = FT_Load_Glyph(self.face, glyph_pos, self.metrics_probe_flags());
+                if succeeded(res) {
+                    let glyph = &(*(*self.face).glyph);
+                    if glyph.metrics.horiAdvance as f64 > width {
+                        width = glyph.metrics.horiAdvance as f64;
+                    }
+                }
+            }
+            if width == 0.0 {
+                // Most likely we're looking at a symbol font with no latin
+                // glyphs at all. Let's just pick a selection of glyphs
+                for glyph_pos in 1..8 {
+                    let res = FT_Load_Glyph(self.face, glyph_pos, self.metrics_probe_flags());
+                    if succeeded(res) {
+                        let glyph = &(*(*self.face).glyph);
+                        if glyph.metrics.horiAdvance as f64 > width {
+                            width = glyph.metrics.horiAdvance as f64;
+                        }
+                    }
+                }
+                if width == 0.0 {
+                    log::error!(
+                        "Couldn't find any glyphs for metrics of {}, so guessing width == height",
+                        self.identity()
+                    );
+                    width = height * 64.;
+                }
+            }
+            (width / 64.0, height)
+        }
+    }
+}
+
+// These aren't covered by our bindings, but are part of the freetype
+// that we build; the driver classes are those that ftmodule.h lists
+extern "C" {
+    fn FT_New_Memory() -> FT_Memory;
+    static tt_driver_class: FT_Module_Class;
+    static t1_driver_class: FT_Module_Class;
+    static cff_driver_class: FT_Module_Class;
+    static t1cid_driver_class: FT_Module_Class;
+    static pfr_driver_class: FT_Module_Class;
+    static t42_driver_class: FT_Module_Class;
+    static winfnt_driver_class: FT_Module_Class;
+    static pcf_driver_class: FT_Module_Class;
+    static bdf_driver_class: FT_Module_Class;
+}
+
+/// The freetype font drivers.  When a face is opened, freetype offers
+/// the data to each registered driver in turn and uses the first that
+/// accepts it, so the order in which they are registered decides how
+/// data that more than one of them understands is interpreted.
+/// Note that there is no separate CFF2 driver; the CFF driver handles
+/// both CFF and CFF2 outlines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FontDriver {
+    TrueType,
+    Type1,
+    Cff,
+    Cid,
+    Pfr,
+    Type42,
+    WinFonts,
+    Pcf,
+    Bdf,
+}
+
+impl FontDriver {
+    /// All of the drivers, in the order in which `FT_Init_FreeType`
+    /// registers them
+    pub const ALL: &'static [FontDriver] = &[
+        FontDriver::TrueType,
+        FontDriver::Type1,
+        FontDriver::Cff,
+        FontDriver::Cid,
+        FontDriver::Pfr,
+        FontDriver::Type42,
+        FontDriver::WinFonts,
+        FontDriver::Pcf,
+        FontDriver::Bdf,
+    ];
+
+    /// The name of the freetype module that implements the driver
+    pub fn module_name(self) -> &'static str {
+        match self {
+            Self::TrueType => "truetype",
+            Self::Type1 => "type1",
+            Self::Cff => "cff",
+            Self::Cid => "t1cid",
+            Self::Pfr => "pfr",
+            Self::Type42 => "type42",
+            Self::WinFonts => "winfonts",
+            Self::Pcf => "pcf",
+            Self::Bdf => "bdf",
+        }
+    }
+
+    fn class(self) -> *const FT_Module_Class {
+        unsafe {
+            match self {
+                Self::TrueType => &tt_driver_class,
+                Self::Type1 => &t1_driver_class,
+                Self::Cff => &cff_driver_class,
+                Self::Cid => &t1cid_driver_class,
+                Self::Pfr => &pfr_driver_class,
+                Self::Type42 => &t42_driver_class,
+                Self::WinFonts => &winfnt_driver_class,
+                Self::Pcf => &pcf_driver_class,
+                Self::Bdf => &bdf_driver_class,
+            }
+        }
+    }
+}
+
+/// Controls the order in which `Library::open` registers the freetype
+/// font drivers, so that the driver that is used for a given font
+/// doesn't depend upon how freetype happened to be configured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModuleOrder {
+    /// Register the modules exactly as `FT_Init_FreeType` does;
+    /// this is what `Library::new` uses
+    Default,
+    /// Register these drivers first, in this order, followed by
+    /// the remaining modules in their default order
+    PreferDrivers(Vec<FontDriver>),
+}
+
+impl ModuleOrder {
+    /// Returns the order in which the font drivers are offered a face
+    pub fn driver_order(&self) -> Vec<FontDriver> {
+        match self {
+            Self::Default => FontDriver::ALL.to_vec(),
+            Self::PreferDrivers(preferred) => {
+                let mut order: Vec<FontDriver> = vec![];
+                for &driver in preferred.iter().chain(FontDriver::ALL) {
+                    if !order.contains(&driver) {
+                        order.push(driver);
+                    }
+                }
+                order
+            }
+        }
+    }
+}
+
+pub struct Library {
+    lib: FT_Library,
+    lcd_filter: Rc<LcdFilterState>,
+    subpixel_order: Rc<SubpixelOrderProvider>,
+    /// See `Library::new_metrics_only`
+    metrics_only: bool,
+}
+
+/// The TrueType interpreter versions that freetype defines.
+/// Which of them are available depends upon how freetype was built.
+pub const INTERPRETER_VERSIONS: &[u32] = &[35, 38, 40];
+
+lazy_static::lazy_static! {
+    /// The interpreter versions that are supported by our freetype
+    static ref SUPPORTED_INTERPRETER_VERSIONS: Vec<u32> = probe_interpreter_versions();
+    /// The unsupported freetype_interpreter_version values that we
+    /// have already warned about, so that the warning is shown once
+    /// rather than for every library that is created
+    static ref REPORTED_INTERPRETER_VERSIONS: Mutex<HashSet<u32>> = Mutex::new(HashSet::new());
+}
+
+/// Determine which of INTERPRETER_VERSIONS freetype accepts, using
+/// a scratch library so that the probing doesn't disturb any other
+fn probe_interpreter_versions() -> Vec<u32> {
+    let mut lib = ptr::null_mut();
+    if !succeeded(unsafe { FT_Init_FreeType(&mut lib as *mut _) }) {
+        return vec![];
+    }
+    let supported = INTERPRETER_VERSIONS
+        .iter()
+        .copied()
+        .filter(|&vers| {
+            let interpreter_version: FT_UInt = vers;
+            succeeded(unsafe {
+                FT_Property_Set(
+                    lib,
+                    b"truetype\0".as_ptr() as *const FT_String,
+                    b"interpreter-version\0".as_ptr() as *const FT_String,
+                    &interpreter_version as *const FT_UInt as *const _,
+                )
+            })
+        })
+        .collect();
+    unsafe {
+        FT_Done_FreeType(lib);
+    }
+    supported
+}
+
+/// Returns the interpreter versions that are supported by our freetype
+pub fn supported_interpreter_versions() -> &'static [u32] {
+    &SUPPORTED_INTERPRETER_VERSIONS
+}
+
+fn join_versions(versions: &[u32]) -> String {
+    versions
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Describes the features of freetype that affect rendering
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The (major, minor, patch) version of freetype
+    pub version: (i32, i32, i32),
+    /// The TrueType interpreter version that is in effect
+    pub interpreter_version: Option<u32>,
+    /// The TrueType interpreter versions that can be selected
+    /// via `freetype_interpreter_version`
+    pub supported_interpreter_versions: Vec<u32>,
+    /// Whether the colors of variable COLRv1 glyphs follow the
+    /// variation coordinates of their face
+    pub variable_color_glyphs: bool,
+}
+
+/// The first version of freetype that applies the variation deltas
+/// of COLRv1 paint tables
+const MIN_VARIABLE_COLR_VERSION: (i32, i32, i32) = (2, 13, 0);
+
+/// Returns the (major, minor, patch) version of freetype
+fn library_version(lib: FT_Library) -> (i32, i32, i32) {
+    let (mut major, mut minor, mut patch) = (0, 0, 0);
+    unsafe {
+        FT_Library_Version(lib, &mut major, &mut minor, &mut patch);
+    }
+    (major, minor, patch)
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (major, minor, patch) = self.version;
+        write!(fmt, "FreeType {}.{}.{}", major, minor, patch)?;
+        if let Some(vers) = self.interpreter_version {
+            write!(fmt, ", TrueType interpreter version {}", vers)?;
+        }
+        write!(
+            fmt,
+            " (supported: {})",
+            join_versions(&self.supported_interpreter_versions)
+        )?;
+        if self.variable_color_glyphs {
+            write!(fmt, ", variable COLRv1 glyphs")?;
+        }
+        Ok(())
+    }
+}
+
+/// The freetype modules that implement the `no-stem-darkening` property
+const STEM_DARKENING_MODULES: &[&[u8]] = &[b"autofitter\0", b"cff\0", b"type1\0", b"t1cid\0"];
+
+/// The library-wide freetype properties that are derived from
+/// the configuration.  None means that freetype's default is used.
+#[derive(Debug, Clone, Copy)]
+struct LibraryProperties {
+    interpreter_version: Option<u32>,
+    lcd_filter: FreeTypeLcdFilter,
+    skip_lcd_filter: bool,
+    stem_darkening: Option<bool>,
+}
+
+impl LibraryProperties {
+    fn from_config(config: &Config) -> Self {
+        Self {
+            interpreter_version: config.freetype_interpreter_version,
+            lcd_filter: config.freetype_lcd_filter,
+            skip_lcd_filter: config.freetype_skip_lcd_filter,
+            stem_darkening: config.freetype_stem_darkening,
+        }
+    }
+
+    /// The properties of `config` that affect the metrics of glyphs,
+    /// leaving those that only affect rendering at their defaults
+    fn for_metrics(config: &Config) -> Self {
+        Self {
+            interpreter_version: config.freetype_interpreter_version,
+            lcd_filter: FreeTypeLcdFilter::None,
+            skip_lcd_filter: false,
+            stem_darkening: config.freetype_stem_darkening,
+        }
+    }
+}
+
+/// Fonts that are expected to be present on a standard installation,
+/// tried in order by `Library::open_last_resort`
+#[cfg(target_os = "macos")]
+const LAST_RESORT_PATHS: &[&str] = &[
+    "/System/Library/Fonts/Menlo.ttc",
+    "/System/Library/Fonts/Monaco.ttf",
+    "/System/Library/Fonts/Helvetica.ttc",
+];
+#[cfg(windows)]
+const LAST_RESORT_PATHS: &[&str] = &[
+    "C:\\Windows\\Fonts\\consola.ttf",
+    "C:\\Windows\\Fonts\\cour.ttf",
+    "C:\\Windows\\Fonts\\arial.ttf",
+];
+#[cfg(all(unix, not(target_os = "macos")))]
+const LAST_RESORT_PATHS: &[&str] = &[
+    "/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf",
+    "/usr/share/fonts/dejavu/DejaVuSansMono.ttf",
+    "/usr/share/fonts/TTF/DejaVuSansMono.ttf",
+    "/usr/share/fonts/dejavu-sans-mono-fonts/DejaVuSansMono.ttf",
+    "/usr/share/fonts/truetype/liberation/LiberationMono-Regular.ttf",
+];
+#[cfg(not(any(windows, unix)))]
+const LAST_RESORT_PATHS: &[&str] = &[];
+
+/// Describes the faces contained in a font source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceDescription {
+    /// The number of faces; 1 for a TTF, more for a TTC
+    pub num_faces: u32,
+    /// true if the source is a font collection (TTC, OTC)
+    pub is_collection: bool,
+    /// The number of named variation instances for each face.
+    /// A face with no named instances is not a variable font.
+    pub per_face_instance_counts: Vec<u32>,
+}
+
+impl SourceDescription {
+    /// Returns true if any of the faces are variable fonts
+    pub fn is_variable(&self) -> bool {
+        self.per_face_instance_counts.iter().any(|&n| n > 0)
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe {
+            FT_Done_FreeType(self.lib);
+        }
+    }
+}
+
+impl Library {
+    pub fn new() -> anyhow::Result<Library> {
+        Self::open(&ModuleOrder::Default)
+    }
+
+    /// Create a library for callers that only need to parse fonts and
+    /// query their names, coverage, variation axes and metrics, such
+    /// as `wezterm ls-fonts`.
+    /// The configuration that only affects rendering, such as the LCD
+    /// filter, isn't applied, while that which affects the hinted
+    /// metrics, such as the interpreter version, is.  Faces opened by
+    /// it probe glyphs for their metrics without decoding any color
+    /// bitmaps, and fail to render glyphs.
+    pub fn new_metrics_only() -> anyhow::Result<Library> {
+        let mut lib = Self::init(&ModuleOrder::Default)?;
+        lib.metrics_only = true;
+        lib.apply_properties(LibraryProperties::for_metrics(&configuration()));
+        Ok(lib)
+    }
+
+    /// Returns true if this library was created by `new_metrics_only`
+    pub fn is_metrics_only(&self) -> bool {
+        self.metrics_only
+    }
+
+    /// Create a library whose font drivers are registered in `order`
+    pub fn open(order: &ModuleOrder) -> anyhow::Result<Library> {
+        let mut lib = Self::init(order)?;
+        lib.apply_properties(LibraryProperties::from_config(&configuration()));
+        Ok(lib)
+    }
+
+    /// Create a library whose font drivers are registered in `order`,
+    /// with freetype's default properties
+    fn init(order: &ModuleOrder) -> anyhow::Result<Library> {
+        let lib = match order {
+            ModuleOrder::Default => {
+                let mut lib = ptr::null_mut();
+                let res = unsafe { FT_Init_FreeType(&mut lib as *mut _) };
+                ft_result(res, lib).context("FT_Init_FreeType")?
+            }
+            ModuleOrder::PreferDrivers(preferred) => Self::new_library_preferring(preferred)?,
+        };
+        Ok(Library {
+            lib,
+            lcd_filter: Rc::new(LcdFilterState::new()),
+            subpixel_order: Rc::new(SubpixelOrderProvider::default()),
+            metrics_only: false,
+        })
+    }
+
+    /// Do what `FT_Init_FreeType` does, except that the `preferred`
+    /// drivers are registered ahead of the default modules.
+    /// `FT_Add_Default_Modules` skips the modules that are already
+    /// registered, so they keep their place at the front.
+    fn new_library_preferring(preferred: &[FontDriver]) -> anyhow::Result<FT_Library> {
+        let memory = unsafe { FT_New_Memory() };
+        if memory.is_null() {
+            anyhow::bail!("FT_New_Memory failed");
+        }
+        let mut lib = ptr::null_mut();
+        let res = unsafe { FT_New_Library(memory, &mut lib as *mut _) };
+        // FT_Done_FreeType, which we use to release the library,
+        // also releases the memory
+        let lib = ft_result(res, lib).context("FT_New_Library")?;
+        for driver in preferred {
+            let res = unsafe { FT_Add_Module(lib, driver.class()) };
+            if let Err(err) = ft_result(res, ()) {
+                unsafe {
+                    FT_Done_FreeType(lib);
+                }
+                return Err(err).with_context(|| format!("FT_Add_Module {:?}", driver));
+            }
+        }
+        unsafe {
+            FT_Add_Default_Modules(lib);
+            FT_Set_Default_Properties(lib);
+        }
+        Ok(lib)
+    }
+
+    /// Register a callback that returns the subpixel order of the
+    /// panel that glyphs are being rendered for, such as that of the
+    /// monitor showing the window that is being painted.  It is
+    /// consulted when rendering LCD glyphs whose load flags don't
+    /// specify the order.  Until a provider is registered, RGB is
+    /// assumed.
+    pub fn set_subpixel_order_provider<F: Fn() -> SubpixelOrder + 'static>(&mut self, provider: F) {
+        self.subpixel_order
+            .provider
+            .borrow_mut()
+            .replace(Box::new(provider));
+    }
+
+    /// Apply `properties` to a newly initialized library
+    fn apply_properties(&mut self, properties: LibraryProperties) {
+        if let Some(vers) = properties.interpreter_version {
+            let interpreter_version: FT_UInt = vers;
+            if !self.set_property(
+                b"truetype\0",
+                b"interpreter-version\0",
+                &interpreter_version,
+            ) {
+                self.report_unsupported_interpreter_version(vers);
+            }
+        }
+
+        self.set_lcd_filter(match properties.lcd_filter {
+            FreeTypeLcdFilter::None => FT_LcdFilter::FT_LCD_FILTER_NONE,
+            FreeTypeLcdFilter::Default => FT_LcdFilter::FT_LCD_FILTER_DEFAULT,
+            FreeTypeLcdFilter::Light => FT_LcdFilter::FT_LCD_FILTER_LIGHT,
+            FreeTypeLcdFilter::Legacy => FT_LcdFilter::FT_LCD_FILTER_LEGACY,
+        });
+        self.lcd_filter.skip.set(properties.skip_lcd_filter);
+
+        if let Some(darken) = properties.stem_darkening {
+            let no_stem_darkening: FT_Bool = if darken { 0 } else { 1 };
+            for module in STEM_DARKENING_MODULES {
+                // Not every build of freetype has every module
+                self.set_property(module, b"no-stem-darkening\0", &no_stem_darkening);
+            }
+        }
+    }
+
+    fn report_unsupported_interpreter_version(&self, vers: u32) {
+        let active = match self.interpreter_version() {
+            Some(active) => format!("version {} is being used instead", active),
+            None => "the default is being used instead".to_string(),
+        };
+        let message = format!(
+            "freetype_interpreter_version = {} is not supported by this build \
+             of freetype, so {}.  The supported versions are: {}",
+            vers,
+            active,
+            join_versions(supported_interpreter_versions())
+        );
+        log::warn!("{}", message);
+        if REPORTED_INTERPRETER_VERSIONS.lock().unwrap().insert(vers) {
+            config::show_error(&message);
+        }
+    }
+
+    /// Returns the TrueType interpreter version that is in effect
+    /// for this library
+    pub fn interpreter_version(&self) -> Option<u32> {
+        let mut interpreter_version: FT_UInt = 0;
+        let res = unsafe {
+            FT_Property_Get(
+                self.lib,
+                b"truetype\0".as_ptr() as *const FT_String,
+                b"interpreter-version\0".as_ptr() as *const FT_String,
+                &mut interpreter_version as *mut FT_UInt as *mut _,
+            )
+        };
+        if succeeded(res) {
+            Some(interpreter_version)
+        } else {
+            None
+        }
+    }
+
+    /// Describe the features of freetype that affect rendering,
+    /// as this library has been configured
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            version: self.version(),
+            interpreter_version: self.interpreter_version(),
+            supported_interpreter_versions: supported_interpreter_versions().to_vec(),
+            variable_color_glyphs: self.supports_variable_color_glyphs(),
+        }
+    }
+
+    /// Set the freetype property `name` of `module`, both of
+    /// which are NUL terminated.  Returns true if successful.
+    fn set_property<T>(&self, module: &[u8], name: &[u8], value: &T) -> bool {
+        unsafe {
+            succeeded(FT_Property_Set(
+                self.lib,
+                module.as_ptr() as *const FT_String,
+                name.as_ptr() as *const FT_String,
+                value as *const T as *const _,
+            ))
+        }
+    }
+
+    /// Returns the number of faces in a given font.
+    /// For a TTF this will be 1.
+    /// For a TTC, it will be the number of contained fonts
+    pub fn query_num_faces(&self, source: &FontDataSource) -> anyhow::Result<u32> {
+        Ok(self
+            .describe_source(source)
+            .context("query_num_faces")?
+            .num_faces)
+    }
+
+    /// Describe the faces and named instances contained in `source`.
+    /// This uses the freetype convention of opening with a negative
+    /// face index, which only loads enough of the font to report
+    /// the number of faces and named instances.
+    pub fn describe_source(&self, source: &FontDataSource) -> anyhow::Result<SourceDescription> {
+        /// Opening face `n` via `-(n+1)` reports the number of faces as
+        /// well as the number of named instances for face `n`.
+        /// Also returns whether the source is a collection, which is
+        /// identified by the `ttcf` tag at its start rather than by the
+        /// number of faces, as a collection may hold a single face.
+        fn probe(
+            lib: &Library,
+            source: &FontDataSource,
+            index: u32,
+        ) -> anyhow::Result<(u32, u32, bool)> {
+            let face = lib
+                .new_face(source, -(index as FT_Long + 1), &[])
+                .context("describe_source")?;
+            let (num_faces, style_flags, is_collection) = unsafe {
+                (
+                    (*face).num_faces,
+                    (*face).style_flags,
+                    stream_starts_with((*face).stream, b"ttcf"),
+                )
+            };
+            unsafe {
+                FT_Done_Face(face);
+            }
+            Ok((
+                num_faces.try_into()?,
+                ((style_flags >> 16) & 0x7fff) as u32,
+                is_collection,
+            ))
+        }
+
+        let (num_faces, first_instances, is_collection) = probe(self, source, 0)?;
+        let mut per_face_instance_counts = vec![first_instances];
+        for index in 1..num_faces {
+            per_face_instance_counts.push(probe(self, source, index)?.1);
+        }
+
+        Ok(SourceDescription {
+            num_faces,
+            is_collection,
+            per_face_instance_counts,
+        })
+    }
+
+    /// Open a font that can be used when every configured font has
+    /// failed to load, so that text can always be rendered.
+    /// A list of fonts that are expected to be present on a standard
+    /// installation of the platform is tried in order, and the bundled
+    /// last resort font is used if none of those can be opened.
+    pub fn open_last_resort(&self) -> anyhow::Result<Face> {
+        for path in LAST_RESORT_PATHS {
+            let handle = FontDataHandle {
+                source: FontDataSource::OnDisk(path.into()),
+                index: 0,
+                variation: 0,
+                origin: FontOrigin::LastResort,
+            };
+            match self.face_from_locator(&handle) {
+                Ok(face) => return Ok(face),
+                Err(err) => log::trace!("open_last_resort: {}: {:#}", path, err),
+            }
+        }
+
+        let (name, data) = crate::parser::LAST_RESORT_FONT;
+        let handle = FontDataHandle {
+            source: FontDataSource::BuiltIn { name, data },
+            index: 0,
+            variation: 0,
+            origin: FontOrigin::BuiltIn,
+        };
+        self.face_from_locator(&handle)
+            .context("open_last_resort: failed to open the built-in last resort font")
+    }
+
+    pub fn face_from_locator(&self, handle: &FontDataHandle) -> anyhow::Result<Face> {
+        self.face_from_locator_with_params(handle, &[])
+    }
+
+    /// Open the face identified by `handle`, passing `params` to
+    /// freetype as it is opened.  The parameters are also applied to
+    /// the duplicates and siblings of the returned face.
+    pub fn face_from_locator_with_params(
+        &self,
+        handle: &FontDataHandle,
+        params: &[OpenParam],
+    ) -> anyhow::Result<Face> {
+        let source = handle.clone();
+
+        let mut index = handle.index;
+        if handle.variation != 0 {
+            index |= handle.variation << 16;
+        }
+
+        let face = self
+            .new_face(&source.source, index as _, params)
+            .with_context(|| format!("face_from_locator({:?})", handle))?;
+
+        Ok(self.wrap_face(face, source, params))
+    }
+
+    /// Open each of the faces contained in `source`, which is
+    /// typically a font collection such as a TTC.
+    /// The source is only opened once: the faces after the first are
+    /// opened from streams that share the data of the first, in the
+    /// same way as `Face::duplicate`, so a file is mapped, or a
+    /// compressed font is decompressed, a single time for all of them.
+    /// Each face holds its own reference to that data, closing it
+    /// independently of the others, so the faces may be dropped in
+    /// any order.
+    /// An `OnDisk` source that can't be memory mapped is read using
+    /// regular file IO, whose file position can't be shared, so the
+    /// file is opened again for each face.
+    /// The faces are attributed to `FontOrigin::BuiltIn` for a
+    /// `BuiltIn` source, and to `FontOrigin::FontDirs` otherwise.
+    pub fn faces_in_collection(&self, source: &FontDataSource) -> anyhow::Result<Vec<Face>> {
+        let origin = match source {
+            FontDataSource::BuiltIn { .. } => FontOrigin::BuiltIn,
+            _ => FontOrigin::FontDirs,
+        };
+        let first = self
+            .face_from_locator(&FontDataHandle {
+                source: source.clone(),
+                index: 0,
+                variation: 0,
+                origin,
+            })
+            .context("faces_in_collection")?;
+        let num_faces: u32 = unsafe { (*first.face).num_faces }.try_into()?;
+
+        let mut faces = Vec::with_capacity(num_faces as usize);
+        faces.push(first);
+        for index in 1..num_faces {
+            let face = faces[0].sibling(self, index)?;
+            faces.push(face);
+        }
+        Ok(faces)
+    }
+
+    /// Wrap `face`, which was opened from `source` with `open_params`,
+    /// so that it is closed when the returned face is dropped
+    fn wrap_face(&self, face: FT_Face, source: FontDataHandle, open_params: &[OpenParam]) -> Face {
+        Face {
+            face,
+            lib: self.lib,
+            source,
+            size: None,
+            size_cache: HashMap::new(),
+            blank_glyphs: HashMap::new(),
+            transform: FaceTransform::IDENTITY,
+            subpixel_offset: 0.,
+            subpixel_granularity: DEFAULT_SUBPIXEL_GRANULARITY,
+            open_params: open_params.to_vec(),
+            features: RefCell::new(None),
+            rtl_mirrors: RefCell::new(None),
+            glyph_codepoints: RefCell::new(None),
+            strikes: None,
+            flags: FaceFlags::from_face(face),
+            compute_metrics: false,
+            lcd_filter: None,
+            alpha_floor: 0,
+            unhinted_below_lowest_rec_ppem: false,
+            normal_render_for_color_glyphs: true,
+            metrics_only: self.metrics_only,
+            rendered_scaled_strike: false,
+            lcd_filter_state: Rc::clone(&self.lcd_filter),
+            subpixel_order: Rc::clone(&self.subpixel_order),
+        }
+    }
+
+    fn new_face(
+        &self,
+        source: &FontDataSource,
+        face_index: FT_Long,
+        params: &[OpenParam],
+    ) -> anyhow::Result<FT_Face> {
+        let stream = FreeTypeStream::from_source(source)?;
+        self.open_stream(stream, source, face_index, params)
+    }
+
+    /// Open the face at `face_index` from `stream`, which was
+    /// produced from `source`, passing `params` to freetype.
+    /// FT_Open_Face will take ownership of the stream and closes it in both
+    /// the error case and the success case (although the latter is when
+    /// the face is dropped).
+    fn open_stream(
+        &self,
+        stream: FT_Stream,
+        source: &FontDataSource,
+        face_index: FT_Long,
+        params: &[OpenParam],
+    ) -> anyhow::Result<FT_Face> {
+        let mut face = ptr::null_mut();
+
+        // These, and the payloads that they point to in `params`,
+        // remain valid until FT_Open_Face returns
+        let mut ft_params: Vec<FT_Parameter> =
+            params.iter().map(OpenParam::to_ft_parameter).collect();
+        let args = stream_open_args(stream, &mut ft_params);
+
+        let res = unsafe { FT_Open_Face(self.lib, &args, face_index, &mut face as *mut _) };
+        if succeeded(res) {
+            // The face owns the stream now, so it is still valid
+            unsafe { FreeTypeStream::set_face(stream, face, face_index) };
+        }
+
+        ft_result(res, face)
+            .with_context(|| format!("FT_Open_Face(\"{:?}\", face_index={})", source, face_index))
+            .map_err(|err| match describe_open_error(res, source) {
+                Some(reason) => err.context(reason),
+                None => err,
+            })
+    }
+
+    /// Returns the (major, minor, patch) version of freetype
+    pub fn version(&self) -> (i32, i32, i32) {
+        library_version(self.lib)
+    }
+
+    /// Returns true if freetype applies the variation coordinates of
+    /// a face to the paint tables of its COLRv1 glyphs, so that their
+    /// colors, and not just the shapes of their layers, follow the
+    /// coordinates set via `Face::set_variation_coords`
+    pub fn supports_variable_color_glyphs(&self) -> bool {
+        self.version() >= MIN_VARIABLE_COLR_VERSION
+    }
+
+    /// Set the LCD filter used when rendering LCD glyphs from the faces
+    /// of this library that haven't selected their own filter.
+    /// The filter is applied when such a glyph is rendered.
+    pub fn set_lcd_filter(&mut self, filter: FT_LcdFilter) {
+        self.lcd_filter.default.set(filter);
+    }
+}
+
+/// Our own stream implementation.
+/// This is present because we cannot guarantee to be able to convert
+/// Path -> c-string on Windows systems, but also because we've seen
+/// mysterious errors about not being able to open a resource.
+/// The intent is to avoid a potential problem and to help reveal
+/// more context on problems opening files as/when that happens.
+struct FreeTypeStream {
+    stream: FT_StreamRec_,
+    backing: StreamBacking,
+    name: String,
+    /// Describes the face that was opened from this stream, once known
+    face: Option<String>,
+}
+
+enum StreamBacking {
+    File(BufReader<File>),
+    Map(Arc<Mmap>),
+    Static(&'static [u8]),
+    Memory(Arc<Box<[u8]>>),
+}
+
+impl FreeTypeStream {
+    pub fn from_source(source: &FontDataSource) -> anyhow::Result<FT_Stream> {
+        let limit = FontTooLarge::configured_limit();
+        let (backing, base, len) = match source {
+            FontDataSource::OnDisk(path) => return Self::open_path(path),
+            #[cfg(unix)]
+            FontDataSource::FileDescriptor { fd, name } => {
+                let fd = fd
+                    .try_clone()
+                    .with_context(|| format!("duplicating fd for {}", name))?;
+                return Self::open_file(File::from(fd), name);
+            }
+            FontDataSource::BuiltIn { data, name } => {
+                if let Some(data) = compression::decompress(data, name, limit)? {
+                    return Self::from_decompressed(name, data);
+                }
+                let base = data.as_ptr();
+                let len = data.len();
+                (StreamBacking::Static(data), base, len)
+            }
+            FontDataSource::Memory { data, name } => {
+                FontTooLarge::check(name, data.len() as u64, limit)?;
+                if let Some(data) = compression::decompress(data, name, limit)? {
+                    return Self::from_decompressed(name, data);
+                }
+                let base = data.as_ptr();
+                let len = data.len();
+                (StreamBacking::Memory(Arc::clone(data)), base, len)
+            }
+        };
+
+        let name = source.name_or_path_str().to_string();
+
+        // Built-in fonts are compiled into the binary, so they ought
+        // to be within range on every platform that we build for
+        debug_assert!(
+            !matches!(source, FontDataSource::BuiltIn { .. }) || len <= c_ulong::MAX as usize,
+            "built-in font {} is too large for freetype",
+            name
+        );
+
+        if len > c_ulong::MAX as usize {
+            match source {
+                FontDataSource::BuiltIn { .. } => anyhow::bail!(
+                    "BuiltIn font {} is too large to pass to freetype! (len={}). \
+                     This is a bug in the wezterm build",
+                    name,
+                    len
+                ),
+                FontDataSource::Memory { .. } => anyhow::bail!(
+                    "Memory font {} is too large to pass to freetype! (len={}). \
+                     The caller should have loaded it from disk instead",
+                    name,
+                    len
+                ),
+                FontDataSource::OnDisk(_) => unreachable!("OnDisk is handled by open_path"),
+                #[cfg(unix)]
+                FontDataSource::FileDescriptor { .. } => {
+                    unreachable!("FileDescriptor is handled by open_file")
+                }
+            }
+        }
+
+        Ok(Self::new_stream(
+            backing,
+            base as *mut _,
+            len as c_ulong,
+            name,
+        ))
+    }
+
+    /// Construct a Memory backed stream from font data that was
+    /// wrapped using the format described in the `compression` module
+    fn from_decompressed(name: &str, data: Vec<u8>) -> anyhow::Result<FT_Stream> {
+        Self::from_source(&FontDataSource::Memory {
+            name: name.to_string(),
+            data: Arc::new(data.into_boxed_slice()),
+        })
+    }
+
+    fn open_path(p: &Path) -> anyhow::Result<FT_Stream> {
+        let file = File::open(p).map_err(|err| {
+            let context = match err.kind() {
+                std::io::ErrorKind::NotFound => format!("{} does not exist", p.display()),
+                std::io::ErrorKind::PermissionDenied => {
+                    format!("permission denied reading {}", p.display())
+                }
+                _ => format!("opening file {}", p.display()),
+            };
+            anyhow::Error::new(err).context(context)
+        })?;
+        Self::open_file(file, &p.display().to_string())
+    }
+
+    /// Construct a stream that maps or reads from `file`.
+    /// `name` identifies the file in diagnostics.
+    fn open_file(mut file: File, name: &str) -> anyhow::Result<FT_Stream> {
+        let meta = file
+            .metadata()
+            .with_context(|| format!("querying metadata for {}", name))?;
+
+        if !meta.is_file() {
+            anyhow::bail!("{} is not a file", name);
+        }
+
+        let len = meta.len();
+
+        if len as usize > c_ulong::MAX as usize {
+            anyhow::bail!("{} is too large to pass to freetype! (len={})", name, len);
+        }
+        let limit = FontTooLarge::configured_limit();
+        FontTooLarge::check(name, len, limit)?;
+
+        // The length is deliberately not cached across opens: mapping
+        // a font that has since been truncated with its prior length
+        // would fault when freetype reads past the end.  It is passed
+        // along so that the mapping doesn't query the metadata again.
+        let (backing, base) = match unsafe { MmapOptions::new().len(len as usize).map(&file) } {
+            Ok(map) => {
+                if let Some(data) = compression::decompress(&map, name, limit)? {
+                    return Self::from_decompressed(name, data);
+                }
+                let base = map.as_ptr() as *mut _;
+                (StreamBacking::Map(Arc::new(map)), base)
+            }
+            Err(err) => {
+                log::warn!(
+                    "Unable to memory map {}: {}, will use regular file IO instead",
+                    name,
+                    err
+                );
+
+                let mut magic = [0u8; 4];
+                let is_compressed =
+                    file.read_exact(&mut magic).is_ok() && compression::is_compressed(&magic);
+                file.seek(SeekFrom::Start(0))
+                    .with_context(|| format!("seeking {}", name))?;
+                if is_compressed {
+                    let mut data = vec![];
+                    file.read_to_end(&mut data)
+                        .with_context(|| format!("reading {}", name))?;
+                    if let Some(data) = compression::decompress(&data, name, limit)? {
+                        return Self::from_decompressed(name, data);
+                    }
+                }
+
+                (StreamBacking::File(BufReader::new(file)), ptr::null_mut())
+            }
+        };
+
+        Ok(Self::new_stream(
+            backing,
+            base,
+            len as c_ulong,
+            name.to_string(),
+        ))
+    }
+
+    /// Construct a stream over `backing`.  If `base` is null then
+    /// the data is read from the backing file, otherwise `base`
+    /// points to the `size` bytes of data held by `backing`.
+    fn new_stream(
+        backing: StreamBacking,
+        base: *mut c_uchar,
+        size: c_ulong,
+        name: String,
+    ) -> FT_Stream {
+        let stream = Box::new(Self {
+            stream: FT_StreamRec_ {
+                base,
+                size,
+                pos: 0,
+                descriptor: FT_StreamDesc_ {
+                    pointer: ptr::null_mut(),
+                },
+                pathname: FT_StreamDesc_ {
+                    pointer: ptr::null_mut(),
+                },
+                read: if base.is_null() {
+                    Some(Self::read)
+                } else {
+                    // when backing is in memory, a null read routine causes
+                    // freetype to simply resolve data from `base`
+                    None
+                },
+                close: Some(Self::close),
+                memory: ptr::null_mut(),
+                cursor: ptr::null_mut(),
+                limit: ptr::null_mut(),
+            },
+            backing,
+            name,
+            face: None,
+        });
+        let stream = Box::into_raw(stream);
+        unsafe {
+            (*stream).stream.descriptor.pointer = stream as *mut _;
+            &mut (*stream).stream
+        }
+    }
+
+    /// Construct another stream over the same data as `stream`
+    /// without reopening the file that it came from.
+    /// Returns None if `stream` wasn't produced by this type, such as
+    /// when freetype has wrapped it to decompress the font, or if it
+    /// reads from a file, as its file position can't be shared.
+    unsafe fn share(stream: FT_Stream) -> Option<FT_Stream> {
+        let myself = Self::from_stream(stream)?;
+        let backing = match &myself.backing {
+            StreamBacking::File(_) => return None,
+            StreamBacking::Map(map) => StreamBacking::Map(Arc::clone(map)),
+            StreamBacking::Static(data) => StreamBacking::Static(*data),
+            StreamBacking::Memory(data) => StreamBacking::Memory(Arc::clone(data)),
+        };
+        Some(Self::new_stream(
+            backing,
+            myself.stream.base,
+            myself.stream.size,
+            myself.name.clone(),
+        ))
+    }
+
+    /// Returns our state for `stream`, or None if it wasn't
+    /// produced by this type
+    unsafe fn from_stream<'a>(stream: FT_Stream) -> Option<&'a mut Self> {
+        let ours = Self::close as unsafe extern "C" fn(FT_Stream) as usize;
+        if stream.is_null() || (*stream).close.map(|close| close as usize) != Some(ours) {
+            return None;
+        }
+        Some(&mut *((*stream).descriptor.pointer as *mut Self))
+    }
+
+    /// Record that `face` has been opened from `stream`, so that
+    /// problems reading the stream name the face and not just the file
+    unsafe fn set_face(stream: FT_Stream, face: FT_Face, face_index: FT_Long) {
+        if let Some(myself) = Self::from_stream(stream) {
+            let name = |s: *const std::os::raw::c_char| {
+                if s.is_null() {
+                    String::new()
+                } else {
+                    CStr::from_ptr(s).to_string_lossy().to_string()
+                }
+            };
+            myself.face.replace(format!(
+                "{} {} face_index={}",
+                name((*face).family_name),
+                name((*face).style_name),
+                face_index
+            ));
+        }
+    }
+
+    /// Describes the stream for use in log messages
+    fn describe(&self) -> String {
+        match &self.face {
+            Some(face) => format!("{} ({})", face, self.name),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Called by freetype when it wants to read data from the file
+    unsafe extern "C" fn read(
+        stream: FT_Stream,
+        offset: c_ulong,
+        buffer: *mut c_uchar,
+        count: c_ulong,
+    ) -> c_ulong {
+        if count == 0 {
+            return 0;
+        }
+
+        let myself = &mut *((*stream).descriptor.pointer as *mut Self);
+        match &mut myself.backing {
+            StreamBacking::Map(_) | StreamBacking::Static(_) | StreamBacking::Memory(_) => {
+                log::error!("read called on memory data {} !?", myself.describe());
+                0
+            }
+            StreamBacking::File(file) => {
+                if let Err(err) = file.seek(SeekFrom::Start(offset.into())) {
+                    log::error!(
+                        "failed to seek {} to offset {}: {:#}",
+                        myself.describe(),
+                        offset,
+                        err
+                    );
+                    return 0;
+                }
+
+                let buf = std::slice::from_raw_parts_mut(buffer, count as usize);
+                match file.read(buf) {
+                    Ok(len) => len as c_ulong,
+                    Err(err) => {
+                        log::error!(
+                            "failed to read {} bytes @ offset {} of {}: {:#}",
+                            count,
+                            offset,
+                            myself.describe(),
+                            err
+                        );
+                        0
+                    }
                 }
             }
         }
@@ -784,3 +4715,2087 @@ impl FreeTypeStream {
         drop(myself);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Opens the built-in JetBrains Mono Regular.  The face must be
+    /// dropped before the library, so bind both, with the face last.
+    fn jetbrains_mono_face() -> (Library, Face) {
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let font = db
+            .resolve(&config::FontAttributes::new("JetBrains Mono"))
+            .unwrap();
+        let lib = Library::new().unwrap();
+        let face = lib.face_from_locator(&font.handle).unwrap();
+        (lib, face)
+    }
+
+    #[test]
+    fn load_flag_names() {
+        assert_eq!(describe_load_flags(0), "TARGET_NORMAL");
+        assert_eq!(
+            describe_load_flags((FT_LOAD_COLOR | FT_LOAD_NO_HINTING | 1 << 16) as i32),
+            "NO_HINTING|COLOR|TARGET_LIGHT"
+        );
+        assert_eq!(
+            describe_load_flags((FT_LOAD_MONOCHROME | 2 << 16 | 1 << 30) as i32),
+            "MONOCHROME|TARGET_MONO|0x40000000"
+        );
+    }
+
+    #[test]
+    fn preview_sheet() {
+        let (_lib, mut face) = jetbrains_mono_face();
+        let (load_flags, render_mode) = compute_load_flags_from_config();
+        assert!(face
+            .render_preview_sheet(&['A'], (20, 30), load_flags, render_mode)
+            .is_err());
+        face.set_font_size(12., 96).unwrap();
+
+        let sheet = face
+            .render_preview_sheet(&['A', 'B', '\u{10ffff}'], (20, 30), load_flags, render_mode)
+            .unwrap();
+        assert_eq!(sheet.pixel_mode, FT_Pixel_Mode::FT_PIXEL_MODE_BGRA);
+        assert_eq!((sheet.width, sheet.rows), (40, 60));
+        assert_eq!(sheet.data.len(), 40 * 60 * 4);
+
+        let inked = |cell_x: usize, cell_y: usize| {
+            (0..30).any(|y| {
+                (0..20).any(|x| sheet.bgra_pixel(cell_x * 20 + x, cell_y * 30 + y).unwrap()[3] != 0)
+            })
+        };
+        assert!(inked(0, 0));
+        assert!(inked(1, 0));
+        // The font doesn't cover the third codepoint, and there is no
+        // fourth, so the second row is empty
+        assert!(!inked(0, 1));
+        assert!(!inked(1, 1));
+    }
+
+    #[test]
+    fn unicode_scan() {
+        let (_lib, face) = jetbrains_mono_face();
+        let prior = unsafe { (*face.face).charmap };
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+
+        assert_eq!(face.find_glyph_by_unicode_scan('A'), Some(glyph));
+        assert_eq!(face.find_glyph_by_unicode_scan('\u{10ffff}'), None);
+        // The selected charmap is restored
+        assert_eq!(unsafe { (*face.face).charmap }, prior);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn measure_string() {
+        let (_lib, mut face) = jetbrains_mono_face();
+        face.set_font_size(12., 96).unwrap();
+        let (load_flags, _) = compute_load_flags_from_config();
+
+        let one = face.measure_string("a", load_flags);
+        assert_eq!(one, 10.);
+        assert_eq!(face.measure_string("", load_flags), 0.);
+        assert_eq!(face.measure_string("hello", load_flags), 5. * one);
+        // The selectors take no space
+        assert_eq!(
+            face.measure_string("a\u{fe0f}b\u{e0100}", load_flags),
+            2. * one
+        );
+        // A char without a glyph is measured as notdef
+        let notdef = {
+            let mut advance: FT_Fixed = 0;
+            unsafe { FT_Get_Advance(face.face, 0, load_flags, &mut advance) };
+            advance as f64 / 65536.0
+        };
+        assert!(notdef > 0.);
+        assert_eq!(face.measure_string("a\u{10ffff}", load_flags), one + notdef);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn blank_glyphs() {
+        let (_lib, mut face) = jetbrains_mono_face();
+        face.set_font_size(12., 96).unwrap();
+        let (load_flags, _) = compute_load_flags_from_config();
+        let space = unsafe { FT_Get_Char_Index(face.face, ' ' as _) };
+        let letter = unsafe { FT_Get_Char_Index(face.face, 'a' as _) };
+        let render = |face: &mut Face, glyph| {
+            face.load_and_render_glyph(
+                glyph,
+                load_flags,
+                FT_Render_Mode::FT_RENDER_MODE_NORMAL,
+                None,
+            )
+            .unwrap();
+        };
+
+        assert_eq!(face.blank_glyph_advance(space, load_flags), None);
+        render(&mut face, space);
+        render(&mut face, letter);
+        assert_eq!(face.blank_glyph_advance(space, load_flags), Some(10.));
+        assert_eq!(face.blank_glyph_advance(letter, load_flags), None);
+        // The flags are part of the key
+        let unhinted = load_flags | FT_LOAD_NO_HINTING as FT_Int32;
+        assert_eq!(face.blank_glyph_advance(space, unhinted), None);
+
+        // Re-selecting the same size keeps what was learned
+        face.set_font_size(12., 96).unwrap();
+        assert_eq!(face.blank_glyph_advance(space, load_flags), Some(10.));
+
+        // The size, dpi and transform are part of the key
+        face.set_font_size(24., 96).unwrap();
+        assert_eq!(face.blank_glyph_advance(space, load_flags), None);
+        render(&mut face, space);
+        assert!(face.blank_glyph_advance(space, load_flags).unwrap() > 10.);
+        face.set_font_size(12., 192).unwrap();
+        assert_eq!(face.blank_glyph_advance(space, load_flags), None);
+        face.set_font_size(12., 96).unwrap();
+        face.set_transform(0.5, 1., 0.);
+        assert_eq!(face.blank_glyph_advance(space, load_flags), None);
+
+        // and switching back to an earlier size finds what was learned
+        // at it, including when rendering at another dpi in between
+        face.set_transform(1., 1., 0.);
+        face.set_font_size(12., 96).unwrap();
+        assert_eq!(face.blank_glyph_advance(space, load_flags), Some(10.));
+        face.render_glyph_at_dpi(
+            space,
+            192,
+            load_flags,
+            FT_Render_Mode::FT_RENDER_MODE_NORMAL,
+        )
+        .unwrap();
+        assert_eq!(face.blank_glyph_advance(space, load_flags), Some(10.));
+        face.set_font_size(12., 192).unwrap();
+        assert!(face.blank_glyph_advance(space, load_flags).unwrap() > 10.);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn subpixel_offset() {
+        assert_eq!(quantize_subpixel_offset(0.3, 4), 16);
+        assert_eq!(quantize_subpixel_offset(0.4, 4), 32);
+        assert_eq!(quantize_subpixel_offset(0.3, 1), 0);
+        assert_eq!(quantize_subpixel_offset(0.3, 0), 19);
+        assert_eq!(quantize_subpixel_offset(0.9, 4), 64);
+
+        let (lib, mut face) = jetbrains_mono_face();
+        face.set_font_size(12., 96).unwrap();
+        let (load_flags, _) = compute_load_flags_from_config();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'l' as _) };
+        let render = |face: &mut Face| {
+            OwnedGlyph::from_slot(
+                face.load_and_render_glyph(
+                    glyph,
+                    load_flags,
+                    FT_Render_Mode::FT_RENDER_MODE_NORMAL,
+                    None,
+                )
+                .unwrap(),
+            )
+        };
+        let unshifted = render(&mut face);
+
+        assert_eq!(face.set_subpixel_offset(0.3), 0.25);
+        let shifted = render(&mut face);
+        assert_ne!(shifted.data, unshifted.data);
+        // Only the fractional part of the offset is applied
+        assert_eq!(face.set_subpixel_offset(2.3), 0.25);
+        assert_eq!(render(&mut face).data, shifted.data);
+        assert_eq!(face.duplicate(&lib).unwrap().subpixel_phase(), 0.25);
+
+        // Rounding to whole pixels is the same as not shifting
+        face.set_subpixel_granularity(1);
+        assert_eq!(face.subpixel_phase(), 0.);
+        assert_eq!(render(&mut face).data, unshifted.data);
+
+        face.set_subpixel_granularity(0);
+        assert_eq!(face.subpixel_phase(), 19. / 64.);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn transform() {
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let font = db
+            .resolve(&config::FontAttributes::new("JetBrains Mono"))
+            .unwrap();
+        let lib = Library::new().unwrap();
+        let mut face = lib.face_from_locator(&font.handle).unwrap();
+        let mut other = lib.face_from_locator(&font.handle).unwrap();
+        let (load_flags, _) = compute_load_flags_from_config();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'W' as _) };
+        let advance = |face: &mut Face| {
+            face.load_and_render_glyph(
+                glyph,
+                load_flags,
+                FT_Render_Mode::FT_RENDER_MODE_NORMAL,
+                None,
+            )
+            .unwrap()
+            .advance
+            .x as f64
+                / 64.0
+        };
+
+        assert_eq!(face.set_font_size(12., 96).unwrap().width, 10.);
+        assert_eq!(advance(&mut face), 10.);
+
+        // The cell is scaled along with the glyphs
+        face.set_transform(0.5, 1., 0.);
+        assert_eq!(face.set_font_size(12., 96).unwrap().width, 5.);
+        assert_eq!(advance(&mut face), 5.);
+        let dup = face.duplicate(&lib).unwrap();
+        assert_eq!(dup.transform(), face.transform());
+
+        // Other faces of the same font are unaffected
+        assert_eq!(other.set_font_size(12., 96).unwrap().width, 10.);
+        assert_eq!(advance(&mut other), 10.);
+
+        // Slanting doesn't change the advance
+        face.set_transform(1., 1., 20.);
+        assert_eq!(face.set_font_size(12., 96).unwrap().width, 10.);
+        assert_eq!(advance(&mut face), 10.);
+
+        face.set_transform(1., 1., 0.);
+        assert!(face.transform().is_identity());
+        assert_eq!(face.set_font_size(12., 96).unwrap().width, 10.);
+    }
+
+    #[test]
+    fn lcd_filter_per_render() {
+        config::use_test_configuration();
+        let mut lib = Library::new().unwrap();
+        lib.set_lcd_filter(FT_LcdFilter::FT_LCD_FILTER_DEFAULT);
+        let mut face = lib.open_last_resort().unwrap();
+        face.set_font_size(12., 96).unwrap();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+        let (load_flags, _) = compute_load_flags_from_config();
+        let render = |face: &mut Face, mode| {
+            face.load_and_render_glyph(glyph, load_flags, mode, None)
+                .unwrap();
+            lib.lcd_filter.applied.get()
+        };
+
+        // The filter isn't applied until an LCD glyph is rendered
+        assert_eq!(
+            render(&mut face, FT_Render_Mode::FT_RENDER_MODE_NORMAL),
+            None
+        );
+        assert_eq!(
+            render(&mut face, FT_Render_Mode::FT_RENDER_MODE_LCD),
+            Some(FT_LcdFilter::FT_LCD_FILTER_DEFAULT)
+        );
+
+        face.set_lcd_filter(Some(FT_LcdFilter::FT_LCD_FILTER_LIGHT));
+        assert_eq!(
+            render(&mut face, FT_Render_Mode::FT_RENDER_MODE_LCD_V),
+            Some(FT_LcdFilter::FT_LCD_FILTER_LIGHT)
+        );
+        // Other modes leave the filter alone
+        assert_eq!(
+            render(&mut face, FT_Render_Mode::FT_RENDER_MODE_NORMAL),
+            Some(FT_LcdFilter::FT_LCD_FILTER_LIGHT)
+        );
+
+        // Another face of the same library gets the library filter
+        let mut other = lib.open_last_resort().unwrap();
+        other.set_font_size(12., 96).unwrap();
+        assert_eq!(
+            render(&mut other, FT_Render_Mode::FT_RENDER_MODE_LCD),
+            Some(FT_LcdFilter::FT_LCD_FILTER_DEFAULT)
+        );
+    }
+
+    #[test]
+    fn skip_lcd_filter() {
+        config::use_test_configuration();
+        let mut lib = Library::init(&ModuleOrder::Default).unwrap();
+        let mut properties = LibraryProperties::from_config(&configuration());
+        properties.skip_lcd_filter = true;
+        lib.apply_properties(properties);
+
+        let mut face = lib.open_last_resort().unwrap();
+        face.set_font_size(12., 96).unwrap();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+        let (load_flags, _) = compute_load_flags_from_config();
+        face.load_and_render_glyph(glyph, load_flags, FT_Render_Mode::FT_RENDER_MODE_LCD, None)
+            .unwrap();
+        assert_eq!(lib.lcd_filter.applied.get(), None);
+    }
+
+    #[test]
+    fn apply_properties() {
+        config::use_test_configuration();
+        let mut lib = Library::init(&ModuleOrder::Default).unwrap();
+        lib.apply_properties(LibraryProperties {
+            interpreter_version: Some(35),
+            lcd_filter: FreeTypeLcdFilter::Light,
+            skip_lcd_filter: false,
+            stem_darkening: Some(true),
+        });
+        assert_eq!(lib.interpreter_version(), Some(35));
+        assert_eq!(
+            lib.lcd_filter.default.get(),
+            FT_LcdFilter::FT_LCD_FILTER_LIGHT
+        );
+        assert!(!lib.lcd_filter.skip.get());
+    }
+
+    #[test]
+    fn duplicate() {
+        let (lib, mut face) = jetbrains_mono_face();
+        let original = face.set_font_size(12., 96).unwrap();
+        let ascender = unsafe { (*(*face.face).size).metrics.ascender };
+
+        let mut dup = face.duplicate(&lib).unwrap();
+        assert_eq!(dup.family_name(), face.family_name());
+        let larger = dup.set_font_size(24., 96).unwrap();
+        assert!(larger.height > original.height);
+
+        // The size of the original face is unaffected
+        assert_eq!(unsafe { (*(*face.face).size).metrics.ascender }, ascender);
+        assert_eq!(face.set_font_size(12., 96).unwrap().height, original.height);
+
+        // The duplicate remains usable after the original is dropped
+        drop(face);
+        assert_eq!(dup.set_font_size(24., 96).unwrap().height, larger.height);
+    }
+
+    #[test]
+    fn open_params() {
+        assert_eq!(
+            OpenParam::IgnoreTypographicFamily.tag(),
+            sfnt::make_tag(b"igpf") as FT_ULong
+        );
+
+        let mut none: Vec<FT_Parameter> = vec![];
+        let args = stream_open_args(ptr::null_mut(), &mut none);
+        assert_eq!(args.flags, FT_OPEN_STREAM);
+        assert_eq!(args.num_params, 0);
+        assert!(args.params.is_null());
+
+        let params = [OpenParam::IgnoreTypographicFamily, OpenParam::IgnoreSbix];
+        let mut ft_params: Vec<FT_Parameter> =
+            params.iter().map(OpenParam::to_ft_parameter).collect();
+        let args = stream_open_args(ptr::null_mut(), &mut ft_params);
+        assert_eq!(args.flags, FT_OPEN_STREAM | FT_OPEN_PARAMS);
+        assert_eq!(args.num_params, 2);
+        let tags = unsafe { std::slice::from_raw_parts(args.params, 2) }
+            .iter()
+            .map(|param| param.tag)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tags,
+            vec![
+                sfnt::make_tag(b"igpf") as FT_ULong,
+                sfnt::make_tag(b"isbx") as FT_ULong
+            ]
+        );
+
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let font = db
+            .resolve(&config::FontAttributes::new("JetBrains Mono"))
+            .unwrap();
+        let lib = Library::new().unwrap();
+        let face = lib
+            .face_from_locator_with_params(&font.handle, &params)
+            .unwrap();
+        assert_eq!(face.family_name(), "JetBrains Mono");
+        // Duplicates are opened with the same parameters
+        let dup = face.duplicate(&lib).unwrap();
+        assert_eq!(dup.open_params, params.to_vec());
+    }
+
+    /// Build a TTC containing `fonts`
+    fn make_ttc(fonts: &[&[u8]]) -> Vec<u8> {
+        let mut ttc = b"ttcf".to_vec();
+        ttc.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+        ttc.extend_from_slice(&(fonts.len() as u32).to_be_bytes());
+        let mut offset = 12 + 4 * fonts.len();
+        for font in fonts {
+            ttc.extend_from_slice(&(offset as u32).to_be_bytes());
+            offset += (font.len() + 3) & !3;
+        }
+        for font in fonts {
+            // The offsets in the table records are relative to the
+            // start of the collection rather than that of the font
+            let base = ttc.len();
+            ttc.extend_from_slice(font);
+            ttc.resize((ttc.len() + 3) & !3, 0);
+            let num_tables = u16::from_be_bytes([font[4], font[5]]) as usize;
+            for table in 0..num_tables {
+                let record = base + 12 + table * 16 + 8;
+                let mut value = [0u8; 4];
+                value.copy_from_slice(&ttc[record..record + 4]);
+                let value = u32::from_be_bytes(value) + base as u32;
+                ttc[record..record + 4].copy_from_slice(&value.to_be_bytes());
+            }
+        }
+        ttc
+    }
+
+    #[test]
+    fn describe_collection() {
+        config::use_test_configuration();
+        let lib = Library::new().unwrap();
+        let regular: &[u8] = include_bytes!("../../assets/fonts/JetBrainsMono-Regular.ttf");
+        let describe = |name: &str, data: Vec<u8>| {
+            lib.describe_source(&FontDataSource::Memory {
+                name: name.to_string(),
+                data: Arc::new(data.into_boxed_slice()),
+            })
+            .unwrap()
+        };
+
+        let desc = describe("JetBrains Mono.ttf", regular.to_vec());
+        assert_eq!((desc.num_faces, desc.is_collection), (1, false));
+
+        // A collection that holds a single face is still a collection
+        let desc = describe("JetBrains Mono.ttc", make_ttc(&[regular]));
+        assert_eq!((desc.num_faces, desc.is_collection), (1, true));
+
+        let desc = describe("JetBrains Mono.ttc", make_ttc(&[regular, regular]));
+        assert_eq!((desc.num_faces, desc.is_collection), (2, true));
+    }
+
+    #[test]
+    fn faces_in_collection() {
+        config::use_test_configuration();
+        let source = FontDataSource::Memory {
+            name: "JetBrains Mono.ttc".to_string(),
+            data: Arc::new(
+                make_ttc(&[
+                    include_bytes!("../../assets/fonts/JetBrainsMono-Regular.ttf"),
+                    include_bytes!("../../assets/fonts/JetBrainsMono-Bold.ttf"),
+                ])
+                .into_boxed_slice(),
+            ),
+        };
+        let lib = Library::new().unwrap();
+        let mut faces = lib.faces_in_collection(&source).unwrap();
+        let styles = faces
+            .iter()
+            .map(|face| (face.source.index, face.style_name()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            styles,
+            vec![(0, "Regular".to_string()), (1, "Bold".to_string())]
+        );
+
+        // Both faces read from the same copy of the collection
+        let base = |face: &Face| unsafe { (*(*face.face).stream).base };
+        assert_eq!(base(&faces[0]), base(&faces[1]));
+
+        // The remaining face is usable after the first is dropped
+        faces.remove(0);
+        let bold = &mut faces[0];
+        bold.set_font_size(12., 96).unwrap();
+        let glyph = bold.find_glyph_by_unicode_scan('A').unwrap();
+        let slot = bold
+            .load_and_render_glyph(
+                glyph,
+                FT_LOAD_DEFAULT as i32,
+                FT_Render_Mode::FT_RENDER_MODE_NORMAL,
+                None,
+            )
+            .unwrap();
+        assert!(slot.bitmap.width > 0);
+    }
+
+    #[test]
+    fn lcd_glyph() {
+        let glyph = |pixel_mode, width, rows, pitch, data: Vec<u8>| OwnedGlyph {
+            pixel_mode,
+            width,
+            rows,
+            pitch,
+            data,
+            left: 0,
+            top: 0,
+        };
+
+        // Two pixels wide, with a padding byte at the end of each row
+        let lcd = glyph(
+            FT_Pixel_Mode::FT_PIXEL_MODE_LCD,
+            6,
+            2,
+            7,
+            vec![1, 2, 3, 4, 5, 6, 0, 7, 8, 9, 10, 11, 12, 0],
+        )
+        .lcd()
+        .unwrap();
+        assert_eq!((lcd.width, lcd.height, lcd.vertical), (2, 2, false));
+        assert_eq!(lcd.pixel(1, 0), [4, 5, 6]);
+        assert_eq!(lcd.pixel(0, 1), [7, 8, 9]);
+
+        // Two pixels tall, with each subpixel on its own row
+        let lcd_v = glyph(
+            FT_Pixel_Mode::FT_PIXEL_MODE_LCD_V,
+            2,
+            6,
+            2,
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+        )
+        .lcd()
+        .unwrap();
+        assert_eq!((lcd_v.width, lcd_v.height, lcd_v.vertical), (2, 2, true));
+        assert_eq!(lcd_v.pixel(0, 0), [1, 3, 5]);
+        assert_eq!(lcd_v.pixel(1, 1), [8, 10, 12]);
+
+        assert!(
+            glyph(FT_Pixel_Mode::FT_PIXEL_MODE_GRAY, 2, 2, 2, vec![0; 4])
+                .lcd()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn trimmed() {
+        let glyph = |pixel_mode, width, rows, pitch, data: Vec<u8>| OwnedGlyph {
+            pixel_mode,
+            width,
+            rows,
+            pitch,
+            data,
+            left: 10,
+            top: 20,
+        };
+        let gray = FT_Pixel_Mode::FT_PIXEL_MODE_GRAY;
+
+        // A blank border on each side, and a padding byte per row
+        let trimmed = glyph(
+            gray,
+            4,
+            3,
+            5,
+            vec![0, 0, 0, 0, 0, 0, 5, 6, 0, 0, 0, 0, 7, 0, 0],
+        )
+        .trimmed();
+        assert_eq!(
+            trimmed,
+            OwnedGlyph {
+                left: 11,
+                top: 19,
+                ..glyph(gray, 2, 2, 2, vec![5, 6, 0, 7])
+            }
+        );
+
+        // Mono pixels are shifted to the start of the row
+        let mono = FT_Pixel_Mode::FT_PIXEL_MODE_MONO;
+        let trimmed = glyph(mono, 10, 2, 2, vec![0x10, 0x40, 0, 0]).trimmed();
+        assert_eq!(
+            trimmed,
+            OwnedGlyph {
+                left: 13,
+                ..glyph(mono, 7, 1, 1, vec![0x82])
+            }
+        );
+
+        // LCD bitmaps are trimmed by whole pixels
+        let lcd = FT_Pixel_Mode::FT_PIXEL_MODE_LCD;
+        let trimmed = glyph(lcd, 9, 1, 9, vec![0, 0, 0, 0, 0, 4, 0, 0, 0]).trimmed();
+        assert_eq!(
+            trimmed,
+            OwnedGlyph {
+                left: 11,
+                ..glyph(lcd, 3, 1, 3, vec![0, 0, 4])
+            }
+        );
+        let lcd_v = FT_Pixel_Mode::FT_PIXEL_MODE_LCD_V;
+        let trimmed = glyph(lcd_v, 1, 6, 1, vec![0, 0, 0, 1, 0, 0]).trimmed();
+        assert_eq!(
+            trimmed,
+            OwnedGlyph {
+                top: 19,
+                ..glyph(lcd_v, 1, 3, 1, vec![1, 0, 0])
+            }
+        );
+
+        let bgra = FT_Pixel_Mode::FT_PIXEL_MODE_BGRA;
+        let trimmed = glyph(bgra, 2, 1, 8, vec![0, 0, 0, 0, 1, 2, 3, 4]).trimmed();
+        assert_eq!(
+            trimmed,
+            OwnedGlyph {
+                left: 11,
+                ..glyph(bgra, 1, 1, 4, vec![1, 2, 3, 4])
+            }
+        );
+
+        // A blank glyph keeps its bearings
+        assert_eq!(
+            glyph(gray, 2, 2, 2, vec![0; 4]).trimmed(),
+            glyph(gray, 0, 0, 0, vec![])
+        );
+
+        // Nothing to trim, or a pixel mode that we don't understand
+        let tight = glyph(gray, 1, 1, 1, vec![1]);
+        assert_eq!(tight.trimmed(), tight);
+        let gray2 = glyph(FT_Pixel_Mode::FT_PIXEL_MODE_GRAY2, 4, 1, 1, vec![0]);
+        assert_eq!(gray2.trimmed(), gray2);
+    }
+
+    #[test]
+    fn already_bold() {
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let lib = Library::new().unwrap();
+        let is_bold = |weight| {
+            let mut attr = config::FontAttributes::new("JetBrains Mono");
+            attr.weight = weight;
+            let font = db.resolve(&attr).unwrap();
+            lib.face_from_locator(&font.handle)
+                .unwrap()
+                .is_already_bold()
+        };
+        assert!(!is_bold(config::FontWeight::Regular));
+        assert!(!is_bold(config::FontWeight::Medium));
+        assert!(is_bold(config::FontWeight::Bold));
+        assert!(is_bold(config::FontWeight::ExtraBold));
+    }
+
+    #[test]
+    fn compute_metrics() {
+        config::use_test_configuration();
+        let lib = Library::new().unwrap();
+        let mut face = lib.open_last_resort().unwrap();
+        let flags = FT_LOAD_COLOR as i32;
+        assert_eq!(face.effective_load_flags(flags), flags);
+
+        face.set_compute_metrics(true);
+        let flags = face.effective_load_flags(flags);
+        assert_eq!(
+            describe_load_flags(flags),
+            "COLOR|COMPUTE_METRICS|TARGET_NORMAL"
+        );
+    }
+
+    #[test]
+    fn lowest_rec_ppem() {
+        let (_lib, mut face) = jetbrains_mono_face();
+        assert_eq!(face.lowest_rec_ppem(), Some(6));
+
+        let mono = FT_Render_Mode::FT_RENDER_MODE_MONO;
+        let flags = (FT_LOAD_COLOR | render_mode_to_load_target(mono)) as i32;
+
+        // 4pt at 96dpi is 5ppem
+        face.set_font_size(4., 96).unwrap();
+        assert!(face.is_below_lowest_rec_ppem());
+        assert_eq!(face.effective_load_flags(flags), flags);
+        assert_eq!(face.effective_render_mode(mono), mono);
+
+        face.set_unhinted_below_lowest_rec_ppem(true);
+        assert_eq!(
+            describe_load_flags(face.effective_load_flags(flags)),
+            "NO_HINTING|COLOR|TARGET_MONO"
+        );
+        assert_eq!(
+            face.effective_render_mode(mono),
+            FT_Render_Mode::FT_RENDER_MODE_NORMAL
+        );
+
+        face.set_font_size(12., 96).unwrap();
+        assert!(!face.is_below_lowest_rec_ppem());
+        assert_eq!(face.effective_load_flags(flags), flags);
+        assert_eq!(face.effective_render_mode(mono), mono);
+    }
+
+    #[test]
+    fn render_is_deterministic() {
+        let (_lib, mut face) = jetbrains_mono_face();
+        face.set_font_size(10., 96).unwrap();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+
+        for &mode in &[
+            FT_Render_Mode::FT_RENDER_MODE_NORMAL,
+            FT_Render_Mode::FT_RENDER_MODE_MONO,
+            FT_Render_Mode::FT_RENDER_MODE_LCD,
+        ] {
+            let flags = render_mode_to_load_target(mode) as i32;
+            let render = |face: &mut Face| {
+                OwnedGlyph::from_slot(
+                    face.load_and_render_glyph(glyph, flags, mode, None)
+                        .unwrap(),
+                )
+            };
+            let first = render(&mut face);
+
+            // Render something else in between, with a different
+            // transform, and then restore the transform
+            face.set_transform(1., 1., 0.25f64.atan().to_degrees());
+            assert_eq!(face.transform().matrix().xy, 0x4000);
+            let slanted = render(&mut face);
+            assert_ne!(first, slanted, "{:?}", mode);
+            face.set_transform(1., 1., 0.);
+
+            assert_eq!(first, render(&mut face), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn row_padding_is_cleared() {
+        let mut data = vec![0xff; 8];
+        clear_row_padding(&mut data, 4, FT_Pixel_Mode::FT_PIXEL_MODE_MONO, 11);
+        assert_eq!(data, vec![0xff, 0xe0, 0, 0, 0xff, 0xe0, 0, 0]);
+
+        let mut data = vec![0xff; 8];
+        clear_row_padding(&mut data, 4, FT_Pixel_Mode::FT_PIXEL_MODE_GRAY, 3);
+        assert_eq!(data, vec![0xff, 0xff, 0xff, 0, 0xff, 0xff, 0xff, 0]);
+
+        let mut data = vec![0xff; 8];
+        clear_row_padding(&mut data, 8, FT_Pixel_Mode::FT_PIXEL_MODE_BGRA, 2);
+        assert_eq!(data, vec![0xff; 8]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn file_descriptor_source() {
+        use std::io::Write;
+        use std::os::unix::io::FromRawFd;
+
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let font = db
+            .resolve(&config::FontAttributes::new("JetBrains Mono"))
+            .unwrap();
+        let data = font.handle.source.load_data().unwrap();
+
+        let fd = unsafe { libc::memfd_create(b"font\0".as_ptr() as *const _, libc::MFD_CLOEXEC) };
+        assert!(fd >= 0, "memfd_create failed");
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(&data).unwrap();
+
+        let handle = FontDataHandle {
+            source: FontDataSource::FileDescriptor {
+                fd: Arc::new(file.into()),
+                name: "memfd:JetBrains Mono".to_string(),
+            },
+            index: 0,
+            variation: 0,
+            origin: FontOrigin::BuiltIn,
+        };
+        // The clone shares the fd, so it must remain usable
+        // after the original has been dropped
+        let cloned = handle.clone();
+        drop(handle);
+
+        assert_eq!(cloned.name_or_path_str(), "memfd:JetBrains Mono");
+        assert_eq!(cloned.source.load_data().unwrap().len(), data.len());
+
+        let lib = Library::new().unwrap();
+        let mut face = lib.face_from_locator(&cloned).unwrap();
+        face.set_font_size(12., 96).unwrap();
+        let glyph_index = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+        assert_ne!(glyph_index, 0);
+        let (load_flags, render_mode) = compute_load_flags_from_config();
+        let slot = face
+            .load_and_render_glyph(glyph_index, load_flags, render_mode, None)
+            .unwrap();
+        assert!(slot.bitmap.width > 0 && slot.bitmap.rows > 0);
+    }
+
+    #[test]
+    fn load_flags_for_display() {
+        let mut config = Config::default_config();
+        config.freetype_load_target = FreeTypeLoadTarget::HorizontalLcd;
+        let display = |subpixel_order| DisplayInfo {
+            dpi: 96,
+            subpixel_order,
+        };
+
+        let unknown = compute_load_flags(&config, &display(SubpixelOrder::Unknown));
+        assert_eq!(unknown.render_mode, FT_Render_Mode::FT_RENDER_MODE_LCD);
+        assert!(!unknown.is_bgr());
+
+        let bgr = compute_load_flags(&config, &display(SubpixelOrder::Bgr));
+        assert_eq!(bgr.render_mode, FT_Render_Mode::FT_RENDER_MODE_LCD);
+        assert_eq!(bgr.load_flags, unknown.load_flags);
+        assert!(bgr.is_bgr());
+        assert_ne!(bgr, unknown);
+
+        let vertical = compute_load_flags(&config, &display(SubpixelOrder::VerticalRgb));
+        assert_eq!(vertical.render_mode, FT_Render_Mode::FT_RENDER_MODE_LCD_V);
+        assert_eq!(
+            vertical.load_flags,
+            (config.freetype_load_flags.bits()
+                | FT_LOAD_COLOR
+                | render_mode_to_load_target(FT_Render_Mode::FT_RENDER_MODE_LCD_V))
+                as i32
+        );
+
+        // Grayscale rendering doesn't depend upon the subpixels
+        config.freetype_load_target = FreeTypeLoadTarget::Normal;
+        assert_eq!(
+            compute_load_flags(&config, &display(SubpixelOrder::VerticalBgr)).render_mode,
+            FT_Render_Mode::FT_RENDER_MODE_NORMAL
+        );
+    }
+
+    #[test]
+    fn with_size() {
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let lib = Library::new().unwrap();
+
+        let font = db
+            .resolve(&config::FontAttributes::new("JetBrains Mono"))
+            .unwrap();
+        let mut face = lib.face_from_locator(&font.handle).unwrap();
+        {
+            let guard = face.with_size(24., 96).unwrap();
+            assert_eq!(guard.current_size(), Some((24., 96)));
+        }
+        assert_eq!(face.current_size(), None);
+
+        face.set_font_size(12., 96).unwrap();
+        let height = face.effective_pixel_size();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+        {
+            let mut guard = face.with_size(24., 192).unwrap();
+            assert_eq!(guard.current_size(), Some((24., 192)));
+            let (load_flags, render_mode) = compute_load_flags_from_config();
+            guard
+                .load_and_render_glyph(glyph, load_flags, render_mode, None)
+                .unwrap();
+        }
+        assert_eq!(face.current_size(), Some((12., 96)));
+        assert_eq!(face.effective_pixel_size(), height);
+        assert_eq!(unsafe { (*(*face.face).size).metrics.y_ppem }, 16);
+
+        // The emoji font only has bitmap strikes, so the strike must
+        // be selected again afterwards
+        let font = db
+            .resolve(&config::FontAttributes::new("Noto Color Emoji"))
+            .unwrap();
+        let mut face = lib.face_from_locator(&font.handle).unwrap();
+        let selected = face.set_font_size(12., 96).unwrap();
+        assert!(!selected.is_scaled);
+        let strike_ppem = unsafe { (*(*face.face).size).metrics.y_ppem };
+        {
+            let guard = face.with_size(8., 96).unwrap();
+            assert_eq!(guard.current_size(), Some((8., 96)));
+        }
+        assert_eq!(face.current_size(), Some((12., 96)));
+        assert_eq!(face.effective_pixel_size(), Some(selected.height));
+        assert_eq!(unsafe { (*(*face.face).size).metrics.y_ppem }, strike_ppem);
+    }
+
+    #[test]
+    fn subpixel_order_provider() {
+        config::use_test_configuration();
+        let mut lib = Library::new().unwrap();
+        let face = lib.open_last_resort().unwrap();
+        assert_eq!(face.subpixel_order(), SubpixelOrder::Rgb);
+
+        let order = Rc::new(Cell::new(SubpixelOrder::Bgr));
+        let provided = Rc::clone(&order);
+        lib.set_subpixel_order_provider(move || provided.get());
+        // The face was opened before the provider was registered
+        assert_eq!(face.subpixel_order(), SubpixelOrder::Bgr);
+        order.set(SubpixelOrder::VerticalRgb);
+        assert_eq!(face.subpixel_order(), SubpixelOrder::VerticalRgb);
+        assert_eq!(
+            orient_lcd_mode(FT_Render_Mode::FT_RENDER_MODE_LCD, face.subpixel_order()),
+            FT_Render_Mode::FT_RENDER_MODE_LCD_V
+        );
+    }
+
+    #[test]
+    fn identity_in_errors() {
+        let (_lib, mut face) = jetbrains_mono_face();
+        let identity = face.identity();
+        assert!(
+            identity.starts_with("JetBrains Mono Regular ("),
+            "{}",
+            identity
+        );
+        assert!(identity.ends_with("; no size selected)"), "{}", identity);
+
+        face.set_font_size(12., 96).unwrap();
+        assert!(face.identity().ends_with("; 12pt at 96dpi)"));
+        assert_eq!(format!("{:?}", face), face.identity());
+
+        let (load_flags, render_mode) = compute_load_flags_from_config();
+        let err = face
+            .load_and_render_glyph(0xffff, load_flags, render_mode, None)
+            .unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains(&face.identity()), "{}", message);
+    }
+
+    #[test]
+    fn open_errors() {
+        config::use_test_configuration();
+        let lib = Library::new().unwrap();
+        let open = |path: &Path| {
+            let handle = FontDataHandle {
+                source: FontDataSource::OnDisk(path.to_path_buf()),
+                index: 0,
+                variation: 0,
+                origin: FontOrigin::FontDirs,
+            };
+            format!("{:#}", lib.face_from_locator(&handle).unwrap_err())
+        };
+        let path = |name: &str| {
+            std::env::temp_dir().join(format!("wezterm-font-open-{}-{}", name, std::process::id()))
+        };
+
+        let missing = path("missing.ttf");
+        assert!(
+            open(&missing).contains(&format!("{} does not exist", missing.display())),
+            "{}",
+            open(&missing)
+        );
+
+        let text = path("text.ttf");
+        std::fs::write(&text, "this is not a font\n".repeat(16)).unwrap();
+        assert!(
+            open(&text).contains(&format!(
+                "{} exists but is not a recognized font format",
+                text.display()
+            )),
+            "{}",
+            open(&text)
+        );
+        std::fs::remove_file(&text).unwrap();
+
+        let data = include_bytes!("../../assets/fonts/JetBrainsMono-Regular.ttf");
+        let truncated = path("truncated.ttf");
+        std::fs::write(&truncated, &data[..1000]).unwrap();
+        assert!(
+            open(&truncated).contains(&format!(
+                "{} looks like a font but is malformed",
+                truncated.display()
+            )),
+            "{}",
+            open(&truncated)
+        );
+        std::fs::remove_file(&truncated).unwrap();
+    }
+
+    #[test]
+    fn too_large() {
+        config::use_test_configuration();
+        let lib = Library::new().unwrap();
+        let limit = config::configuration().font_max_file_size;
+
+        // A sparse file, so that it doesn't really use the disk space
+        let dir = std::env::temp_dir().join(format!("wezterm-font-large-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("huge.ttf");
+        File::create(&path).unwrap().set_len(limit + 1).unwrap();
+
+        let handle = FontDataHandle {
+            source: FontDataSource::OnDisk(path.clone()),
+            index: 0,
+            variation: 0,
+            origin: FontOrigin::FontDirs,
+        };
+        let err = lib.face_from_locator(&handle).unwrap_err();
+        let too_large = err.downcast_ref::<FontTooLarge>().unwrap();
+        assert_eq!(too_large.len, Some(limit + 1));
+        assert_eq!(too_large.limit, limit);
+        assert!(
+            err.to_string().contains(&path.display().to_string()),
+            "{}",
+            err
+        );
+        assert!(handle.source.load_data().is_err());
+
+        // Enumerating font_dirs skips it, and remembers that it did
+        let mut config = config::Config::default_config();
+        config.font_dirs = vec![dir.clone()];
+        let db = crate::FontDatabase::with_font_dirs(&config).unwrap();
+        assert_eq!(db.too_large().len(), 1);
+        assert_eq!(db.too_large()[0].name, path.display().to_string());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn max_advance() {
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_font_dirs(&configuration()).unwrap();
+        let lib = Library::new().unwrap();
+        let open = |family: &str| {
+            let font = db.resolve(&config::FontAttributes::new(family)).unwrap();
+            let mut face = lib.face_from_locator(&font.handle).unwrap();
+            face.set_font_size(12., 96).unwrap();
+            face
+        };
+
+        // Every glyph of JetBrains Mono has the same advance
+        let mut face = open("JetBrains Mono");
+        assert!(face.flags().is_fixed_width());
+        assert_eq!(face.monospace_cell_width(), Some(face.max_advance()));
+        assert_eq!(face.cell_metrics().0, face.max_advance());
+
+        // Fira Code claims to be fixed width, but has some glyphs that
+        // are twice the width of a cell, so the cell width is measured
+        let mut face = open("Fira Code");
+        assert!(face.flags().is_fixed_width());
+        assert_eq!(face.monospace_cell_width(), None);
+        assert!(face.cell_metrics().0 < face.max_advance());
+    }
+
+    #[test]
+    fn face_flags() {
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let lib = Library::new().unwrap();
+        let flags = |family: &str| {
+            let font = db.resolve(&config::FontAttributes::new(family)).unwrap();
+            lib.face_from_locator(&font.handle).unwrap().flags()
+        };
+
+        let mono = flags("JetBrains Mono");
+        assert!(
+            mono.contains(
+                FaceFlags::SCALABLE
+                    | FaceFlags::SFNT
+                    | FaceFlags::HORIZONTAL
+                    | FaceFlags::FIXED_WIDTH
+            ),
+            "{:?}",
+            mono
+        );
+        assert!(!mono.intersects(FaceFlags::COLOR | FaceFlags::FIXED_SIZES | FaceFlags::VERTICAL));
+
+        let emoji = flags("Noto Color Emoji");
+        assert!(
+            emoji.contains(FaceFlags::SFNT | FaceFlags::COLOR | FaceFlags::FIXED_SIZES),
+            "{:?}",
+            emoji
+        );
+        assert!(emoji.has_color() && emoji.has_fixed_sizes());
+    }
+
+    #[test]
+    fn color_formats() {
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let lib = Library::new().unwrap();
+        let face = |family: &str| {
+            let font = db.resolve(&config::FontAttributes::new(family)).unwrap();
+            lib.face_from_locator(&font.handle).unwrap()
+        };
+
+        let mono = face("JetBrains Mono");
+        assert!(mono.has_table(sfnt::make_tag(b"glyf")));
+        assert!(!mono.has_table(sfnt::make_tag(b"CBDT")));
+        assert_eq!(mono.color_formats(), ColorFormats::empty());
+        assert_eq!(mono.color_formats().to_string(), "none");
+        assert!(mono.palette_flags().is_empty());
+        assert_eq!(mono.palette_for_background(true), None);
+        assert!(mono.cbdt_bit_depths().is_empty());
+
+        let emoji = face("Noto Color Emoji");
+        let formats = emoji.color_formats();
+        assert_eq!(formats, ColorFormats::CBDT);
+        assert_eq!(emoji.cbdt_bit_depths(), vec![32]);
+        assert!(formats.has_bitmaps() && !formats.has_colr());
+        assert_eq!(
+            (ColorFormats::COLR_V1 | ColorFormats::CPAL | ColorFormats::SVG).to_string(),
+            "COLRv1, CPAL, OT-SVG"
+        );
+    }
+
+    #[test]
+    fn sfnt_table() {
+        let (lib, face) = jetbrains_mono_face();
+
+        let head = face.sfnt_table(b"head").unwrap();
+        assert_eq!(head.len(), 54);
+        // magicNumber
+        assert_eq!(sfnt::read_u32(&head, 12), Some(0x5f0f3cf5));
+        assert_eq!(face.sfnt_table(b"CBDT"), None);
+
+        // The case feature is made of single substitutions, but there
+        // is no rtlm feature to mirror glyphs with
+        let gsub = face.sfnt_table(b"GSUB").unwrap();
+        let case = sfnt::parse_single_substitutions(&gsub, sfnt::make_tag(b"case")).unwrap();
+        assert!(!case.is_empty());
+        let paren = unsafe { FT_Get_Char_Index(face.face, '(' as _) };
+        assert_eq!(face.mirrored_glyph(paren), None);
+
+        let directory = face.sfnt_table_directory().unwrap();
+        assert_eq!(directory.len(), 18);
+        assert!(directory.contains(&(*b"head", 54)), "{:?}", directory);
+        assert!(directory.iter().all(|(tag, _)| *tag != *b"CBDT"));
+
+        // A BDF font isn't an sfnt, so has no tables
+        let bdf = "STARTFONT 2.1\n\
+                   FONT -misc-test-medium-r-normal--8-80-75-75-c-80-iso10646-1\n\
+                   SIZE 8 75 75\n\
+                   FONTBOUNDINGBOX 8 8 0 0\n\
+                   STARTPROPERTIES 2\n\
+                   FONT_ASCENT 8\n\
+                   FONT_DESCENT 0\n\
+                   ENDPROPERTIES\n\
+                   CHARS 1\n\
+                   STARTCHAR A\n\
+                   ENCODING 65\n\
+                   SWIDTH 1000 0\n\
+                   DWIDTH 8 0\n\
+                   BBX 8 8 0 0\n\
+                   BITMAP\n\
+                   18\n24\n42\n42\n7E\n42\n42\n00\n\
+                   ENDCHAR\n\
+                   ENDFONT\n";
+        let source = FontDataSource::Memory {
+            name: "test.bdf".to_string(),
+            data: Arc::new(bdf.as_bytes().to_vec().into_boxed_slice()),
+        };
+        let faces = lib.faces_in_collection(&source).unwrap();
+        assert!(!faces[0].flags().is_sfnt());
+        assert_eq!(faces[0].sfnt_table(b"head"), None);
+        assert_eq!(faces[0].sfnt_table_directory(), None);
+    }
+
+    #[test]
+    fn module_order() {
+        config::use_test_configuration();
+        assert_eq!(ModuleOrder::Default.driver_order(), FontDriver::ALL);
+        let order = ModuleOrder::PreferDrivers(vec![FontDriver::Bdf, FontDriver::Cff]);
+        let drivers = order.driver_order();
+        assert_eq!(drivers.len(), FontDriver::ALL.len());
+        assert_eq!(
+            &drivers[..4],
+            &[
+                FontDriver::Bdf,
+                FontDriver::Cff,
+                FontDriver::TrueType,
+                FontDriver::Type1
+            ]
+        );
+
+        let lib = Library::open(&order).unwrap();
+        for name in FontDriver::ALL
+            .iter()
+            .map(|driver| driver.module_name())
+            .chain(vec!["sfnt", "psaux", "psnames", "autofitter", "smooth"])
+        {
+            let name = std::ffi::CString::new(name).unwrap();
+            assert!(
+                !unsafe { FT_Get_Module(lib.lib, name.as_ptr()) }.is_null(),
+                "{:?}",
+                name
+            );
+        }
+
+        // Faces are still handled by the driver that understands them
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let font = db
+            .resolve(&config::FontAttributes::new("JetBrains Mono"))
+            .unwrap();
+        let mut face = lib.face_from_locator(&font.handle).unwrap();
+        assert_eq!(face.font_format(), Some(FontFormat::TrueType));
+        face.set_font_size(10., 96).unwrap();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+        let mode = FT_Render_Mode::FT_RENDER_MODE_NORMAL;
+        let slot = face
+            .load_and_render_glyph(glyph, render_mode_to_load_target(mode) as i32, mode, None)
+            .unwrap();
+        assert!(slot.bitmap.width > 0);
+    }
+
+    #[test]
+    fn interpreter_versions() {
+        config::use_test_configuration();
+        let supported = supported_interpreter_versions();
+        assert!(supported.contains(&35), "{:?}", supported);
+        assert!(supported.iter().all(|v| INTERPRETER_VERSIONS.contains(v)));
+
+        let mut lib = Library::new().unwrap();
+        let active = lib.interpreter_version();
+        assert!(active.is_some());
+
+        // An absurd version is rejected and the prior one remains active
+        let mut properties = LibraryProperties::from_config(&configuration());
+        properties.interpreter_version = Some(39);
+        lib.apply_properties(properties);
+        assert_eq!(lib.interpreter_version(), active);
+        assert!(REPORTED_INTERPRETER_VERSIONS.lock().unwrap().contains(&39));
+
+        properties.interpreter_version = Some(35);
+        lib.apply_properties(properties);
+        let caps = lib.capabilities();
+        assert_eq!(caps.interpreter_version, Some(35));
+        assert_eq!(caps.version, lib.version());
+        assert_eq!(
+            caps.variable_color_glyphs,
+            caps.version >= MIN_VARIABLE_COLR_VERSION
+        );
+    }
+
+    #[test]
+    fn metrics_only() {
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let lib = Library::new().unwrap();
+        let metrics_lib = Library::new_metrics_only().unwrap();
+        assert!(!lib.is_metrics_only());
+        assert!(metrics_lib.is_metrics_only());
+        assert_eq!(
+            metrics_lib.capabilities().interpreter_version,
+            lib.capabilities().interpreter_version
+        );
+
+        let (load_flags, render_mode) = compute_load_flags_from_config();
+        for name in &["JetBrains Mono", "Noto Color Emoji"] {
+            let font = db.resolve(&config::FontAttributes::new(name)).unwrap();
+            let mut face = lib.face_from_locator(&font.handle).unwrap();
+            let mut metrics_face = metrics_lib.face_from_locator(&font.handle).unwrap();
+            face.set_font_size(12., 96).unwrap();
+            metrics_face.set_font_size(12., 96).unwrap();
+
+            assert_eq!(metrics_face.cell_metrics(), face.cell_metrics(), "{}", name);
+            assert_eq!(metrics_face.family_name(), face.family_name());
+            assert_eq!(metrics_face.compute_coverage(), face.compute_coverage());
+
+            let glyph = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+            let err = metrics_face
+                .load_and_render_glyph(glyph, load_flags, render_mode, None)
+                .unwrap_err();
+            assert!(format!("{:#}", err).contains("metrics only"), "{:#}", err);
+
+            // A duplicate for a library that renders can render
+            let mut dup = metrics_face.duplicate(&lib).unwrap();
+            dup.set_font_size(12., 96).unwrap();
+            dup.load_and_render_glyph(glyph, load_flags, render_mode, None)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn coverage_in_range() {
+        let (_lib, face) = jetbrains_mono_face();
+        let all = face.compute_coverage();
+
+        // Box drawing, the latin blocks, a range that starts at
+        // zero, and one that extends beyond the unicode space
+        for &(start, end) in &[
+            (0x2500, 0x257f),
+            (0x20, 0x24f),
+            (0, 0x7f),
+            (0x10fff0, 0x1fffff),
+            (0x41, 0x41),
+        ] {
+            let expected = all.intersection_with_range(start..end + 1);
+            assert_eq!(
+                face.coverage_in_range(start, end),
+                expected,
+                "{:x}-{:x}",
+                start,
+                end
+            );
+        }
+        assert!(face.coverage_in_range(0x2500, 0x257f).contains(0x2502));
+        assert!(face.coverage_in_range(0x42, 0x41).is_empty());
+
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+        assert_eq!(face.codepoint_for_glyph(glyph), Some(0x41));
+        assert_eq!(face.codepoint_for_glyph(0), None);
+    }
+
+    #[test]
+    fn charmap_is_restored() {
+        let (_lib, face) = jetbrains_mono_face();
+        // Select a charmap other than the one that the unicode
+        // lookups select, so that failing to restore it is visible
+        let other = unsafe {
+            FT_Select_Charmap(face.face, FT_Encoding::FT_ENCODING_UNICODE);
+            let unicode = (*face.face).charmap;
+            std::slice::from_raw_parts((*face.face).charmaps, (*face.face).num_charmaps as usize)
+                .iter()
+                .copied()
+                .find(|&cmap| cmap != unicode)
+                .unwrap()
+        };
+        unsafe {
+            FT_Set_Charmap(face.face, other);
+        }
+
+        assert!(face.coverage_in_range(0x41, 0x41).contains(0x41));
+        assert_eq!(unsafe { (*face.face).charmap }, other);
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+        assert_eq!(face.codepoint_for_glyph(glyph), Some(0x41));
+        assert_eq!(unsafe { (*face.face).charmap }, other);
+    }
+
+    #[test]
+    fn uncovered_chars() {
+        let (_lib, face) = jetbrains_mono_face();
+        let prior = unsafe { (*face.face).charmap };
+
+        assert!(face.covers_str("abc"));
+        assert!(face.covers_str(""));
+        assert!(!face.covers_str("a\u{10ffff}b"));
+        assert_eq!(face.uncovered_chars("abc"), Vec::<char>::new());
+        assert_eq!(
+            face.uncovered_chars("a\u{10ffff}b\u{10fffe}\u{10ffff}"),
+            vec!['\u{10ffff}', '\u{10fffe}']
+        );
+
+        let all = face.compute_coverage();
+        let text = "┃╋ → ☃ ∀x";
+        for c in face.uncovered_chars(text) {
+            assert!(!all.contains(c as u32), "{:?}", c);
+        }
+        for c in text.chars() {
+            if all.contains(c as u32) {
+                assert!(face.covers_str(&c.to_string()), "{:?}", c);
+            }
+        }
+
+        assert_eq!(unsafe { (*face.face).charmap }, prior);
+    }
+
+    #[test]
+    fn render_glyph_both() {
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let lib = Library::new().unwrap();
+        let (load_flags, render_mode) = compute_load_flags_from_config();
+
+        let font = db
+            .resolve(&config::FontAttributes::new("JetBrains Mono"))
+            .unwrap();
+        let mut face = lib.face_from_locator(&font.handle).unwrap();
+        face.set_font_size(12., 96).unwrap();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+        let (mono, color) = face
+            .render_glyph_both(glyph, load_flags, render_mode)
+            .unwrap();
+        assert_eq!(mono.pixel_mode, FT_Pixel_Mode::FT_PIXEL_MODE_GRAY);
+        assert!(mono.data.iter().any(|&b| b != 0));
+        assert!(color.is_none());
+
+        let font = db
+            .resolve(&config::FontAttributes::new("Noto Color Emoji"))
+            .unwrap();
+        let mut face = lib.face_from_locator(&font.handle).unwrap();
+        face.set_font_size(12., 96).unwrap();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 0x1f600) };
+        let (mono, color) = face
+            .render_glyph_both(glyph, load_flags, render_mode)
+            .unwrap();
+        assert_ne!(mono.pixel_mode, FT_Pixel_Mode::FT_PIXEL_MODE_BGRA);
+        let color = color.unwrap();
+        assert_eq!(color.pixel_mode, FT_Pixel_Mode::FT_PIXEL_MODE_BGRA);
+        assert_eq!((color.width, color.rows), (mono.width, mono.rows));
+    }
+
+    #[test]
+    fn rendered_scaled_strike() {
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let lib = Library::new().unwrap();
+        let (load_flags, render_mode) = compute_load_flags_from_config();
+
+        let font = db
+            .resolve(&config::FontAttributes::new("JetBrains Mono"))
+            .unwrap();
+        let mut face = lib.face_from_locator(&font.handle).unwrap();
+        face.set_font_size(12., 96).unwrap();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+        face.load_and_render_glyph(glyph, load_flags, render_mode, None)
+            .unwrap();
+        assert!(!face.rendered_scaled_strike());
+
+        let font = db
+            .resolve(&config::FontAttributes::new("Noto Color Emoji"))
+            .unwrap();
+        let mut face = lib.face_from_locator(&font.handle).unwrap();
+        let ppem = face.strike_ppems()[0];
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 0x1f600) };
+        let mut render = |point_size| {
+            face.set_font_size(point_size, 96).unwrap();
+            face.load_and_render_glyph(glyph, load_flags, render_mode, None)
+                .unwrap();
+            let rendered = face.rendered_scaled_strike();
+            let fitted = face
+                .render_fitted_glyph(glyph, 1000., 1000., load_flags, render_mode)
+                .unwrap()
+                .was_scaled_strike;
+            assert_eq!(rendered, fitted);
+            rendered
+        };
+        // 12pt is far smaller than the only strike
+        assert!(render(12.));
+        assert!(!render(pixels_to_point_size(ppem, 96)));
+    }
+
+    #[test]
+    fn color_glyphs_ignore_mono_target() {
+        config::use_test_configuration();
+        let mut config = config::Config::default_config();
+        config.freetype_load_target = FreeTypeLoadTarget::Mono;
+        let flags = compute_load_flags(&config, &DisplayInfo::default());
+        assert_eq!(flags.render_mode, FT_Render_Mode::FT_RENDER_MODE_MONO);
+        assert!(flags.normal_render_for_color_glyphs);
+
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let lib = Library::new().unwrap();
+        let face = |name: &str| {
+            let font = db.resolve(&config::FontAttributes::new(name)).unwrap();
+            let mut face = lib.face_from_locator(&font.handle).unwrap();
+            face.set_font_size(12., 96).unwrap();
+            face
+        };
+        let render = |face: &mut Face, glyph| {
+            OwnedGlyph::from_slot(
+                face.load_and_render_glyph(glyph, flags.load_flags, flags.render_mode, None)
+                    .unwrap(),
+            )
+        };
+
+        let mut emoji = face("Noto Color Emoji");
+        let glyph = unsafe { FT_Get_Char_Index(emoji.face, 0x1f600) };
+        let colored = render(&mut emoji, glyph);
+        assert_eq!(colored.pixel_mode, FT_Pixel_Mode::FT_PIXEL_MODE_BGRA);
+        assert!(colored.data.chunks(4).any(|p| p[0] != p[1] || p[1] != p[2]));
+
+        // Text is still rendered in monochrome
+        let mut text = face("JetBrains Mono");
+        let glyph = unsafe { FT_Get_Char_Index(text.face, 'A' as _) };
+        assert_eq!(
+            render(&mut text, glyph).pixel_mode,
+            FT_Pixel_Mode::FT_PIXEL_MODE_MONO
+        );
+
+        let mono = FT_Render_Mode::FT_RENDER_MODE_MONO;
+        let mode = |face: &Face, glyph| unsafe {
+            face.render_mode_for_loaded_glyph(glyph, flags.load_flags, mono)
+        };
+        let glyph = unsafe { FT_Get_Char_Index(emoji.face, 0x1f600) };
+        render(&mut emoji, glyph);
+        assert_eq!(mode(&emoji, glyph), FT_Render_Mode::FT_RENDER_MODE_NORMAL);
+        emoji.set_normal_render_for_color_glyphs(false);
+        assert_eq!(mode(&emoji, glyph), mono);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn glyph_to_f32() {
+        let gray = OwnedGlyph {
+            pixel_mode: FT_Pixel_Mode::FT_PIXEL_MODE_GRAY,
+            width: 2,
+            rows: 1,
+            pitch: 2,
+            data: vec![0, 0xff],
+            left: 1,
+            top: 2,
+        };
+        let converted = gray.to_f32().unwrap();
+        assert_eq!((converted.width, converted.height), (2, 1));
+        assert_eq!((converted.left, converted.top), (1, 2));
+        assert_eq!(converted.pixel(0, 0), [0.; 4]);
+        assert_eq!(converted.pixel(1, 0), [1.; 4]);
+
+        // Half transparent sRGB mid-gray, premultiplied
+        let color = OwnedGlyph {
+            pixel_mode: FT_Pixel_Mode::FT_PIXEL_MODE_BGRA,
+            width: 1,
+            rows: 1,
+            pitch: 4,
+            data: vec![0x40, 0x40, 0x80, 0x80],
+            left: 0,
+            top: 0,
+        };
+        let [red, green, blue, alpha] = color.to_f32().unwrap().pixel(0, 0);
+        let alpha_expected = 128. / 255.;
+        assert!((alpha - alpha_expected).abs() < 1e-6);
+        // sRGB decoding makes the colors darker than their encoded value
+        assert!(green < 0x40 as f32 / 255.);
+        assert!((blue - green).abs() < 1e-6);
+        assert!((red - srgb_to_linear(1.) * alpha_expected).abs() < 1e-6);
+
+        let lcd = OwnedGlyph {
+            pixel_mode: FT_Pixel_Mode::FT_PIXEL_MODE_LCD,
+            width: 3,
+            rows: 1,
+            pitch: 3,
+            data: vec![0xff, 0x80, 0],
+            left: 0,
+            top: 0,
+        };
+        let pixel = lcd.to_f32().unwrap().pixel(0, 0);
+        assert_eq!(pixel, [1., 128. / 255., 0., 1.]);
+
+        let gray2 = OwnedGlyph {
+            pixel_mode: FT_Pixel_Mode::FT_PIXEL_MODE_GRAY2,
+            ..gray
+        };
+        assert!(gray2.to_f32().is_none());
+    }
+
+    #[test]
+    fn render_glyph_f32() {
+        let (_lib, mut face) = jetbrains_mono_face();
+        face.set_font_size(12., 96).unwrap();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'A' as _) };
+        let (load_flags, _) = compute_load_flags_from_config();
+        let render_mode = FT_Render_Mode::FT_RENDER_MODE_NORMAL;
+
+        let converted = face
+            .render_glyph_f32(glyph, load_flags, render_mode)
+            .unwrap();
+        let bytes = OwnedGlyph::from_slot(
+            face.load_and_render_glyph(glyph, load_flags, render_mode, None)
+                .unwrap(),
+        );
+        assert_eq!(converted, bytes.to_f32().unwrap());
+        assert!(converted.data.iter().all(|&v| (0. ..=1.).contains(&v)));
+        assert!(converted.data.iter().any(|&v| v > 0.));
+    }
+
+    #[test]
+    fn blend_lcd() {
+        // A vertical stem, three pixels wide: fully covered in the
+        // middle, with partial coverage of the neighboring subpixels
+        let stem = OwnedGlyph {
+            pixel_mode: FT_Pixel_Mode::FT_PIXEL_MODE_LCD,
+            width: 9,
+            rows: 2,
+            pitch: 9,
+            data: [0, 0x40, 0x80, 0xff, 0xff, 0xff, 0x80, 0x40, 0].repeat(2),
+            left: 1,
+            top: 2,
+        };
+        let white = [0xff; 3];
+        let black = [0; 3];
+        let blended = stem.blend_lcd(black, white).unwrap();
+        assert_eq!((blended.width, blended.height), (3, 2));
+        assert_eq!((blended.left, blended.top), (1, 2));
+        for y in 0..2 {
+            // The coverage is blended in linear space, so partial
+            // coverage looks lighter than its value would suggest
+            assert_eq!(blended.pixel(0, y), [255, 224, 187]);
+            assert_eq!(blended.pixel(1, y), [0, 0, 0]);
+            assert_eq!(blended.pixel(2, y), [187, 224, 255]);
+        }
+
+        // No coverage leaves the background as it was
+        let blank = OwnedGlyph {
+            data: vec![0; 18],
+            ..stem.clone()
+        };
+        let background = [0x12, 0x34, 0x56];
+        let blended = blank.blend_lcd([0xff; 3], background).unwrap();
+        assert!(blended.pixels().iter().all(|&pixel| pixel == background));
+
+        let gray = OwnedGlyph {
+            pixel_mode: FT_Pixel_Mode::FT_PIXEL_MODE_GRAY,
+            ..stem
+        };
+        assert!(gray.blend_lcd(black, white).is_none());
+    }
+
+    #[test]
+    fn render_glyph_over_background() {
+        let (_lib, mut face) = jetbrains_mono_face();
+        // Large enough that the stem covers several whole pixels
+        face.set_font_size(36., 96).unwrap();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, '|' as _) };
+        let (load_flags, _) = compute_load_flags_from_config();
+        let render_mode = FT_Render_Mode::FT_RENDER_MODE_LCD;
+        let (black, white) = ([0; 3], [0xff; 3]);
+
+        let blended = face
+            .render_glyph_over_background(glyph, load_flags, render_mode, black, white)
+            .unwrap();
+        let lcd = OwnedGlyph::from_slot(
+            face.load_and_render_glyph(glyph, load_flags, render_mode, None)
+                .unwrap(),
+        );
+        assert_eq!(blended, lcd.blend_lcd(black, white).unwrap());
+        // The middle of the stem is solid black
+        assert!(blended.pixels().iter().any(|&pixel| pixel == black));
+
+        assert!(face
+            .render_glyph_over_background(
+                glyph,
+                load_flags,
+                FT_Render_Mode::FT_RENDER_MODE_NORMAL,
+                black,
+                white
+            )
+            .is_err());
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn render_fitted_glyph() {
+        let (_lib, mut face) = jetbrains_mono_face();
+        face.set_font_size(12., 96).unwrap();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'W' as _) };
+        let (load_flags, _) = compute_load_flags_from_config();
+        let render_mode = FT_Render_Mode::FT_RENDER_MODE_NORMAL;
+
+        let natural = OwnedGlyph::from_slot(
+            face.load_and_render_glyph(glyph, load_flags, render_mode, None)
+                .unwrap(),
+        );
+
+        // A box that is larger than the glyph leaves it alone
+        let fitted = face
+            .render_fitted_glyph(glyph, 100., 100., load_flags, render_mode)
+            .unwrap();
+        assert_eq!((fitted.outline_scale, fitted.bitmap_scale), (1.0, 1.0));
+        assert_eq!(OwnedGlyph::from_slot(fitted.slot), natural);
+
+        // Squeeze it into a box half of its size
+        let (width, height) = (natural.width as f64 / 2., natural.rows as f64 / 2.);
+        let fitted = face
+            .render_fitted_glyph(glyph, width, height, load_flags, render_mode)
+            .unwrap();
+        assert!(fitted.outline_scale < 1.0);
+        assert_eq!(fitted.bitmap_scale, 1.0);
+        let slot = fitted.slot;
+        // Antialiasing may touch one more pixel at each edge
+        assert!(f64::from(slot.bitmap.width) <= width.ceil() + 1.);
+        assert!(f64::from(slot.bitmap.rows) <= height.ceil() + 1.);
+        assert!(slot.bitmap_left >= 0);
+        // Scaling about the origin keeps the glyph on the baseline,
+        // so the top of the ink moves down with the scale
+        assert!(slot.bitmap_top > 0 && slot.bitmap_top < natural.top);
+
+        assert_eq!(fit_scale(10., 5., 5., 5.), 0.5);
+        assert_eq!(fit_scale(4., 20., 5., 5.), 0.25);
+        assert_eq!(fit_scale(5., 5., 5., 5.), 1.0);
+        assert_eq!(fit_scale(0., 0., 5., 5.), 1.0);
+    }
+
+    #[test]
+    fn alpha_floor() {
+        let (_lib, mut face) = jetbrains_mono_face();
+        face.set_font_size(12., 96).unwrap();
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'e' as _) };
+        let (load_flags, _) = compute_load_flags_from_config();
+        let render_mode = FT_Render_Mode::FT_RENDER_MODE_NORMAL;
+        let render = |face: &mut Face| {
+            OwnedGlyph::from_slot(
+                face.load_and_render_glyph(glyph, load_flags, render_mode, None)
+                    .unwrap(),
+            )
+        };
+
+        let floor = 0x40;
+        let natural = render(&mut face);
+        assert!(natural.data.iter().any(|&v| v > 0 && v < floor));
+
+        face.set_alpha_floor(floor);
+        let floored = render(&mut face);
+        assert_eq!(floored.data.len(), natural.data.len());
+        for (&before, &after) in natural.data.iter().zip(floored.data.iter()) {
+            let expected = if before < floor { 0 } else { before };
+            assert_eq!(after, expected);
+        }
+
+        // The colors of premultiplied BGRA pixels go along with their alpha
+        let mut data = vec![1, 1, 1, 2, 0x20, 0x20, 0x20, 0x40];
+        let mut bitmap: FT_Bitmap = unsafe { std::mem::zeroed() };
+        bitmap.rows = 1;
+        bitmap.width = 2;
+        bitmap.pitch = 8;
+        bitmap.buffer = data.as_mut_ptr();
+        bitmap.pixel_mode = FT_Pixel_Mode::FT_PIXEL_MODE_BGRA as _;
+        apply_alpha_floor(&mut bitmap, 3);
+        assert_eq!(data, vec![0, 0, 0, 0, 0x20, 0x20, 0x20, 0x40]);
+    }
+
+    #[test]
+    fn no_kerning_pairs() {
+        let (_lib, face) = jetbrains_mono_face();
+        assert!(!face.has_legacy_kern());
+        assert!(face.all_kerning_pairs().is_empty());
+    }
+
+    #[test]
+    fn font_format() {
+        let (_lib, face) = jetbrains_mono_face();
+        assert_eq!(face.font_format(), Some(FontFormat::TrueType));
+
+        assert_eq!(font_format_from_name(b"PCF"), Some(FontFormat::Pcf));
+        assert_eq!(
+            font_format_from_name(b"Windows FNT"),
+            Some(FontFormat::WinFnt)
+        );
+        assert_eq!(font_format_from_name(b"SFNT"), None);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn otb_strike() {
+        config::use_test_configuration();
+        let lib = Library::new().unwrap();
+        let handle = FontDataHandle {
+            source: FontDataSource::BuiltIn {
+                name: "wezterm-test-8x16.otb",
+                data: include_bytes!("../test-data/wezterm-test-8x16.otb"),
+            },
+            index: 0,
+            variation: 0,
+            origin: FontOrigin::BuiltIn,
+        };
+        let mut face = lib.face_from_locator(&handle).unwrap();
+        assert!(face.is_bitmap_only_sfnt());
+        assert_eq!(face.font_format(), Some(FontFormat::Otb));
+        assert_eq!(face.strike_ppems(), vec![16.]);
+
+        // 12pt at 96dpi is exactly the size of the strike, which
+        // set_char_size would accept, and 10pt is not
+        for &point_size in &[12., 10.] {
+            let selected = face.set_font_size(point_size, 96).unwrap();
+            assert_eq!(
+                (selected.width, selected.height, selected.is_scaled),
+                (8., 16., false),
+                "{}pt",
+                point_size
+            );
+            let (ascender, descender) = unsafe {
+                let metrics = &(*(*face.face).size).metrics;
+                (metrics.ascender / 64, metrics.descender / 64)
+            };
+            assert_eq!((ascender, descender), (13, -3), "{}pt", point_size);
+        }
+
+        // Color emoji fonts are bitmap-only too, but are not OTB fonts
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let font = db
+            .resolve(&config::FontAttributes::new("Noto Color Emoji"))
+            .unwrap();
+        let face = lib.face_from_locator(&font.handle).unwrap();
+        assert!(face.is_bitmap_only_sfnt());
+        assert_eq!(face.font_format(), Some(FontFormat::TrueType));
+    }
+
+    #[test]
+    fn name_strings() {
+        let (_lib, face) = jetbrains_mono_face();
+
+        let version = face.version_string().unwrap();
+        assert!(version.starts_with("Version "), "{}", version);
+        assert!(face.unique_id().is_some());
+        assert!(face.license().is_some());
+        assert_eq!(face.name_string(0x7fff), None);
+    }
+
+    #[test]
+    fn negative_pitch() {
+        // A 2x3 gray bitmap with an "up" flow, so the bottom row is
+        // first in memory
+        let mut buffer = vec![5u8, 6, 3, 4, 1, 2];
+        let mut slot: FT_GlyphSlotRec_ = unsafe { std::mem::zeroed() };
+        slot.bitmap.rows = 3;
+        slot.bitmap.width = 2;
+        slot.bitmap.pitch = -2;
+        slot.bitmap.buffer = buffer.as_mut_ptr();
+        slot.bitmap.pixel_mode = FT_Pixel_Mode::FT_PIXEL_MODE_GRAY as _;
+
+        assert_eq!(&*bitmap_rows_top_down(&slot.bitmap), &[1, 2, 3, 4, 5, 6]);
+        let glyph = OwnedGlyph::from_slot(&slot);
+        assert_eq!(glyph.pitch, 2);
+        assert_eq!(glyph.data, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(glyph.bgra_pixel(1, 0), Some([2; 4]));
+
+        // A "down" flow is used as-is
+        slot.bitmap.pitch = 2;
+        assert_eq!(&*bitmap_rows_top_down(&slot.bitmap), &[5, 6, 3, 4, 1, 2]);
+
+        slot.bitmap.buffer = ptr::null_mut();
+        assert!(bitmap_rows_top_down(&slot.bitmap).is_empty());
+    }
+
+    #[test]
+    fn strike_matching() {
+        let strike = |height, y_ppem| FT_Bitmap_Size {
+            height,
+            width: height,
+            size: y_ppem,
+            x_ppem: y_ppem,
+            y_ppem,
+        };
+
+        // The heights include leading, and disagree with the ppem
+        // about which strike is closest to 20px
+        let sizes = [
+            strike(19, 16 * 64),
+            strike(26, 20 * 64),
+            strike(34, 32 * 64),
+        ];
+        assert_eq!(best_strike(&sizes, 20.), Some(1));
+        assert_eq!(best_strike(&sizes, 17.), Some(0));
+        assert_eq!(best_strike(&sizes, 27.), Some(2));
+        // Larger than all of the strikes
+        assert_eq!(best_strike(&sizes, 100.), Some(2));
+        assert_eq!(best_strike(&sizes, 1e6), Some(2));
+        // Equally close; the first one wins
+        assert_eq!(best_strike(&sizes, 18.), Some(0));
+        assert_eq!(best_strike(&[], 20.), None);
+
+        // Of strikes with the same ppem, the deepest is preferred
+        let sizes = [
+            strike(20, 20 * 64),
+            strike(20, 20 * 64),
+            strike(34, 32 * 64),
+            strike(20, 20 * 64),
+        ];
+        assert_eq!(deepest_strike(&sizes, &[1, 8, 32, 4], 0), 1);
+        assert_eq!(deepest_strike(&sizes, &[2, 8, 32, 32], 1), 3);
+        assert_eq!(deepest_strike(&sizes, &[32, 32, 8, 32], 1), 0);
+        // The depths are ignored if they don't match the strikes
+        assert_eq!(deepest_strike(&sizes, &[1, 32], 0), 0);
+        assert_eq!(deepest_strike(&sizes, &[], 3), 3);
+
+        // The largest strike is found even when they are not sorted,
+        // and the height is used when the ppem is missing
+        let sizes = [strike(109, 0), strike(20, 0), strike(136, 0)];
+        assert_eq!(best_strike(&sizes, 200.), Some(2));
+        assert_eq!(best_strike(&sizes, 16.), Some(1));
+        assert_eq!(best_strike(&sizes, 110.), Some(0));
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn strike_advice_sizes() {
+        assert_eq!(point_size_to_pixels(12., 96), 16.);
+        assert_eq!(pixels_to_point_size(16., 96), 12.);
+        assert_eq!(pixels_to_point_size(109., 144), 54.5);
+
+        let ppems = [16., 32., 64.];
+        // Already exactly on a strike
+        assert_eq!(strike_advice(&ppems, 12., 96), None);
+        assert_eq!(strike_advice(&ppems, 24., 96), None);
+        assert_eq!(strike_advice(&[], 10., 96), None);
+
+        // 18pt at 96dpi is 24ppem, between the first two strikes
+        let advice = strike_advice(&ppems, 18., 96).unwrap();
+        assert_eq!(advice.pixel_size, 24.);
+        assert_eq!(advice.selected, 16.);
+        assert_eq!(
+            advice.nearest,
+            vec![
+                StrikeSuggestion {
+                    ppem: 16.,
+                    point_size: 12.
+                },
+                StrikeSuggestion {
+                    ppem: 32.,
+                    point_size: 24.
+                },
+            ]
+        );
+
+        // Smaller and larger than all of the strikes
+        let advice = strike_advice(&ppems, 6., 96).unwrap();
+        assert_eq!(advice.selected, 16.);
+        assert_eq!(advice.nearest.len(), 1);
+        assert_eq!(advice.nearest[0].ppem, 16.);
+        let advice = strike_advice(&ppems, 72., 96).unwrap();
+        assert_eq!(advice.selected, 64.);
+        assert_eq!(advice.nearest.len(), 1);
+        assert_eq!(advice.nearest[0].point_size, 48.);
+    }
+
+    /// Returns a variable font that is installed on the system, along
+    /// with its number of named styles
+    fn find_variable_font(lib: &Library, dir: &Path) -> Option<(FontDataSource, u32)> {
+        for entry in std::fs::read_dir(dir).ok()?.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                if let Some(found) = find_variable_font(lib, &path) {
+                    return Some(found);
+                }
+                continue;
+            }
+            let source = FontDataSource::OnDisk(path);
+            if let Ok(desc) = lib.describe_source(&source) {
+                if !desc.is_collection && desc.is_variable() {
+                    return Some((source, desc.per_face_instance_counts[0]));
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns a scalable font that is installed on the system and
+    /// that also has embedded bitmap strikes, along with one of its
+    /// glyphs that has a bitmap in the first of those strikes
+    fn find_font_with_embedded_bitmaps(lib: &Library, dir: &Path) -> Option<(Face, FT_UInt)> {
+        for entry in std::fs::read_dir(dir).ok()?.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                if let Some(found) = find_font_with_embedded_bitmaps(lib, &path) {
+                    return Some(found);
+                }
+                continue;
+            }
+            let handle = FontDataHandle {
+                source: FontDataSource::OnDisk(path),
+                index: 0,
+                variation: 0,
+                origin: FontOrigin::FontDirs,
+            };
+            let mut face = match lib.face_from_locator(&handle) {
+                Ok(face) => face,
+                Err(_) => continue,
+            };
+            let flags = face.flags();
+            if !flags.is_scalable() || !flags.has_fixed_sizes() || flags.has_color() {
+                continue;
+            }
+            let ppem = face.strike_ppem_at(0)?;
+            if face
+                .set_font_size(pixels_to_point_size(ppem, 96), 96)
+                .is_err()
+            {
+                continue;
+            }
+            let glyph = unsafe {
+                let mut glyph_index = 0;
+                let mut charcode = FT_Get_First_Char(face.face, &mut glyph_index);
+                let mut found = None;
+                while glyph_index != 0 && found.is_none() {
+                    if FT_Load_Glyph(face.face, glyph_index, FT_LOAD_DEFAULT as i32) == 0
+                        && (*(*face.face).glyph).format == FT_Glyph_Format::FT_GLYPH_FORMAT_BITMAP
+                    {
+                        found = Some(glyph_index);
+                    }
+                    charcode = FT_Get_Next_Char(face.face, charcode, &mut glyph_index);
+                }
+                found
+            };
+            if let Some(glyph) = glyph {
+                return Some((face, glyph));
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn embedded_bitmap_bearings() {
+        config::use_test_configuration();
+        let lib = Library::new().unwrap();
+        // None of the bundled fonts have both outlines and strikes
+        let (mut face, glyph) =
+            match find_font_with_embedded_bitmaps(&lib, Path::new("/usr/share/fonts")) {
+                Some(found) => found,
+                None => return,
+            };
+        let ppem = face.strike_ppem_at(0).unwrap();
+        fn bearings(face: &mut Face, glyph: FT_UInt, pixels: f64) -> (FT_Int, FT_Int, u32) {
+            face.set_font_size(pixels_to_point_size(pixels, 96), 96)
+                .unwrap();
+            let slot = face
+                .load_and_render_glyph(
+                    glyph,
+                    FT_LOAD_DEFAULT as i32,
+                    FT_Render_Mode::FT_RENDER_MODE_NORMAL,
+                    None,
+                )
+                .unwrap();
+            (slot.bitmap_left, slot.bitmap_top, slot.bitmap.rows)
+        }
+
+        // At exactly the size of the strike, there is nothing to correct
+        let (left, top, rows) = bearings(&mut face, glyph, ppem);
+        assert!(face.embedded_bitmap_scale().is_none());
+
+        // A nearby size uses the same strike, so the bitmap is the
+        // same, but the bearings are scaled to match the text around it
+        let requested = ppem + 0.4;
+        let (scaled_left, scaled_top, scaled_rows) = bearings(&mut face, glyph, requested);
+        assert_eq!(scaled_rows, rows);
+        let scale = face.embedded_bitmap_scale().unwrap();
+        assert!((scale - requested / ppem).abs() < 1e-3, "{}", scale);
+        assert_eq!(scaled_left, (f64::from(left) * scale).round() as FT_Int);
+        assert_eq!(scaled_top, (f64::from(top) * scale).round() as FT_Int);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn variation_coords() {
+        let (lib, mut face) = jetbrains_mono_face();
+        assert!(face.variation_coords().is_err());
+        assert!(face.set_variation_coords(&[400.]).is_err());
+
+        // None of the bundled fonts are variable fonts
+        let (source, _) = match find_variable_font(&lib, Path::new("/usr/share/fonts")) {
+            Some(found) => found,
+            None => return,
+        };
+        let handle = FontDataHandle {
+            source,
+            index: 0,
+            variation: 0,
+            origin: FontOrigin::FontDirs,
+        };
+        let mut face = lib.face_from_locator(&handle).unwrap();
+        face.set_font_size(24., 96).unwrap();
+        let (min, max) = unsafe {
+            let mut mm = ptr::null_mut();
+            assert_eq!(FT_Get_MM_Var(face.face, &mut mm), 0);
+            let axis = &*(*mm).axis;
+            let range = (axis.minimum as f64 / 65536., axis.maximum as f64 / 65536.);
+            FT_Done_MM_Var(face.lib, mm);
+            range
+        };
+        let num_axis = face.variation_coords().unwrap().len();
+        assert!(face.set_variation_coords(&vec![0.; num_axis + 1]).is_err());
+
+        let glyph = unsafe { FT_Get_Char_Index(face.face, 'W' as _) };
+        let (load_flags, render_mode) = compute_load_flags_from_config();
+        let render = |face: &mut Face, coord| {
+            face.set_variation_coords(&[coord]).unwrap();
+            assert_eq!(face.variation_coords().unwrap()[0], coord);
+            OwnedGlyph::from_slot(
+                face.load_and_render_glyph(glyph, load_flags, render_mode, None)
+                    .unwrap(),
+            )
+        };
+        let at_min = render(&mut face, min);
+        let at_max = render(&mut face, max);
+        if min != max {
+            assert_ne!(at_min.data, at_max.data);
+        }
+    }
+
+    #[test]
+    fn out_of_range_variation() {
+        config::use_test_configuration();
+        let lib = Library::new().unwrap();
+        // None of the bundled fonts are variable fonts
+        let (source, num_styles) = match find_variable_font(&lib, Path::new("/usr/share/fonts")) {
+            Some(found) => found,
+            None => return,
+        };
+        let handle = |variation| FontDataHandle {
+            source: source.clone(),
+            index: 0,
+            variation,
+            origin: FontOrigin::FontDirs,
+        };
+
+        let face = lib.face_from_locator(&handle(1)).unwrap();
+        assert!(face.named_style_scales(0).is_ok());
+        let err = face.named_style_scales(num_styles as usize).unwrap_err();
+        assert!(format!("{}", err).contains("out of range"), "{}", err);
+
+        // freetype may refuse to open the face at all, but if it
+        // does open it, the weight and width come from the OS/2 table
+        if let Ok(face) = lib.face_from_locator(&handle(num_styles + 1)) {
+            let expected = face
+                .get_os2_table()
+                .map(|os2| (os2.usWeightClass, os2.usWidthClass))
+                .unwrap_or((400, 5));
+            assert_eq!(face.weight_and_width(), expected);
+        }
+    }
+}
@@ -1,18 +1,25 @@
 use crate::db::FontDatabase;
-use crate::locator::{new_locator, FontLocator};
-use crate::parser::ParsedFont;
+use crate::ftwrap::LoadFlags;
+use crate::locator::{
+    built_in_generic_defaults, fallback_for_codepoint, load_generic_families, new_locator,
+    FontLocator,
+};
+use crate::parser::{ParsedFont, SyntheticStyle};
 use crate::rasterizer::{new_rasterizer, FontRasterizer};
 use crate::shaper::{new_shaper, FontShaper};
+use crate::units::PixelLength;
 use anyhow::{Context, Error};
 use config::{
-    configuration, ConfigHandle, FontRasterizerSelection, FontStretch, FontWeight, TextStyle,
+    compile_unicode_range_rules, configuration, ConfigHandle, FontFormat, FontRasterizerSelection,
+    FontStretch, FontWeight, TextStyle,
 };
 use rangeset::RangeSet;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
 use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use wezterm_term::CellAttributes;
 use wezterm_toast_notification::ToastNotification;
@@ -20,38 +27,132 @@ use window::default_dpi;
 
 mod hbwrap;
 
+pub mod compression;
+pub mod coverage;
 pub mod db;
+pub mod digest;
+pub mod emoji;
 pub mod ftwrap;
 pub mod locator;
 pub mod parser;
 pub mod rasterizer;
+pub mod sfnt;
 pub mod shaper;
 pub mod units;
 
 #[cfg(all(unix, not(target_os = "macos")))]
 pub mod fcwrap;
 
-pub use crate::rasterizer::RasterizedGlyph;
+pub use crate::rasterizer::{
+    CellFit, ColorSpace, CoverageFormat, DisplayInfo, FontSynthesis, RasterizedGlyph,
+    SubpixelOrder, SubpixelOrderProvider,
+};
 pub use crate::shaper::{FallbackIdx, FontMetrics, GlyphInfo};
 
 #[derive(Debug, Error)]
 #[error("Font fallback recalculated")]
 pub struct ClearShapeCache {}
 
+/// Describes how the cell metrics of a fallback font compare with
+/// those of the primary font, at the same size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MetricsDivergence {
+    /// The cell width of the fallback divided by that of the primary
+    pub width_ratio: f64,
+    /// The cell height of the fallback divided by that of the primary
+    pub height_ratio: f64,
+}
+
+impl MetricsDivergence {
+    /// Compare the metrics of `fallback` with those of `primary`,
+    /// returning the divergence if the fallback is more than
+    /// `threshold` times wider, narrower, taller or shorter than
+    /// the primary.  A `threshold` of 0 disables the comparison.
+    pub fn between(primary: &FontMetrics, fallback: &FontMetrics, threshold: f64) -> Option<Self> {
+        if threshold <= 0. {
+            return None;
+        }
+        let divergence = Self {
+            width_ratio: fallback.cell_width.get() / primary.cell_width.get(),
+            height_ratio: fallback.cell_height.get() / primary.cell_height.get(),
+        };
+        let exceeds =
+            |ratio: f64| ratio.is_finite() && ratio > 0. && ratio.max(1. / ratio) > threshold;
+        if exceeds(divergence.width_ratio) || exceeds(divergence.height_ratio) {
+            Some(divergence)
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Display for MetricsDivergence {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "{:.2}x the width and {:.2}x the height",
+            self.width_ratio, self.height_ratio
+        )
+    }
+}
+
+/// Records that a font was skipped when resolving a text style,
+/// because the style would have had to be synthesized for it but
+/// its font attributes don't allow that
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeclinedSynthesis {
+    /// The family name of the font that was skipped
+    pub family: String,
+    /// The style that it lacks
+    pub style: SyntheticStyle,
+}
+
+impl std::fmt::Display for DeclinedSynthesis {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "{0} has no {1} face and allow_synthetic_{1}=false, \
+             so the next font in the fallback list is used for {1} text",
+            self.family, self.style
+        )
+    }
+}
+
 pub struct LoadedFont {
     rasterizers: RefCell<HashMap<FallbackIdx, Box<dyn FontRasterizer>>>,
     handles: RefCell<Vec<ParsedFont>>,
+    declined_synthesis: Vec<DeclinedSynthesis>,
     shaper: RefCell<Box<dyn FontShaper>>,
-    metrics: FontMetrics,
-    font_size: f64,
-    dpi: u32,
+    metrics: Cell<FontMetrics>,
+    font_size: Cell<f64>,
+    dpi: Cell<u32>,
     font_config: Weak<FontConfigInner>,
     pending_fallback: Arc<Mutex<Vec<ParsedFont>>>,
 }
 
 impl LoadedFont {
     pub fn metrics(&self) -> FontMetrics {
-        self.metrics
+        self.metrics.get()
+    }
+
+    /// Adjust the size of this font in place, retaining the loaded
+    /// faces and everything that doesn't depend upon the size, such
+    /// as coverage and parsed font information.
+    fn rescale(&self, font_size: f64, dpi: u32) -> anyhow::Result<()> {
+        let metrics = self
+            .shaper
+            .borrow()
+            .metrics(font_size, dpi)
+            .with_context(|| {
+                format!(
+                    "obtaining metrics for font_size={} @ dpi {}",
+                    font_size, dpi
+                )
+            })?;
+        self.metrics.set(metrics);
+        self.font_size.set(font_size);
+        self.dpi.set(dpi);
+        Ok(())
     }
 
     fn insert_fallback_handles(&self, extra_handles: Vec<ParsedFont>) -> anyhow::Result<bool> {
@@ -60,7 +161,11 @@ impl LoadedFont {
             let mut handles = self.handles.borrow_mut();
             for h in extra_handles {
                 if !handles.iter().any(|existing| *existing == h) {
-                    let idx = handles.len() - 1;
+                    // Keep the last resort font, if any, at the end
+                    let idx = match handles.last() {
+                        Some(last) if last.is_last_resort() => handles.len() - 1,
+                        _ => handles.len(),
+                    };
                     handles.insert(idx, h);
                     self.rasterizers.borrow_mut().remove(&idx);
                     loaded = true;
@@ -72,8 +177,11 @@ impl LoadedFont {
         }
         if loaded {
             if let Some(font_config) = self.font_config.upgrade() {
-                *self.shaper.borrow_mut() =
-                    new_shaper(&*font_config.config.borrow(), &self.handles.borrow())?;
+                *self.shaper.borrow_mut() = new_shaper(
+                    &*font_config.config.borrow(),
+                    &self.handles.borrow(),
+                    &font_config.load_flags.get(),
+                )?;
             }
         }
         Ok(loaded)
@@ -99,10 +207,10 @@ impl LoadedFont {
             }
         }
 
-        let result = self
-            .shaper
-            .borrow()
-            .shape(text, self.font_size, self.dpi, &mut no_glyphs);
+        let result =
+            self.shaper
+                .borrow()
+                .shape(text, self.font_size.get(), self.dpi.get(), &mut no_glyphs);
 
         if !no_glyphs.is_empty() {
             if let Some(font_config) = self.font_config.upgrade() {
@@ -120,7 +228,38 @@ impl LoadedFont {
     pub fn metrics_for_idx(&self, font_idx: usize) -> anyhow::Result<FontMetrics> {
         self.shaper
             .borrow()
-            .metrics_for_idx(font_idx, self.font_size, self.dpi)
+            .metrics_for_idx(font_idx, self.font_size.get(), self.dpi.get())
+    }
+
+    /// Compare the cell metrics of the fallback at `font_idx` with
+    /// those of the primary font, returning the divergence if it
+    /// exceeds `threshold`; see `MetricsDivergence::between`.
+    pub fn fallback_metrics_divergence(
+        &self,
+        font_idx: usize,
+        threshold: f64,
+    ) -> anyhow::Result<Option<MetricsDivergence>> {
+        if font_idx == 0 {
+            return Ok(None);
+        }
+        let primary = self.metrics_for_idx(0)?;
+        let fallback = self.metrics_for_idx(font_idx)?;
+        Ok(MetricsDivergence::between(&primary, &fallback, threshold))
+    }
+
+    /// Returns how far the glyphs from the specified fallback slot are
+    /// moved up from the baseline at the current size and dpi, which
+    /// is zero unless the font has a baseline_offset
+    pub fn baseline_offset(&self, fallback: FallbackIdx) -> PixelLength {
+        let offset = self
+            .handles
+            .borrow()
+            .get(fallback)
+            .and_then(|parsed| parsed.baseline_offset());
+        match offset {
+            Some(offset) => PixelLength::new(offset.pixels(self.font_size.get(), self.dpi.get())),
+            None => PixelLength::zero(),
+        }
     }
 
     pub fn rasterize_glyph(
@@ -128,26 +267,155 @@ impl LoadedFont {
         glyph_pos: u32,
         fallback: FallbackIdx,
     ) -> anyhow::Result<RasterizedGlyph> {
+        self.rasterize_glyph_in_color_space(glyph_pos, fallback, ColorSpace::Srgb)
+    }
+
+    /// Rasterize the glyph with its coverage encoded using `color_space`.
+    /// Color glyphs are always produced in the sRGB color space.
+    pub fn rasterize_glyph_in_color_space(
+        &self,
+        glyph_pos: u32,
+        fallback: FallbackIdx,
+        color_space: ColorSpace,
+    ) -> anyhow::Result<RasterizedGlyph> {
+        let mut glyph = self.with_rasterizer(fallback, |raster, load_flags| {
+            raster.rasterize_glyph_in_color_space(
+                glyph_pos,
+                self.font_size.get(),
+                self.dpi.get(),
+                load_flags,
+                color_space,
+            )
+        })?;
+        // This only moves the glyph; the cell metrics are unaffected
+        glyph.bearing_y += self.baseline_offset(fallback);
+        Ok(glyph)
+    }
+
+    /// Rasterize the glyph, scaling its outline down so that its ink
+    /// fits within a box that is `cell_width` by `cell_height` pixels.
+    /// Returns the glyph along with the scale that should be applied
+    /// to its bitmap; see `FontRasterizer::rasterize_fitted_glyph`.
+    pub fn rasterize_fitted_glyph(
+        &self,
+        glyph_pos: u32,
+        fallback: FallbackIdx,
+        cell_width: f64,
+        cell_height: f64,
+    ) -> anyhow::Result<(RasterizedGlyph, f64)> {
+        let (mut glyph, scale) = self.with_rasterizer(fallback, |raster, load_flags| {
+            raster.rasterize_fitted_glyph(
+                glyph_pos,
+                self.font_size.get(),
+                self.dpi.get(),
+                load_flags,
+                cell_width,
+                cell_height,
+            )
+        })?;
+        // The caller scales the bearing along with the bitmap, so the
+        // offset is expressed in unscaled pixels to cancel that out
+        glyph.bearing_y += self.baseline_offset(fallback) / scale;
+        Ok((glyph, scale))
+    }
+
+    /// Call `func` with the rasterizer for the specified fallback slot,
+    /// creating it if necessary, and the load flags for the display
+    /// that glyphs are being rendered for
+    fn with_rasterizer<R>(
+        &self,
+        fallback: FallbackIdx,
+        func: impl FnOnce(&dyn FontRasterizer, &LoadFlags) -> anyhow::Result<R>,
+    ) -> anyhow::Result<R> {
+        let font_config = self.font_config.upgrade();
+        let load_flags = match font_config.as_ref() {
+            Some(c) => c.load_flags.get(),
+            None => ftwrap::compute_load_flags(&configuration(), &DisplayInfo::default()),
+        };
         let mut rasterizers = self.rasterizers.borrow_mut();
         if let Some(raster) = rasterizers.get(&fallback) {
-            raster.rasterize_glyph(glyph_pos, self.font_size, self.dpi)
+            func(raster.as_ref(), &load_flags)
         } else {
-            let raster_selection = self
-                .font_config
-                .upgrade()
+            let raster_selection = font_config
+                .as_ref()
                 .map_or(FontRasterizerSelection::default(), |c| {
                     c.config.borrow().font_rasterizer
                 });
-            let raster = new_rasterizer(raster_selection, &(self.handles.borrow())[fallback])?;
-            let result = raster.rasterize_glyph(glyph_pos, self.font_size, self.dpi);
+            let subpixel_order = font_config
+                .as_ref()
+                .and_then(|c| c.subpixel_order.borrow().clone());
+            let raster = new_rasterizer(
+                raster_selection,
+                &(self.handles.borrow())[fallback],
+                subpixel_order,
+            )?;
+            let result = func(raster.as_ref(), &load_flags);
             rasterizers.insert(fallback, raster);
+            // This is the first glyph from this fallback, so this is
+            // when to check whether it will look out of place
+            if let Some(font_config) = font_config.as_ref() {
+                font_config.warn_about_fallback_metrics(self, fallback);
+            }
+            result
+        }
+    }
+
+    /// Rasterize a batch of glyphs, each identified by its glyph
+    /// index and fallback slot.
+    /// If `cancel` is specified, it is checked between glyphs and the
+    /// batch stops early once it has been set, such as when the
+    /// reason for rendering the batch has gone away.
+    pub fn load_glyphs(
+        &self,
+        glyphs: &[(u32, FallbackIdx)],
+        cancel: Option<&AtomicBool>,
+    ) -> anyhow::Result<LoadedGlyphs> {
+        let mut result = LoadedGlyphs {
+            glyphs: Vec::with_capacity(glyphs.len()),
+            cancelled: false,
+        };
+        for &(glyph_pos, fallback) in glyphs {
+            if cancel.map_or(false, |cancel| cancel.load(Ordering::Relaxed)) {
+                result.cancelled = true;
+                break;
+            }
             result
+                .glyphs
+                .push(self.rasterize_glyph(glyph_pos, fallback)?);
         }
+        Ok(result)
+    }
+
+    /// Returns the synthetic styling that is applied to glyphs
+    /// rendered from the specified fallback slot
+    pub fn synthesis_for_idx(&self, fallback: FallbackIdx) -> FontSynthesis {
+        self.handles
+            .borrow()
+            .get(fallback)
+            .map(|parsed| parsed.synthesis())
+            .unwrap_or_default()
     }
 
     pub fn clone_handles(&self) -> Vec<ParsedFont> {
         self.handles.borrow().clone()
     }
+
+    /// Returns the fonts that were skipped when this font was
+    /// resolved, rather than synthesizing a style for them
+    pub fn declined_synthesis(&self) -> &[DeclinedSynthesis] {
+        &self.declined_synthesis
+    }
+}
+
+/// The result of `LoadedFont::load_glyphs`
+#[derive(Debug)]
+pub struct LoadedGlyphs {
+    /// The glyphs that were rendered, in the order that they
+    /// were requested
+    pub glyphs: Vec<RasterizedGlyph>,
+    /// true if the batch was cancelled before all of the requested
+    /// glyphs were rendered
+    pub cancelled: bool,
 }
 
 struct FontConfigInner {
@@ -160,6 +428,19 @@ struct FontConfigInner {
     font_dirs: RefCell<Arc<FontDatabase>>,
     built_in: RefCell<Arc<FontDatabase>>,
     no_glyphs: RefCell<HashSet<char>>,
+    /// The names of the bitmap fonts that warn_about_strike_sizes
+    /// has already warned about
+    strike_warnings: RefCell<HashSet<String>>,
+    /// The names of the fallback fonts that warn_about_fallback_metrics
+    /// has already warned about
+    fallback_metrics_warnings: RefCell<HashSet<String>>,
+    /// The display that glyphs are being rendered for
+    display: Cell<DisplayInfo>,
+    /// The load flags computed from the config for `display`
+    load_flags: Cell<LoadFlags>,
+    /// Passed to the rasterizers, which consult it when `display`
+    /// doesn't specify the subpixel order
+    subpixel_order: RefCell<Option<SubpixelOrderProvider>>,
 }
 
 /// Matches and loads fonts for a given input style
@@ -173,6 +454,10 @@ impl FontConfigInner {
         let config = config.unwrap_or_else(|| configuration());
         let locator = new_locator(config.font_locator);
         let dpi = config.dpi.unwrap_or_else(|| default_dpi()) as usize;
+        let display = DisplayInfo {
+            dpi: dpi as u32,
+            subpixel_order: SubpixelOrder::Unknown,
+        };
         Ok(Self {
             fonts: RefCell::new(HashMap::new()),
             locator,
@@ -183,6 +468,11 @@ impl FontConfigInner {
             font_dirs: RefCell::new(Arc::new(FontDatabase::with_font_dirs(&config)?)),
             built_in: RefCell::new(Arc::new(FontDatabase::with_built_in()?)),
             no_glyphs: RefCell::new(HashSet::new()),
+            strike_warnings: RefCell::new(HashSet::new()),
+            fallback_metrics_warnings: RefCell::new(HashSet::new()),
+            load_flags: Cell::new(ftwrap::compute_load_flags(&config, &display)),
+            display: Cell::new(display),
+            subpixel_order: RefCell::new(None),
         })
     }
 
@@ -193,16 +483,36 @@ impl FontConfigInner {
         fonts.clear();
         self.metrics.borrow_mut().take();
         self.no_glyphs.borrow_mut().clear();
+        self.load_flags
+            .set(ftwrap::compute_load_flags(config, &self.display.get()));
         *self.font_dirs.borrow_mut() = Arc::new(FontDatabase::with_font_dirs(config)?);
         Ok(())
     }
 
+    fn set_display_info(&self, display: DisplayInfo) -> bool {
+        self.display.set(display);
+        let load_flags = ftwrap::compute_load_flags(&self.config.borrow(), &display);
+        let changed = load_flags != self.load_flags.get();
+        if changed {
+            log::debug!("load flags for {:?} are now {:?}", display, load_flags);
+            self.load_flags.set(load_flags);
+            // The shapers of the loaded fonts hint with the prior flags
+            self.fonts.borrow_mut().clear();
+        }
+        changed
+    }
+
     fn schedule_fallback_resolve<F: FnOnce() + Send + Sync + 'static>(
         &self,
         mut no_glyphs: Vec<char>,
         pending: &Arc<Mutex<Vec<ParsedFont>>>,
         completion: F,
     ) {
+        // Decide this before removing codepoints that were already
+        // looked up, as that may separate a variation selector from
+        // the codepoint that it applies to
+        let prefer_emoji = emoji::prefers_emoji_presentation(&no_glyphs);
+
         let mut ng = self.no_glyphs.borrow_mut();
         no_glyphs.retain(|c| !ng.contains(c));
         for c in &no_glyphs {
@@ -255,6 +565,11 @@ impl FontConfigInner {
                 ),
             }
 
+            // Check this before the coverage is computed for sorting,
+            // as that is expensive
+            extra_handles.retain(|p| format_allowed(&config, p));
+            apply_emoji_overrides(&config, &mut extra_handles);
+
             let mut wanted = RangeSet::new();
             for c in no_glyphs {
                 wanted.add(c as u32);
@@ -281,17 +596,44 @@ impl FontConfigInner {
                 );
             }
 
-            // iteratively reduce to just the fonts that we need
-            extra_handles.retain(|p| match p.coverage_intersection(&wanted) {
-                Ok(cov) if cov.is_empty() => false,
-                Ok(cov) => {
-                    // Remove the matches from the set, so that we avoid
-                    // picking up multiple fonts for the same glyphs
-                    wanted = wanted.difference(&cov);
-                    true
+            reduce_fallbacks(&mut extra_handles, &mut wanted, prefer_emoji);
+
+            // None of the configured fallbacks could help with these;
+            // ask the system for its best candidate for each codepoint
+            if !wanted.is_empty() {
+                let attributes = config.font.font.first().cloned().unwrap_or_default();
+                for c in wanted.iter_values().collect::<Vec<_>>() {
+                    if !wanted.contains(c) {
+                        continue;
+                    }
+                    let c = match std::char::from_u32(c) {
+                        Some(c) => c,
+                        None => continue,
+                    };
+                    let handle = match fallback_for_codepoint(&*locator, c, &attributes) {
+                        Some(handle) => handle,
+                        None => continue,
+                    };
+                    if extra_handles.iter().any(|p| p.handle == handle) {
+                        continue;
+                    }
+                    match ParsedFont::from_locator(&handle) {
+                        Ok(parsed) if !format_allowed(&config, &parsed) => {}
+                        Ok(parsed) => match parsed.coverage_intersection(&wanted) {
+                            Ok(cov) if !cov.is_empty() => {
+                                wanted = wanted.difference(&cov);
+                                extra_handles.push(parsed);
+                            }
+                            _ => {}
+                        },
+                        Err(err) => log::error!(
+                            "Error: {:#} while loading {}",
+                            err,
+                            handle.diagnostic_string()
+                        ),
+                    }
                 }
-                Err(_) => false,
-            });
+            }
 
             if !extra_handles.is_empty() {
                 let mut pending = pending.lock().unwrap();
@@ -310,7 +652,7 @@ impl FontConfigInner {
                     let url = "https://wezfurlong.org/wezterm/config/fonts.html";
                     log::warn!(
                         "No fonts contain glyphs for these codepoints: {}.\n\
-                     Placeholder 'Last Resort' glyphs are being displayed instead.\n\
+                     Placeholder glyphs are being displayed instead.\n\
                      You may wish to install additional fonts, or adjust your\n\
                      configuration so that it can find them.\n\
                      {} has more information about configuring fonts.\n\
@@ -355,7 +697,7 @@ impl FontConfigInner {
             return Ok(Rc::clone(entry));
         }
 
-        let attributes = style.font_with_fallback();
+        let attributes = style.font_with_fallback(&config);
         let preferred_attributes = attributes
             .iter()
             .filter(|a| !a.is_fallback)
@@ -366,17 +708,42 @@ impl FontConfigInner {
             .filter(|a| a.is_fallback)
             .map(|a| a.clone())
             .collect::<Vec<_>>();
+        let range_attributes = config
+            .unicode_range_rules
+            .iter()
+            .flat_map(|rule| rule.font.font.iter().cloned())
+            .collect::<Vec<_>>();
+        let substitution_attributes = config
+            .glyph_substitutions
+            .iter()
+            .filter_map(|rule| rule.font.as_ref())
+            .flat_map(|font| font.font.iter().cloned())
+            .collect::<Vec<_>>();
         let mut loaded = HashSet::new();
 
         let mut handles = vec![];
-        for attrs in &[&preferred_attributes, &fallback_attributes] {
+        for attrs in &[
+            &preferred_attributes,
+            &substitution_attributes,
+            &range_attributes,
+            &fallback_attributes,
+        ] {
             self.font_dirs
                 .borrow()
                 .resolve_multiple(attrs, &mut handles, &mut loaded);
             handles.append(&mut self.locator.load_fonts(attrs, &mut loaded)?);
-            self.built_in
-                .borrow()
-                .resolve_multiple(attrs, &mut handles, &mut loaded);
+            let built_in = self.built_in.borrow();
+            built_in.resolve_multiple(attrs, &mut handles, &mut loaded);
+            handles.append(&mut load_generic_families(
+                attrs,
+                &mut loaded,
+                built_in_generic_defaults,
+                |attrs, loaded| {
+                    let mut handles = vec![];
+                    built_in.resolve_multiple(attrs, &mut handles, loaded);
+                    Ok(handles)
+                },
+            )?);
         }
 
         for attr in &attributes {
@@ -418,16 +785,158 @@ impl FontConfigInner {
                     )
                 };
 
+                // The font may be one of the files that were skipped
+                // because of their size, but we can't tell which fonts
+                // they hold without reading them
+                let too_large = self.font_dirs.borrow().too_large().len();
+                let too_large_extra = if too_large > 0 {
+                    format!(
+                        ". {} file(s) in font_dirs were skipped because they are \
+                        larger than font_max_file_size; see the log for their names",
+                        too_large
+                    )
+                } else {
+                    String::new()
+                };
+
                 config::show_error(&format!(
                     "{}. Fallback(s) are being used instead, and the terminal \
-                    may not render as intended{}. See \
+                    may not render as intended{}{}. See \
                     https://wezfurlong.org/wezterm/config/fonts.html for more information",
-                    explanation, styled_extra
+                    explanation, styled_extra, too_large_extra
                 ));
             }
         }
 
-        let shaper = new_shaper(&*config, &handles)?;
+        for attr in &range_attributes {
+            if !loaded.contains(attr) {
+                log::warn!(
+                    "Unable to load a font matching one of your unicode_range_rules: {}",
+                    attr
+                );
+            }
+        }
+
+        for attr in &substitution_attributes {
+            if !loaded.contains(attr) {
+                log::warn!(
+                    "Unable to load a font matching one of your glyph_substitutions: {}",
+                    attr
+                );
+            }
+        }
+
+        // Text in the ranges claimed by unicode_range_rules is shaped
+        // using the font from the matching rule
+        for (rule, claimed) in config
+            .unicode_range_rules
+            .iter()
+            .zip(compile_unicode_range_rules(&config.unicode_range_rules))
+        {
+            for parsed in &mut handles {
+                if parsed.unicode_ranges().is_none()
+                    && rule.font.font.iter().any(|attr| parsed.matches_name(attr))
+                {
+                    parsed.set_unicode_ranges(claimed.clone());
+                }
+            }
+        }
+
+        // Codepoints that glyph_substitutions assigns to a font are
+        // handled in the same way, but take precedence over any
+        // unicode_range_rules that include them
+        for (idx, rule) in config.glyph_substitutions.iter().enumerate() {
+            let font = match &rule.font {
+                Some(font) => font,
+                None => continue,
+            };
+            let claimed = config.glyph_substitution_map.font_ranges(idx);
+            for parsed in &mut handles {
+                let mut ranges = parsed
+                    .unicode_ranges()
+                    .cloned()
+                    .unwrap_or_else(RangeSet::new);
+                if font.font.iter().any(|attr| parsed.matches_name(attr)) {
+                    ranges.add_set(&claimed);
+                } else if parsed.unicode_ranges().is_some() {
+                    ranges.remove_set(&claimed);
+                } else {
+                    continue;
+                }
+                parsed.set_unicode_ranges(ranges);
+            }
+        }
+
+        // Record where each of the fallback fonts came from, so that
+        // the fallback chain can be explained by ls-fonts
+        let fallback_sources = style.fallback_fonts(&config);
+        for parsed in &mut handles {
+            if preferred_attributes
+                .iter()
+                .any(|attr| parsed.matches_name(attr))
+            {
+                continue;
+            }
+            if let Some((_, source)) = fallback_sources
+                .iter()
+                .find(|(attr, _)| parsed.matches_name(attr))
+            {
+                parsed.set_fallback_source(*source);
+            }
+        }
+
+        // Fonts that are named by the configuration are used even if
+        // font_allowed_formats excludes their format
+        for parsed in &handles {
+            if !FontFormat::is_allowed(parsed.format(), config.font_allowed_formats.as_deref()) {
+                log::warn!(
+                    "{} is a {} font, which font_allowed_formats excludes, \
+                     but it is being used anyway because it was named \
+                     by the configuration",
+                    parsed.names().full_name,
+                    parsed.format().map(|f| f.to_string()).unwrap_or_default()
+                );
+            }
+        }
+
+        // Fonts whose attributes forbid synthesizing a style that they
+        // lack are skipped, so that the next font in the fallback list
+        // is used for that style instead
+        let mut declined_synthesis = vec![];
+        handles.retain(|parsed| {
+            let declined = attributes
+                .iter()
+                .find(|attr| parsed.matches_name(attr))
+                .and_then(|attr| parsed.disallowed_synthesis(attr));
+            match declined {
+                Some(style) => {
+                    declined_synthesis.push(DeclinedSynthesis {
+                        family: parsed.names().family.clone(),
+                        style,
+                    });
+                    false
+                }
+                None => true,
+            }
+        });
+        warn_about_declined_synthesis(&declined_synthesis, &handles);
+
+        // Fonts that don't natively provide the requested bold or
+        // italic style will have that style synthesized
+        for parsed in &mut handles {
+            if let Some(attr) = attributes.iter().find(|attr| parsed.matches_name(attr)) {
+                parsed.synthesize(attr, config.synthetic_bold_mode);
+                parsed.set_assume_emoji_presentation(attr.assume_emoji_presentation);
+                parsed.set_baseline_offset(attr.baseline_offset);
+                parsed.set_transform(attr.horizontal_scale, attr.slant_degrees);
+            }
+        }
+
+        if *style == config.font {
+            validate_harfbuzz_features(&config, handles.first());
+        }
+
+        let shaper = new_shaper(&*config, &handles, &self.load_flags.get())?;
 
         let font_size = config.font_size * *self.font_scale.borrow();
         let dpi = *self.dpi.borrow() as u32;
@@ -438,13 +947,18 @@ impl FontConfigInner {
             )
         })?;
 
+        if config.warn_about_strike_sizes {
+            self.warn_about_strike_sizes(&handles, font_size, dpi);
+        }
+
         let loaded = Rc::new(LoadedFont {
             rasterizers: RefCell::new(HashMap::new()),
             handles: RefCell::new(handles),
+            declined_synthesis,
             shaper: RefCell::new(shaper),
-            metrics,
-            font_size,
-            dpi,
+            metrics: Cell::new(metrics),
+            font_size: Cell::new(font_size),
+            dpi: Cell::new(dpi),
             font_config: Rc::downgrade(myself),
             pending_fallback: Arc::new(Mutex::new(vec![])),
         });
@@ -454,15 +968,133 @@ impl FontConfigInner {
         Ok(loaded)
     }
 
+    /// Warn, once per font, about bitmap fonts in `handles` whose
+    /// strikes don't match `font_size`, as the strike is scaled to
+    /// fit, which blurs it
+    fn warn_about_strike_sizes(&self, handles: &[ParsedFont], font_size: f64, dpi: u32) {
+        let font_scale = *self.font_scale.borrow();
+        let mut warned = self.strike_warnings.borrow_mut();
+        for parsed in handles {
+            let advice = match parsed.strike_advice(font_size, dpi) {
+                Some(advice) => advice,
+                None => continue,
+            };
+            if !warned.insert(parsed.names().full_name.clone()) {
+                continue;
+            }
+            let suggestions = advice
+                .nearest
+                .iter()
+                .map(|s| {
+                    format!(
+                        "font_size={:.2} for {}px",
+                        s.point_size / font_scale,
+                        s.ppem
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" or ");
+            log::warn!(
+                "{} only has bitmap strikes, and font_size={} at {}dpi is {:.1}px, \
+                 so its {}px strike is scaled and may appear blurry. \
+                 {} would match a strike exactly. \
+                 Set warn_about_strike_sizes=false to suppress this message.",
+                parsed.names().full_name,
+                font_size / font_scale,
+                dpi,
+                advice.pixel_size,
+                advice.selected,
+                suggestions
+            );
+        }
+    }
+
+    /// Warn, once per font, if the cell metrics of the fallback at
+    /// `fallback` in `font` diverge from those of its primary font by
+    /// more than fallback_metrics_warning_ratio, as text that mixes
+    /// the two will look uneven.  This is only called once a glyph is
+    /// rendered from the fallback, so that fallbacks that are never
+    /// used are not loaded.
+    fn warn_about_fallback_metrics(&self, font: &LoadedFont, fallback: FallbackIdx) {
+        let threshold = self.config.borrow().fallback_metrics_warning_ratio;
+        let (primary, parsed) = {
+            let handles = font.handles.borrow();
+            match (handles.first(), handles.get(fallback)) {
+                (Some(primary), Some(parsed)) => (primary.clone(), parsed.clone()),
+                _ => return,
+            }
+        };
+        // The glyphs of the last resort font only indicate that a
+        // glyph is missing, so their proportions don't matter
+        if parsed.is_last_resort()
+            || self
+                .fallback_metrics_warnings
+                .borrow()
+                .contains(&parsed.names().full_name)
+        {
+            return;
+        }
+        let divergence = match font.fallback_metrics_divergence(fallback, threshold) {
+            Ok(Some(divergence)) => divergence,
+            Ok(None) => return,
+            Err(err) => {
+                log::debug!(
+                    "unable to compare the metrics of {} with {}: {:#}",
+                    parsed.names().full_name,
+                    primary.names().full_name,
+                    err
+                );
+                return;
+            }
+        };
+        self.fallback_metrics_warnings
+            .borrow_mut()
+            .insert(parsed.names().full_name.clone());
+        log::warn!(
+            "fallback_metrics: fallback={:?} primary={:?} width_ratio={:.2} height_ratio={:.2}: \
+             the cells of the fallback font are {} of those of the primary font, \
+             so text that mixes the two will look uneven.  Consider listing a fallback \
+             with closer proportions ahead of it, or setting \
+             wide_glyph_policy=\"FitToCells\" to shrink its glyphs to fit the cells. \
+             Set fallback_metrics_warning_ratio=0 to suppress this message.",
+            parsed.names().full_name,
+            primary.names().full_name,
+            divergence.width_ratio,
+            divergence.height_ratio,
+            divergence
+        );
+    }
+
     pub fn change_scaling(&self, font_scale: f64, dpi: usize) -> (f64, usize) {
         let prior_font = *self.font_scale.borrow();
         let prior_dpi = *self.dpi.borrow();
 
         *self.dpi.borrow_mut() = dpi;
         *self.font_scale.borrow_mut() = font_scale;
-        self.fonts.borrow_mut().clear();
         self.metrics.borrow_mut().take();
-        self.no_glyphs.borrow_mut().clear();
+
+        // Rather than discarding the loaded fonts, resize them in place.
+        // The faces remember the sizes that they have previously selected,
+        // so flipping between the DPIs of two monitors is cheap.
+        // Coverage is unaffected by the size, so the set of glyphs that
+        // we know to be missing remains valid.
+        let start = Instant::now();
+        let font_size = self.config.borrow().font_size * font_scale;
+        self.fonts
+            .borrow_mut()
+            .retain(|style, font| match font.rescale(font_size, dpi as u32) {
+                Ok(()) => true,
+                Err(err) => {
+                    log::error!("{:#}; font for {:?} will be reloaded", err, style);
+                    false
+                }
+            });
+        log::debug!(
+            "change_scaling to font_scale={} dpi={} took {:?}",
+            font_scale,
+            dpi,
+            start.elapsed()
+        );
 
         (prior_font, prior_dpi)
     }
@@ -533,6 +1165,130 @@ impl FontConfigInner {
     }
 }
 
+/// Returns true if font_allowed_formats permits `parsed` to be used
+/// as a fallback font that was found by searching
+fn format_allowed(config: &config::Config, parsed: &ParsedFont) -> bool {
+    let allowed = FontFormat::is_allowed(parsed.format(), config.font_allowed_formats.as_deref());
+    if !allowed {
+        log::trace!(
+            "Not using {} as a fallback; font_allowed_formats excludes its format",
+            parsed.names().full_name
+        );
+    }
+    allowed
+}
+
+/// Fallback fonts that are found by searching, rather than being named
+/// by the configuration, take the assume_emoji_presentation override
+/// of any configured font with the same name
+fn apply_emoji_overrides(config: &config::Config, handles: &mut [ParsedFont]) {
+    let configured: Vec<_> = config
+        .font
+        .font
+        .iter()
+        .chain(
+            config
+                .font_rules
+                .iter()
+                .flat_map(|rule| rule.font.font.iter()),
+        )
+        .chain(config.font_fallback_prepend.iter())
+        .chain(config.font_fallback_append.iter())
+        .filter(|attr| attr.assume_emoji_presentation.is_some())
+        .collect();
+    for parsed in handles {
+        if parsed.assume_emoji_presentation_override().is_some() {
+            continue;
+        }
+        if let Some(attr) = configured.iter().find(|attr| parsed.matches_name(attr)) {
+            parsed.set_assume_emoji_presentation(attr.assume_emoji_presentation);
+        }
+    }
+}
+
+/// Reduce `handles` to just the fonts that are needed to cover the
+/// codepoints in `wanted`, removing those codepoints from `wanted`.
+/// Emoji fonts are tried first when `prefer_emoji` is true, and last
+/// otherwise; this is a stable sort, so the order is otherwise retained.
+fn reduce_fallbacks(handles: &mut Vec<ParsedFont>, wanted: &mut RangeSet<u32>, prefer_emoji: bool) {
+    handles.sort_by_key(|p| p.assume_emoji_presentation() != prefer_emoji);
+
+    handles.retain(|p| match p.coverage_intersection(wanted) {
+        Ok(cov) if cov.is_empty() => false,
+        Ok(cov) => {
+            // Remove the matches from the set, so that we avoid
+            // picking up multiple fonts for the same glyphs
+            *wanted = wanted.difference(&cov);
+            true
+        }
+        Err(_) => false,
+    });
+}
+
+/// Warn about fonts that were skipped because they lack a style that
+/// they don't allow to be synthesized, when none of the fonts that
+/// remain provide that style either, as the text is then rendered
+/// without the real style regardless
+fn warn_about_declined_synthesis(declined: &[DeclinedSynthesis], handles: &[ParsedFont]) {
+    for declined in declined {
+        log::debug!("{}", declined);
+        let provided = handles
+            .iter()
+            .any(|parsed| !parsed.is_last_resort() && parsed.provides_style(declined.style));
+        if !provided {
+            log::warn!(
+                "{0} has no {1} face and allow_synthetic_{1}=false, but none of \
+                 the fallback fonts have a {1} face either, so {1} text will \
+                 not be rendered with a real {1} face",
+                declined.family,
+                declined.style
+            );
+        }
+    }
+}
+
+/// Warn about harfbuzz_features entries that are malformed, or that
+/// the user enabled but that have no effect with the primary font.
+/// The default features are not reported, as they are requested on
+/// a best-effort basis.
+fn validate_harfbuzz_features(config: &ConfigHandle, primary: Option<&ParsedFont>) {
+    let defaults = config::default_harfbuzz_features();
+    for spec in &config.harfbuzz_features {
+        let feature = match hbwrap::feature_from_string(spec) {
+            Ok(feature) => feature,
+            Err(_) => {
+                log::warn!(
+                    "harfbuzz_features: `{}` is not a valid feature and will be ignored",
+                    spec
+                );
+                continue;
+            }
+        };
+        let primary = match primary {
+            Some(primary) => primary,
+            None => continue,
+        };
+        let tag_bytes = feature.tag.to_be_bytes();
+        let tag = String::from_utf8_lossy(&tag_bytes);
+        let tag = tag.trim_end();
+        if feature.value == 0 || defaults.iter().any(|d| d == tag) {
+            continue;
+        }
+        if !primary.supports_feature(tag) {
+            let kind = match tag {
+                "calt" | "clig" | "dlig" | "liga" => "the ligature feature",
+                _ => "the feature",
+            };
+            log::warn!(
+                "harfbuzz_features: {} `{}` is not provided by {} and will have no effect",
+                kind,
+                tag,
+                primary.names().full_name
+            );
+        }
+    }
+}
+
 impl FontConfiguration {
     /// Create a new empty configuration
     pub fn new(config: Option<ConfigHandle>) -> anyhow::Result<Self> {
@@ -558,6 +1314,34 @@ impl FontConfiguration {
         self.inner.change_scaling(font_scale, dpi)
     }
 
+    /// Record the display that glyphs are being rendered for, such
+    /// as when the window has moved to a different monitor.
+    /// Returns true if the load flags for the new display differ,
+    /// in which case any previously rasterized glyphs are stale.
+    pub fn set_display_info(&self, display: DisplayInfo) -> bool {
+        self.inner.set_display_info(display)
+    }
+
+    /// Register a callback that returns the subpixel order of the
+    /// display, which is consulted when rendering LCD glyphs if the
+    /// display info passed to `set_display_info` doesn't specify it.
+    /// Glyphs that were previously rasterized should be discarded.
+    pub fn set_subpixel_order_provider<F: Fn() -> SubpixelOrder + 'static>(&self, provider: F) {
+        self.inner
+            .subpixel_order
+            .borrow_mut()
+            .replace(Rc::new(provider));
+        // The rasterizers capture the provider when they are created
+        for font in self.inner.fonts.borrow().values() {
+            font.rasterizers.borrow_mut().clear();
+        }
+    }
+
+    /// Returns the load flags used to rasterize glyphs for the display
+    pub fn load_flags(&self) -> LoadFlags {
+        self.inner.load_flags.get()
+    }
+
     /// Returns the baseline font specified in the configuration
     pub fn default_font(&self) -> anyhow::Result<Rc<LoadedFont>> {
         self.inner.default_font(&self.inner)
@@ -582,3 +1366,236 @@ impl FontConfiguration {
         self.inner.match_style(config, attrs)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn built_in(family: &str) -> ParsedFont {
+        let db = FontDatabase::with_built_in().unwrap();
+        db.resolve(&config::FontAttributes::new(family))
+            .unwrap()
+            .clone()
+    }
+
+    /// Returns the family of the font that fallback selects for the
+    /// heart in `text`
+    fn select(handles: &[ParsedFont], text: &str) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut wanted = RangeSet::new();
+        for &c in &chars {
+            wanted.add(c as u32);
+        }
+        let mut handles = handles.to_vec();
+        reduce_fallbacks(
+            &mut handles,
+            &mut wanted,
+            emoji::prefers_emoji_presentation(&chars),
+        );
+        let mut heart = RangeSet::new();
+        heart.add(0x2764);
+        handles
+            .iter()
+            .find(|p| {
+                p.coverage_intersection(&heart)
+                    .map(|cov| !cov.is_empty())
+                    .unwrap_or(false)
+            })
+            .map(|p| p.names().family.clone())
+            .unwrap()
+    }
+
+    #[test]
+    fn emoji_presentation_override() {
+        config::use_test_configuration();
+        let last_resort = "Last Resort High-Efficiency";
+        let noto = "Noto Color Emoji";
+        let mut handles = vec![built_in(last_resort), built_in(noto)];
+
+        assert_eq!(select(&handles, "\u{2764}"), last_resort);
+        assert_eq!(select(&handles, "\u{2764}\u{fe0f}"), noto);
+
+        // Overriding the heuristic flips the selection
+        handles[0].set_assume_emoji_presentation(Some(true));
+        handles[1].set_assume_emoji_presentation(Some(false));
+        assert_eq!(select(&handles, "\u{2764}"), noto);
+        assert_eq!(select(&handles, "\u{2764}\u{fe0f}"), last_resort);
+    }
+
+    #[test]
+    fn fallback_metrics_divergence() {
+        config::use_test_configuration();
+        let fonts = FontConfiguration::new(None).unwrap();
+        let style = TextStyle {
+            font: vec![
+                config::FontAttributes::new("JetBrains Mono"),
+                config::FontAttributes::new("Fira Code"),
+                config::FontAttributes::new(config::LAST_RESORT_FAMILY),
+            ],
+            foreground: None,
+        };
+        let font = fonts.resolve_font(&style).unwrap();
+        let handles = font.clone_handles();
+        let idx = |family: &str| {
+            handles
+                .iter()
+                .position(|p| p.names().family == family)
+                .unwrap()
+        };
+        let fira = idx("Fira Code");
+        let last_resort = idx(config::LAST_RESORT_FAMILY);
+
+        // Fira Code has similar proportions to JetBrains Mono
+        assert_eq!(font.fallback_metrics_divergence(fira, 1.5).unwrap(), None);
+
+        // The glyphs of the Last Resort font are almost twice as wide
+        let divergence = font
+            .fallback_metrics_divergence(last_resort, 1.5)
+            .unwrap()
+            .unwrap();
+        assert!(divergence.width_ratio > 1.5);
+
+        // The divergence is only reported above the threshold
+        let deviation = |ratio: f64| ratio.max(1. / ratio);
+        let ratio = deviation(divergence.width_ratio).max(deviation(divergence.height_ratio));
+        assert!(font
+            .fallback_metrics_divergence(last_resort, ratio - 0.01)
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            font.fallback_metrics_divergence(last_resort, ratio + 0.01)
+                .unwrap(),
+            None
+        );
+
+        // A threshold of 0 disables the comparison
+        assert_eq!(
+            font.fallback_metrics_divergence(last_resort, 0.).unwrap(),
+            None
+        );
+        assert_eq!(font.fallback_metrics_divergence(0, 1.5).unwrap(), None);
+    }
+
+    #[test]
+    fn declined_synthesis() {
+        config::use_test_configuration();
+        let fonts = FontConfiguration::new(None).unwrap();
+        let bold = |family: &str, allow_synthetic_bold: bool| {
+            let mut attr = config::FontAttributes::new(family);
+            attr.weight = FontWeight::Bold;
+            attr.allow_synthetic_bold = allow_synthetic_bold;
+            attr
+        };
+        let families = |font: &LoadedFont| {
+            font.clone_handles()
+                .iter()
+                .map(|p| p.names().full_name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        // The built-in Fira Code has no bold face, so it is normally
+        // emboldened
+        let style = TextStyle {
+            font: vec![bold("Fira Code", true), bold("JetBrains Mono", true)],
+            foreground: None,
+        };
+        let font = fonts.resolve_font(&style).unwrap();
+        assert_eq!(families(&font)[0], "Fira Code Regular");
+        assert!(font.synthesis_for_idx(0).bold);
+        assert!(font.declined_synthesis().is_empty());
+
+        // but when that isn't allowed, the bold face of the next
+        // font is used instead
+        let style = TextStyle {
+            font: vec![bold("Fira Code", false), bold("JetBrains Mono", true)],
+            foreground: None,
+        };
+        let font = fonts.resolve_font(&style).unwrap();
+        assert_eq!(families(&font)[0], "JetBrains Mono Bold");
+        assert!(!font.synthesis_for_idx(0).bold);
+        assert_eq!(
+            font.declined_synthesis(),
+            &[DeclinedSynthesis {
+                family: "Fira Code".to_string(),
+                style: SyntheticStyle::Bold,
+            }]
+        );
+        assert_eq!(
+            font.declined_synthesis()[0].to_string(),
+            "Fira Code has no bold face and allow_synthetic_bold=false, \
+             so the next font in the fallback list is used for bold text"
+        );
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn baseline_offset() {
+        config::use_test_configuration();
+        let fonts = FontConfiguration::new(None).unwrap();
+        let stacked = |primary: Option<&str>, fallback: Option<&str>| {
+            let attr = |family: &str, offset: Option<&str>| {
+                let mut attr = config::FontAttributes::new(family);
+                attr.baseline_offset = offset.map(|offset| offset.parse().unwrap());
+                attr
+            };
+            TextStyle {
+                font: vec![attr("JetBrains Mono", primary), attr("Fira Code", fallback)],
+                foreground: None,
+            }
+        };
+        let plain = fonts.resolve_font(&stacked(None, None)).unwrap();
+        let moved = fonts
+            .resolve_font(&stacked(Some("-2px"), Some("0.25em")))
+            .unwrap();
+        let fira = moved
+            .clone_handles()
+            .iter()
+            .position(|p| p.names().family == "Fira Code")
+            .unwrap();
+
+        // Only the placement of the glyphs changes, not the cells
+        assert_eq!(plain.metrics(), moved.metrics());
+
+        let em = moved.font_size.get() * moved.dpi.get() as f64 / 72.;
+        assert_eq!(plain.baseline_offset(0).get(), 0.);
+        assert_eq!(moved.baseline_offset(0).get(), -2.);
+        assert_eq!(moved.baseline_offset(fira).get(), em / 4.);
+
+        // Whichever glyph this is in each font, it is raised or
+        // lowered by the offset of the font that it is from
+        let glyph_pos = plain.shape("A", || {}).unwrap()[0].glyph_pos;
+        for &idx in &[0, fira] {
+            let before = plain.rasterize_glyph(glyph_pos, idx).unwrap();
+            let after = moved.rasterize_glyph(glyph_pos, idx).unwrap();
+            assert_eq!(after.bearing_x, before.bearing_x);
+            assert_eq!(
+                after.bearing_y - before.bearing_y,
+                moved.baseline_offset(idx)
+            );
+            assert_eq!(after.data, before.data);
+        }
+
+        // An offset in pixels stays the same as the font is scaled,
+        // but one in em units scales with it
+        let (font_scale, dpi) = fonts.change_scaling(2.0, moved.dpi.get() as usize);
+        assert_eq!(moved.baseline_offset(0).get(), -2.);
+        assert_eq!(moved.baseline_offset(fira).get(), em / 2.);
+        fonts.change_scaling(font_scale, dpi);
+    }
+
+    #[test]
+    fn emoji_override_from_config() {
+        config::use_test_configuration();
+        let mut config = (*config::configuration()).clone();
+        let mut attr = config::FontAttributes::new("Noto Color Emoji");
+        attr.assume_emoji_presentation = Some(false);
+        config.font_fallback_append.push(attr);
+
+        let mut handles = vec![built_in("Noto Color Emoji"), built_in("JetBrains Mono")];
+        assert!(handles[0].assume_emoji_presentation());
+        apply_emoji_overrides(&config, &mut handles);
+        assert_eq!(handles[0].assume_emoji_presentation_override(), Some(false));
+        assert!(!handles[0].assume_emoji_presentation());
+        assert_eq!(handles[1].assume_emoji_presentation_override(), None);
+    }
+}
@@ -1,6 +1,8 @@
 #![cfg(target_os = "macos")]
 
-use crate::locator::{FontDataSource, FontLocator, FontOrigin};
+use crate::locator::{
+    load_generic_families, platform_generic_defaults, FontDataSource, FontLocator, FontOrigin,
+};
 use crate::parser::ParsedFont;
 use config::{FontAttributes, FontStretch, FontWeight};
 use core_foundation::array::CFArray;
@@ -66,11 +68,19 @@ fn handles_from_descriptor(descriptor: &CTFontDescriptor) -> Vec<ParsedFont> {
 
         let mut font_info = vec![];
         let source = FontDataSource::OnDisk(path);
-        let _ = crate::parser::parse_and_collect_font_info(
+        if let Err(err) = crate::parser::parse_and_collect_font_info(
             &source,
             &mut font_info,
             FontOrigin::CoreText,
-        );
+        ) {
+            if err
+                .downcast_ref::<crate::parser::ResourceForkSuitcase>()
+                .is_some()
+                || err.downcast_ref::<crate::locator::FontTooLarge>().is_some()
+            {
+                log::warn!("{:#}", err);
+            }
+        }
 
         for parsed in font_info {
             if parsed.names().full_name == family_name || parsed.names().family == family_name {
@@ -102,6 +112,13 @@ impl FontLocator for CoreTextFontLocator {
             }
         }
 
+        fonts.append(&mut load_generic_families(
+            fonts_selection,
+            loaded,
+            platform_generic_defaults,
+            |attrs, loaded| self.load_fonts(attrs, loaded),
+        )?);
+
         Ok(fonts)
     }
 
@@ -169,6 +186,12 @@ fn build_fallback_list_impl() -> anyhow::Result<Vec<ParsedFont>> {
         italic: false,
         is_fallback: true,
         is_synthetic: true,
+        assume_emoji_presentation: None,
+        allow_synthetic_bold: true,
+        allow_synthetic_italic: true,
+        baseline_offset: None,
+        horizontal_scale: None,
+        slant_degrees: None,
     };
     if let Ok(descriptor) = descriptor_from_attr(&symbols) {
         fonts.append(&mut handles_from_descriptor(&descriptor));
@@ -1,9 +1,12 @@
 use crate::fcwrap;
-use crate::locator::{FontDataHandle, FontDataSource, FontLocator, FontOrigin};
+use crate::locator::{is_generic_family, FontDataHandle, FontDataSource, FontLocator, FontOrigin};
 use crate::parser::ParsedFont;
 use anyhow::Context;
 use config::FontAttributes;
-use fcwrap::{CharSet, FontSet, Pattern as FontPattern, FC_DUAL, FC_MONO};
+use fcwrap::{
+    to_fc_weight, to_fc_width, CharSet, FontSet, MatchKind, Pattern as FontPattern, FC_DUAL,
+    FC_MONO, FC_SLANT_ITALIC, FC_SLANT_ROMAN,
+};
 use std::collections::HashSet;
 use std::convert::TryInto;
 
@@ -64,7 +67,60 @@ impl FontLocator for FontConfigFontLocator {
             Ok(matches)
         }
 
+        /// Resolve a generic family such as "monospace" through the
+        /// fontconfig substitution rules, which is where the system's
+        /// preferred font for each of the aliases is configured.
+        fn by_alias(attr: &FontAttributes) -> anyhow::Result<Option<ParsedFont>> {
+            let mut pattern = FontPattern::new()?;
+            pattern.family(&attr.family)?;
+            pattern.add_integer("weight", to_fc_weight(attr.weight))?;
+            pattern.add_integer("width", to_fc_width(attr.stretch))?;
+            pattern.add_integer(
+                "slant",
+                if attr.italic {
+                    FC_SLANT_ITALIC
+                } else {
+                    FC_SLANT_ROMAN
+                },
+            )?;
+            pattern.config_substitute(MatchKind::Pattern)?;
+            pattern.default_substitute();
+
+            let sorted = pattern.sort(true).context("pattern.sort")?;
+            for pat in sorted.iter() {
+                let index = pat.get_integer("index")? as u32;
+                let handle = FontDataHandle {
+                    source: FontDataSource::OnDisk(pat.get_file()?.into()),
+                    index: index & 0xffff,
+                    variation: index >> 16,
+                    origin: FontOrigin::FontConfig,
+                };
+                if let Ok(mut parsed) = crate::parser::ParsedFont::from_locator(&handle) {
+                    parsed.set_alias(&attr.family);
+                    return Ok(Some(parsed));
+                }
+            }
+            Ok(None)
+        }
+
         for attr in fonts_selection {
+            if is_generic_family(&attr.family) {
+                match by_alias(attr) {
+                    Ok(Some(parsed)) => {
+                        log::trace!(
+                            "font-config resolved alias {} to {:?}",
+                            attr.family,
+                            parsed.names()
+                        );
+                        fonts.push(parsed);
+                        loaded.insert(attr.clone());
+                    }
+                    Ok(None) => log::trace!("font-config has no match for alias {}", attr.family),
+                    Err(err) => log::trace!("while resolving alias {:?}: {:#}", attr, err),
+                }
+                continue;
+            }
+
             let mut candidates = vec![];
 
             // Aggregate results of both family and postscript name lookups
@@ -86,11 +142,30 @@ impl FontLocator for FontConfigFontLocator {
                             // fontconfig will give us a boatload of random fallbacks.
                             // so we need to parse the returned font
                             // here to see if we got what we asked for.
-                            if let Ok(parsed) = crate::parser::ParsedFont::from_locator(&handle) {
-                                if parsed.matches_name(attr) {
-                                    log::trace!("found font-config match for {:?}", parsed.names());
-                                    candidates.push(parsed);
+                            match crate::parser::ParsedFont::from_locator(&handle) {
+                                Ok(parsed) => {
+                                    if parsed.matches_name(attr) {
+                                        log::trace!(
+                                            "found font-config match for {:?}",
+                                            parsed.names()
+                                        );
+                                        candidates.push(parsed);
+                                    }
                                 }
+                                // fontconfig matched the file by name, so
+                                // it is the font that the configuration
+                                // asked for; explain why it can't be used
+                                Err(err)
+                                    if err
+                                        .downcast_ref::<crate::locator::FontTooLarge>()
+                                        .is_some() =>
+                                {
+                                    config::show_error(&format!(
+                                        "Unable to use a font matching {}: {:#}",
+                                        attr, err
+                                    ));
+                                }
+                                Err(_) => {}
                             }
                         }
                     }
@@ -166,4 +241,57 @@ impl FontLocator for FontConfigFontLocator {
 
         Ok(fonts)
     }
+
+    fn fallback_for_codepoint(
+        &self,
+        c: char,
+        attributes: &FontAttributes,
+    ) -> anyhow::Result<Option<FontDataHandle>> {
+        let mut charset = CharSet::new()?;
+        charset.add(c)?;
+
+        let mut pattern = FontPattern::new()?;
+        pattern.add_charset(&charset)?;
+        pattern.add_integer("weight", to_fc_weight(attributes.weight))?;
+        pattern.add_integer("width", to_fc_width(attributes.stretch))?;
+        pattern.add_integer(
+            "slant",
+            if attributes.italic {
+                FC_SLANT_ITALIC
+            } else {
+                FC_SLANT_ROMAN
+            },
+        )?;
+        pattern.config_substitute(MatchKind::Pattern)?;
+        pattern.default_substitute();
+
+        let sorted = pattern.sort(true).context("pattern.sort")?;
+
+        // Take the first monospaced candidate, but settle for the
+        // best proportional candidate if there are no monospaced fonts
+        let mut best = None;
+        for pat in sorted.iter() {
+            if pat.charset_intersect_count(&charset)? == 0 {
+                continue;
+            }
+
+            let handle = FontDataHandle {
+                source: FontDataSource::OnDisk(pat.get_file().context("pat.get_file")?.into()),
+                index: pat.get_integer("index")?.try_into()?,
+                variation: 0,
+                origin: FontOrigin::FontConfig,
+            };
+
+            match pat.get_integer("spacing") {
+                Ok(n) if SPACING.contains(&n) => return Ok(Some(handle)),
+                _ => {
+                    if best.is_none() {
+                        best.replace(handle);
+                    }
+                }
+            }
+        }
+
+        Ok(best)
+    }
 }
@@ -1,6 +1,8 @@
 #![cfg(windows)]
 
-use crate::locator::{FontDataSource, FontLocator, FontOrigin};
+use crate::locator::{
+    load_generic_families, platform_generic_defaults, FontDataSource, FontLocator, FontOrigin,
+};
 use crate::parser::{best_matching_font, parse_and_collect_font_info, ParsedFont};
 use config::{FontAttributes, FontStretch as WTFontStretch, FontWeight as WTFontWeight};
 use dwrote::{FontDescriptor, FontStretch, FontStyle, FontWeight};
@@ -209,6 +211,13 @@ impl FontLocator for GdiFontLocator {
             }
         }
 
+        fonts.append(&mut load_generic_families(
+            fonts_selection,
+            loaded,
+            platform_generic_defaults,
+            |attrs, loaded| self.load_fonts(attrs, loaded),
+        )?);
+
         Ok(fonts)
     }
 
@@ -276,6 +285,12 @@ impl FontLocator for GdiFontLocator {
                         family: font.family_name(),
                         is_fallback: true,
                         is_synthetic: true,
+                        assume_emoji_presentation: None,
+                        allow_synthetic_bold: true,
+                        allow_synthetic_italic: true,
+                        baseline_offset: None,
+                        horizontal_scale: None,
+                        slant_degrees: None,
                     };
 
                     if !resolved.contains(&attr) {
@@ -1,19 +1,24 @@
 use crate::parser::ParsedFont;
+use anyhow::Context;
 use config::FontAttributes;
 use enum_display_derive::Display;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::borrow::Cow;
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+#[cfg(unix)]
+use std::os::unix::io::OwnedFd;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Weak};
+use thiserror::Error;
 
 pub mod core_text;
 #[cfg(all(unix, not(target_os = "macos")))]
 pub mod font_config;
 pub mod gdi;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Ord, PartialOrd, Display)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Ord, PartialOrd, Display, Serialize, Deserialize)]
 pub enum FontOrigin {
     FontConfig,
     CoreText,
@@ -21,11 +26,82 @@ pub enum FontOrigin {
     Gdi,
     FontDirs,
     BuiltIn,
+    LastResort,
+}
+
+impl FontOrigin {
+    /// When otherwise equally good matches for a font are found, the
+    /// one with the lowest priority value is selected: fonts from
+    /// `font_dirs` come first, then those provided by the system, then
+    /// those that are built in to wezterm.
+    pub fn priority(self) -> u8 {
+        match self {
+            Self::FontDirs => 0,
+            Self::FontConfig | Self::CoreText | Self::DirectWrite | Self::Gdi => 1,
+            Self::BuiltIn => 2,
+            Self::LastResort => 3,
+        }
+    }
+}
+
+/// Returned when a font file, or the decompressed data of a compressed
+/// font, is larger than the font_max_file_size configuration allows
+#[derive(Debug, Error)]
+#[error(
+    "{} is larger than the font_max_file_size limit of {} bytes{}",
+    .name,
+    .limit,
+    describe_len(.len)
+)]
+pub struct FontTooLarge {
+    pub name: String,
+    /// The size of the data, if it is known.  It isn't known when
+    /// decompression was stopped at the limit.
+    pub len: Option<u64>,
+    pub limit: u64,
+}
+
+fn describe_len(len: &Option<u64>) -> String {
+    match len {
+        Some(len) => format!(" ({} bytes)", len),
+        None => String::new(),
+    }
+}
+
+impl FontTooLarge {
+    /// Returns the font_max_file_size from the configuration
+    pub fn configured_limit() -> u64 {
+        config::configuration().font_max_file_size
+    }
+
+    /// Returns an error if `len` bytes of font data from `name`
+    /// exceed `limit`
+    pub fn check(name: &str, len: u64, limit: u64) -> Result<(), Self> {
+        if len > limit {
+            Err(Self {
+                name: name.to_string(),
+                len: Some(len),
+                limit,
+            })
+        } else {
+            Ok(())
+        }
+    }
 }
 
 #[derive(Clone)]
 pub enum FontDataSource {
     OnDisk(PathBuf),
+    /// A font that was passed to us as an open file descriptor,
+    /// such as via a sandbox portal, rather than as a path.
+    /// `name` is used to identify it in diagnostics.
+    /// Clones share the descriptor; it is duplicated when the font
+    /// is opened, so that failing to do so can be reported.
+    #[cfg(unix)]
+    FileDescriptor {
+        fd: Arc<OwnedFd>,
+        name: String,
+    },
     BuiltIn {
         name: &'static str,
         data: &'static [u8],
@@ -40,29 +116,179 @@ impl FontDataSource {
     pub fn name_or_path_str(&self) -> Cow<str> {
         match self {
             Self::OnDisk(path) => path.to_string_lossy(),
+            #[cfg(unix)]
+            Self::FileDescriptor { name, .. } => Cow::Borrowed(name),
             Self::BuiltIn { name, .. } => Cow::Borrowed(name),
             Self::Memory { name, .. } => Cow::Borrowed(name),
         }
     }
 
     pub fn load_data<'a>(&'a self) -> anyhow::Result<Cow<'a, [u8]>> {
-        match self {
+        let limit = FontTooLarge::configured_limit();
+        let data = match self {
             Self::OnDisk(path) => {
+                let len = std::fs::metadata(path)?.len();
+                FontTooLarge::check(&path.display().to_string(), len, limit)?;
                 let data = std::fs::read(path)?;
-                Ok(Cow::Owned(data))
+                Cow::Owned(data)
+            }
+            #[cfg(unix)]
+            Self::FileDescriptor { fd, name } => {
+                use std::io::{Read, Seek, SeekFrom};
+                let mut file = std::fs::File::from(
+                    fd.try_clone()
+                        .with_context(|| format!("duplicating fd for {}", name))?,
+                );
+                let len = file
+                    .metadata()
+                    .with_context(|| format!("querying metadata for {}", name))?
+                    .len();
+                FontTooLarge::check(name, len, limit)?;
+                let mut data = vec![];
+                file.seek(SeekFrom::Start(0))
+                    .and_then(|_| file.read_to_end(&mut data))
+                    .with_context(|| format!("reading {}", name))?;
+                Cow::Owned(data)
+            }
+            Self::BuiltIn { data, .. } => Cow::Borrowed(*data),
+            Self::Memory { data, name } => {
+                FontTooLarge::check(name, data.len() as u64, limit)?;
+                Cow::Borrowed(&***data)
+            }
+        };
+        match crate::compression::decompress(&data, &self.name_or_path_str(), limit)? {
+            Some(decompressed) => Ok(Cow::Owned(decompressed)),
+            None => Ok(data),
+        }
+    }
+}
+
+/// The serialized form of a `FontDataSource`.  Only the location or
+/// the identity of the font data is recorded, never the data itself.
+#[derive(Serialize, Deserialize)]
+enum SerializedSource {
+    OnDisk(PathBuf),
+    /// A descriptor doesn't outlive the process that holds it, so it
+    /// is recorded for diagnostic purposes but can't be deserialized
+    FileDescriptor {
+        name: String,
+    },
+    BuiltIn {
+        name: String,
+    },
+    /// `digest` is the `content_digest` of the data, in hex
+    Memory {
+        name: String,
+        digest: String,
+    },
+}
+
+lazy_static::lazy_static! {
+    /// The data of the Memory sources that have been serialized, keyed
+    /// by their content digest, so that they can be deserialized again
+    /// for as long as the data remains alive
+    static ref SERIALIZED_MEMORY: Mutex<HashMap<u64, Weak<Box<[u8]>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// A 64 bit FNV-1a hash of `data`, which, unlike the std hashers, is
+/// the same in every build
+fn content_digest(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl Serialize for FontDataSource {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let source = match self {
+            Self::OnDisk(path) => SerializedSource::OnDisk(path.clone()),
+            #[cfg(unix)]
+            Self::FileDescriptor { name, .. } => {
+                SerializedSource::FileDescriptor { name: name.clone() }
+            }
+            Self::BuiltIn { name, .. } => SerializedSource::BuiltIn {
+                name: name.to_string(),
+            },
+            Self::Memory { name, data } => {
+                let digest = content_digest(data);
+                let mut memory = SERIALIZED_MEMORY.lock().unwrap();
+                memory.retain(|_, data| data.strong_count() > 0);
+                memory.insert(digest, Arc::downgrade(data));
+                SerializedSource::Memory {
+                    name: name.clone(),
+                    digest: format!("{:016x}", digest),
+                }
+            }
+        };
+        source.serialize(serializer)
+    }
+}
+
+/// Memory sources can only be deserialized in the process that
+/// serialized them, and only while their data is still in use; this
+/// fails for those whose data is gone, for file descriptors, and for
+/// built-in fonts that this build doesn't have.
+impl<'de> Deserialize<'de> for FontDataSource {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        match SerializedSource::deserialize(deserializer)? {
+            SerializedSource::OnDisk(path) => Ok(Self::OnDisk(path)),
+            SerializedSource::FileDescriptor { name } => Err(D::Error::custom(format!(
+                "{} was opened from a file descriptor, which can't be restored",
+                name
+            ))),
+            SerializedSource::BuiltIn { name } => match crate::parser::built_in_font(&name) {
+                Some((name, data)) => Ok(Self::BuiltIn { name, data }),
+                None => Err(D::Error::custom(format!(
+                    "{} is not one of the built-in fonts",
+                    name
+                ))),
+            },
+            SerializedSource::Memory { name, digest } => {
+                let data = u64::from_str_radix(&digest, 16).ok().and_then(|digest| {
+                    SERIALIZED_MEMORY
+                        .lock()
+                        .unwrap()
+                        .get(&digest)
+                        .and_then(Weak::upgrade)
+                });
+                match data {
+                    Some(data) => Ok(Self::Memory { name, data }),
+                    None => Err(D::Error::custom(format!(
+                        "the in-memory data of {} is no longer available",
+                        name
+                    ))),
+                }
             }
-            Self::BuiltIn { data, .. } => Ok(Cow::Borrowed(data)),
-            Self::Memory { data, .. } => Ok(Cow::Borrowed(&*data)),
         }
     }
 }
 
+/// Returns the device and inode of the file open as `fd`
+#[cfg(unix)]
+fn fd_identity(fd: &OwnedFd) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    // Borrow the descriptor as a File without taking ownership of it
+    let file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd.as_raw_fd()) });
+    file.metadata().ok().map(|meta| (meta.dev(), meta.ino()))
+}
+
 impl Eq for FontDataSource {}
 
 impl PartialEq for FontDataSource {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Self::OnDisk(path_a), Self::OnDisk(path_b)) => path_a == path_b,
+            #[cfg(unix)]
+            (Self::FileDescriptor { fd: fd_a, .. }, Self::FileDescriptor { fd: fd_b, .. }) => {
+                Arc::ptr_eq(fd_a, fd_b)
+                    || match (fd_identity(fd_a), fd_identity(fd_b)) {
+                        (Some(a), Some(b)) => a == b,
+                        _ => false,
+                    }
+            }
             (Self::Memory { name: name_a, .. }, Self::Memory { name: name_b, .. }) => {
                 name_a == name_b
             }
@@ -92,6 +318,12 @@ impl std::fmt::Debug for FontDataSource {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         match self {
             Self::OnDisk(path) => fmt.debug_struct("OnDisk").field("path", &path).finish(),
+            #[cfg(unix)]
+            Self::FileDescriptor { fd, name } => fmt
+                .debug_struct("FileDescriptor")
+                .field("name", &name)
+                .field("fd", &fd)
+                .finish(),
             Self::BuiltIn { name, .. } => fmt.debug_struct("BuiltIn").field("name", &name).finish(),
             Self::Memory { data, name } => fmt
                 .debug_struct("Memory")
@@ -108,7 +340,7 @@ impl std::fmt::Debug for FontDataSource {
 /// The `index` parameter is the index into a font
 /// collection if the data represents a collection of
 /// fonts.
-#[derive(Clone, Debug, PartialEq, Eq, Ord, PartialOrd)]
+#[derive(Clone, Debug, PartialEq, Eq, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct FontDataHandle {
     pub source: FontDataSource,
     pub index: u32,
@@ -132,6 +364,8 @@ impl FontDataHandle {
     pub fn diagnostic_string(&self) -> String {
         let source = match &self.source {
             FontDataSource::OnDisk(path) => format!("{}", path.display()),
+            #[cfg(unix)]
+            FontDataSource::FileDescriptor { name, .. } => format!("{} (fd)", name),
             FontDataSource::BuiltIn { .. } => "<built-in>".to_string(),
             FontDataSource::Memory { .. } => "<imported to RAM>".to_string(),
         };
@@ -160,6 +394,155 @@ pub trait FontLocator {
         &self,
         codepoints: &[char],
     ) -> anyhow::Result<Vec<ParsedFont>>;
+
+    /// Ask the system for the single best fallback font for `c`,
+    /// taking into account the weight, stretch and style from
+    /// `attributes`.  Monospaced candidates are preferred.
+    /// The default implementation doesn't know of any fonts.
+    /// Callers should generally use the caching `fallback_for_codepoint`
+    /// function rather than calling this directly.
+    fn fallback_for_codepoint(
+        &self,
+        _c: char,
+        _attributes: &FontAttributes,
+    ) -> anyhow::Result<Option<FontDataHandle>> {
+        Ok(None)
+    }
+}
+
+/// The number of codepoints that share a fallback cache entry
+const FALLBACK_BLOCK_SIZE: u32 = 128;
+
+lazy_static::lazy_static! {
+    static ref FALLBACK_CACHE: Mutex<HashMap<(u32, FontAttributes), Option<FontDataHandle>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Returns the best single fallback font for `c` from `locator`.
+/// Results are cached per block of codepoints and attributes, on the
+/// basis that a font that covers one codepoint from a block is very
+/// likely to cover its neighbours too, so repeated rare characters
+/// don't repeatedly query the system.  Callers must still verify the
+/// coverage of the returned font.
+pub fn fallback_for_codepoint(
+    locator: &dyn FontLocator,
+    c: char,
+    attributes: &FontAttributes,
+) -> Option<FontDataHandle> {
+    let key = (c as u32 / FALLBACK_BLOCK_SIZE, attributes.clone());
+    if let Some(handle) = FALLBACK_CACHE.lock().unwrap().get(&key) {
+        return handle.clone();
+    }
+
+    let handle = match locator.fallback_for_codepoint(c, attributes) {
+        Ok(handle) => handle,
+        Err(err) => {
+            log::error!(
+                "Error: {:#} while resolving fallback for {}",
+                err,
+                c.escape_unicode()
+            );
+            None
+        }
+    };
+    FALLBACK_CACHE.lock().unwrap().insert(key, handle.clone());
+    handle
+}
+
+/// The generic family names that are understood as aliases for
+/// the system's preferred font of that kind, rather than as the
+/// name of a specific font family
+const GENERIC_FAMILIES: &[&str] = &["monospace", "sans-serif", "serif", "emoji"];
+
+/// Returns true if `family` is one of the generic family aliases
+pub fn is_generic_family(family: &str) -> bool {
+    GENERIC_FAMILIES
+        .iter()
+        .any(|generic| generic.eq_ignore_ascii_case(family))
+}
+
+#[cfg(target_os = "macos")]
+const PLATFORM_GENERIC_DEFAULTS: &[(&str, &[&str])] = &[
+    ("monospace", &["Menlo", "Monaco"]),
+    ("sans-serif", &["Helvetica Neue", "Helvetica"]),
+    ("serif", &["Times New Roman", "Times"]),
+    ("emoji", &["Apple Color Emoji"]),
+];
+
+#[cfg(windows)]
+const PLATFORM_GENERIC_DEFAULTS: &[(&str, &[&str])] = &[
+    ("monospace", &["Consolas", "Courier New"]),
+    ("sans-serif", &["Segoe UI", "Arial"]),
+    ("serif", &["Times New Roman"]),
+    ("emoji", &["Segoe UI Emoji"]),
+];
+
+/// fontconfig resolves the aliases itself, so there is no table here
+#[cfg(not(any(target_os = "macos", windows)))]
+const PLATFORM_GENERIC_DEFAULTS: &[(&str, &[&str])] = &[];
+
+/// The built-in fonts that stand in for the generic families when
+/// the system doesn't have anything better to offer
+const BUILT_IN_GENERIC_DEFAULTS: &[(&str, &[&str])] = &[
+    ("monospace", &["JetBrains Mono"]),
+    ("emoji", &["Noto Color Emoji"]),
+];
+
+fn lookup_generic_defaults(
+    table: &'static [(&'static str, &'static [&'static str])],
+    family: &str,
+) -> &'static [&'static str] {
+    table
+        .iter()
+        .find(|(generic, _)| generic.eq_ignore_ascii_case(family))
+        .map(|(_, families)| *families)
+        .unwrap_or(&[])
+}
+
+/// Returns the families that the system conventionally uses for the
+/// generic `family`, for use by locators that don't have their own
+/// notion of aliases
+pub fn platform_generic_defaults(family: &str) -> &'static [&'static str] {
+    lookup_generic_defaults(PLATFORM_GENERIC_DEFAULTS, family)
+}
+
+/// Returns the built-in fonts that can be used for the generic `family`
+pub fn built_in_generic_defaults(family: &str) -> &'static [&'static str] {
+    lookup_generic_defaults(BUILT_IN_GENERIC_DEFAULTS, family)
+}
+
+/// Resolves the generic families in `fonts_selection` that have not
+/// yet been loaded by trying each of the concrete families returned
+/// by `defaults` in turn, using `load` to load them.
+/// The first font that loads is recorded as the resolution of the alias.
+pub fn load_generic_families<F>(
+    fonts_selection: &[FontAttributes],
+    loaded: &mut HashSet<FontAttributes>,
+    defaults: fn(&str) -> &'static [&'static str],
+    mut load: F,
+) -> anyhow::Result<Vec<ParsedFont>>
+where
+    F: FnMut(&[FontAttributes], &mut HashSet<FontAttributes>) -> anyhow::Result<Vec<ParsedFont>>,
+{
+    let mut fonts = vec![];
+    for attr in fonts_selection {
+        if loaded.contains(attr) || !is_generic_family(&attr.family) {
+            continue;
+        }
+        for family in defaults(&attr.family) {
+            let mut concrete = attr.clone();
+            concrete.family = family.to_string();
+            let mut concrete_loaded = HashSet::new();
+            if let Some(mut parsed) = load(&[concrete], &mut concrete_loaded)?.into_iter().next() {
+                log::trace!("resolved generic family {} to {}", attr.family, family);
+                parsed.set_alias(&attr.family);
+                fonts.push(parsed);
+                loaded.insert(attr.clone());
+                break;
+            }
+        }
+    }
+    Ok(fonts)
 }
 
 pub fn new_locator(locator: FontLocatorSelection) -> Arc<dyn FontLocator + Send + Sync> {
@@ -223,4 +606,125 @@ mod test {
         };
         eprintln!("{:?}", source);
     }
+
+    #[test]
+    fn serialize_sources() {
+        let round_trip = |source: &FontDataSource| -> Result<FontDataSource, String> {
+            let json = serde_json::to_string(source).unwrap();
+            serde_json::from_str(&json).map_err(|err| err.to_string())
+        };
+
+        let on_disk = FontDataSource::OnDisk("/fonts/test.ttf".into());
+        assert_eq!(round_trip(&on_disk).unwrap(), on_disk);
+
+        let (name, data) = crate::parser::LAST_RESORT_FONT;
+        let built_in = FontDataSource::BuiltIn { name, data };
+        match round_trip(&built_in).unwrap() {
+            FontDataSource::BuiltIn { name: n, data: d } => {
+                assert_eq!(n, name);
+                assert_eq!(d.len(), data.len());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let unknown = FontDataSource::BuiltIn {
+            name: "not-built-in.ttf",
+            data: b"",
+        };
+        assert!(round_trip(&unknown)
+            .unwrap_err()
+            .contains("not one of the built-in"));
+
+        // The data isn't serialized, only its digest
+        let data = Arc::new(b"hello".to_vec().into_boxed_slice());
+        let memory = FontDataSource::Memory {
+            name: "hello!".to_string(),
+            data: Arc::clone(&data),
+        };
+        let json = serde_json::to_string(&memory).unwrap();
+        assert!(json.contains(&format!("{:016x}", content_digest(b"hello"))));
+        match serde_json::from_str::<FontDataSource>(&json).unwrap() {
+            FontDataSource::Memory { name, data: d } => {
+                assert_eq!(name, "hello!");
+                assert!(Arc::ptr_eq(&d, &data));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        // Once the data is gone, it can't be restored
+        drop(memory);
+        drop(data);
+        let err = serde_json::from_str::<FontDataSource>(&json).unwrap_err();
+        assert!(err.to_string().contains("no longer available"));
+
+        #[cfg(unix)]
+        {
+            let fd = FontDataSource::FileDescriptor {
+                fd: Arc::new(std::fs::File::open("/dev/null").unwrap().into()),
+                name: "portal font".to_string(),
+            };
+            assert!(round_trip(&fd).unwrap_err().contains("file descriptor"));
+        }
+
+        let handle = FontDataHandle {
+            source: on_disk,
+            index: 1,
+            variation: 2,
+            origin: FontOrigin::FontDirs,
+        };
+        let json = serde_json::to_string(&handle).unwrap();
+        assert_eq!(
+            serde_json::from_str::<FontDataHandle>(&json).unwrap(),
+            handle
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fd_sources_compare_by_file() {
+        let fd = |path: &str| FontDataSource::FileDescriptor {
+            fd: Arc::new(std::fs::File::open(path).unwrap().into()),
+            name: "portal font".to_string(),
+        };
+        let null = fd("/dev/null");
+        assert_eq!(null, null.clone());
+        // A separate descriptor for the same file is equal
+        assert_eq!(null, fd("/dev/null"));
+        // but the name alone doesn't make them equal
+        assert_ne!(null, fd(env!("CARGO_MANIFEST_DIR")));
+    }
+
+    #[test]
+    fn test_generic_families() {
+        assert!(is_generic_family("monospace"));
+        assert!(is_generic_family("Sans-Serif"));
+        assert!(!is_generic_family("JetBrains Mono"));
+
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let selection = vec![
+            FontAttributes::new("monospace"),
+            FontAttributes::new("serif"),
+            FontAttributes::new("JetBrains Mono"),
+        ];
+        let mut loaded = HashSet::new();
+        let fonts = load_generic_families(
+            &selection,
+            &mut loaded,
+            built_in_generic_defaults,
+            |attrs, loaded| {
+                let mut handles = vec![];
+                db.resolve_multiple(attrs, &mut handles, loaded);
+                Ok(handles)
+            },
+        )
+        .unwrap();
+
+        assert_eq!(fonts.len(), 1);
+        assert_eq!(fonts[0].names().family, "JetBrains Mono");
+        assert_eq!(fonts[0].alias(), Some("monospace"));
+        assert!(fonts[0].matches_name(&selection[0]));
+        assert!(loaded.contains(&selection[0]));
+        // There is no built-in serif font, and concrete families
+        // are left for the regular resolution to handle
+        assert!(!loaded.contains(&selection[1]));
+        assert!(!loaded.contains(&selection[2]));
+    }
 }
@@ -1,10 +1,21 @@
+use crate::ftwrap::{ColorFormats, FaceFlags, FaceTransform, StrikeAdvice};
 use crate::locator::{FontDataHandle, FontDataSource, FontOrigin};
+use crate::rasterizer::FontSynthesis;
 use crate::shaper::GlyphInfo;
-use config::FontAttributes;
+use anyhow::Context;
+use config::{
+    BaselineOffset, FallbackSource, FontAttributes, FontFormat, HorizontalScale, SlantDegrees,
+    SyntheticBoldMode, LAST_RESORT_FAMILY,
+};
 pub use config::{FontStretch, FontWeight};
 use rangeset::RangeSet;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use thiserror::Error;
 
 #[derive(Debug)]
 pub enum MaybeShaped {
@@ -12,7 +23,25 @@ pub enum MaybeShaped {
     Unresolved { raw: String, slice_start: usize },
 }
 
+/// A style that can be synthesized for a font that lacks it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntheticStyle {
+    Bold,
+    Italic,
+}
+
+impl std::fmt::Display for SyntheticStyle {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Bold => write!(fmt, "bold"),
+            Self::Italic => write!(fmt, "italic"),
+        }
+    }
+}
+
 /// Represents a parsed font
+#[derive(Serialize, Deserialize)]
+#[serde(into = "SerializedFont", try_from = "SerializedFont")]
 pub struct ParsedFont {
     names: Names,
     weight: FontWeight,
@@ -21,6 +50,142 @@ pub struct ParsedFont {
     cap_height: Option<f64>,
     pub handle: FontDataHandle,
     coverage: Mutex<RangeSet<u32>>,
+    /// The GSUB/GPOS feature tags; computed on first use.
+    /// The inner value is None if they couldn't be determined.
+    features: Mutex<Option<Option<HashSet<u32>>>>,
+    /// Whether the GPOS table defines the kern feature; computed on
+    /// first use.
+    gpos_kern: Mutex<Option<bool>>,
+    synthesis: FontSynthesis,
+    emoji_heuristic: bool,
+    emoji_override: Option<bool>,
+    baseline_offset: Option<BaselineOffset>,
+    transform: FaceTransform,
+    unicode_ranges: Option<RangeSet<u32>>,
+    fallback_source: Option<FallbackSource>,
+    flags: FaceFlags,
+    color_formats: ColorFormats,
+    format: Option<FontFormat>,
+    alias: Option<String>,
+    /// The fontRevision from the head table, in 16.16 fixed point
+    revision: Option<i64>,
+    /// The version string from the name table
+    version: Option<String>,
+    /// The ppem of each bitmap strike, if the font has no outlines
+    strike_ppems: Vec<f64>,
+    rejected_candidates: Vec<RejectedCandidate>,
+}
+
+/// The stage of font matching at which a candidate was eliminated;
+/// later stages are listed first, as those candidates were closer
+/// to being selected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Elimination {
+    /// The candidate matched as well as the selected font, but lost
+    /// the tie-break
+    TieBreak,
+    Weight,
+    Style,
+    Stretch,
+}
+
+impl std::fmt::Display for Elimination {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let label = match self {
+            Self::TieBreak => "tie-break",
+            Self::Weight => "weight",
+            Self::Style => "style",
+            Self::Stretch => "stretch",
+        };
+        write!(fmt, "{}", label)
+    }
+}
+
+/// How far a candidate is from the requested attributes.
+/// An exact match is zero in each field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MatchScore {
+    pub stretch_delta: u16,
+    pub style_mismatch: bool,
+    pub weight_delta: u16,
+}
+
+impl MatchScore {
+    pub fn compute(attr: &FontAttributes, font: &ParsedFont) -> Self {
+        let delta = |a: u16, b: u16| if a > b { a - b } else { b - a };
+        Self {
+            stretch_delta: delta(
+                attr.stretch.to_opentype_stretch(),
+                font.stretch.to_opentype_stretch(),
+            ),
+            style_mismatch: attr.italic != font.italic,
+            weight_delta: delta(
+                attr.weight.to_opentype_weight(),
+                font.weight.to_opentype_weight(),
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for MatchScore {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            fmt,
+            "stretch_delta={} style_mismatch={} weight_delta={}",
+            self.stretch_delta, self.style_mismatch, self.weight_delta
+        )
+    }
+}
+
+/// Where, if anywhere, a font defines its kerning
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KerningSource {
+    None,
+    /// A legacy kern table, which can be read using FT_Get_Kerning
+    Kern,
+    /// The kern feature of the GPOS table
+    Gpos,
+    Both,
+}
+
+impl KerningSource {
+    pub fn new(legacy_kern: bool, gpos_kern: bool) -> Self {
+        match (legacy_kern, gpos_kern) {
+            (false, false) => Self::None,
+            (true, false) => Self::Kern,
+            (false, true) => Self::Gpos,
+            (true, true) => Self::Both,
+        }
+    }
+
+    /// Returns true if FT_Get_Kerning can return anything for this font;
+    /// if not, there is no point asking it about each pair of glyphs
+    pub fn has_legacy_kern(self) -> bool {
+        matches!(self, Self::Kern | Self::Both)
+    }
+}
+
+impl std::fmt::Display for KerningSource {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let label = match self {
+            Self::None => "none",
+            Self::Kern => "kern table",
+            Self::Gpos => "GPOS",
+            Self::Both => "kern table and GPOS",
+        };
+        write!(fmt, "{}", label)
+    }
+}
+
+/// A font that was considered but not selected by `ParsedFont::best_match`
+#[derive(Debug, Clone)]
+pub struct RejectedCandidate {
+    pub lua_name: String,
+    pub diagnostic: String,
+    pub revision: Option<f64>,
+    pub version: Option<String>,
+    pub score: MatchScore,
+    pub eliminated_by: Elimination,
 }
 
 impl std::fmt::Debug for ParsedFont {
@@ -32,6 +197,20 @@ impl std::fmt::Debug for ParsedFont {
             .field("italic", &self.italic)
             .field("handle", &self.handle)
             .field("cap_height", &self.cap_height)
+            .field("synthesis", &self.synthesis)
+            .field(
+                "assume_emoji_presentation",
+                &self.assume_emoji_presentation(),
+            )
+            .field("baseline_offset", &self.baseline_offset)
+            .field("transform", &self.transform)
+            .field("unicode_ranges", &self.unicode_ranges)
+            .field("fallback_source", &self.fallback_source)
+            .field("flags", &self.flags)
+            .field("format", &self.format)
+            .field("alias", &self.alias)
+            .field("revision", &self.font_revision())
+            .field("version", &self.version)
             .finish()
     }
 }
@@ -46,10 +225,119 @@ impl Clone for ParsedFont {
             handle: self.handle.clone(),
             cap_height: self.cap_height.clone(),
             coverage: Mutex::new(self.coverage.lock().unwrap().clone()),
+            features: Mutex::new(self.features.lock().unwrap().clone()),
+            gpos_kern: Mutex::new(*self.gpos_kern.lock().unwrap()),
+            synthesis: self.synthesis,
+            emoji_heuristic: self.emoji_heuristic,
+            emoji_override: self.emoji_override,
+            baseline_offset: self.baseline_offset,
+            transform: self.transform,
+            unicode_ranges: self.unicode_ranges.clone(),
+            fallback_source: self.fallback_source,
+            flags: self.flags,
+            color_formats: self.color_formats,
+            format: self.format,
+            alias: self.alias.clone(),
+            revision: self.revision,
+            version: self.version.clone(),
+            strike_ppems: self.strike_ppems.clone(),
+            rejected_candidates: self.rejected_candidates.clone(),
+        }
+    }
+}
+
+/// The version of the serialized form of ParsedFont.  This must be
+/// bumped when that form changes, so that data that was serialized
+/// by an older build, such as a cached resolution, is rejected rather
+/// than misinterpreted.
+pub const SERIALIZED_FONT_VERSION: u32 = 1;
+
+/// The serialized form of a ParsedFont, which holds what was parsed
+/// from the font.  The settings that the configuration applies to a
+/// font, such as its synthesis and unicode ranges, aren't included, as
+/// they are applied again when the font is resolved, and the coverage
+/// is computed again on demand.
+#[derive(Serialize, Deserialize)]
+struct SerializedFont {
+    version: u32,
+    names: Names,
+    weight: FontWeight,
+    stretch: FontStretch,
+    italic: bool,
+    cap_height: Option<f64>,
+    handle: FontDataHandle,
+    emoji_heuristic: bool,
+    flags: u32,
+    color_formats: u8,
+    format: Option<FontFormat>,
+    revision: Option<i64>,
+    font_version: Option<String>,
+    strike_ppems: Vec<f64>,
+}
+
+impl From<ParsedFont> for SerializedFont {
+    fn from(font: ParsedFont) -> Self {
+        Self {
+            version: SERIALIZED_FONT_VERSION,
+            names: font.names,
+            weight: font.weight,
+            stretch: font.stretch,
+            italic: font.italic,
+            cap_height: font.cap_height,
+            handle: font.handle,
+            emoji_heuristic: font.emoji_heuristic,
+            flags: font.flags.bits(),
+            color_formats: font.color_formats.bits(),
+            format: font.format,
+            revision: font.revision,
+            font_version: font.version,
+            strike_ppems: font.strike_ppems,
         }
     }
 }
 
+impl TryFrom<SerializedFont> for ParsedFont {
+    type Error = String;
+
+    fn try_from(font: SerializedFont) -> Result<Self, String> {
+        if font.version != SERIALIZED_FONT_VERSION {
+            return Err(format!(
+                "{} was serialized by an incompatible version of wezterm \
+                 (format version {}, expected {})",
+                font.handle.diagnostic_string(),
+                font.version,
+                SERIALIZED_FONT_VERSION
+            ));
+        }
+        Ok(Self {
+            names: font.names,
+            weight: font.weight,
+            stretch: font.stretch,
+            italic: font.italic,
+            handle: font.handle,
+            coverage: Mutex::new(RangeSet::new()),
+            features: Mutex::new(None),
+            gpos_kern: Mutex::new(None),
+            cap_height: font.cap_height,
+            synthesis: FontSynthesis::default(),
+            emoji_heuristic: font.emoji_heuristic,
+            emoji_override: None,
+            baseline_offset: None,
+            transform: FaceTransform::IDENTITY,
+            unicode_ranges: None,
+            fallback_source: None,
+            flags: FaceFlags::from_bits_truncate(font.flags),
+            color_formats: ColorFormats::from_bits_truncate(font.color_formats),
+            format: font.format,
+            alias: None,
+            revision: font.revision,
+            version: font.font_version,
+            strike_ppems: font.strike_ppems,
+            rejected_candidates: vec![],
+        })
+    }
+}
+
 impl Eq for ParsedFont {}
 
 impl PartialEq for ParsedFont {
@@ -82,7 +370,7 @@ impl PartialOrd for ParsedFont {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, PartialOrd, Ord)]
+#[derive(Debug, PartialEq, Eq, Clone, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Names {
     pub full_name: String,
     pub family: String,
@@ -130,6 +418,39 @@ impl ParsedFont {
 
         for p in handles {
             code.push_str(&format!("  -- {}\n", p.handle.diagnostic_string()));
+            if let Some(version) = &p.version {
+                code.push_str(&format!("  -- {}\n", version));
+            }
+            if let Some(source) = p.fallback_source {
+                code.push_str(&format!("  -- fallback from {}\n", source));
+            }
+            if p.is_last_resort() {
+                code.push_str(
+                    "  -- last resort: a placeholder for codepoints that no other font has\n",
+                );
+            }
+            if let Some(alias) = &p.alias {
+                code.push_str(&format!("  -- resolved from alias \"{}\"\n", alias));
+            }
+            match p.emoji_override {
+                Some(assume) => code.push_str(&format!(
+                    "  -- assume_emoji_presentation={} (user override)\n",
+                    assume
+                )),
+                None if p.emoji_heuristic => {
+                    code.push_str("  -- assume_emoji_presentation=true (heuristic)\n")
+                }
+                None => {}
+            }
+            if let Some(offset) = &p.baseline_offset {
+                code.push_str(&format!("  -- baseline_offset={}\n", offset));
+            }
+            if !p.transform.is_identity() {
+                code.push_str(&format!(
+                    "  -- horizontal_scale={} slant_degrees={}\n",
+                    p.transform.x_scale, p.transform.slant_degrees
+                ));
+            }
 
             if p.weight == FontWeight::Regular && p.stretch == FontStretch::Normal && !p.italic {
                 code.push_str(&format!("  \"{}\",\n", p.names.family));
@@ -159,22 +480,174 @@ impl ParsedFont {
         let stretch = FontStretch::from_opentype_stretch(width);
         let cap_height = face.cap_height();
 
+        // Only color fonts are considered to be emoji fonts, so we
+        // can avoid computing the coverage for the majority of fonts
+        let mut coverage = RangeSet::new();
+        let mut emoji_heuristic = false;
+        let mut color_formats = ColorFormats::empty();
+        let flags = face.flags();
+        if flags.has_color() {
+            coverage = face.compute_coverage();
+            emoji_heuristic = is_emoji_coverage(&coverage);
+            color_formats = face.color_formats();
+        }
+
         Ok(Self {
             names: Names::from_ft_face(&face),
             weight,
             stretch,
             italic,
             handle,
-            coverage: Mutex::new(RangeSet::new()),
+            coverage: Mutex::new(coverage),
+            features: Mutex::new(None),
+            gpos_kern: Mutex::new(None),
             cap_height,
+            synthesis: FontSynthesis::default(),
+            emoji_heuristic,
+            emoji_override: None,
+            baseline_offset: None,
+            transform: FaceTransform::IDENTITY,
+            unicode_ranges: None,
+            fallback_source: None,
+            flags,
+            color_formats,
+            format: face.font_format(),
+            alias: None,
+            revision: face.font_revision(),
+            version: face.version_string(),
+            strike_ppems: face.strike_ppems(),
+            rejected_candidates: vec![],
         })
     }
 
+    /// Returns true if this font should be treated as a dedicated emoji
+    /// font; it will be preferred when falling back for codepoints that
+    /// default to emoji presentation, and avoided for those that default
+    /// to text presentation.
+    /// This is a heuristic unless overridden by the configuration.
+    pub fn assume_emoji_presentation(&self) -> bool {
+        self.emoji_override.unwrap_or(self.emoji_heuristic)
+    }
+
+    /// Returns the value of assume_emoji_presentation from the
+    /// configuration, if it was set
+    pub fn assume_emoji_presentation_override(&self) -> Option<bool> {
+        self.emoji_override
+    }
+
+    pub fn set_assume_emoji_presentation(&mut self, assume: Option<bool>) {
+        self.emoji_override = assume;
+    }
+
+    /// Returns the baseline_offset from the configuration, if it was set
+    pub fn baseline_offset(&self) -> Option<BaselineOffset> {
+        self.baseline_offset
+    }
+
+    pub fn set_baseline_offset(&mut self, offset: Option<BaselineOffset>) {
+        self.baseline_offset = offset;
+    }
+
+    /// Returns the transform that the horizontal_scale and slant_degrees
+    /// from the configuration apply to the glyphs of this font
+    pub fn transform(&self) -> FaceTransform {
+        self.transform
+    }
+
+    pub fn set_transform(
+        &mut self,
+        horizontal_scale: Option<HorizontalScale>,
+        slant_degrees: Option<SlantDegrees>,
+    ) {
+        self.transform = FaceTransform {
+            x_scale: horizontal_scale.map_or(1.0, HorizontalScale::get),
+            y_scale: 1.0,
+            slant_degrees: slant_degrees.map_or(0.0, SlantDegrees::get),
+        };
+    }
+
+    /// Returns the codepoints that have been assigned to this font
+    /// by the unicode_range_rules configuration, if any
+    pub fn unicode_ranges(&self) -> Option<&RangeSet<u32>> {
+        self.unicode_ranges.as_ref()
+    }
+
+    pub fn set_unicode_ranges(&mut self, ranges: RangeSet<u32>) {
+        self.unicode_ranges.replace(ranges);
+    }
+
+    /// Returns the flags of the face that this font was parsed from
+    pub fn flags(&self) -> FaceFlags {
+        self.flags
+    }
+
+    /// Returns the tables that this font uses to provide color glyphs.
+    /// This is only determined for fonts that freetype considers to
+    /// have color, and is empty for all others.
+    pub fn color_formats(&self) -> ColorFormats {
+        self.color_formats
+    }
+
+    /// Returns the format of the file that this font was parsed from
+    pub fn format(&self) -> Option<FontFormat> {
+        self.format
+    }
+
+    /// Returns where this font came from, if it is one of the
+    /// fallback fonts rather than one of the fonts of the style
+    pub fn fallback_source(&self) -> Option<FallbackSource> {
+        self.fallback_source
+    }
+
+    pub fn set_fallback_source(&mut self, source: FallbackSource) {
+        self.fallback_source.replace(source);
+    }
+
+    /// Returns the generic family name, such as "monospace", that
+    /// was resolved to this font, if any
+    pub fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    pub fn set_alias(&mut self, alias: &str) {
+        self.alias.replace(alias.to_string());
+    }
+
+    /// Returns the revision of the font from its head table
+    pub fn font_revision(&self) -> Option<f64> {
+        self.revision.map(|rev| rev as f64 / 65536.0)
+    }
+
+    /// Returns the version string from the name table, which is more
+    /// useful than the revision for telling builds of a font apart
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Returns the ppem of each of the bitmap strikes of this font
+    /// if it has no outlines, such as Noto Color Emoji.
+    /// The list is empty for fonts that can be scaled to any size.
+    pub fn strike_ppems(&self) -> &[f64] {
+        &self.strike_ppems
+    }
+
+    /// Returns advice about the strikes that are closest to the
+    /// specified point size, if this font only has bitmap strikes and
+    /// none of them exactly match the size
+    pub fn strike_advice(&self, point_size: f64, dpi: u32) -> Option<StrikeAdvice> {
+        crate::ftwrap::strike_advice(&self.strike_ppems, point_size, dpi)
+    }
+
     /// Computes the intersection of the wanted set of codepoints with
     /// the set of codepoints covered by this font entry.
     /// Computes the codepoint coverage for this font entry if we haven't
     /// already done so.
+    /// The Last Resort font has a glyph for every codepoint, so its
+    /// enormous cmap isn't scanned and all of `wanted` is returned.
     pub fn coverage_intersection(&self, wanted: &RangeSet<u32>) -> anyhow::Result<RangeSet<u32>> {
+        if self.is_last_resort() {
+            return Ok(wanted.clone());
+        }
         let mut cov = self.coverage.lock().unwrap();
         if cov.is_empty() {
             let t = std::time::Instant::now();
@@ -192,6 +665,52 @@ impl ParsedFont {
         Ok(wanted.intersection(&cov))
     }
 
+    /// Returns true if the GSUB or GPOS tables of this font define the
+    /// feature `tag`, such as `"liga"`.
+    /// If the feature tables are malformed, or the font cannot be loaded,
+    /// then support is unknown and this returns true so that callers don't
+    /// warn about features that may actually be present.
+    /// The feature set is cached after it is first computed.
+    pub fn supports_feature(&self, tag: &str) -> bool {
+        let tag = match crate::sfnt::tag_from_str(tag) {
+            Some(tag) => tag,
+            None => return false,
+        };
+        let mut features = self.features.lock().unwrap();
+        let features = features.get_or_insert_with(|| {
+            let lib = crate::ftwrap::Library::new().ok()?;
+            let face = lib.face_from_locator(&self.handle).ok()?;
+            face.feature_tags()
+        });
+        match features {
+            Some(features) => features.contains(&tag),
+            None => true,
+        }
+    }
+
+    /// Returns where this font defines its kerning.
+    /// If the font cannot be loaded, only the legacy kern flag that
+    /// was captured when the font was parsed is considered.
+    /// The GPOS kern feature is cached after it is first checked.
+    pub fn kerning_source(&self) -> KerningSource {
+        let legacy = self.flags.has_kerning();
+        let gpos = *self.gpos_kern.lock().unwrap().get_or_insert_with(|| {
+            crate::ftwrap::Library::new()
+                .and_then(|lib| {
+                    let face = lib.face_from_locator(&self.handle)?;
+                    Ok(face.has_gpos_kern())
+                })
+                .unwrap_or(false)
+        });
+        KerningSource::new(legacy, gpos)
+    }
+
+    /// Returns true if this is the Last Resort font, either the one
+    /// built in to wezterm or one provided by the system
+    pub fn is_last_resort(&self) -> bool {
+        self.handle.origin == FontOrigin::LastResort || self.names.family == LAST_RESORT_FAMILY
+    }
+
     pub fn names(&self) -> &Names {
         &self.names
     }
@@ -208,10 +727,77 @@ impl ParsedFont {
         self.italic
     }
 
+    pub fn synthesis(&self) -> FontSynthesis {
+        self.synthesis
+    }
+
+    /// Compute the synthetic styling required to make this font
+    /// resemble the style requested by `attr`.
+    /// Bold is synthesized when a DemiBold or heavier weight was
+    /// requested but this font is lighter than DemiBold, and italic
+    /// is synthesized as an oblique shear when italics were requested
+    /// but this font is upright.
+    /// `bold_mode` controls whether synthesized bold glyphs retain
+    /// the advance of the regular glyph.
+    pub fn synthesize(&mut self, attr: &FontAttributes, bold_mode: SyntheticBoldMode) {
+        let bold = self.lacks_style(attr, SyntheticStyle::Bold);
+        self.synthesis = FontSynthesis {
+            bold,
+            oblique_slant: if self.lacks_style(attr, SyntheticStyle::Italic) {
+                Some(FontSynthesis::DEFAULT_OBLIQUE_SLANT)
+            } else {
+                None
+            },
+            embolden_strength: if bold {
+                FontSynthesis::DEFAULT_EMBOLDEN_STRENGTH
+            } else {
+                0
+            },
+            preserve_advance: bold && bold_mode == SyntheticBoldMode::PreserveAdvance,
+        };
+    }
+
+    /// Returns true if `attr` requests `style` but this font doesn't
+    /// provide it, so that it would have to be synthesized
+    fn lacks_style(&self, attr: &FontAttributes, style: SyntheticStyle) -> bool {
+        match style {
+            SyntheticStyle::Bold => {
+                attr.weight >= FontWeight::DemiBold && !self.provides_style(style)
+            }
+            SyntheticStyle::Italic => attr.italic && !self.provides_style(style),
+        }
+    }
+
+    /// Returns true if this font natively provides `style`
+    pub fn provides_style(&self, style: SyntheticStyle) -> bool {
+        match style {
+            SyntheticStyle::Bold => self.weight >= FontWeight::DemiBold,
+            SyntheticStyle::Italic => self.italic,
+        }
+    }
+
+    /// Returns the style that would have to be synthesized to make
+    /// this font resemble `attr`, but which the allow_synthetic_bold
+    /// or allow_synthetic_italic attributes of `attr` forbid, if any
+    pub fn disallowed_synthesis(&self, attr: &FontAttributes) -> Option<SyntheticStyle> {
+        if !attr.allow_synthetic_bold && self.lacks_style(attr, SyntheticStyle::Bold) {
+            Some(SyntheticStyle::Bold)
+        } else if !attr.allow_synthetic_italic && self.lacks_style(attr, SyntheticStyle::Italic) {
+            Some(SyntheticStyle::Italic)
+        } else {
+            None
+        }
+    }
+
     pub fn matches_name(&self, attr: &FontAttributes) -> bool {
         if attr.family == self.names.family {
             return true;
         }
+        if let Some(alias) = &self.alias {
+            if alias.eq_ignore_ascii_case(&attr.family) {
+                return true;
+            }
+        }
         self.matches_full_or_ps_name(attr)
     }
 
@@ -232,15 +818,36 @@ impl ParsedFont {
     /// in the font-kit crate which is
     /// Copyright © 2018 The Pathfinder Project Developers.
     /// https://drafts.csswg.org/css-fonts-3/#font-style-matching says
-    pub fn best_matching_index<P: std::ops::Deref<Target = Self> + std::fmt::Debug>(
+    ///
+    /// Candidates are narrowed down by stretch, then by style and then
+    /// by weight.  Any candidates that remain are equally good matches,
+    /// and are ordered by `tie_break` so that the selection is stable
+    /// regardless of the order in which the fonts were discovered.
+    ///
+    /// Returns the indices of all of the fonts, ranked from best to
+    /// worst, along with the stage at which each was eliminated.
+    /// The first entry is the best match and is never eliminated.
+    pub fn rank_matches<P: std::ops::Deref<Target = Self> + std::fmt::Debug>(
         attr: &FontAttributes,
         fonts: &[P],
-    ) -> Option<usize> {
+    ) -> Option<Vec<(usize, Option<Elimination>)>> {
         if fonts.is_empty() {
             return None;
         }
 
         let mut candidates: Vec<usize> = (0..fonts.len()).collect();
+        let mut eliminated: Vec<(usize, Option<Elimination>)> = vec![];
+        let mut eliminate =
+            |candidates: &mut Vec<usize>, stage: Elimination, keep: &dyn Fn(usize) -> bool| {
+                candidates.retain(|&idx| {
+                    if keep(idx) {
+                        true
+                    } else {
+                        eliminated.push((idx, Some(stage)));
+                        false
+                    }
+                })
+            };
 
         // First, filter by stretch
         let stretch_value = attr.stretch.to_opentype_stretch();
@@ -283,7 +890,9 @@ impl ParsedFont {
         };
 
         // Reduce to matching stretches
-        candidates.retain(|&idx| fonts[idx].stretch == stretch);
+        eliminate(&mut candidates, Elimination::Stretch, &|idx| {
+            fonts[idx].stretch == stretch
+        });
 
         // Now match style: italics
         let styles = [attr.italic, !attr.italic];
@@ -293,7 +902,9 @@ impl ParsedFont {
             .next()?;
 
         // Reduce to matching italics
-        candidates.retain(|&idx| fonts[idx].italic == italic);
+        eliminate(&mut candidates, Elimination::Style, &|idx| {
+            fonts[idx].italic == italic
+        });
 
         // And now match by font weight
         let query_weight = attr.weight.to_opentype_weight();
@@ -353,49 +964,165 @@ impl ParsedFont {
         };
 
         // Reduce to matching weight
-        candidates.retain(|&idx| fonts[idx].weight == weight);
+        eliminate(&mut candidates, Elimination::Weight, &|idx| {
+            fonts[idx].weight == weight
+        });
+
+        // The remaining candidates are equally good matches, so
+        // break the tie
+        candidates.sort_by(|&a, &b| Self::tie_break(&fonts[a], &fonts[b]));
+
+        let mut ranked = vec![];
+        for (i, idx) in candidates.into_iter().enumerate() {
+            ranked.push((
+                idx,
+                if i == 0 {
+                    None
+                } else {
+                    Some(Elimination::TieBreak)
+                },
+            ));
+        }
+        // The candidates that were eliminated last were the closest
+        // to matching
+        eliminated.sort_by(|(a, a_stage), (b, b_stage)| {
+            a_stage
+                .cmp(b_stage)
+                .then_with(|| Self::tie_break(&fonts[*a], &fonts[*b]))
+        });
+        ranked.append(&mut eliminated);
+        Some(ranked)
+    }
 
-        // The first one in this set is our best match
-        candidates.into_iter().next()
+    /// Orders candidates that matched the requested attributes equally
+    /// well; the first is preferred.  Ties are broken by the priority
+    /// of the origin of the font, then by font revision, with newer
+    /// revisions preferred, then by the path to the font data.
+    pub fn tie_break(a: &Self, b: &Self) -> Ordering {
+        a.handle
+            .origin
+            .priority()
+            .cmp(&b.handle.origin.priority())
+            .then_with(|| b.revision.cmp(&a.revision))
+            .then_with(|| a.handle.cmp(&b.handle))
     }
 
+    pub fn best_matching_index<P: std::ops::Deref<Target = Self> + std::fmt::Debug>(
+        attr: &FontAttributes,
+        fonts: &[P],
+    ) -> Option<usize> {
+        Self::rank_matches(attr, fonts)?
+            .into_iter()
+            .next()
+            .map(|(idx, _)| idx)
+    }
+
+    /// Selects the best match for `attr` from `fonts`.  The candidates
+    /// that lost out are recorded on the returned font so that the
+    /// selection can be explained by `wezterm ls-fonts --explain`.
     pub fn best_match(attr: &FontAttributes, mut fonts: Vec<Self>) -> Option<Self> {
         let refs: Vec<&Self> = fonts.iter().collect();
-        let idx = Self::best_matching_index(attr, &refs)?;
-        fonts.drain(idx..=idx).next()
+        let ranked = Self::rank_matches(attr, &refs)?;
+        let rejected = ranked
+            .iter()
+            .filter_map(|&(idx, eliminated_by)| {
+                let eliminated_by = eliminated_by?;
+                let font = &fonts[idx];
+                Some(RejectedCandidate {
+                    lua_name: font.lua_name(),
+                    diagnostic: font.handle.diagnostic_string(),
+                    revision: font.font_revision(),
+                    version: font.version.clone(),
+                    score: MatchScore::compute(attr, font),
+                    eliminated_by,
+                })
+            })
+            .collect();
+        let idx = ranked[0].0;
+        let mut parsed = fonts.drain(idx..=idx).next()?;
+        parsed.rejected_candidates = rejected;
+        Some(parsed)
+    }
+
+    /// Returns the candidates that lost out to this font when it was
+    /// selected, best first
+    pub fn rejected_candidates(&self) -> &[RejectedCandidate] {
+        &self.rejected_candidates
     }
 }
 
+/// Heuristically decide whether the coverage is that of an emoji font,
+/// by checking whether the majority of its non-latin codepoints are
+/// concentrated in the emoji blocks.
+/// The latin range is excluded because emoji fonts generally cover
+/// eg: the digits that are used in keycap sequences.
+fn is_emoji_coverage(coverage: &RangeSet<u32>) -> bool {
+    let mut total = 0;
+    let mut emoji = 0;
+    for c in coverage.iter_values() {
+        if c <= 0xff {
+            continue;
+        }
+        total += 1;
+        if crate::emoji::is_in_emoji_block(c) {
+            emoji += 1;
+        }
+    }
+    total > 0 && emoji * 2 > total
+}
+
+/// The name and data of the bundled last resort font, which has
+/// a placeholder glyph for every codepoint
+pub(crate) static LAST_RESORT_FONT: (&str, &[u8]) = (
+    "../../assets/fonts/LastResortHE-Regular.ttf",
+    include_bytes!("../../assets/fonts/LastResortHE-Regular.ttf"),
+);
+
+macro_rules! font {
+    ($font:literal) => {
+        ($font, include_bytes!($font) as &'static [u8])
+    };
+}
+
+/// The names and data of the fonts that are compiled in to wezterm
+static BUILT_IN_FONTS: &[(&str, &[u8])] = &[
+    font!("../../assets/fonts/JetBrainsMono-BoldItalic.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-Bold.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-ExtraBoldItalic.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-ExtraBold.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-ExtraLightItalic.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-ExtraLight.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-Italic.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-LightItalic.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-Light.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-MediumItalic.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-Medium.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-Regular.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-ThinItalic.ttf"),
+    font!("../../assets/fonts/JetBrainsMono-Thin.ttf"),
+    font!("../../assets/fonts/NotoColorEmoji.ttf"),
+    font!("../../assets/fonts/PowerlineExtraSymbols.otf"),
+];
+
+/// Returns the name and data of the built-in font named `name`
+pub(crate) fn built_in_font(name: &str) -> Option<(&'static str, &'static [u8])> {
+    BUILT_IN_FONTS
+        .iter()
+        .chain(std::iter::once(&LAST_RESORT_FONT))
+        .find(|(built_in, _)| *built_in == name)
+        .copied()
+}
+
 /// In case the user has a broken configuration, or no configuration,
 /// we bundle JetBrains Mono and Noto Color Emoji to act as reasonably
 /// sane fallback fonts.
 /// This function loads those.
 pub(crate) fn load_built_in_fonts(font_info: &mut Vec<ParsedFont>) -> anyhow::Result<()> {
-    macro_rules! font {
-        ($font:literal) => {
-            (include_bytes!($font) as &'static [u8], $font)
-        };
-    }
     let lib = crate::ftwrap::Library::new()?;
-    for (data, name) in &[
-        font!("../../assets/fonts/JetBrainsMono-BoldItalic.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-Bold.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-ExtraBoldItalic.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-ExtraBold.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-ExtraLightItalic.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-ExtraLight.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-Italic.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-LightItalic.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-Light.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-MediumItalic.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-Medium.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-Regular.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-ThinItalic.ttf"),
-        font!("../../assets/fonts/JetBrainsMono-Thin.ttf"),
-        font!("../../assets/fonts/NotoColorEmoji.ttf"),
-        font!("../../assets/fonts/PowerlineExtraSymbols.otf"),
-        font!("../../assets/fonts/LastResortHE-Regular.ttf"),
-    ] {
+    for (name, data) in BUILT_IN_FONTS
+        .iter()
+        .chain(std::iter::once(&LAST_RESORT_FONT))
+    {
         let locator = FontDataHandle {
             source: FontDataSource::BuiltIn { data, name },
             index: 0,
@@ -420,21 +1147,81 @@ pub fn best_matching_font(
     Ok(ParsedFont::best_match(font_attr, font_info))
 }
 
+/// Returned when a font file is a classic Mac font suitcase, which
+/// keeps its fonts in the resource fork of the file and leaves the
+/// data fork empty.  freetype only reads the data fork of our files.
+#[derive(Debug, Error)]
+#[error(
+    "{} is a font suitcase that stores its fonts in its resource fork, \
+     which is not supported. Convert it to a .dfont, .ttf or .otf file \
+     to use it, for example using the fondu utility",
+    .path.display()
+)]
+pub struct ResourceForkSuitcase {
+    pub path: PathBuf,
+}
+
+/// Returns true if `path` has the .dfont extension.  A .dfont file
+/// holds the same resources as a suitcase, but in its data fork.
+fn is_dfont(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("dfont"))
+}
+
+/// Returns true if `path` has an empty data fork but a non-empty
+/// resource fork
+#[cfg(target_os = "macos")]
+fn is_resource_fork_only(path: &Path) -> bool {
+    let len = |path: &Path| std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    len(path) == 0 && len(&path.join("..namedfork/rsrc")) > 0
+}
+
+#[cfg(not(target_os = "macos"))]
+fn is_resource_fork_only(_path: &Path) -> bool {
+    false
+}
+
 pub(crate) fn parse_and_collect_font_info(
     source: &FontDataSource,
     font_info: &mut Vec<ParsedFont>,
     origin: FontOrigin,
 ) -> anyhow::Result<()> {
     let lib = crate::ftwrap::Library::new()?;
-    let num_faces = lib.query_num_faces(&source)?;
+    let path = match source {
+        FontDataSource::OnDisk(path) => Some(path.as_path()),
+        _ => None,
+    };
+    let per_face_instance_counts = match path {
+        Some(path) if is_resource_fork_only(path) => {
+            return Err(ResourceForkSuitcase {
+                path: path.to_path_buf(),
+            }
+            .into());
+        }
+        // freetype understands the resource map of a .dfont, and
+        // exposes each of the sfnt resources in it as a face, as
+        // with a TTC.  They predate variable fonts, so there are no
+        // named instances to enumerate.
+        Some(path) if is_dfont(path) => {
+            let num_faces = lib
+                .query_num_faces(&source)
+                .with_context(|| format!("reading the faces of {}", path.display()))?;
+            vec![0; num_faces as usize]
+        }
+        _ => lib.describe_source(&source)?.per_face_instance_counts,
+    };
 
+    /// Parse face `index`, which is opened from the same data as
+    /// `first`, the first face of the collection, if that was opened
     fn load_one(
         lib: &crate::ftwrap::Library,
         source: &FontDataSource,
+        first: Option<&crate::ftwrap::Face>,
         index: u32,
+        num_instances: u32,
         font_info: &mut Vec<ParsedFont>,
         origin: FontOrigin,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<crate::ftwrap::Face> {
         let locator = FontDataHandle {
             source: source.clone(),
             index,
@@ -442,23 +1229,416 @@ pub(crate) fn parse_and_collect_font_info(
             origin,
         };
 
-        let face = lib.face_from_locator(&locator)?;
-        if let Ok(variations) = face.variations() {
-            for parsed in variations {
-                font_info.push(parsed);
+        let face = match first {
+            Some(first) => first.sibling(lib, index)?,
+            None => lib.face_from_locator(&locator)?,
+        };
+        if num_instances > 0 {
+            if let Ok(variations) = face.variations() {
+                font_info.extend(variations);
+                return Ok(face);
             }
-        } else {
-            let parsed = ParsedFont::from_locator(&locator)?;
-            font_info.push(parsed);
         }
-        Ok(())
+        let parsed = ParsedFont::from_face(&face, locator)?;
+        font_info.push(parsed);
+        Ok(face)
     }
 
-    for index in 0..num_faces {
-        if let Err(err) = load_one(&lib, &source, index, font_info, origin) {
-            log::trace!("error while parsing {:?} index {}: {}", source, index, err);
+    // The faces of a collection are opened from the data of the first,
+    // rather than opening, and mapping, the file again for each face
+    let mut first = None;
+    for (index, &num_instances) in per_face_instance_counts.iter().enumerate() {
+        match load_one(
+            &lib,
+            &source,
+            first.as_ref(),
+            index as u32,
+            num_instances,
+            font_info,
+            origin,
+        ) {
+            Ok(face) => {
+                if index == 0 {
+                    first.replace(face);
+                }
+            }
+            Err(err) => {
+                log::trace!("error while parsing {:?} index {}: {}", source, index, err)
+            }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn jetbrains_mono() -> ParsedFont {
+        let lib = crate::ftwrap::Library::new().unwrap();
+        let handle = FontDataHandle {
+            source: FontDataSource::BuiltIn {
+                name: "JetBrainsMono-Regular.ttf",
+                data: include_bytes!("../../assets/fonts/JetBrainsMono-Regular.ttf"),
+            },
+            index: 0,
+            variation: 0,
+            origin: FontOrigin::BuiltIn,
+        };
+        let face = lib.face_from_locator(&handle).unwrap();
+        ParsedFont::from_face(&face, handle).unwrap()
+    }
+
+    /// Returns the offset of the directory record for `tag`
+    fn table_record(data: &[u8], tag: &[u8; 4]) -> usize {
+        let num_tables = crate::sfnt::read_u16(data, 4).unwrap() as usize;
+        (0..num_tables)
+            .map(|idx| 12 + idx * 16)
+            .find(|&record| &data[record..record + 4] == tag)
+            .unwrap()
+    }
+
+    /// Load a copy of JetBrains Mono, which has neither a kern table nor
+    /// a GPOS kern feature, patched to have either or both of them
+    fn kerned_jetbrains_mono(legacy_kern: bool, gpos_kern: bool) -> ParsedFont {
+        let mut data = include_bytes!("../../assets/fonts/JetBrainsMono-Regular.ttf").to_vec();
+
+        if gpos_kern {
+            // Rename the first feature of the GPOS FeatureList
+            let record = table_record(&data, b"GPOS");
+            let gpos = crate::sfnt::read_u32(&data, record + 8).unwrap() as usize;
+            let feature_list = gpos + crate::sfnt::read_u16(&data, gpos + 6).unwrap() as usize;
+            data[feature_list + 2..feature_list + 6].copy_from_slice(b"kern");
+        }
+
+        if legacy_kern {
+            // Repurpose the DSIG record for a kern table with a single
+            // format 0 subtable holding one pair, appended to the file
+            while data.len() % 4 != 0 {
+                data.push(0);
+            }
+            let offset = data.len() as u32;
+            let kern: [u16; 12] = [0, 1, 0, 20, 0x0001, 1, 6, 0, 0, 36, 37, 0xffc0];
+            for value in &kern {
+                data.extend_from_slice(&value.to_be_bytes());
+            }
+            let record = table_record(&data, b"DSIG");
+            data[record..record + 4].copy_from_slice(b"kern");
+            data[record + 8..record + 12].copy_from_slice(&offset.to_be_bytes());
+            data[record + 12..record + 16].copy_from_slice(&24u32.to_be_bytes());
+        }
+
+        let lib = crate::ftwrap::Library::new().unwrap();
+        let handle = FontDataHandle {
+            source: FontDataSource::Memory {
+                name: "kerned JetBrainsMono-Regular.ttf".to_string(),
+                data: std::sync::Arc::new(data.into_boxed_slice()),
+            },
+            index: 0,
+            variation: 0,
+            origin: FontOrigin::BuiltIn,
+        };
+        let face = lib.face_from_locator(&handle).unwrap();
+        ParsedFont::from_face(&face, handle).unwrap()
+    }
+
+    /// Make a copy of `font` that appears to have come from somewhere else
+    fn duplicate(font: &ParsedFont, origin: FontOrigin, revision: i64, path: &str) -> ParsedFont {
+        let mut dup = font.clone();
+        dup.handle.origin = origin;
+        dup.handle.source = FontDataSource::OnDisk(path.into());
+        dup.revision = Some(revision);
+        dup
+    }
+
+    fn path(font: &ParsedFont) -> String {
+        font.handle.name_or_path_str().to_string()
+    }
+
+    #[test]
+    fn tie_break_duplicates() {
+        let attr = FontAttributes::new("JetBrains Mono");
+        let base = jetbrains_mono();
+        let mut bold = duplicate(&base, FontOrigin::FontDirs, 3 << 16, "/fonts/bold.ttf");
+        bold.weight = FontWeight::Bold;
+
+        let fonts = vec![
+            duplicate(
+                &base,
+                FontOrigin::FontConfig,
+                1 << 16,
+                "/usr/share/fonts/old.ttf",
+            ),
+            duplicate(
+                &base,
+                FontOrigin::FontConfig,
+                2 << 16,
+                "/usr/share/fonts/b.ttf",
+            ),
+            bold,
+            duplicate(
+                &base,
+                FontOrigin::FontConfig,
+                2 << 16,
+                "/usr/share/fonts/a.ttf",
+            ),
+            duplicate(
+                &base,
+                FontOrigin::FontDirs,
+                1 << 16,
+                "/home/user/.fonts/user.ttf",
+            ),
+        ];
+        let expected = vec![
+            ("/home/user/.fonts/user.ttf", None),
+            ("/usr/share/fonts/a.ttf", Some(Elimination::TieBreak)),
+            ("/usr/share/fonts/b.ttf", Some(Elimination::TieBreak)),
+            ("/usr/share/fonts/old.ttf", Some(Elimination::TieBreak)),
+            ("/fonts/bold.ttf", Some(Elimination::Weight)),
+        ];
+
+        // The ranking must not depend on the order in which the
+        // candidates were discovered
+        for rotation in 0..fonts.len() {
+            for &reverse in &[false, true] {
+                let mut fonts = fonts.clone();
+                fonts.rotate_left(rotation);
+                if reverse {
+                    fonts.reverse();
+                }
+
+                let ranked: Vec<(String, Option<Elimination>)> =
+                    ParsedFont::rank_matches(&attr, &fonts.iter().collect::<Vec<_>>())
+                        .unwrap()
+                        .into_iter()
+                        .map(|(idx, eliminated)| (path(&fonts[idx]), eliminated))
+                        .collect();
+                let expected: Vec<(String, Option<Elimination>)> =
+                    expected.iter().map(|(p, e)| (p.to_string(), *e)).collect();
+                assert_eq!(ranked, expected);
+
+                let best = ParsedFont::best_match(&attr, fonts).unwrap();
+                assert_eq!(path(&best), "/home/user/.fonts/user.ttf");
+                let rejected = best.rejected_candidates();
+                assert_eq!(rejected.len(), 4);
+                assert_eq!(rejected[0].diagnostic, "/usr/share/fonts/a.ttf, FontConfig");
+                assert_eq!(rejected[0].revision, Some(2.0));
+                assert_eq!(rejected[3].eliminated_by, Elimination::Weight);
+                assert_eq!(rejected[3].score.weight_delta, 300);
+            }
+        }
+    }
+
+    #[test]
+    fn serialize() {
+        let lib = crate::ftwrap::Library::new().unwrap();
+        let (name, data) = built_in_font("../../assets/fonts/NotoColorEmoji.ttf").unwrap();
+        let handle = FontDataHandle {
+            source: FontDataSource::BuiltIn { name, data },
+            index: 0,
+            variation: 0,
+            origin: FontOrigin::BuiltIn,
+        };
+        let face = lib.face_from_locator(&handle).unwrap();
+        let font = ParsedFont::from_face(&face, handle).unwrap();
+
+        let json = serde_json::to_string(&font).unwrap();
+        let restored: ParsedFont = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, font);
+        assert_eq!(restored.handle, font.handle);
+        assert_eq!(restored.flags(), font.flags());
+        assert_eq!(restored.color_formats(), font.color_formats());
+        assert_eq!(restored.format(), font.format());
+        assert_eq!(restored.version, font.version);
+        assert!(restored.assume_emoji_presentation());
+        // The coverage is computed again when it is needed
+        assert!(restored.coverage.lock().unwrap().is_empty());
+
+        let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        value["version"] = (SERIALIZED_FONT_VERSION + 1).into();
+        let err = serde_json::from_value::<ParsedFont>(value.clone()).unwrap_err();
+        assert!(err.to_string().contains("incompatible version"), "{}", err);
+        // as is data from before the version was recorded
+        value.as_object_mut().unwrap().remove("version");
+        assert!(serde_json::from_value::<ParsedFont>(value).is_err());
+    }
+
+    #[test]
+    fn kerning_source() {
+        assert_eq!(KerningSource::new(false, false), KerningSource::None);
+        assert_eq!(KerningSource::new(true, false), KerningSource::Kern);
+        assert_eq!(KerningSource::new(false, true), KerningSource::Gpos);
+        assert_eq!(KerningSource::new(true, true), KerningSource::Both);
+        assert_eq!(KerningSource::Both.to_string(), "kern table and GPOS");
+
+        assert_eq!(jetbrains_mono().kerning_source(), KerningSource::None);
+        for &(legacy, gpos) in &[(true, false), (false, true), (true, true)] {
+            let font = kerned_jetbrains_mono(legacy, gpos);
+            let source = font.kerning_source();
+            assert_eq!(source, KerningSource::new(legacy, gpos));
+            assert_eq!(source.has_legacy_kern(), legacy);
+            assert_eq!(*font.gpos_kern.lock().unwrap(), Some(gpos));
+            assert_eq!(font.flags().has_kerning(), legacy);
+
+            let lib = crate::ftwrap::Library::new().unwrap();
+            let face = lib.face_from_locator(&font.handle).unwrap();
+            let expected = if legacy { vec![(36, 37, -64.)] } else { vec![] };
+            assert_eq!(face.all_kerning_pairs(), expected);
+        }
+    }
+
+    #[test]
+    fn last_resort() {
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let last_resort = db
+            .resolve(&FontAttributes::new(LAST_RESORT_FAMILY))
+            .unwrap()
+            .clone();
+        assert!(last_resort.is_last_resort());
+        assert!(!jetbrains_mono().is_last_resort());
+
+        // Its coverage is assumed rather than computed
+        let mut wanted = RangeSet::new();
+        wanted.add(0x41);
+        wanted.add(0x10fffd);
+        assert_eq!(last_resort.coverage_intersection(&wanted).unwrap(), wanted);
+        assert!(last_resort.coverage.lock().unwrap().is_empty());
+
+        let lua = ParsedFont::lua_fallback(&[jetbrains_mono(), last_resort]);
+        assert_eq!(lua.matches("-- last resort").count(), 1);
+    }
+
+    /// Build a .dfont containing `fonts` as its sfnt resources
+    fn make_dfont(fonts: &[&[u8]]) -> Vec<u8> {
+        const DATA_OFFSET: usize = 256;
+        let mut data = vec![];
+        let mut refs = vec![];
+        for (idx, font) in fonts.iter().enumerate() {
+            // id, no name, then the attributes and 24-bit data offset
+            // packed together, and a reserved handle
+            refs.extend_from_slice(&(128 + idx as u16).to_be_bytes());
+            refs.extend_from_slice(&0xffffu16.to_be_bytes());
+            refs.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            refs.extend_from_slice(&[0; 4]);
+            data.extend_from_slice(&(font.len() as u32).to_be_bytes());
+            data.extend_from_slice(font);
+        }
+
+        // The type list has a single type, sfnt, whose references
+        // immediately follow it
+        let mut types = vec![];
+        types.extend_from_slice(&0u16.to_be_bytes());
+        types.extend_from_slice(b"sfnt");
+        types.extend_from_slice(&(fonts.len() as u16 - 1).to_be_bytes());
+        types.extend_from_slice(&10u16.to_be_bytes());
+        types.extend_from_slice(&refs);
+
+        let map_len = 28 + types.len();
+        let mut header = vec![];
+        for value in &[DATA_OFFSET, DATA_OFFSET + data.len(), data.len(), map_len] {
+            header.extend_from_slice(&(*value as u32).to_be_bytes());
+        }
+
+        let mut dfont = header.clone();
+        dfont.resize(DATA_OFFSET, 0);
+        dfont.extend_from_slice(&data);
+        // The map starts with a copy of the header, followed by
+        // the handle, file reference and attributes, which are unused
+        dfont.extend_from_slice(&header);
+        dfont.extend_from_slice(&[0; 8]);
+        dfont.extend_from_slice(&28u16.to_be_bytes());
+        dfont.extend_from_slice(&(map_len as u16).to_be_bytes());
+        dfont.extend_from_slice(&types);
+        dfont
+    }
+
+    #[test]
+    fn dfont() {
+        let path = std::env::temp_dir().join(format!("wezterm-font-{}.dfont", std::process::id()));
+        std::fs::write(
+            &path,
+            make_dfont(&[
+                include_bytes!("../../assets/fonts/JetBrainsMono-Regular.ttf"),
+                include_bytes!("../../assets/fonts/JetBrainsMono-Bold.ttf"),
+            ]),
+        )
+        .unwrap();
+
+        let mut font_info = vec![];
+        let result = parse_and_collect_font_info(
+            &FontDataSource::OnDisk(path.clone()),
+            &mut font_info,
+            FontOrigin::FontDirs,
+        );
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        let faces = font_info
+            .iter()
+            .map(|parsed| {
+                (
+                    parsed.handle.index,
+                    parsed.names().family.as_str(),
+                    parsed.weight,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            faces,
+            vec![
+                (0, "JetBrains Mono", FontWeight::Regular),
+                (1, "JetBrains Mono", FontWeight::Bold),
+            ]
+        );
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn resource_fork_suitcase() {
+        let path =
+            std::env::temp_dir().join(format!("wezterm-font-suitcase-{}", std::process::id()));
+        std::fs::write(&path, b"").unwrap();
+        std::fs::write(
+            path.join("..namedfork/rsrc"),
+            make_dfont(&[include_bytes!(
+                "../../assets/fonts/JetBrainsMono-Regular.ttf"
+            )]),
+        )
+        .unwrap();
+
+        let mut font_info = vec![];
+        let err = parse_and_collect_font_info(
+            &FontDataSource::OnDisk(path.clone()),
+            &mut font_info,
+            FontOrigin::FontDirs,
+        )
+        .unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.downcast_ref::<ResourceForkSuitcase>().is_some());
+        assert!(font_info.is_empty());
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn strike_ppems() {
+        let font = jetbrains_mono();
+        assert!(font.strike_ppems().is_empty());
+        assert_eq!(font.strike_advice(10., 96), None);
+
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let emoji = db
+            .resolve(&FontAttributes::new("Noto Color Emoji"))
+            .unwrap();
+        assert_eq!(emoji.strike_ppems(), &[109.]);
+        assert_eq!(emoji.color_formats(), ColorFormats::CBDT);
+        assert!(font.color_formats().is_empty());
+        assert_eq!(emoji.strike_advice(81.75, 96), None);
+
+        let advice = emoji.strike_advice(12., 96).unwrap();
+        assert_eq!(advice.pixel_size, 16.);
+        assert_eq!(advice.selected, 109.);
+        assert_eq!(advice.nearest.len(), 1);
+        assert_eq!(advice.nearest[0].point_size, 81.75);
+    }
+}
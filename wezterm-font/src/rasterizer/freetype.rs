@@ -1,66 +1,384 @@
-use crate::parser::ParsedFont;
-use crate::rasterizer::FontRasterizer;
+use crate::locator::{FontDataHandle, FontDataSource, FontOrigin};
+use crate::parser::{ParsedFont, LAST_RESORT_FONT};
+use crate::rasterizer::{
+    ColorSpace, CoverageFormat, FontRasterizer, FontSynthesis, SubpixelOrder, SubpixelOrderProvider,
+};
 use crate::units::*;
 use crate::{ftwrap, RasterizedGlyph};
 use ::freetype::FT_GlyphSlotRec_;
 use anyhow::bail;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::mem;
-use std::slice;
 use window::color::linear_u8_to_srgb8;
 
 pub struct FreeTypeRasterizer {
     has_color: bool,
+    synthesis: FontSynthesis,
     face: RefCell<ftwrap::Face>,
+    /// Glyphs that failed to render with the requested load flags,
+    /// keyed by glyph index and those flags
+    degraded: RefCell<HashMap<(u32, ftwrap::FT_Int32), Degraded>>,
+    /// The last resort font, opened on demand to supply placeholders
+    /// for glyphs that cannot be rendered
+    placeholder: RefCell<Option<ftwrap::Face>>,
+    /// Maps the subpixel coverage of LCD glyphs to the values that
+    /// are stored, applying freetype_lcd_coverage_gamma; None if the
+    /// coverage is stored as rendered
+    lcd_coverage_ramp: Option<[u8; 256]>,
     _lib: ftwrap::Library,
 }
 
+/// Shown for a glyph that can't be rendered and that isn't mapped
+/// to a codepoint, such as a ligature
+const REPLACEMENT_CHARACTER: u32 = 0xfffd;
+
 impl FontRasterizer for FreeTypeRasterizer {
-    fn rasterize_glyph(
+    fn rasterize_glyph_in_color_space(
         &self,
         glyph_pos: u32,
         size: f64,
         dpi: u32,
+        flags: &ftwrap::LoadFlags,
+        color_space: ColorSpace,
     ) -> anyhow::Result<RasterizedGlyph> {
         self.face.borrow_mut().set_font_size(size, dpi)?;
 
-        let (load_flags, render_mode) = ftwrap::compute_load_flags_from_config();
-
         let mut face = self.face.borrow_mut();
+
+        let load_flags = flags.load_flags;
+        // There's nothing to render for a glyph that is already known
+        // to be blank at this size
+        if let Some(advance) = face.blank_glyph_advance(glyph_pos, load_flags) {
+            return Ok(RasterizedGlyph {
+                data: vec![],
+                height: 0,
+                width: 0,
+                bearing_x: PixelLength::new(0.),
+                bearing_y: PixelLength::new(0.),
+                has_color: false,
+                overhang_left: PixelLength::new(0.),
+                overhang_right: PixelLength::new(0.),
+                color_space,
+                coverage: CoverageFormat::Gray,
+                natural_advance: PixelLength::new(advance),
+                was_scaled_strike: false,
+            });
+        }
+        let (render_mode, bgr, strike_format) = Self::render_mode(&mut face, flags);
         let descender = unsafe { (*(*face.face).size).metrics.descender as f64 / 64.0 };
-        let ft_glyph = face.load_and_render_glyph(glyph_pos, load_flags, render_mode)?;
+        let embolden = if self.synthesis.bold {
+            // Express the strength relative to the em size in 26.6
+            let em = unsafe {
+                ftwrap::FT_MulFix(
+                    (*face.face).units_per_EM as _,
+                    (*(*face.face).size).metrics.y_scale,
+                )
+            };
+            let strength = em * self.synthesis.embolden_strength as ftwrap::FT_Pos / 1000;
+            Some(if self.synthesis.preserve_advance {
+                ftwrap::Embolden {
+                    x: strength * FontSynthesis::PRESERVE_ADVANCE_X_PERCENT as ftwrap::FT_Pos / 100,
+                    y: strength,
+                    preserve_advance: true,
+                }
+            } else {
+                ftwrap::Embolden {
+                    x: strength,
+                    y: strength,
+                    preserve_advance: false,
+                }
+            })
+        } else {
+            None
+        };
+        let key = (glyph_pos, load_flags);
+        let degraded = self.degraded.borrow().get(&key).copied();
+        let rendered = match degraded {
+            None => self.render_with_fallback(&mut face, key, render_mode, embolden),
+            Some(Degraded::Flags(safer)) => {
+                face.load_and_render_glyph(glyph_pos, safer, render_mode, embolden)?;
+                true
+            }
+            Some(Degraded::Placeholder) => false,
+        };
+        if !rendered {
+            return self.rasterize_placeholder(
+                &face,
+                glyph_pos,
+                size,
+                dpi,
+                load_flags,
+                render_mode,
+                color_space,
+                bgr,
+            );
+        }
+        let ft_glyph = unsafe { &*(*face.face).glyph };
+
+        let mut glyph = self.convert_slot(ft_glyph, descender, color_space, bgr)?;
+        if let Some(format) = strike_format {
+            glyph.has_color = format == ftwrap::StrikeFormat::Bgra;
+        }
+        glyph.was_scaled_strike = face.rendered_scaled_strike();
+        Ok(glyph)
+    }
+
+    fn rasterize_fitted_glyph(
+        &self,
+        glyph_pos: u32,
+        size: f64,
+        dpi: u32,
+        flags: &ftwrap::LoadFlags,
+        cell_width: f64,
+        cell_height: f64,
+    ) -> anyhow::Result<(RasterizedGlyph, f64)> {
+        // Synthetic bold is applied to the outline as it is loaded, and
+        // glyphs that needed safer load flags rely on the retries of the
+        // regular path, so those are rendered normally and scaled as a
+        // bitmap instead
+        let degraded = self
+            .degraded
+            .borrow()
+            .contains_key(&(glyph_pos, flags.load_flags));
+        if !self.synthesis.bold && !degraded {
+            self.face.borrow_mut().set_font_size(size, dpi)?;
+            let mut face = self.face.borrow_mut();
+            let (render_mode, bgr, strike_format) = Self::render_mode(&mut face, flags);
+            let descender = unsafe { (*(*face.face).size).metrics.descender as f64 / 64.0 };
+            match face.render_fitted_glyph(
+                glyph_pos,
+                cell_width,
+                cell_height,
+                flags.load_flags,
+                render_mode,
+            ) {
+                Ok(fitted) => {
+                    let mut glyph =
+                        self.convert_slot(fitted.slot, descender, ColorSpace::Srgb, bgr)?;
+                    if let Some(format) = strike_format {
+                        glyph.has_color = format == ftwrap::StrikeFormat::Bgra;
+                    }
+                    glyph.was_scaled_strike = fitted.was_scaled_strike;
+                    return Ok((glyph, fitted.bitmap_scale));
+                }
+                Err(err) => log::debug!("{:#}; scaling the bitmap to fit instead", err),
+            }
+        }
+
+        let glyph = self.rasterize_glyph(glyph_pos, size, dpi, flags)?;
+        let scale = ftwrap::fit_scale(
+            glyph.width as f64,
+            glyph.height as f64,
+            cell_width,
+            cell_height,
+        );
+        Ok((glyph, scale))
+    }
+}
+
+/// Records how a glyph that could not be rendered with the requested
+/// load flags was rendered instead, so that subsequent frames don't
+/// repeat the failed attempts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Degraded {
+    /// Rendering succeeded with these safer load flags
+    Flags(ftwrap::FT_Int32),
+    /// No combination of load flags worked; the placeholder glyph
+    /// from the last resort font is shown instead
+    Placeholder,
+}
+
+/// Returns the load flags to try, in order, when rendering a glyph
+/// with `load_flags` failed.  Each step is safer than the last:
+/// color layers are dropped first, then the hinter, which is the most
+/// common casualty of malformed bytecode, and finally any embedded
+/// bitmaps.
+/// Unscaled loading isn't attempted as it would produce a bitmap in
+/// font units; the last resort placeholder is used instead.
+fn safer_load_flags(load_flags: ftwrap::FT_Int32) -> Vec<ftwrap::FT_Int32> {
+    let no_color = load_flags & !(ftwrap::FT_LOAD_COLOR as ftwrap::FT_Int32);
+    let no_hinting = no_color | ftwrap::FT_LOAD_NO_HINTING as ftwrap::FT_Int32;
+    let no_bitmap = no_hinting | ftwrap::FT_LOAD_NO_BITMAP as ftwrap::FT_Int32;
+    let mut steps: Vec<ftwrap::FT_Int32> = vec![];
+    for &flags in &[no_color, no_hinting, no_bitmap] {
+        if flags != load_flags && !steps.contains(&flags) {
+            steps.push(flags);
+        }
+    }
+    steps
+}
+
+impl FreeTypeRasterizer {
+    /// Returns the render mode to use for `flags` in `face`, along
+    /// with whether the display has its blue subpixel first and the
+    /// format of the selected bitmap strike, if any
+    fn render_mode(
+        face: &mut ftwrap::Face,
+        flags: &ftwrap::LoadFlags,
+    ) -> (ftwrap::FT_Render_Mode, bool, Option<ftwrap::StrikeFormat>) {
+        // If the display that we're rendering for didn't tell us the
+        // subpixel order, ask the embedder for it
+        let subpixel_order = match flags.subpixel_order {
+            SubpixelOrder::Unknown => face.subpixel_order(),
+            order => order,
+        };
+        let mut render_mode = ftwrap::orient_lcd_mode(flags.render_mode, subpixel_order);
+        face.set_normal_render_for_color_glyphs(flags.normal_render_for_color_glyphs);
+
+        // For bitmap fonts, we know what kind of pixels the selected
+        // strike holds without having to render anything
+        let strike_format = face.selected_strike_format();
+        if strike_format == Some(ftwrap::StrikeFormat::Bgra) {
+            // LCD filtering doesn't make sense for color bitmaps
+            render_mode = ftwrap::FT_Render_Mode::FT_RENDER_MODE_NORMAL;
+        }
+        (render_mode, subpixel_order.is_bgr(), strike_format)
+    }
+
+    /// Render the glyph identified by `key` into the glyph slot of
+    /// `face`, retrying with progressively safer load flags if that
+    /// fails.  The outcome of the retries is logged and remembered
+    /// once per glyph.  Returns false if the glyph couldn't be
+    /// rendered at all and the placeholder should be used.
+    fn render_with_fallback(
+        &self,
+        face: &mut ftwrap::Face,
+        key: (u32, ftwrap::FT_Int32),
+        render_mode: ftwrap::FT_Render_Mode,
+        embolden: Option<ftwrap::Embolden>,
+    ) -> bool {
+        let (glyph_pos, load_flags) = key;
+        let err = match face.load_and_render_glyph(glyph_pos, load_flags, render_mode, embolden) {
+            Ok(_) => return true,
+            Err(err) => err,
+        };
+
+        for safer in safer_load_flags(load_flags) {
+            if face
+                .load_and_render_glyph(glyph_pos, safer, render_mode, embolden)
+                .is_ok()
+            {
+                log::warn!(
+                    "{:#}; rendered it using load_flags {} instead",
+                    err,
+                    ftwrap::describe_load_flags(safer)
+                );
+                self.degraded
+                    .borrow_mut()
+                    .insert(key, Degraded::Flags(safer));
+                return true;
+            }
+        }
+
+        log::warn!("{:#}; showing a placeholder glyph instead", err);
+        self.degraded
+            .borrow_mut()
+            .insert(key, Degraded::Placeholder);
+        false
+    }
+
+    /// Rasterize the last resort font's placeholder for the codepoint
+    /// that maps to `glyph_pos` in `face`, for use when that glyph
+    /// itself cannot be rendered
+    #[allow(clippy::too_many_arguments)]
+    fn rasterize_placeholder(
+        &self,
+        face: &ftwrap::Face,
+        glyph_pos: u32,
+        size: f64,
+        dpi: u32,
+        load_flags: ftwrap::FT_Int32,
+        render_mode: ftwrap::FT_Render_Mode,
+        color_space: ColorSpace,
+        bgr: bool,
+    ) -> anyhow::Result<RasterizedGlyph> {
+        let codepoint = face
+            .codepoint_for_glyph(glyph_pos)
+            .unwrap_or(REPLACEMENT_CHARACTER);
+
+        let mut placeholder = self.placeholder.borrow_mut();
+        if placeholder.is_none() {
+            let (name, data) = LAST_RESORT_FONT;
+            let handle = FontDataHandle {
+                source: FontDataSource::BuiltIn { name, data },
+                index: 0,
+                variation: 0,
+                origin: FontOrigin::BuiltIn,
+            };
+            placeholder.replace(self._lib.face_from_locator(&handle)?);
+        }
+        let placeholder = placeholder.as_mut().unwrap();
+        placeholder.set_font_size(size, dpi)?;
 
+        let index = unsafe { ftwrap::FT_Get_Char_Index(placeholder.face, codepoint as _) };
+        let load_flags = load_flags & !(ftwrap::FT_LOAD_COLOR as ftwrap::FT_Int32);
+        let ft_glyph = placeholder.load_and_render_glyph(index, load_flags, render_mode, None)?;
+        let descender = unsafe { (*(*placeholder.face).size).metrics.descender as f64 / 64.0 };
+        let mut glyph = self.convert_slot(ft_glyph, descender, color_space, bgr)?;
+        glyph.has_color = false;
+        Ok(glyph)
+    }
+
+    /// Convert the rendered bitmap in `ft_glyph` into a RasterizedGlyph
+    fn convert_slot(
+        &self,
+        ft_glyph: &FT_GlyphSlotRec_,
+        descender: f64,
+        color_space: ColorSpace,
+        bgr: bool,
+    ) -> anyhow::Result<RasterizedGlyph> {
         let mode: ftwrap::FT_Pixel_Mode =
             unsafe { mem::transmute(u32::from(ft_glyph.bitmap.pixel_mode)) };
 
-        // pitch is the number of bytes per source row
+        // pitch is the number of bytes per source row.
+        // The rows of a bitmap with a negative pitch are stored bottom
+        // up; normalize them so that the code below can assume that
+        // the top row comes first.
         let pitch = ft_glyph.bitmap.pitch.abs() as usize;
-        let data = unsafe {
-            slice::from_raw_parts_mut(
-                ft_glyph.bitmap.buffer,
-                ft_glyph.bitmap.rows as usize * pitch,
-            )
-        };
+        let data = ftwrap::bitmap_rows_top_down(&ft_glyph.bitmap);
+
+        let advance = PixelLength::new(ft_glyph.advance.x as f64 / 64.0);
 
-        let glyph = match mode {
-            ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_LCD => self.rasterize_lcd(pitch, ft_glyph, data),
+        let mut glyph = match mode {
+            ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_LCD => {
+                self.rasterize_lcd(pitch, ft_glyph, &data, color_space, bgr)
+            }
+            ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_LCD_V => {
+                self.rasterize_lcd_v(pitch, ft_glyph, &data, color_space, bgr)
+            }
             ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_BGRA => {
-                self.rasterize_bgra(pitch, descender, ft_glyph, data)
+                self.rasterize_bgra(pitch, descender, ft_glyph, &data)
+            }
+            ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_GRAY => {
+                self.rasterize_gray(pitch, ft_glyph, &data, color_space)
+            }
+            ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_MONO => {
+                self.rasterize_mono(pitch, ft_glyph, &data, color_space)
+            }
+            ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_GRAY2 => {
+                let gray = expand_packed_gray(pitch, ft_glyph, &data, 2);
+                let width = ft_glyph.bitmap.width as usize;
+                self.rasterize_gray(width, ft_glyph, &gray, color_space)
+            }
+            ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_GRAY4 => {
+                let gray = expand_packed_gray(pitch, ft_glyph, &data, 4);
+                let width = ft_glyph.bitmap.width as usize;
+                self.rasterize_gray(width, ft_glyph, &gray, color_space)
             }
-            ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_GRAY => self.rasterize_gray(pitch, ft_glyph, data),
-            ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_MONO => self.rasterize_mono(pitch, ft_glyph, data),
             mode => bail!("unhandled pixel mode: {:?}", mode),
         };
+        glyph.compute_overhang(advance);
+        glyph.natural_advance = advance;
         Ok(glyph)
     }
-}
 
-impl FreeTypeRasterizer {
     fn rasterize_mono(
         &self,
         pitch: usize,
         ft_glyph: &FT_GlyphSlotRec_,
         data: &[u8],
+        color_space: ColorSpace,
     ) -> RasterizedGlyph {
         let width = ft_glyph.bitmap.width as usize;
         let height = ft_glyph.bitmap.rows as usize;
@@ -96,6 +414,12 @@ impl FreeTypeRasterizer {
             bearing_x: PixelLength::new(ft_glyph.bitmap_left as f64),
             bearing_y: PixelLength::new(ft_glyph.bitmap_top as f64),
             has_color: false,
+            overhang_left: PixelLength::new(0.),
+            overhang_right: PixelLength::new(0.),
+            color_space,
+            coverage: CoverageFormat::Gray,
+            natural_advance: PixelLength::new(0.),
+            was_scaled_strike: false,
         }
     }
 
@@ -104,6 +428,7 @@ impl FreeTypeRasterizer {
         pitch: usize,
         ft_glyph: &FT_GlyphSlotRec_,
         data: &[u8],
+        color_space: ColorSpace,
     ) -> RasterizedGlyph {
         let width = ft_glyph.bitmap.width as usize;
         let height = ft_glyph.bitmap.rows as usize;
@@ -115,7 +440,10 @@ impl FreeTypeRasterizer {
             for x in 0..width {
                 let linear_gray = data[src_offset + x];
 
-                let gray = linear_u8_to_srgb8(linear_gray);
+                let gray = match color_space {
+                    ColorSpace::Srgb => linear_u8_to_srgb8(linear_gray),
+                    ColorSpace::Linear => linear_gray,
+                };
 
                 // Texture is SRGBA, which in OpenGL means
                 // that the RGB values are gamma adjusted
@@ -135,6 +463,12 @@ impl FreeTypeRasterizer {
             bearing_x: PixelLength::new(ft_glyph.bitmap_left as f64),
             bearing_y: PixelLength::new(ft_glyph.bitmap_top as f64),
             has_color: false,
+            overhang_left: PixelLength::new(0.),
+            overhang_right: PixelLength::new(0.),
+            color_space,
+            coverage: CoverageFormat::Gray,
+            natural_advance: PixelLength::new(0.),
+            was_scaled_strike: false,
         }
     }
 
@@ -143,18 +477,64 @@ impl FreeTypeRasterizer {
         pitch: usize,
         ft_glyph: &FT_GlyphSlotRec_,
         data: &[u8],
+        color_space: ColorSpace,
+        bgr: bool,
     ) -> RasterizedGlyph {
+        // Each pixel is 3 consecutive bytes in a row
         let width = ft_glyph.bitmap.width as usize / 3;
         let height = ft_glyph.bitmap.rows as usize;
+        self.lcd_to_rgba(ft_glyph, width, height, color_space, bgr, |x, y| {
+            let offset = y * pitch + x * 3;
+            [data[offset], data[offset + 1], data[offset + 2]]
+        })
+    }
+
+    fn rasterize_lcd_v(
+        &self,
+        pitch: usize,
+        ft_glyph: &FT_GlyphSlotRec_,
+        data: &[u8],
+        color_space: ColorSpace,
+        bgr: bool,
+    ) -> RasterizedGlyph {
+        // Each pixel is the same byte in 3 consecutive rows
+        let width = ft_glyph.bitmap.width as usize;
+        let height = ft_glyph.bitmap.rows as usize / 3;
+        self.lcd_to_rgba(ft_glyph, width, height, color_space, bgr, |x, y| {
+            let offset = y * 3 * pitch + x;
+            [data[offset], data[offset + pitch], data[offset + 2 * pitch]]
+        })
+    }
+
+    /// Convert LCD coverage, which freetype always produces in RGB
+    /// order, to RGBA.  `sample` returns the coverage of the pixel
+    /// at the specified coordinates.
+    /// If `bgr` is true, the red and blue channels are swapped to
+    /// match a display whose subpixels are in BGR order.
+    fn lcd_to_rgba<F: Fn(usize, usize) -> [u8; 3]>(
+        &self,
+        ft_glyph: &FT_GlyphSlotRec_,
+        width: usize,
+        height: usize,
+        color_space: ColorSpace,
+        bgr: bool,
+        sample: F,
+    ) -> RasterizedGlyph {
         let size = (width * height * 4) as usize;
         let mut rgba = vec![0u8; size];
         for y in 0..height {
-            let src_offset = y * pitch as usize;
             let dest_offset = y * width * 4;
             for x in 0..width {
-                let red = data[src_offset + (x * 3)];
-                let green = data[src_offset + (x * 3) + 1];
-                let blue = data[src_offset + (x * 3) + 2];
+                let [red, green, blue] = sample(x, y);
+                let (red, blue) = if bgr { (blue, red) } else { (red, blue) };
+                let (red, green, blue) = match &self.lcd_coverage_ramp {
+                    Some(ramp) => (
+                        ramp[red as usize],
+                        ramp[green as usize],
+                        ramp[blue as usize],
+                    ),
+                    None => (red, green, blue),
+                };
                 let alpha = red.max(green).max(blue);
 
                 // Texture is SRGBA, which in OpenGL means
@@ -162,9 +542,14 @@ impl FreeTypeRasterizer {
                 // non-linear values, but the A value is
                 // linear!
 
-                let red = linear_u8_to_srgb8(red);
-                let green = linear_u8_to_srgb8(green);
-                let blue = linear_u8_to_srgb8(blue);
+                let (red, green, blue) = match color_space {
+                    ColorSpace::Srgb => (
+                        linear_u8_to_srgb8(red),
+                        linear_u8_to_srgb8(green),
+                        linear_u8_to_srgb8(blue),
+                    ),
+                    ColorSpace::Linear => (red, green, blue),
+                };
 
                 rgba[dest_offset + (x * 4)] = red;
                 rgba[dest_offset + (x * 4) + 1] = green;
@@ -180,6 +565,12 @@ impl FreeTypeRasterizer {
             bearing_x: PixelLength::new(ft_glyph.bitmap_left as f64),
             bearing_y: PixelLength::new(ft_glyph.bitmap_top as f64),
             has_color: self.has_color,
+            overhang_left: PixelLength::new(0.),
+            overhang_right: PixelLength::new(0.),
+            color_space,
+            coverage: CoverageFormat::Subpixel,
+            natural_advance: PixelLength::new(0.),
+            was_scaled_strike: false,
         }
     }
 
@@ -286,20 +677,390 @@ impl FreeTypeRasterizer {
             ),
 
             has_color: self.has_color,
+            overhang_left: PixelLength::new(0.),
+            overhang_right: PixelLength::new(0.),
+            color_space: ColorSpace::Srgb,
+            coverage: CoverageFormat::Color,
+            natural_advance: PixelLength::new(0.),
+            was_scaled_strike: false,
         }
     }
 
-    pub fn from_locator(parsed: &ParsedFont) -> anyhow::Result<Self> {
+    pub fn from_locator(
+        parsed: &ParsedFont,
+        subpixel_order: Option<SubpixelOrderProvider>,
+    ) -> anyhow::Result<Self> {
         log::trace!("Rasterizier wants {:?}", parsed);
-        let lib = ftwrap::Library::new()?;
-        let face = lib.face_from_locator(&parsed.handle)?;
-        let has_color = unsafe {
-            (((*face.face).face_flags as u32) & (ftwrap::FT_FACE_FLAG_COLOR as u32)) != 0
-        };
+        let mut lib = ftwrap::Library::new()?;
+        if let Some(provider) = subpixel_order {
+            lib.set_subpixel_order_provider(move || provider());
+        }
+        let mut face = lib.face_from_locator(&parsed.handle)?;
+        face.set_unhinted_below_lowest_rec_ppem(
+            config::configuration().freetype_unhinted_below_lowest_rec_ppem,
+        );
+        let has_color = face.flags().has_color();
+        let mut synthesis = parsed.synthesis();
+        if synthesis.bold && face.is_already_bold() {
+            // Emboldening a face that is already bold just
+            // produces blobs
+            log::debug!(
+                "{} is already bold; skipping synthetic bold",
+                parsed.lua_name()
+            );
+            synthesis.bold = false;
+            synthesis.embolden_strength = 0;
+            synthesis.preserve_advance = false;
+        }
+        let transform = parsed.transform();
+        if let Some(slant) = synthesis.oblique_slant {
+            // Shears combine by adding their tangents
+            let tan = transform.slant_degrees.to_radians().tan() + slant as f64 / 65536.0;
+            face.set_transform(
+                transform.x_scale,
+                transform.y_scale,
+                tan.atan().to_degrees(),
+            );
+        } else if !transform.is_identity() {
+            face.set_transform(
+                transform.x_scale,
+                transform.y_scale,
+                transform.slant_degrees,
+            );
+        }
         Ok(Self {
             _lib: lib,
             face: RefCell::new(face),
             has_color,
+            synthesis,
+            degraded: RefCell::new(HashMap::new()),
+            placeholder: RefCell::new(None),
+            lcd_coverage_ramp: lcd_coverage_ramp(
+                config::configuration().freetype_lcd_coverage_gamma,
+            ),
         })
     }
 }
+
+/// Computes the table that applies `gamma` to coverage values,
+/// returning None if it would leave them unchanged
+fn lcd_coverage_ramp(gamma: f64) -> Option<[u8; 256]> {
+    if !gamma.is_finite() || gamma <= 0. {
+        log::warn!(
+            "freetype_lcd_coverage_gamma={} is invalid; it must be a positive number. \
+             Using 1.0 instead.",
+            gamma
+        );
+        return None;
+    }
+    if (gamma - 1.).abs() < f64::EPSILON {
+        return None;
+    }
+    let mut ramp = [0u8; 256];
+    for (coverage, value) in ramp.iter_mut().enumerate() {
+        *value = ((coverage as f64 / 255.).powf(1. / gamma) * 255.).round() as u8;
+    }
+    Some(ramp)
+}
+
+/// Expands the 2 or 4 bit per pixel gray bitmap that some bitmap
+/// strikes of CBDT and EBDT fonts hold into one byte per pixel, so
+/// that it can be rasterized in the same way as an 8 bit strike.
+/// Returns rows of `ft_glyph.bitmap.width` bytes.
+fn expand_packed_gray(
+    pitch: usize,
+    ft_glyph: &FT_GlyphSlotRec_,
+    data: &[u8],
+    bits: u32,
+) -> Vec<u8> {
+    let width = ft_glyph.bitmap.width as usize;
+    let height = ft_glyph.bitmap.rows as usize;
+    let per_byte = 8 / bits as usize;
+    let max = (1u32 << bits) - 1;
+    let mut gray = vec![0u8; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let byte = data[y * pitch + x / per_byte];
+            // The leftmost pixel is in the most significant bits
+            let shift = 8 - bits as usize * (1 + x % per_byte);
+            let value = (u32::from(byte) >> shift) & max;
+            gray[y * width + x] = (value * 255 / max) as u8;
+        }
+    }
+    gray
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sfnt::{make_tag, read_u16, read_u32};
+    use std::sync::Arc;
+
+    fn jetbrains_mono_rasterizer() -> FreeTypeRasterizer {
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let font = db
+            .resolve(&config::FontAttributes::new("JetBrains Mono"))
+            .unwrap();
+        FreeTypeRasterizer::from_locator(font, None).unwrap()
+    }
+
+    /// Returns a copy of the font in `data` in which the outline of
+    /// `glyph` claims to have far more contours than it actually does
+    fn corrupt_glyph(data: &[u8], glyph: usize) -> Vec<u8> {
+        let num_tables = read_u16(data, 4).unwrap() as usize;
+        let table = |tag: &[u8; 4]| {
+            (0..num_tables)
+                .map(|i| 12 + i * 16)
+                .find(|&record| read_u32(data, record) == Some(make_tag(tag)))
+                .map(|record| read_u32(data, record + 8).unwrap() as usize)
+                .unwrap()
+        };
+        let head = table(b"head");
+        let loca = table(b"loca");
+        let glyf = table(b"glyf");
+        let offset = if read_u16(data, head + 50) == Some(0) {
+            read_u16(data, loca + glyph * 2).unwrap() as usize * 2
+        } else {
+            read_u32(data, loca + glyph * 4).unwrap() as usize
+        };
+
+        let mut data = data.to_vec();
+        data[glyf + offset..glyf + offset + 2].copy_from_slice(&0x7fffu16.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn corrupt_glyph_uses_placeholder() {
+        config::use_test_configuration();
+        let lib = ftwrap::Library::new().unwrap();
+        let data: &[u8] = include_bytes!("../../../assets/fonts/JetBrainsMono-Regular.ttf");
+        let handle = |name: &str, data: Vec<u8>| FontDataHandle {
+            source: FontDataSource::Memory {
+                name: name.to_string(),
+                data: Arc::new(data.into_boxed_slice()),
+            },
+            index: 0,
+            variation: 0,
+            origin: FontOrigin::BuiltIn,
+        };
+
+        let glyph_pos = {
+            let face = lib
+                .face_from_locator(&handle("intact", data.to_vec()))
+                .unwrap();
+            unsafe { ftwrap::FT_Get_Char_Index(face.face, 'A' as _) }
+        };
+        let corrupt = handle("corrupt", corrupt_glyph(data, glyph_pos as usize));
+        let face = lib.face_from_locator(&corrupt).unwrap();
+        let parsed = ParsedFont::from_face(&face, corrupt).unwrap();
+        let raster = FreeTypeRasterizer::from_locator(&parsed, None).unwrap();
+
+        let flags = ftwrap::compute_load_flags(&config::configuration(), &Default::default());
+        let glyph = raster
+            .rasterize_glyph_in_color_space(glyph_pos, 12., 96, &flags, ColorSpace::Srgb)
+            .unwrap();
+        assert!(glyph.width > 0 && glyph.height > 0);
+        assert!(glyph.data.iter().any(|&b| b != 0));
+        assert_eq!(
+            raster.degraded.borrow().get(&(glyph_pos, flags.load_flags)),
+            Some(&Degraded::Placeholder)
+        );
+
+        // The failure is remembered rather than retried
+        let again = raster
+            .rasterize_glyph_in_color_space(glyph_pos, 12., 96, &flags, ColorSpace::Srgb)
+            .unwrap();
+        assert_eq!(again.data, glyph.data);
+
+        // and the rest of the font is unaffected
+        let b = unsafe { ftwrap::FT_Get_Char_Index(face.face, 'B' as _) };
+        let glyph_b = raster
+            .rasterize_glyph_in_color_space(b, 12., 96, &flags, ColorSpace::Srgb)
+            .unwrap();
+        assert!(glyph_b.natural_advance.get() > 0.);
+        assert!(raster
+            .degraded
+            .borrow()
+            .get(&(b, flags.load_flags))
+            .is_none());
+    }
+
+    #[test]
+    fn safer_flags() {
+        let color = ftwrap::FT_LOAD_COLOR as ftwrap::FT_Int32;
+        let no_hinting = ftwrap::FT_LOAD_NO_HINTING as ftwrap::FT_Int32;
+        let no_bitmap = ftwrap::FT_LOAD_NO_BITMAP as ftwrap::FT_Int32;
+        assert_eq!(
+            safer_load_flags(color),
+            vec![0, no_hinting, no_hinting | no_bitmap]
+        );
+        // Steps that don't change anything are skipped
+        assert_eq!(safer_load_flags(no_hinting), vec![no_hinting | no_bitmap]);
+        assert!(safer_load_flags(no_hinting | no_bitmap).is_empty());
+    }
+
+    #[test]
+    fn lcd_coverage() {
+        config::use_test_configuration();
+        let db = crate::FontDatabase::with_built_in().unwrap();
+        let font = db
+            .resolve(&config::FontAttributes::new("JetBrains Mono"))
+            .unwrap();
+        let mut raster = FreeTypeRasterizer::from_locator(font, None).unwrap();
+        // Large enough that the stem covers several whole pixels
+        let (size, dpi) = (36., 96);
+        let glyph_pos = unsafe { ftwrap::FT_Get_Char_Index(raster.face.borrow().face, '|' as _) };
+        let flags = ftwrap::LoadFlags {
+            render_mode: ftwrap::FT_Render_Mode::FT_RENDER_MODE_LCD,
+            subpixel_order: SubpixelOrder::Rgb,
+            ..ftwrap::compute_load_flags(&config::configuration(), &Default::default())
+        };
+        let rasterize = |raster: &FreeTypeRasterizer, color_space| {
+            raster
+                .rasterize_glyph_in_color_space(glyph_pos, size, dpi, &flags, color_space)
+                .unwrap()
+        };
+
+        // The reference is freetype's rendering of the same glyph,
+        // drawn in white over black with each channel blended by its
+        // own coverage in linear space
+        let lib = ftwrap::Library::new().unwrap();
+        let mut face = lib.face_from_locator(&font.handle).unwrap();
+        face.set_font_size(size, dpi).unwrap();
+        let reference = ftwrap::OwnedGlyph::from_slot(
+            face.load_and_render_glyph(glyph_pos, flags.load_flags, flags.render_mode, None)
+                .unwrap(),
+        )
+        .blend_lcd([0xff; 3], [0; 3])
+        .unwrap();
+
+        // Sampling the sRGB coverage from an sRGB texture yields the
+        // linear coverage, so blending that per channel on the GPU
+        // matches the reference
+        let srgb = rasterize(&raster, ColorSpace::Srgb);
+        assert_eq!(srgb.coverage, CoverageFormat::Subpixel);
+        assert_eq!(
+            (srgb.width, srgb.height),
+            (reference.width, reference.height)
+        );
+        for y in 0..srgb.height {
+            for x in 0..srgb.width {
+                let offset = (y * srgb.width + x) * 4;
+                for (channel, &expected) in reference.pixel(x, y).iter().enumerate() {
+                    let actual = srgb.data[offset + channel];
+                    assert!(
+                        (actual as i16 - expected as i16).abs() <= 1,
+                        "{},{} channel {}: {} vs {}",
+                        x,
+                        y,
+                        channel,
+                        actual,
+                        expected
+                    );
+                }
+            }
+        }
+        // Some of the subpixels are only partially covered
+        let linear = rasterize(&raster, ColorSpace::Linear);
+        assert!(linear
+            .data
+            .chunks(4)
+            .any(|pixel| pixel[0] != pixel[1] || pixel[1] != pixel[2]));
+
+        // freetype_lcd_coverage_gamma adjusts each channel
+        let ramp = lcd_coverage_ramp(2.0).unwrap();
+        assert_eq!((ramp[0], ramp[64], ramp[255]), (0, 128, 255));
+        raster.lcd_coverage_ramp = Some(ramp);
+        let adjusted = rasterize(&raster, ColorSpace::Linear);
+        for (a, l) in adjusted.data.chunks(4).zip(linear.data.chunks(4)) {
+            assert_eq!(
+                &a[..3],
+                &[
+                    ramp[l[0] as usize],
+                    ramp[l[1] as usize],
+                    ramp[l[2] as usize]
+                ]
+            );
+        }
+        assert_eq!(lcd_coverage_ramp(1.0), None);
+        assert_eq!(lcd_coverage_ramp(0.), None);
+        assert_eq!(lcd_coverage_ramp(f64::NAN), None);
+
+        // Grayscale glyphs hold a single coverage value
+        let gray_flags = ftwrap::LoadFlags {
+            render_mode: ftwrap::FT_Render_Mode::FT_RENDER_MODE_NORMAL,
+            ..flags
+        };
+        let gray = raster
+            .rasterize_glyph_in_color_space(glyph_pos, size, dpi, &gray_flags, ColorSpace::Linear)
+            .unwrap();
+        assert_eq!(gray.coverage, CoverageFormat::Gray);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn negative_pitch() {
+        let raster = jetbrains_mono_rasterizer();
+
+        // A single column gray bitmap whose rows are stored bottom up
+        let mut buffer = vec![0x30u8, 0, 0x20, 0, 0x10, 0];
+        let mut slot: FT_GlyphSlotRec_ = unsafe { std::mem::zeroed() };
+        slot.bitmap.rows = 3;
+        slot.bitmap.width = 1;
+        slot.bitmap.pitch = -2;
+        slot.bitmap.buffer = buffer.as_mut_ptr();
+        slot.bitmap.pixel_mode = ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_GRAY as _;
+        slot.advance.x = 7 * 64;
+
+        let glyph = raster
+            .convert_slot(&slot, 0., ColorSpace::Linear, false)
+            .unwrap();
+        let alpha: Vec<u8> = glyph.data.chunks(4).map(|pixel| pixel[3]).collect();
+        assert_eq!(alpha, vec![0x10, 0x20, 0x30]);
+        assert_eq!(glyph.natural_advance.get(), 7.);
+    }
+
+    #[test]
+    fn packed_gray() {
+        let raster = jetbrains_mono_rasterizer();
+
+        // Two rows of five 2 bit pixels, with padding to the pitch
+        let mut buffer = vec![
+            0b00_01_10_11,
+            0b11_00_00_00,
+            0,
+            0b11_10_01_00,
+            0b01_00_00_00,
+            0,
+        ];
+        let mut slot: FT_GlyphSlotRec_ = unsafe { std::mem::zeroed() };
+        slot.bitmap.rows = 2;
+        slot.bitmap.width = 5;
+        slot.bitmap.pitch = 3;
+        slot.bitmap.buffer = buffer.as_mut_ptr();
+        slot.bitmap.pixel_mode = ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_GRAY2 as _;
+
+        let glyph = raster
+            .convert_slot(&slot, 0., ColorSpace::Linear, false)
+            .unwrap();
+        assert_eq!((glyph.width, glyph.height), (5, 2));
+        let alpha: Vec<u8> = glyph.data.chunks(4).map(|pixel| pixel[3]).collect();
+        assert_eq!(alpha, vec![0, 85, 170, 255, 255, 255, 170, 85, 0, 85]);
+
+        // A single row of three 4 bit pixels
+        let mut buffer = vec![0x0f, 0x80];
+        slot.bitmap.rows = 1;
+        slot.bitmap.width = 3;
+        slot.bitmap.pitch = 2;
+        slot.bitmap.buffer = buffer.as_mut_ptr();
+        slot.bitmap.pixel_mode = ftwrap::FT_Pixel_Mode::FT_PIXEL_MODE_GRAY4 as _;
+
+        let glyph = raster
+            .convert_slot(&slot, 0., ColorSpace::Linear, false)
+            .unwrap();
+        let alpha: Vec<u8> = glyph.data.chunks(4).map(|pixel| pixel[3]).collect();
+        assert_eq!(alpha, vec![0, 255, 136]);
+        assert_eq!(glyph.coverage, CoverageFormat::Gray);
+    }
+}
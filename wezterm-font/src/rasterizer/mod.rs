@@ -1,6 +1,8 @@
+use crate::ftwrap::LoadFlags;
 use crate::parser::ParsedFont;
 use crate::units::*;
-use config::FontRasterizerSelection;
+use config::{FontRasterizerSelection, WideGlyphPolicy};
+use std::rc::Rc;
 
 pub mod freetype;
 
@@ -13,7 +15,278 @@ pub struct RasterizedGlyph {
     pub width: usize,
     pub bearing_x: PixelLength,
     pub bearing_y: PixelLength,
+    /// True if `data` holds the colors of the glyph, as it does for
+    /// the BGRA bitmaps of color emoji, which must be drawn as-is.
+    /// Otherwise `data` holds only coverage, which doesn't depend on
+    /// the color of the text; it is up to the caller to tint it with
+    /// the foreground color when compositing the glyph, so that the
+    /// same rendering can be used for any color.
     pub has_color: bool,
+    /// How far the ink extends to the left of the glyph origin,
+    /// such as for italic glyphs with a negative left side bearing
+    pub overhang_left: PixelLength,
+    /// How far the ink extends to the right of the advance,
+    /// such as for the slanted strokes of italic glyphs
+    pub overhang_right: PixelLength,
+    /// How the color channels of `data` are encoded
+    pub color_space: ColorSpace,
+    /// What the channels of `data` hold
+    pub coverage: CoverageFormat,
+    /// The advance of the glyph as designed by the font.  In a
+    /// terminal, where glyphs are laid out on a grid of cells, this
+    /// can differ from the advance that the glyph is drawn with; it
+    /// is up to the caller to compare the two and decide what to do
+    /// with a glyph that doesn't fit.
+    pub natural_advance: PixelLength,
+    /// True if the glyph came from a bitmap strike whose size doesn't
+    /// match the requested font size, so that it is drawn at a size
+    /// other than that of the surrounding text unless it is scaled.
+    /// Such glyphs are the ones for which scaling, such as when
+    /// downscaling emoji to fit their cells, is worth considering.
+    pub was_scaled_strike: bool,
+}
+
+/// Describes how the color channels of a rasterized glyph are encoded.
+/// The alpha channel is always linear coverage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// The coverage has been gamma adjusted for use with an sRGB texture
+    Srgb,
+    /// The coverage is linear, exactly as produced by freetype, which
+    /// is appropriate for renderers that do their own gamma handling.
+    Linear,
+}
+
+/// Describes what the channels of a rasterized glyph hold, so that
+/// the renderer can blend it appropriately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoverageFormat {
+    /// The color channels each hold the same coverage, which is
+    /// tinted by the foreground color
+    Gray,
+    /// The red, green and blue channels each hold the coverage of the
+    /// corresponding subpixel, in the subpixel order of the display.
+    /// The alpha channel is the greatest of the three and is only an
+    /// approximation: each color channel should be blended by its own
+    /// coverage, as dual source blending does.
+    Subpixel,
+    /// The glyph holds its own pre-multiplied color, such as an emoji
+    Color,
+}
+
+/// Describes how the ink of a rasterized glyph fits into the
+/// cells that it occupies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellFit {
+    /// The ink lies within the cells
+    Fits,
+    /// The ink extends beyond the cells.
+    /// `ink_cells` is the number of cells required to hold it.
+    TooWide { ink_cells: usize },
+}
+
+impl RasterizedGlyph {
+    /// How far, as a fraction of a cell, the ink may extend beyond
+    /// its cells before the glyph is considered to be too wide.
+    /// This accommodates the slight overhang that is typical of
+    /// italic glyphs.
+    pub const CELL_FIT_TOLERANCE: f64 = 0.25;
+
+    /// Compute the overhang fields from the ink bounds of the bitmap,
+    /// relative to the origin and the supplied advance
+    pub fn compute_overhang(&mut self, advance: PixelLength) {
+        let ink_left = self.bearing_x.get();
+        let ink_right = ink_left + self.width as f64;
+        self.overhang_left = PixelLength::new((-ink_left).max(0.));
+        self.overhang_right = PixelLength::new((ink_right - advance.get()).max(0.));
+    }
+
+    /// Returns the horizontal space required to hold the ink,
+    /// measured from the origin or from the left edge of the ink
+    /// if that lies to the left of the origin
+    pub fn ink_width(&self) -> PixelLength {
+        let left = self.bearing_x.get().min(0.);
+        let right = (self.bearing_x.get() + self.width as f64).max(0.);
+        PixelLength::new(right - left)
+    }
+
+    /// Classify whether this glyph fits within `num_cells` cells
+    /// that are each `cell_width` wide
+    pub fn cell_fit(&self, cell_width: PixelLength, num_cells: usize) -> CellFit {
+        let cell_width = cell_width.get();
+        let ink_width = self.ink_width().get();
+        if self.width == 0
+            || ink_width <= cell_width * (num_cells as f64 + Self::CELL_FIT_TOLERANCE)
+        {
+            CellFit::Fits
+        } else {
+            CellFit::TooWide {
+                ink_cells: (ink_width / cell_width).ceil() as usize,
+            }
+        }
+    }
+
+    /// Crop the bitmap so that the ink lies within `num_cells` cells
+    /// that are each `cell_width` wide, starting from the origin
+    pub fn clip_to_cells(&mut self, cell_width: PixelLength, num_cells: usize) {
+        let available = cell_width.get() * num_cells as f64;
+        let bearing_x = self.bearing_x.get();
+        // Columns to the left of the origin are dropped, as are
+        // columns that extend beyond the right edge of the last cell
+        let first = ((-bearing_x).max(0.).ceil() as usize).min(self.width);
+        let last = ((available - bearing_x).max(0.).floor() as usize)
+            .min(self.width)
+            .max(first);
+        if first == 0 && last == self.width {
+            return;
+        }
+
+        let width = last - first;
+        let mut data = Vec::with_capacity(width * self.height * 4);
+        for row in 0..self.height {
+            let start = (row * self.width + first) * 4;
+            data.extend_from_slice(&self.data[start..start + width * 4]);
+        }
+
+        self.data = data;
+        self.width = width;
+        self.bearing_x = PixelLength::new(bearing_x + first as f64);
+        self.compute_overhang(PixelLength::new(available));
+    }
+
+    /// Compute the scale factor required to shrink the ink so that
+    /// it fits within `num_cells` cells that are each `cell_width`
+    /// wide.  Scaling is relative to the origin, so a negative left
+    /// bearing is removed to keep the scaled ink within the cells.
+    pub fn scale_to_fit(&mut self, cell_width: PixelLength, num_cells: usize) -> f64 {
+        let available = cell_width.get() * num_cells as f64;
+        let ink_width = self.ink_width().get();
+        if ink_width <= available {
+            return 1.0;
+        }
+        if self.bearing_x.get() < 0. {
+            self.bearing_x = PixelLength::new(0.);
+        }
+        let scale = available / ink_width;
+        self.compute_overhang(PixelLength::new(available / scale));
+        scale
+    }
+
+    /// Apply `policy` if this glyph is too wide for `num_cells` cells
+    /// that are each `cell_width` wide.
+    /// Returns the scale factor that should be used to render the
+    /// glyph.
+    pub fn apply_wide_glyph_policy(
+        &mut self,
+        policy: WideGlyphPolicy,
+        cell_width: PixelLength,
+        num_cells: usize,
+    ) -> f64 {
+        if self.cell_fit(cell_width, num_cells) == CellFit::Fits {
+            return 1.0;
+        }
+        match policy {
+            WideGlyphPolicy::Clip => {
+                self.clip_to_cells(cell_width, num_cells);
+                1.0
+            }
+            WideGlyphPolicy::ScaleToFit | WideGlyphPolicy::FitToCells => {
+                self.scale_to_fit(cell_width, num_cells)
+            }
+            WideGlyphPolicy::Overflow => 1.0,
+        }
+    }
+}
+
+/// Describes the synthetic styling that is applied when rasterizing
+/// glyphs from a face that doesn't natively provide the requested
+/// bold or italic style.
+/// Glyphs rendered with different synthesis produce different pixels,
+/// so this is part of the identity of a rendered glyph and is hashable
+/// for use in cache keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct FontSynthesis {
+    /// Embolden the outline to simulate a bold weight
+    pub bold: bool,
+    /// If set, shear the outline to simulate italics.
+    /// The value is the xy term of the shear matrix in 16.16
+    /// fixed point, which quantizes the slant amount so that
+    /// it is suitable for use as a hash key.
+    pub oblique_slant: Option<i32>,
+    /// The embolden strength, expressed in thousandths of an em.
+    /// Only meaningful when `bold` is true.
+    pub embolden_strength: u16,
+    /// If true, the emboldened glyph is kept centered within its
+    /// original advance so that it fits the same cells as the
+    /// regular glyph.  Only meaningful when `bold` is true.
+    pub preserve_advance: bool,
+}
+
+impl FontSynthesis {
+    /// The slant used by FT_GlyphSlot_Oblique: approximately 12 degrees
+    pub const DEFAULT_OBLIQUE_SLANT: i32 = 0x0366A;
+    /// The strength used by FT_GlyphSlot_Embolden: 1/24th of an em
+    pub const DEFAULT_EMBOLDEN_STRENGTH: u16 = 42;
+    /// When preserving the advance, the horizontal strength is
+    /// reduced to this many percent of the vertical strength, so that
+    /// less ink overflows into the neighboring cells
+    pub const PRESERVE_ADVANCE_X_PERCENT: i64 = 75;
+
+    pub fn is_none(&self) -> bool {
+        !self.bold && self.oblique_slant.is_none()
+    }
+}
+
+/// The arrangement of the color subpixels of a display
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SubpixelOrder {
+    /// The geometry is not known; LCD glyphs are rendered in the
+    /// orientation selected by the configuration
+    Unknown,
+    /// Horizontal stripes in red, green, blue order
+    Rgb,
+    /// Horizontal stripes in blue, green, red order
+    Bgr,
+    /// Vertical stripes with red at the top
+    VerticalRgb,
+    /// Vertical stripes with blue at the top
+    VerticalBgr,
+}
+
+impl SubpixelOrder {
+    /// Returns true if the blue subpixel comes first
+    pub fn is_bgr(&self) -> bool {
+        match self {
+            Self::Bgr | Self::VerticalBgr => true,
+            _ => false,
+        }
+    }
+}
+
+impl Default for SubpixelOrder {
+    fn default() -> Self {
+        Self::Unknown
+    }
+}
+
+/// A callback that returns the subpixel order of the display that
+/// glyphs are being rendered for
+pub type SubpixelOrderProvider = Rc<dyn Fn() -> SubpixelOrder>;
+
+/// Describes the display that glyphs are being rendered for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DisplayInfo {
+    pub dpi: u32,
+    pub subpixel_order: SubpixelOrder,
+}
+
+impl Default for DisplayInfo {
+    fn default() -> Self {
+        Self {
+            dpi: ::window::default_dpi() as u32,
+            subpixel_order: SubpixelOrder::Unknown,
+        }
+    }
 }
 
 /// Rasterizes the specified glyph index in the associated font
@@ -24,16 +297,122 @@ pub trait FontRasterizer {
         glyph_pos: u32,
         size: f64,
         dpi: u32,
+        load_flags: &LoadFlags,
+    ) -> anyhow::Result<RasterizedGlyph> {
+        self.rasterize_glyph_in_color_space(glyph_pos, size, dpi, load_flags, ColorSpace::Srgb)
+    }
+
+    /// Rasterize the glyph with its coverage encoded using the requested
+    /// `color_space`.  Color glyphs are always produced in the sRGB
+    /// color space, so callers should check `RasterizedGlyph::color_space`.
+    /// `load_flags` are those computed for the display that the glyph
+    /// is being rendered for.
+    fn rasterize_glyph_in_color_space(
+        &self,
+        glyph_pos: u32,
+        size: f64,
+        dpi: u32,
+        load_flags: &LoadFlags,
+        color_space: ColorSpace,
     ) -> anyhow::Result<RasterizedGlyph>;
+
+    /// Rasterize the glyph, scaling its outline down first if its ink
+    /// doesn't fit within a box that is `cell_width` by `cell_height`
+    /// pixels; see `Face::render_fitted_glyph`.
+    /// Returns the glyph along with the scale that the caller should
+    /// apply to its bitmap, which is 1.0 unless the glyph could not be
+    /// scaled as an outline, such as for bitmap fonts.
+    fn rasterize_fitted_glyph(
+        &self,
+        glyph_pos: u32,
+        size: f64,
+        dpi: u32,
+        load_flags: &LoadFlags,
+        cell_width: f64,
+        cell_height: f64,
+    ) -> anyhow::Result<(RasterizedGlyph, f64)>;
 }
 
+/// `subpixel_order` is consulted when rendering LCD glyphs for a
+/// display whose subpixel order is unknown
 pub fn new_rasterizer(
     rasterizer: FontRasterizerSelection,
     handle: &ParsedFont,
+    subpixel_order: Option<SubpixelOrderProvider>,
 ) -> anyhow::Result<Box<dyn FontRasterizer>> {
     match rasterizer {
         FontRasterizerSelection::FreeType => Ok(Box::new(
-            freetype::FreeTypeRasterizer::from_locator(handle)?,
+            freetype::FreeTypeRasterizer::from_locator(handle, subpixel_order)?,
         )),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A solid glyph that spans two 8px cells, with a 2px negative
+    /// left bearing, like a powerline or nerd font icon
+    fn double_width_icon() -> RasterizedGlyph {
+        let width = 18;
+        let height = 16;
+        RasterizedGlyph {
+            data: vec![0xff; width * height * 4],
+            height,
+            width,
+            bearing_x: PixelLength::new(-2.),
+            bearing_y: PixelLength::new(16.),
+            has_color: false,
+            overhang_left: PixelLength::new(2.),
+            overhang_right: PixelLength::new(8.),
+            color_space: ColorSpace::Srgb,
+            coverage: CoverageFormat::Gray,
+            natural_advance: PixelLength::new(16.),
+            was_scaled_strike: false,
+        }
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn classify() {
+        let cell_width = PixelLength::new(8.);
+        let icon = double_width_icon();
+        assert_eq!(icon.ink_width().get(), 18.);
+        assert_eq!(
+            icon.cell_fit(cell_width, 1),
+            CellFit::TooWide { ink_cells: 3 }
+        );
+        assert_eq!(
+            icon.cell_fit(cell_width, 2),
+            CellFit::TooWide { ink_cells: 3 }
+        );
+        assert_eq!(icon.cell_fit(cell_width, 3), CellFit::Fits);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn policies() {
+        let cell_width = PixelLength::new(8.);
+
+        let mut clipped = double_width_icon();
+        let scale = clipped.apply_wide_glyph_policy(WideGlyphPolicy::Clip, cell_width, 1);
+        assert_eq!(scale, 1.0);
+        assert_eq!(clipped.width, 8);
+        assert_eq!(clipped.height, 16);
+        assert_eq!(clipped.data.len(), 8 * 16 * 4);
+        assert_eq!(clipped.bearing_x.get(), 0.);
+        assert_eq!(clipped.cell_fit(cell_width, 1), CellFit::Fits);
+
+        let mut scaled = double_width_icon();
+        let scale = scaled.apply_wide_glyph_policy(WideGlyphPolicy::ScaleToFit, cell_width, 1);
+        assert_eq!(scale, 8. / 18.);
+        assert_eq!(scaled.bearing_x.get(), 0.);
+        assert!(scaled.width as f64 * scale <= cell_width.get());
+
+        let mut overflow = double_width_icon();
+        let scale = overflow.apply_wide_glyph_policy(WideGlyphPolicy::Overflow, cell_width, 1);
+        assert_eq!(scale, 1.0);
+        assert_eq!(overflow.width, 18);
+        assert_eq!(overflow.bearing_x.get(), -2.);
+    }
+}
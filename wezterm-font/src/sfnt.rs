@@ -0,0 +1,498 @@
+//! Minimal parsing of raw sfnt tables.
+//! These helpers operate on table data obtained via `Face::sfnt_table`
+//! and only understand the handful of structures that we need.
+//! Malformed data is reported as `None` rather than an error.
+
+/// Construct a table or feature tag from its 4 byte name
+pub fn make_tag(tag: &[u8; 4]) -> u32 {
+    u32::from_be_bytes(*tag)
+}
+
+/// Construct a tag from its textual form, such as `"liga"`.
+/// Tags that are shorter than 4 characters are padded with spaces.
+/// Returns None if `tag` is not a valid tag.
+pub fn tag_from_str(tag: &str) -> Option<u32> {
+    if tag.is_empty() || tag.len() > 4 || !tag.bytes().all(|b| (0x20..=0x7e).contains(&b)) {
+        return None;
+    }
+    let mut bytes = [b' '; 4];
+    bytes[..tag.len()].copy_from_slice(tag.as_bytes());
+    Some(make_tag(&bytes))
+}
+
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Parse the FeatureList of a GSUB or GPOS table and return the
+/// tags of the features that it defines.
+pub fn parse_feature_tags(table: &[u8]) -> Option<Vec<u32>> {
+    // The GSUB and GPOS headers share the same layout:
+    // majorVersion, minorVersion, scriptListOffset,
+    // featureListOffset, lookupListOffset
+    if read_u16(table, 0)? != 1 {
+        return None;
+    }
+    let feature_list = read_u16(table, 6)? as usize;
+    let count = read_u16(table, feature_list)? as usize;
+    let mut tags = Vec::with_capacity(count);
+    for i in 0..count {
+        // Each FeatureRecord is a 4 byte tag followed by a 2 byte offset
+        tags.push(read_u32(table, feature_list + 2 + i * 6)?);
+    }
+    Some(tags)
+}
+
+/// Parse a GSUB table and return the substitutions that the single
+/// substitution lookups of the feature `tag` make, as pairs of the
+/// original and substituted glyph, ordered by original glyph.
+/// The feature is looked up regardless of script and language, and
+/// if more than one lookup substitutes the same glyph, the first one
+/// to do so wins.  Lookups of the other types are skipped.
+pub fn parse_single_substitutions(table: &[u8], tag: u32) -> Option<Vec<(u16, u16)>> {
+    const SINGLE: u16 = 1;
+    const EXTENSION: u16 = 7;
+
+    if read_u16(table, 0)? != 1 {
+        return None;
+    }
+    let feature_list = read_u16(table, 6)? as usize;
+    let lookup_list = read_u16(table, 8)? as usize;
+
+    // A feature may be listed once for each script that uses it
+    let mut lookups = vec![];
+    let num_features = read_u16(table, feature_list)? as usize;
+    for i in 0..num_features {
+        let record = feature_list + 2 + i * 6;
+        if read_u32(table, record)? != tag {
+            continue;
+        }
+        let feature = feature_list + read_u16(table, record + 4)? as usize;
+        let num_lookups = read_u16(table, feature + 2)? as usize;
+        for j in 0..num_lookups {
+            let index = read_u16(table, feature + 4 + j * 2)?;
+            if !lookups.contains(&index) {
+                lookups.push(index);
+            }
+        }
+    }
+    lookups.sort_unstable();
+
+    let mut substitutions = std::collections::BTreeMap::new();
+    for index in lookups {
+        let lookup = lookup_list + read_u16(table, lookup_list + 2 + index as usize * 2)? as usize;
+        let lookup_type = read_u16(table, lookup)?;
+        let num_subtables = read_u16(table, lookup + 4)? as usize;
+        for i in 0..num_subtables {
+            let mut subtable = lookup + read_u16(table, lookup + 6 + i * 2)? as usize;
+            let mut subtable_type = lookup_type;
+            if lookup_type == EXTENSION {
+                // format, extensionLookupType, extensionOffset
+                subtable_type = read_u16(table, subtable + 2)?;
+                subtable += read_u32(table, subtable + 4)? as usize;
+            }
+            if subtable_type != SINGLE {
+                continue;
+            }
+            let format = read_u16(table, subtable)?;
+            let coverage =
+                parse_coverage(table, subtable + read_u16(table, subtable + 2)? as usize)?;
+            for (coverage_index, glyph) in coverage.into_iter().enumerate() {
+                let substitute = match format {
+                    1 => glyph.wrapping_add(read_u16(table, subtable + 4)?),
+                    2 => read_u16(table, subtable + 6 + coverage_index * 2)?,
+                    _ => return None,
+                };
+                substitutions.entry(glyph).or_insert(substitute);
+            }
+        }
+    }
+    Some(substitutions.into_iter().collect())
+}
+
+/// Parse the coverage table at `offset` and return its glyphs in
+/// coverage index order
+fn parse_coverage(table: &[u8], offset: usize) -> Option<Vec<u16>> {
+    let count = read_u16(table, offset + 2)? as usize;
+    match read_u16(table, offset)? {
+        1 => (0..count)
+            .map(|i| read_u16(table, offset + 4 + i * 2))
+            .collect(),
+        2 => {
+            let mut glyphs = vec![];
+            for i in 0..count {
+                // Each RangeRecord is start, end, startCoverageIndex
+                let record = offset + 4 + i * 6;
+                let start = read_u16(table, record)?;
+                let end = read_u16(table, record + 2)?;
+                if end < start {
+                    return None;
+                }
+                glyphs.extend(start..=end);
+            }
+            Some(glyphs)
+        }
+        _ => None,
+    }
+}
+
+/// The unique font identifier name record
+pub const NAME_ID_UNIQUE_ID: u16 = 3;
+/// The version string name record, such as "Version 5.002"
+pub const NAME_ID_VERSION: u16 = 5;
+/// The license description name record
+pub const NAME_ID_LICENSE: u16 = 13;
+/// The license information URL name record
+pub const NAME_ID_LICENSE_URL: u16 = 14;
+
+/// Find the record for `name_id` in a name table and decode it.
+/// When there are several records for the same name, the US English
+/// Windows one is preferred, followed by any other Windows or Unicode
+/// platform record, and lastly the Macintosh Roman one.
+/// Empty records are treated as missing.
+pub fn parse_name(table: &[u8], name_id: u16) -> Option<String> {
+    let count = read_u16(table, 2)? as usize;
+    let storage = read_u16(table, 4)? as usize;
+
+    let mut best: Option<(u8, &[u8], bool)> = None;
+    for i in 0..count {
+        let record = 6 + i * 12;
+        if read_u16(table, record + 6)? != name_id {
+            continue;
+        }
+        let platform = read_u16(table, record)?;
+        let encoding = read_u16(table, record + 2)?;
+        let language = read_u16(table, record + 4)?;
+        let (rank, utf16) = match (platform, encoding) {
+            (3, 1) | (3, 10) if language == 0x409 => (0, true),
+            (3, 1) | (3, 10) | (0, _) => (1, true),
+            (1, 0) if language == 0 => (2, false),
+            _ => continue,
+        };
+        if best.map_or(false, |(best_rank, _, _)| best_rank <= rank) {
+            continue;
+        }
+        let len = read_u16(table, record + 8)? as usize;
+        let start = storage + read_u16(table, record + 10)? as usize;
+        if let Some(data) = table.get(start..start + len) {
+            best.replace((rank, data, utf16));
+        }
+    }
+
+    let (_, data, utf16) = best?;
+    let name = if utf16 {
+        let units: Vec<u16> = data
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        // Only the ASCII subset of Mac Roman is the same as latin-1;
+        // that is sufficient for the names that we look up
+        data.iter().map(|&b| b as char).collect()
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// Parse the pairs of the format 0 horizontal kerning subtables of a
+/// Windows style (version 0) kern table, returning the left glyph,
+/// right glyph and x-kerning in font units of each pair, ordered by
+/// glyph.  When several subtables kern the same pair, their values are
+/// added together, unless a subtable overrides the accumulated value.
+/// Subtables in the other formats, vertical, cross-stream and minimum
+/// value subtables are skipped, as are the subtables of Apple style
+/// (version 1) tables, which are reported as None.
+pub fn parse_kern_pairs(table: &[u8]) -> Option<Vec<(u16, u16, i32)>> {
+    const HORIZONTAL: u16 = 0x01;
+    const MINIMUM: u16 = 0x02;
+    const CROSS_STREAM: u16 = 0x04;
+    const OVERRIDE: u16 = 0x08;
+
+    if read_u16(table, 0)? != 0 {
+        return None;
+    }
+    let num_tables = read_u16(table, 2)? as usize;
+    let mut pairs = std::collections::BTreeMap::new();
+    let mut subtable = 4;
+    for _ in 0..num_tables {
+        let length = read_u16(table, subtable + 2)? as usize;
+        let coverage = read_u16(table, subtable + 4)?;
+        let format = coverage >> 8;
+        if format == 0 && coverage & (HORIZONTAL | MINIMUM | CROSS_STREAM) == HORIZONTAL {
+            let num_pairs = read_u16(table, subtable + 6)? as usize;
+            for i in 0..num_pairs {
+                // Each pair is left, right, value after the 14 byte
+                // subtable header
+                let pair = subtable + 14 + i * 6;
+                let left = read_u16(table, pair)?;
+                let right = read_u16(table, pair + 2)?;
+                let value = read_u16(table, pair + 4)? as i16 as i32;
+                let entry = pairs.entry((left, right)).or_insert(0);
+                if coverage & OVERRIDE != 0 {
+                    *entry = value;
+                } else {
+                    *entry += value;
+                }
+            }
+        }
+        if length < 6 {
+            return None;
+        }
+        subtable += length;
+    }
+    Some(
+        pairs
+            .into_iter()
+            .map(|((left, right), value)| (left, right, value))
+            .collect(),
+    )
+}
+
+/// Parse a CPAL table and return the paletteTypes flags of each of
+/// its palettes.  Version 0 tables, and version 1 tables without a
+/// palette types array, have no flags, so every palette is reported
+/// as 0.
+pub fn parse_palette_types(table: &[u8]) -> Option<Vec<u32>> {
+    let version = read_u16(table, 0)?;
+    let num_palettes = read_u16(table, 4)? as usize;
+    // The header is followed by the colorRecordIndices array, and in
+    // version 1, by the offset to the paletteTypes array
+    let types_offset = match version {
+        0 => 0,
+        _ => read_u32(table, 12 + num_palettes * 2)? as usize,
+    };
+    if types_offset == 0 {
+        return Some(vec![0; num_palettes]);
+    }
+    (0..num_palettes)
+        .map(|i| read_u32(table, types_offset + i * 4))
+        .collect()
+}
+
+/// Parse a CBLC table, or an EBLC table, which has the same layout,
+/// and return the bitDepth of each of its strikes in the order in
+/// which they are listed
+pub fn parse_strike_bit_depths(table: &[u8]) -> Option<Vec<u8>> {
+    const BITMAP_SIZE_LEN: usize = 48;
+    const BIT_DEPTH_OFFSET: usize = 46;
+    let num_sizes = read_u32(table, 4)? as usize;
+    (0..num_sizes)
+        .map(|i| {
+            table
+                .get(8 + i * BITMAP_SIZE_LEN + BIT_DEPTH_OFFSET)
+                .copied()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn feature_tags() {
+        let mut table = vec![0, 1, 0, 0, 0, 10, 0, 10, 0, 0];
+        // FeatureList at offset 10 with two records
+        table.extend_from_slice(&[0, 2]);
+        table.extend_from_slice(b"calt");
+        table.extend_from_slice(&[0, 0]);
+        table.extend_from_slice(b"liga");
+        table.extend_from_slice(&[0, 0]);
+
+        assert_eq!(
+            parse_feature_tags(&table),
+            Some(vec![make_tag(b"calt"), make_tag(b"liga")])
+        );
+
+        // Truncated records are rejected
+        assert_eq!(parse_feature_tags(&table[0..18]), None);
+        // As is an unknown major version
+        table[1] = 2;
+        assert_eq!(parse_feature_tags(&table), None);
+    }
+
+    #[test]
+    fn single_substitutions() {
+        fn push(table: &mut Vec<u8>, values: &[u16]) {
+            for value in values {
+                table.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        let mut table = vec![];
+        // The FeatureList is at 10 and the LookupList at 38
+        push(&mut table, &[1, 0, 0, 10, 38]);
+        // rtlm uses lookups 1 and 0, and liga uses lookup 2
+        push(&mut table, &[2]);
+        table.extend_from_slice(b"rtlm");
+        push(&mut table, &[14]);
+        table.extend_from_slice(b"liga");
+        push(&mut table, &[22]);
+        push(&mut table, &[0, 2, 1, 0]);
+        push(&mut table, &[0, 1, 2]);
+        assert_eq!(table.len(), 38);
+        push(&mut table, &[3, 8, 30, 66]);
+
+        // Lookup 0 adds 10 to glyphs 3 and 5
+        push(&mut table, &[1, 0, 1, 8]);
+        push(&mut table, &[1, 6, 10]);
+        push(&mut table, &[1, 2, 3, 5]);
+        assert_eq!(table.len(), 38 + 30);
+
+        // Lookup 1 is an extension that maps glyphs 5 and 6 to 20 and 21
+        push(&mut table, &[7, 0, 1, 8]);
+        push(&mut table, &[1, 1, 0, 8]);
+        push(&mut table, &[2, 10, 2, 20, 21]);
+        push(&mut table, &[2, 1, 5, 6, 0]);
+        assert_eq!(table.len(), 38 + 66);
+
+        // Lookup 2 is a ligature lookup without subtables
+        push(&mut table, &[4, 0, 0]);
+
+        // Lookup 0 comes first, so it substitutes glyph 5
+        assert_eq!(
+            parse_single_substitutions(&table, make_tag(b"rtlm")),
+            Some(vec![(3, 13), (5, 15), (6, 21)])
+        );
+        assert_eq!(
+            parse_single_substitutions(&table, make_tag(b"liga")),
+            Some(vec![])
+        );
+        assert_eq!(
+            parse_single_substitutions(&table, make_tag(b"ss01")),
+            Some(vec![])
+        );
+        assert_eq!(
+            parse_single_substitutions(&table[..38 + 60], make_tag(b"rtlm")),
+            None
+        );
+    }
+
+    #[test]
+    fn tags_from_str() {
+        assert_eq!(tag_from_str("liga"), Some(make_tag(b"liga")));
+        assert_eq!(tag_from_str("cv1"), Some(make_tag(b"cv1 ")));
+        assert_eq!(tag_from_str(""), None);
+        assert_eq!(tag_from_str("toolong"), None);
+    }
+
+    #[test]
+    fn names() {
+        fn record(table: &mut Vec<u8>, ids: [u16; 4], len: u16, offset: u16) {
+            for value in &ids {
+                table.extend_from_slice(&value.to_be_bytes());
+            }
+            table.extend_from_slice(&len.to_be_bytes());
+            table.extend_from_slice(&offset.to_be_bytes());
+        }
+
+        // A Mac Roman and a US English Windows version string,
+        // and an empty license record
+        let mut table = vec![0, 0, 0, 3, 0, 42];
+        record(&mut table, [1, 0, 0, 5], 3, 0);
+        record(&mut table, [3, 1, 0x409, 5], 6, 3);
+        record(&mut table, [3, 1, 0x409, 13], 0, 9);
+        table.extend_from_slice(b"1.0");
+        table.extend_from_slice(&[0, b'2', 0, b'.', 0, b'0']);
+
+        assert_eq!(parse_name(&table, NAME_ID_VERSION), Some("2.0".to_string()));
+        assert_eq!(parse_name(&table, NAME_ID_LICENSE), None);
+        assert_eq!(parse_name(&table, NAME_ID_UNIQUE_ID), None);
+
+        // Without the Windows record, the Mac one is used
+        table[19] = 2;
+        assert_eq!(parse_name(&table, NAME_ID_VERSION), Some("1.0".to_string()));
+
+        // Truncated tables are rejected
+        assert_eq!(parse_name(&table[..20], NAME_ID_VERSION), None);
+    }
+
+    #[test]
+    fn palette_types() {
+        // Three palettes of one color each, with the palette types
+        // at offset 30 and the color records at offset 42
+        let mut table = vec![0, 1, 0, 1, 0, 3, 0, 3, 0, 0, 0, 42, 0, 0, 0, 1, 0, 2];
+        table.extend_from_slice(&[0, 0, 0, 30, 0, 0, 0, 0, 0, 0, 0, 0]);
+        table.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0]);
+        table.extend_from_slice(&[0; 12]);
+        assert_eq!(parse_palette_types(&table), Some(vec![1, 2, 0]));
+
+        // Without the palette types array, there are no flags
+        table[21] = 0;
+        assert_eq!(parse_palette_types(&table), Some(vec![0, 0, 0]));
+        // nor for a version 0 table
+        table[21] = 30;
+        table[1] = 0;
+        assert_eq!(parse_palette_types(&table), Some(vec![0, 0, 0]));
+
+        // Truncated tables are rejected
+        table[1] = 1;
+        assert_eq!(parse_palette_types(&table[..38]), None);
+    }
+
+    #[test]
+    fn kern_pairs() {
+        fn subtable(table: &mut Vec<u8>, coverage: u16, pairs: &[(u16, u16, i16)]) {
+            let length = 14 + pairs.len() * 6;
+            for value in &[0, length as u16, coverage, pairs.len() as u16, 0, 0, 0] {
+                table.extend_from_slice(&value.to_be_bytes());
+            }
+            for &(left, right, value) in pairs {
+                table.extend_from_slice(&left.to_be_bytes());
+                table.extend_from_slice(&right.to_be_bytes());
+                table.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+
+        let mut table = vec![0, 0, 0, 4];
+        subtable(&mut table, 0x0001, &[(3, 4, -50), (1, 2, 10)]);
+        // Vertical and minimum subtables are skipped
+        subtable(&mut table, 0x0000, &[(1, 2, 1000)]);
+        subtable(&mut table, 0x0003, &[(1, 2, 1000)]);
+        // Values for the same pair accumulate
+        subtable(&mut table, 0x0001, &[(1, 2, 5)]);
+
+        assert_eq!(
+            parse_kern_pairs(&table),
+            Some(vec![(1, 2, 15), (3, 4, -50)])
+        );
+
+        // unless the subtable overrides them
+        table[3] = 5;
+        subtable(&mut table, 0x0009, &[(3, 4, 20)]);
+        assert_eq!(parse_kern_pairs(&table), Some(vec![(1, 2, 15), (3, 4, 20)]));
+
+        // Truncated pairs are rejected
+        assert_eq!(parse_kern_pairs(&table[..table.len() - 2]), None);
+        // as are Apple style tables
+        assert_eq!(parse_kern_pairs(&[0, 1, 0, 0, 0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn strike_bit_depths() {
+        let mut table = vec![0, 3, 0, 0, 0, 0, 0, 2];
+        for &(ppem, depth) in &[(109u8, 32u8), (20, 8)] {
+            let mut size = vec![0u8; 48];
+            size[44] = ppem;
+            size[45] = ppem;
+            size[46] = depth;
+            table.extend_from_slice(&size);
+        }
+        assert_eq!(parse_strike_bit_depths(&table), Some(vec![32, 8]));
+        assert_eq!(parse_strike_bit_depths(&table[..8]), None);
+        assert_eq!(
+            parse_strike_bit_depths(&[0, 3, 0, 0, 0, 0, 0, 0]),
+            Some(vec![])
+        );
+    }
+}
@@ -446,7 +446,11 @@ pub struct AllsortsShaper {
 }
 
 impl AllsortsShaper {
-    pub fn new(_: &config::ConfigHandle, handles: &[ParsedFont]) -> anyhow::Result<Self> {
+    pub fn new(
+        _: &config::ConfigHandle,
+        handles: &[ParsedFont],
+        _: &crate::ftwrap::LoadFlags,
+    ) -> anyhow::Result<Self> {
         let mut fonts = vec![];
         let mut success = false;
         for handle in handles {
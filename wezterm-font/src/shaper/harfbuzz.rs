@@ -4,9 +4,10 @@ use crate::parser::ParsedFont;
 use crate::shaper::{FallbackIdx, FontMetrics, FontShaper, GlyphInfo};
 use crate::units::*;
 use anyhow::{anyhow, Context};
-use config::ConfigHandle;
+use config::{ConfigHandle, GlyphSubstitutionMap};
 use log::error;
 use ordered_float::NotNan;
+use rangeset::RangeSet;
 use std::cell::{RefCell, RefMut};
 use std::collections::HashMap;
 use termwiz::cell::unicode_column_width;
@@ -31,9 +32,18 @@ impl<'a> std::fmt::Debug for Info<'a> {
     }
 }
 
-fn make_glyphinfo(text: &str, font_idx: usize, info: &Info) -> GlyphInfo {
+/// `transform` is that of the face that shaped the glyph; harfbuzz
+/// measures the glyphs without it, so it is applied here
+fn make_glyphinfo(
+    text: &str,
+    font_idx: usize,
+    info: &Info,
+    transform: &ftwrap::FaceTransform,
+) -> GlyphInfo {
     let num_cells = unicode_column_width(text) as u8;
     let is_space = text == " ";
+    let x_scale = transform.x_scale;
+    let y_scale = transform.y_scale;
     GlyphInfo {
         #[cfg(debug_assertions)]
         text: text.into(),
@@ -42,10 +52,10 @@ fn make_glyphinfo(text: &str, font_idx: usize, info: &Info) -> GlyphInfo {
         font_idx,
         glyph_pos: info.codepoint,
         cluster: info.cluster as u32,
-        x_advance: PixelLength::new(f64::from(info.pos.x_advance) / 64.0),
-        y_advance: PixelLength::new(f64::from(info.pos.y_advance) / 64.0),
-        x_offset: PixelLength::new(f64::from(info.pos.x_offset) / 64.0),
-        y_offset: PixelLength::new(f64::from(info.pos.y_offset) / 64.0),
+        x_advance: PixelLength::new(f64::from(info.pos.x_advance) * x_scale / 64.0),
+        y_advance: PixelLength::new(f64::from(info.pos.y_advance) * y_scale / 64.0),
+        x_offset: PixelLength::new(f64::from(info.pos.x_offset) * x_scale / 64.0),
+        y_offset: PixelLength::new(f64::from(info.pos.y_offset) * y_scale / 64.0),
     }
 }
 
@@ -66,8 +76,12 @@ pub struct HarfbuzzShaper {
     handles: Vec<ParsedFont>,
     fonts: Vec<RefCell<Option<FontPair>>>,
     lib: ftwrap::Library,
+    /// The load flags for the display that is being rendered for,
+    /// which influence the hinted advances
+    load_flags: ftwrap::FT_Int32,
     metrics: RefCell<HashMap<MetricsKey, FontMetrics>>,
     features: Vec<harfbuzz::hb_feature_t>,
+    substitutions: GlyphSubstitutionMap,
 }
 
 #[derive(Error, Debug)]
@@ -104,7 +118,11 @@ fn is_question_string(s: &str) -> bool {
 }
 
 impl HarfbuzzShaper {
-    pub fn new(config: &ConfigHandle, handles: &[ParsedFont]) -> anyhow::Result<Self> {
+    pub fn new(
+        config: &ConfigHandle,
+        handles: &[ParsedFont],
+        load_flags: &ftwrap::LoadFlags,
+    ) -> anyhow::Result<Self> {
         let lib = ftwrap::Library::new()?;
         let handles = handles.to_vec();
         let mut fonts = vec![];
@@ -122,8 +140,10 @@ impl HarfbuzzShaper {
             fonts,
             handles,
             lib,
+            load_flags: load_flags.load_flags,
             metrics: RefCell::new(HashMap::new()),
             features,
+            substitutions: config.glyph_substitution_map.clone(),
         })
     }
 
@@ -137,10 +157,20 @@ impl HarfbuzzShaper {
                 let mut opt_pair = opt_pair.borrow_mut();
                 if opt_pair.is_none() {
                     log::trace!("shaper wants {} {:?}", font_idx, &self.handles[font_idx]);
-                    let face = self.lib.face_from_locator(&self.handles[font_idx].handle)?;
+                    let parsed = &self.handles[font_idx];
+                    let mut face = self.lib.face_from_locator(&parsed.handle)?;
+                    // The transform is applied to the face so that its
+                    // cell metrics account for it, but harfbuzz is told
+                    // to ignore it so that the glyph positions can be
+                    // scaled consistently in make_glyphinfo
+                    let transform = parsed.transform();
+                    face.set_transform(
+                        transform.x_scale,
+                        transform.y_scale,
+                        transform.slant_degrees,
+                    );
                     let mut font = harfbuzz::Font::new(face.face);
-                    let (load_flags, _) = ftwrap::compute_load_flags_from_config();
-                    font.set_load_flags(load_flags);
+                    font.set_load_flags(self.load_flags | ftwrap::FT_LOAD_IGNORE_TRANSFORM as i32);
                     *opt_pair = Some(FontPair {
                         face,
                         font,
@@ -174,6 +204,7 @@ impl HarfbuzzShaper {
 
         let cell_width;
         let shaped_any;
+        let transform;
 
         {
             match self.load_fallback(font_idx).context("load_fallback")? {
@@ -181,6 +212,7 @@ impl HarfbuzzShaper {
                     let size = pair.face.set_font_size(font_size, dpi)?;
                     cell_width = size.width;
                     shaped_any = pair.shaped_any;
+                    transform = pair.face.transform();
                     pair.font.shape(&mut buf, self.features.as_slice());
                 }
                 None => {
@@ -197,7 +229,7 @@ impl HarfbuzzShaper {
             }
         }
 
-        if font_idx > 0 && font_idx + 1 == self.fonts.len() {
+        if font_idx > 0 && self.handles[font_idx].is_last_resort() {
             // We are the last resort font, so each codepoint is considered
             // to be worthy of a fallback lookup
             for c in s.chars() {
@@ -302,8 +334,9 @@ impl HarfbuzzShaper {
                     continue;
                 }
 
-                let nom_width =
-                    ((f64::from(info.pos.x_advance) / 64.0) / cell_width).ceil() as usize;
+                let nom_width = ((f64::from(info.pos.x_advance) * transform.x_scale / 64.0)
+                    / cell_width)
+                    .ceil() as usize;
 
                 let len;
                 if nom_width == 0 || !substr.is_char_boundary(next_idx + nom_width) {
@@ -319,9 +352,9 @@ impl HarfbuzzShaper {
 
                 let glyph = if len > 0 {
                     let text = &substr[next_idx..next_idx + len];
-                    make_glyphinfo(text, font_idx, info)
+                    make_glyphinfo(text, font_idx, info, &transform)
                 } else {
-                    make_glyphinfo("__", font_idx, info)
+                    make_glyphinfo("__", font_idx, info, &transform)
                 };
 
                 if glyph.x_advance != PixelLength::new(0.0) {
@@ -355,6 +388,128 @@ impl HarfbuzzShaper {
 
         Ok(cluster)
     }
+
+    /// Returns the index of the font that unicode_range_rules assigned
+    /// to all of the codepoints in `grapheme`, provided that the font
+    /// has glyphs for them
+    fn range_font_for_grapheme(&self, grapheme: &str) -> Option<FallbackIdx> {
+        let mut wanted = RangeSet::new();
+        for c in grapheme.chars() {
+            wanted.add(c as u32);
+        }
+        self.handles
+            .iter()
+            .enumerate()
+            .position(|(font_idx, p)| match p.unicode_ranges() {
+                Some(ranges) => {
+                    ranges.intersection(&wanted) == wanted
+                        && self.font_covers(font_idx, grapheme, &wanted)
+                }
+                None => false,
+            })
+    }
+
+    /// Returns true if the font at `font_idx` has glyphs for all of the
+    /// codepoints of `text`, which are those in `wanted`.
+    /// If the face is already loaded, its charmaps are probed for just
+    /// those codepoints, which is much cheaper than computing the
+    /// coverage of the font.
+    fn font_covers(&self, font_idx: FallbackIdx, text: &str, wanted: &RangeSet<u32>) -> bool {
+        let parsed = &self.handles[font_idx];
+        if !parsed.is_last_resort() {
+            if let Ok(opt_pair) = self.fonts[font_idx].try_borrow() {
+                if let Some(pair) = opt_pair.as_ref() {
+                    return pair.face.covers_str(text);
+                }
+            }
+        }
+        parsed
+            .coverage_intersection(wanted)
+            .map(|cov| cov == *wanted)
+            .unwrap_or(false)
+    }
+
+    /// Returns `grapheme` with any codepoints that glyph_substitutions
+    /// replaces with a different codepoint substituted, or None if
+    /// none of them are replaced
+    fn substitute_grapheme(&self, grapheme: &str) -> Option<String> {
+        if !grapheme
+            .chars()
+            .any(|c| self.substitutions.replacement_char(c).is_some())
+        {
+            return None;
+        }
+        Some(
+            grapheme
+                .chars()
+                .map(|c| self.substitutions.replacement_char(c).unwrap_or(c))
+                .collect(),
+        )
+    }
+
+    /// Split the text into runs according to unicode_range_rules and
+    /// glyph_substitutions, and shape the runs that fall in those ranges
+    /// using the assigned font rather than starting from the primary font.
+    /// Graphemes with replaced codepoints are shaped from their replacement
+    /// text, but their glyphs are attributed to the original text.
+    fn shape_with_ranges(
+        &self,
+        text: &str,
+        font_size: f64,
+        dpi: u32,
+        no_glyphs: &mut Vec<char>,
+    ) -> anyhow::Result<Vec<GlyphInfo>> {
+        if self.substitutions.is_empty()
+            && self.handles.iter().all(|p| p.unicode_ranges().is_none())
+        {
+            return self.do_shape(0, text, font_size, dpi, no_glyphs);
+        }
+
+        let mut runs: Vec<(usize, usize, Option<FallbackIdx>, Option<String>)> = vec![];
+        for (start, grapheme) in text.grapheme_indices(true) {
+            if let Some(replacement) = self.substitute_grapheme(grapheme) {
+                let font_idx = self.range_font_for_grapheme(&replacement);
+                runs.push((start, grapheme.len(), font_idx, Some(replacement)));
+                continue;
+            }
+            let font_idx = self.range_font_for_grapheme(grapheme);
+            match runs.last_mut() {
+                Some((_, len, idx, None)) if *idx == font_idx => *len += grapheme.len(),
+                _ => runs.push((start, grapheme.len(), font_idx, None)),
+            }
+        }
+
+        let mut result = vec![];
+        for (start, len, font_idx, replacement) in runs {
+            let original = &text[start..start + len];
+            let mut shape = self.do_shape(
+                font_idx.unwrap_or(0),
+                replacement.as_deref().unwrap_or(original),
+                font_size,
+                dpi,
+                no_glyphs,
+            )?;
+            if replacement.is_some() {
+                let num_cells = unicode_column_width(original) as u8;
+                let single = shape.len() == 1;
+                for info in &mut shape {
+                    info.cluster = 0;
+                    if single {
+                        info.num_cells = num_cells;
+                    }
+                    #[cfg(debug_assertions)]
+                    {
+                        info.text = original.into();
+                    }
+                }
+            }
+            for info in &mut shape {
+                info.cluster += start as u32;
+            }
+            result.append(&mut shape);
+        }
+        Ok(result)
+    }
 }
 
 impl FontShaper for HarfbuzzShaper {
@@ -367,7 +522,7 @@ impl FontShaper for HarfbuzzShaper {
     ) -> anyhow::Result<Vec<GlyphInfo>> {
         log::trace!("shape {} `{}`", text.len(), text);
         let start = std::time::Instant::now();
-        let result = self.do_shape(0, text, size, dpi, no_glyphs);
+        let result = self.shape_with_ranges(text, size, dpi, no_glyphs);
         metrics::histogram!("shape.harfbuzz", start.elapsed());
         /*
         if let Ok(glyphs) = &result {
@@ -476,8 +631,46 @@ impl FontShaper for HarfbuzzShaper {
             metrics_idx += 1;
         }
 
-        self.metrics_for_idx(metrics_idx, size, dpi)
+        if metrics_idx == 0 || metrics_idx >= self.handles.len() {
+            // The cell geometry comes from the primary font alone,
+            // including when none of the fonts has a plausible size
+            return self.metrics_for_idx(0, size, dpi);
+        }
+
+        let primary = self.metrics_for_idx(0, size, dpi)?;
+        let fallback = self.metrics_for_idx(metrics_idx, size, dpi)?;
+        log::warn!(
+            "The size of {} is implausible for font_size={} at {}dpi, \
+             so the cell dimensions are taken from the fallback font {}",
+            self.handles[0].names().full_name,
+            size,
+            dpi,
+            self.handles[metrics_idx].names().full_name
+        );
+        Ok(clamp_fallback_metrics(&primary, fallback))
+    }
+}
+
+/// Limit `fallback`, the metrics of a fallback font that are being used
+/// for the cell because those of the primary font are implausible, so
+/// that the fallback can shrink the cell but never make it taller than
+/// the primary font would have.  Glyphs that are taller than the cell
+/// are dealt with when they are rendered, rather than by growing the cell.
+fn clamp_fallback_metrics(primary: &FontMetrics, mut fallback: FontMetrics) -> FontMetrics {
+    if fallback.cell_height > primary.cell_height {
+        log::warn!(
+            "Clamping the cell height from the fallback font, {}px, \
+             to that of the primary font, {}px",
+            fallback.cell_height.get(),
+            primary.cell_height.get()
+        );
+        // Keep the baseline and underline in the same relative place
+        let scale = primary.cell_height.get() / fallback.cell_height.get();
+        fallback.descender = PixelLength::new(fallback.descender.get() * scale);
+        fallback.underline_position = PixelLength::new(fallback.underline_position.get() * scale);
+        fallback.cell_height = primary.cell_height;
     }
+    fallback
 }
 
 #[cfg(test)]
@@ -503,13 +696,24 @@ mod test {
                 is_fallback: false,
                 is_synthetic: false,
                 italic: false,
+                assume_emoji_presentation: None,
+                allow_synthetic_bold: true,
+                allow_synthetic_italic: true,
+                baseline_offset: None,
+                horizontal_scale: None,
+                slant_degrees: None,
             })
             .unwrap()
             .clone();
 
         let config = config::configuration();
 
-        let shaper = HarfbuzzShaper::new(&config, &[handle]).unwrap();
+        let shaper = HarfbuzzShaper::new(
+            &config,
+            &[handle],
+            &ftwrap::compute_load_flags(&config, &Default::default()),
+        )
+        .unwrap();
         {
             let mut no_glyphs = vec![];
             let info = shaper.shape("abc", 10., 72, &mut no_glyphs).unwrap();
@@ -668,4 +872,170 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn glyph_substitutions() {
+        let db = FontDatabase::with_built_in().unwrap();
+        let handle = db
+            .resolve(&FontAttributes::new("JetBrains Mono"))
+            .unwrap()
+            .clone();
+
+        let config = config::configuration();
+        let mut shaper = HarfbuzzShaper::new(
+            &config,
+            &[handle],
+            &ftwrap::compute_load_flags(&config, &Default::default()),
+        )
+        .unwrap();
+        let mut no_glyphs = vec![];
+        let dash = shaper.shape("-", 10., 72, &mut no_glyphs).unwrap();
+
+        shaper.substitutions = GlyphSubstitutionMap::compile(&[config::GlyphSubstitution {
+            codepoints: "U+2014".parse().unwrap(),
+            replacement: Some("-".to_string()),
+            font: None,
+        }]);
+        let info = shaper.shape("a\u{2014}b", 10., 72, &mut no_glyphs).unwrap();
+        assert!(no_glyphs.is_empty(), "{:?}", no_glyphs);
+        assert_eq!(
+            info.iter().map(|info| info.cluster).collect::<Vec<_>>(),
+            vec![0, 1, 4]
+        );
+        assert_eq!(info[1].glyph_pos, dash[0].glyph_pos);
+        assert_eq!(info[1].num_cells, 1);
+    }
+
+    #[test]
+    fn cell_metrics_come_from_primary() {
+        let db = FontDatabase::with_built_in().unwrap();
+        let handles = ["JetBrains Mono", "Noto Color Emoji"]
+            .iter()
+            .map(|family| db.resolve(&FontAttributes::new(family)).unwrap().clone())
+            .collect::<Vec<_>>();
+
+        let config = config::configuration();
+        let shaper = HarfbuzzShaper::new(
+            &config,
+            &handles,
+            &ftwrap::compute_load_flags(&config, &Default::default()),
+        )
+        .unwrap();
+        for &size in &[8., 10., 12., 24.] {
+            let metrics = shaper.metrics(size, 96).unwrap();
+            let primary = shaper.metrics_for_idx(0, size, 96).unwrap();
+            assert_eq!(metrics.cell_height, primary.cell_height);
+            assert_eq!(metrics.cell_width, primary.cell_width);
+        }
+    }
+
+    #[test]
+    fn fallback_metrics_are_clamped() {
+        let metrics = |height: f64, descender: f64| FontMetrics {
+            cell_width: PixelLength::new(8.),
+            cell_height: PixelLength::new(height),
+            descender: PixelLength::new(descender),
+            underline_thickness: PixelLength::new(1.),
+            underline_position: PixelLength::new(-2.),
+            cap_height_ratio: None,
+            is_scaled: true,
+        };
+        let primary = metrics(16., -4.);
+
+        // A fallback may make the cell shorter
+        let shorter = metrics(14., -3.);
+        assert_eq!(clamp_fallback_metrics(&primary, shorter), shorter);
+
+        // but not taller
+        let clamped = clamp_fallback_metrics(&primary, metrics(32., -8.));
+        assert_eq!(clamped.cell_height, primary.cell_height);
+        assert_eq!(clamped.descender, PixelLength::new(-4.));
+        assert_eq!(clamped.underline_position, PixelLength::new(-1.));
+    }
+
+    #[test]
+    fn cell_dimension_rounding() {
+        use config::CellDimensionRounding::{Ceil, Floor, Round};
+
+        let db = FontDatabase::with_built_in().unwrap();
+        let handle = db
+            .resolve(&FontAttributes::new("JetBrains Mono"))
+            .unwrap()
+            .clone();
+
+        let config = config::configuration();
+        let shaper = HarfbuzzShaper::new(
+            &config,
+            &[handle],
+            &ftwrap::compute_load_flags(&config, &Default::default()),
+        )
+        .unwrap();
+
+        // JetBrains Mono has 1000 units per em, an advance of 600 units
+        // and a line height of 1320 units, so at 96 dpi these sizes
+        // yield 12, 16, 20 and 24 pixels per em.  The advance is hinted
+        // to a whole pixel, while the height remains fractional.
+        for &(size, width, floor, round, ceil) in &[
+            (9., 7, 15, 16, 16),
+            (12., 10, 21, 21, 22),
+            (15., 12, 26, 26, 27),
+            (18., 14, 31, 32, 32),
+        ] {
+            let metrics = shaper.metrics(size, 96).unwrap();
+            for &(rounding, height) in &[(Floor, floor), (Round, round), (Ceil, ceil)] {
+                // The size and policy are included to identify a failure
+                assert_eq!(
+                    (
+                        size.to_string(),
+                        rounding,
+                        metrics.cell_pixel_size(1.0, rounding)
+                    ),
+                    (size.to_string(), rounding, (width, height))
+                );
+            }
+        }
+
+        // line_height is applied before rounding
+        let metrics = shaper.metrics(12., 96).unwrap();
+        assert_eq!(metrics.cell_pixel_size(1.2, Floor), (10, 25));
+        assert_eq!(metrics.cell_pixel_size(1.2, Ceil), (10, 26));
+    }
+
+    #[test]
+    fn horizontal_scale() {
+        config::use_test_configuration();
+        let db = FontDatabase::with_built_in().unwrap();
+        let plain = db
+            .resolve(&FontAttributes::new("JetBrains Mono"))
+            .unwrap()
+            .clone();
+        let mut condensed = plain.clone();
+        condensed.set_transform(
+            Some(std::convert::TryFrom::try_from(0.5).unwrap()),
+            Some(std::convert::TryFrom::try_from(20.).unwrap()),
+        );
+        let config = config::configuration();
+
+        let advances = |handle: &ParsedFont| {
+            let shaper = HarfbuzzShaper::new(
+                &config,
+                &[handle.clone()],
+                &ftwrap::compute_load_flags(&config, &Default::default()),
+            )
+            .unwrap();
+            let mut no_glyphs = vec![];
+            let info = shaper.shape("abc", 12., 96, &mut no_glyphs).unwrap();
+            let cell_width = shaper.metrics(12., 96).unwrap().cell_width.get();
+            (
+                info.iter().map(|g| g.x_advance.get()).collect::<Vec<_>>(),
+                info.iter().map(|g| g.num_cells).collect::<Vec<_>>(),
+                cell_width,
+            )
+        };
+
+        // The advances and the cell are scaled together, so each glyph
+        // still occupies a single cell; the slant doesn't change them
+        assert_eq!(advances(&plain), (vec![10., 10., 10.], vec![1, 1, 1], 10.));
+        assert_eq!(advances(&condensed), (vec![5., 5., 5.], vec![1, 1, 1], 5.));
+    }
 }
@@ -1,5 +1,7 @@
+use crate::ftwrap::LoadFlags;
 use crate::parser::ParsedFont;
 use crate::units::PixelLength;
+use config::CellDimensionRounding;
 
 pub mod allsorts;
 pub mod harfbuzz;
@@ -61,6 +63,23 @@ pub struct FontMetrics {
     pub is_scaled: bool,
 }
 
+impl FontMetrics {
+    /// Returns the `(width, height)` of a cell in whole pixels, with
+    /// the height scaled by `line_height`.
+    /// This is where the fractional cell dimensions are converted
+    /// to pixels, according to the `rounding` policy.
+    pub fn cell_pixel_size(
+        &self,
+        line_height: f64,
+        rounding: CellDimensionRounding,
+    ) -> (usize, usize) {
+        (
+            rounding.apply(self.cell_width.get()),
+            rounding.apply(self.cell_height.get() * line_height),
+        )
+    }
+}
+
 pub trait FontShaper {
     /// Shape text and return a vector of GlyphInfo
     fn shape(
@@ -84,13 +103,14 @@ pub use config::FontShaperSelection;
 pub fn new_shaper(
     config: &config::ConfigHandle,
     handles: &[ParsedFont],
+    load_flags: &LoadFlags,
 ) -> anyhow::Result<Box<dyn FontShaper>> {
     match config.font_shaper {
-        FontShaperSelection::Harfbuzz => {
-            Ok(Box::new(harfbuzz::HarfbuzzShaper::new(config, handles)?))
-        }
-        FontShaperSelection::Allsorts => {
-            Ok(Box::new(allsorts::AllsortsShaper::new(config, handles)?))
-        }
+        FontShaperSelection::Harfbuzz => Ok(Box::new(harfbuzz::HarfbuzzShaper::new(
+            config, handles, load_flags,
+        )?)),
+        FontShaperSelection::Allsorts => Ok(Box::new(allsorts::AllsortsShaper::new(
+            config, handles, load_flags,
+        )?)),
     }
 }
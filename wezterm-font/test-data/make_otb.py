@@ -0,0 +1,254 @@
+#!/usr/bin/env python3
+"""Generates wezterm-test-8x16.otb, a minimal bitmap-only OpenType font
+with a single 8x16 strike, for the wezterm-font tests.
+
+The font has a .notdef box, a space and the letter A, each of which is
+8 pixels wide.  The strike has an ascender of 13 pixels and a descender
+of 3 pixels."""
+
+import struct
+import sys
+
+PPEM = 16
+WIDTH = 8
+ASCENDER = 13
+DESCENDER = -3
+UNITS_PER_EM = PPEM
+
+GLYPHS = [
+    # name, codepoint, rows of the bitmap from the top, bearing_y
+    (
+        ".notdef",
+        None,
+        ["11111110"] + ["10000010"] * 10 + ["11111110"],
+        12,
+    ),
+    ("space", 0x20, [], 0),
+    (
+        "A",
+        0x41,
+        [
+            "00010000",
+            "00101000",
+            "01000100",
+            "10000010",
+            "10000010",
+            "11111110",
+            "10000010",
+            "10000010",
+            "10000010",
+            "10000010",
+        ],
+        10,
+    ),
+]
+
+
+def checksum(data):
+    data += b"\0" * (-len(data) % 4)
+    return sum(struct.unpack(">%dI" % (len(data) // 4), data)) & 0xFFFFFFFF
+
+
+def head():
+    return struct.pack(
+        ">IIIIHHqqhhhhHHhhh",
+        0x00010000,  # version
+        0x00010000,  # fontRevision
+        0,  # checksumAdjustment, patched later
+        0x5F0F3CF5,  # magicNumber
+        0x000B,  # flags: baseline at 0, lsb at 0, integer ppem
+        UNITS_PER_EM,
+        0,  # created
+        0,  # modified
+        0,
+        DESCENDER,
+        WIDTH,
+        ASCENDER,
+        0,  # macStyle
+        8,  # lowestRecPPEM
+        2,  # fontDirectionHint
+        0,  # indexToLocFormat
+        0,  # glyphDataFormat
+    )
+
+
+def hhea():
+    return struct.pack(
+        ">IhhhHhhhhhhhhhhhH",
+        0x00010000,
+        ASCENDER,
+        DESCENDER,
+        0,  # lineGap
+        WIDTH,  # advanceWidthMax
+        0,
+        0,
+        WIDTH,  # xMaxExtent
+        1,  # caretSlopeRise
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,  # metricDataFormat
+        len(GLYPHS),
+    )
+
+
+def maxp():
+    # Version 0.5 is used by fonts without TrueType outlines
+    return struct.pack(">IH", 0x00005000, len(GLYPHS))
+
+
+def hmtx():
+    return b"".join(struct.pack(">Hh", WIDTH, 0) for _ in GLYPHS)
+
+
+def cmap():
+    mapped = sorted((cp, gid) for gid, (_, cp, _, _) in enumerate(GLYPHS) if cp)
+    # Format 4 with one segment per codepoint, plus the final 0xffff
+    segments = [(cp, cp, gid - cp) for cp, gid in mapped] + [(0xFFFF, 0xFFFF, 1)]
+    seg_count = len(segments)
+    search_range = 2 * (1 << (seg_count.bit_length() - 1))
+    subtable = struct.pack(
+        ">HHHHHHH",
+        4,
+        16 + 8 * seg_count,
+        0,
+        seg_count * 2,
+        search_range,
+        (search_range // 2).bit_length() - 1,
+        seg_count * 2 - search_range,
+    )
+    subtable += b"".join(struct.pack(">H", end) for _, end, _ in segments)
+    subtable += struct.pack(">H", 0)
+    subtable += b"".join(struct.pack(">H", start) for start, _, _ in segments)
+    subtable += b"".join(struct.pack(">h", delta) for _, _, delta in segments)
+    subtable += b"".join(struct.pack(">H", 0) for _ in segments)
+    return struct.pack(">HHHHI", 0, 1, 3, 1, 12) + subtable
+
+
+def name():
+    names = [
+        (1, "wezterm test"),
+        (2, "Regular"),
+        (4, "wezterm test Regular"),
+        (6, "weztermtest-Regular"),
+    ]
+    strings = b""
+    records = b""
+    for name_id, value in names:
+        encoded = value.encode("utf-16-be")
+        records += struct.pack(">HHHHHH", 3, 1, 0x409, name_id, len(encoded), len(strings))
+        strings += encoded
+    return struct.pack(">HHH", 0, len(names), 6 + 12 * len(names)) + records + strings
+
+
+def os2():
+    return struct.pack(
+        ">HhHHHhhhhhhhhhhh10sIIII4sHHHhhhHH",
+        1,  # version
+        WIDTH,  # xAvgCharWidth
+        400,  # usWeightClass
+        5,  # usWidthClass
+        0,  # fsType
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0,  # sub/superscript, strikeout
+        0,  # sFamilyClass
+        b"\0" * 10,  # panose
+        1,  # ulUnicodeRange1: Basic Latin
+        0,
+        0,
+        0,
+        b"wez ",
+        0x40,  # fsSelection: REGULAR
+        0x20,
+        0x41,
+        ASCENDER,
+        DESCENDER,
+        0,
+        ASCENDER,
+        -DESCENDER,
+    ) + struct.pack(">II", 1, 0)
+
+
+def post():
+    # Version 3 has no glyph names
+    return struct.pack(">IIhhIIIII", 0x00030000, 0, -2, 1, 1, 0, 0, 0, 0)
+
+
+def ebdt_eblc():
+    # Each glyph is stored using image format 1: small metrics followed
+    # by byte aligned rows
+    ebdt = struct.pack(">I", 0x00020000)
+    offsets = []
+    for _, _, rows, bearing_y in GLYPHS:
+        offsets.append(len(ebdt) - 4)
+        ebdt += struct.pack(">BBbbB", len(rows), WIDTH if rows else 0, 0, bearing_y, WIDTH)
+        ebdt += bytes(int(row, 2) for row in rows)
+    offsets.append(len(ebdt) - 4)
+
+    # A single index subtable of format 1, with 32-bit offsets
+    # relative to the image data offset of 4
+    index_subtable = struct.pack(">HHI", 1, 1, 4)
+    index_subtable += b"".join(struct.pack(">I", offset) for offset in offsets)
+    index_array = struct.pack(">HHI", 0, len(GLYPHS) - 1, 8)
+
+    def line_metrics():
+        return struct.pack(
+            ">bbBbbbbbbbbb", ASCENDER, DESCENDER, WIDTH, 0, 1, 0, 0, 0, 0, 0, 0, 0
+        )
+
+    size_table_len = 48
+    index_array_offset = 8 + size_table_len
+    index_tables_size = len(index_array) + len(index_subtable)
+    eblc = struct.pack(">II", 0x00020000, 1)
+    eblc += struct.pack(">IIII", index_array_offset, index_tables_size, 1, 0)
+    eblc += line_metrics() + line_metrics()
+    eblc += struct.pack(">HHBBBb", 0, len(GLYPHS) - 1, PPEM, PPEM, 1, 1)
+    eblc += index_array + index_subtable
+    return ebdt, eblc
+
+
+def build():
+    ebdt, eblc = ebdt_eblc()
+    tables = {
+        b"EBDT": ebdt,
+        b"EBLC": eblc,
+        b"OS/2": os2(),
+        b"cmap": cmap(),
+        b"head": head(),
+        b"hhea": hhea(),
+        b"hmtx": hmtx(),
+        b"maxp": maxp(),
+        b"name": name(),
+        b"post": post(),
+    }
+    num_tables = len(tables)
+    entry_selector = num_tables.bit_length() - 1
+    search_range = 16 * (1 << entry_selector)
+    font = struct.pack(
+        ">IHHHH", 0x00010000, num_tables, search_range, entry_selector,
+        num_tables * 16 - search_range,
+    )
+    offset = 12 + 16 * num_tables
+    directory = b""
+    data = b""
+    head_offset = None
+    for tag in sorted(tables):
+        table = tables[tag]
+        if tag == b"head":
+            head_offset = offset
+        directory += struct.pack(">4sIII", tag, checksum(table), offset, len(table))
+        padded = table + b"\0" * (-len(table) % 4)
+        data += padded
+        offset += len(padded)
+    font = bytearray(font + directory + data)
+    adjustment = (0xB1B0AFBA - checksum(bytes(font))) & 0xFFFFFFFF
+    font[head_offset + 8 : head_offset + 12] = struct.pack(">I", adjustment)
+    return bytes(font)
+
+
+if __name__ == "__main__":
+    path = sys.argv[1] if len(sys.argv) > 1 else "wezterm-test-8x16.otb"
+    with open(path, "wb") as f:
+        f.write(build())
@@ -110,4 +110,91 @@ pub struct ConnectCommand {
 }
 
 #[derive(Debug, StructOpt, Clone)]
-pub struct LsFontsCommand {}
+pub struct LsFontsCommand {
+    /// Explain the font selection by also listing the candidates
+    /// that were considered but not selected
+    #[structopt(long = "explain")]
+    pub explain: bool,
+
+    /// Instead of listing the fonts, shape and rasterize the
+    /// specified text and print a preview of it to the terminal
+    #[structopt(long = "rasterize")]
+    pub rasterize: Option<String>,
+
+    /// Instead of listing the fonts, explain which font, and
+    /// which glyph_substitutions, are used to render the
+    /// specified text
+    #[structopt(long = "text", conflicts_with = "rasterize")]
+    pub text: Option<String>,
+
+    /// With --text, use the font that font_rules select for
+    /// bold text
+    #[structopt(long = "bold", requires = "text")]
+    pub bold: bool,
+
+    /// With --text, use the font that font_rules select for
+    /// italic text
+    #[structopt(long = "italic", requires = "text")]
+    pub italic: bool,
+
+    /// Instead of listing the fonts, print the codepoints that
+    /// are covered by the specified font family
+    #[structopt(long = "coverage", conflicts_with_all = &["rasterize", "text"])]
+    pub coverage: Option<String>,
+
+    /// Instead of listing the fonts, compare the coverage of two font
+    /// families, printing the codepoints that are covered by only one
+    /// of them and a summary of the differences for each script
+    #[structopt(
+        long = "coverage-diff",
+        number_of_values = 2,
+        value_names = &["FONT_A", "FONT_B"],
+        conflicts_with_all = &["rasterize", "text", "coverage"]
+    )]
+    pub coverage_diff: Vec<String>,
+
+    /// With --coverage or --coverage-diff, print every range rather
+    /// than truncating a long list
+    #[structopt(long = "full")]
+    pub full: bool,
+
+    /// With --coverage or --coverage-diff, show this page of a long
+    /// list of ranges rather than the first
+    #[structopt(long = "page", conflicts_with = "full")]
+    pub page: Option<usize>,
+
+    /// Instead of listing the configured fonts, list all of the fonts
+    /// found in font_dirs and the built-in fonts, noting any that
+    /// font_allowed_formats excludes from fallback
+    #[structopt(
+        long = "all",
+        conflicts_with_all = &["rasterize", "text", "coverage", "coverage_diff"]
+    )]
+    pub all: bool,
+
+    /// With --rasterize, use this font family rather than
+    /// the configured font
+    #[structopt(long = "font", requires = "rasterize")]
+    pub font: Option<String>,
+
+    /// With --rasterize, use this font size in points rather
+    /// than the configured font_size
+    #[structopt(long = "size", requires = "rasterize")]
+    pub size: Option<f64>,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub struct FontDigestCommand {
+    /// The text to render.  A sample of latin letters, digits
+    /// and punctuation is rendered if not specified.
+    #[structopt(long = "text")]
+    pub text: Option<String>,
+
+    /// The point sizes at which to render the text
+    #[structopt(long = "size", default_value = "10", number_of_values = 1)]
+    pub sizes: Vec<f64>,
+
+    /// The dpi at which to render the text
+    #[structopt(long = "dpi", default_value = "96")]
+    pub dpi: u32,
+}
@@ -0,0 +1,270 @@
+//! Renders a preview of some text into the terminal, for
+//! `wezterm ls-fonts --rasterize`.
+use ::window::default_dpi;
+use anyhow::anyhow;
+use config::{ConfigHandle, FontAttributes, TextStyle};
+use std::sync::mpsc::channel;
+use std::sync::Mutex;
+use std::time::Duration;
+use wezterm_font::parser::ParsedFont;
+use wezterm_font::{ClearShapeCache, FontConfiguration, GlyphInfo, LoadedFont};
+
+/// Previews taller than this many rows of the terminal are scaled down
+const MAX_PREVIEW_ROWS: usize = 24;
+
+/// How long to wait for the fallback fonts to be resolved
+const FALLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An RGBA image of some rendered text
+struct Image {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+impl Image {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0; width * height * 4],
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> [u8; 4] {
+        let offset = (y * self.width + x) * 4;
+        let mut pixel = [0u8; 4];
+        pixel.copy_from_slice(&self.data[offset..offset + 4]);
+        pixel
+    }
+
+    /// Reduce the size of the image by an integer `factor`, averaging
+    /// each `factor` x `factor` block of pixels
+    fn downscale(&self, factor: usize) -> Self {
+        if factor <= 1 {
+            return Self {
+                width: self.width,
+                height: self.height,
+                data: self.data.clone(),
+            };
+        }
+        let mut scaled = Self::new(
+            (self.width + factor - 1) / factor,
+            (self.height + factor - 1) / factor,
+        );
+        for y in 0..scaled.height {
+            for x in 0..scaled.width {
+                let mut sum = [0usize; 4];
+                let mut count = 0;
+                for src_y in y * factor..((y + 1) * factor).min(self.height) {
+                    for src_x in x * factor..((x + 1) * factor).min(self.width) {
+                        for (total, value) in sum.iter_mut().zip(self.pixel(src_x, src_y).iter()) {
+                            *total += *value as usize;
+                        }
+                        count += 1;
+                    }
+                }
+                let offset = (y * scaled.width + x) * 4;
+                for i in 0..4 {
+                    scaled.data[offset + i] = (sum[i] / count) as u8;
+                }
+            }
+        }
+        scaled
+    }
+}
+
+/// Shape `text`, waiting for any fallback fonts that are needed to
+/// be resolved so that the result is complete
+pub fn shape(font: &LoadedFont, text: &str) -> anyhow::Result<Vec<GlyphInfo>> {
+    let mut shaped = None;
+    for _ in 0..3 {
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+        match font.shape(text, move || {
+            tx.lock().unwrap().send(()).ok();
+        }) {
+            Ok(infos) => {
+                // The completion is only retained, and later called,
+                // if a fallback search was started.  Otherwise it has
+                // already been dropped and we have the final result.
+                if rx.recv_timeout(FALLBACK_TIMEOUT).is_err() {
+                    return Ok(infos);
+                }
+                shaped.replace(infos);
+            }
+            Err(err) if err.root_cause().downcast_ref::<ClearShapeCache>().is_some() => {}
+            Err(err) => return Err(err),
+        }
+    }
+    // No fallback font could supply the missing glyphs; show
+    // what we have
+    shaped.ok_or_else(|| anyhow!("unable to resolve fallback fonts for {:?}", text))
+}
+
+/// Shape and rasterize `text` into a single line image
+fn render_text(font: &LoadedFont, text: &str) -> anyhow::Result<Image> {
+    let infos = shape(font, text)?;
+    let metrics = font.metrics();
+    let cell_height = metrics.cell_height.get().ceil() as usize;
+    let baseline = metrics.cell_height.get() + metrics.descender.get();
+    let width = infos
+        .iter()
+        .map(|info| info.x_advance.get())
+        .sum::<f64>()
+        .ceil() as usize;
+
+    let mut image = Image::new(width.max(1), cell_height.max(1));
+    let mut pen_x = 0.;
+    for info in &infos {
+        let glyph = font.rasterize_glyph(info.glyph_pos, info.font_idx)?;
+        let left = (pen_x + info.x_offset.get() + glyph.bearing_x.get()).round() as isize;
+        let top = (baseline - info.y_offset.get() - glyph.bearing_y.get()).round() as isize;
+        for y in 0..glyph.height {
+            let dest_y = top + y as isize;
+            if dest_y < 0 || dest_y >= image.height as isize {
+                continue;
+            }
+            for x in 0..glyph.width {
+                let dest_x = left + x as isize;
+                if dest_x < 0 || dest_x >= image.width as isize {
+                    continue;
+                }
+                let src = (y * glyph.width + x) * 4;
+                let dest = (dest_y as usize * image.width + dest_x as usize) * 4;
+                // Overlapping glyphs are combined by taking the
+                // greater coverage
+                for i in 0..4 {
+                    image.data[dest + i] = image.data[dest + i].max(glyph.data[src + i]);
+                }
+            }
+        }
+        pen_x += info.x_advance.get();
+    }
+    Ok(image)
+}
+
+fn luminance(pixel: [u8; 4]) -> u8 {
+    ((pixel[0] as u32 * 299 + pixel[1] as u32 * 587 + pixel[2] as u32 * 114) / 1000) as u8
+}
+
+/// Render `image` using the upper and lower half block characters,
+/// so that each row of text shows two rows of pixels.
+/// When `truecolor` is true, the colors of the pixels are shown,
+/// otherwise each half is either lit or unlit based on its luminance.
+fn to_half_blocks(image: &Image, truecolor: bool) -> String {
+    let mut output = String::new();
+    for y in (0..image.height).step_by(2) {
+        for x in 0..image.width {
+            let top = image.pixel(x, y);
+            let bottom = if y + 1 < image.height {
+                image.pixel(x, y + 1)
+            } else {
+                [0; 4]
+            };
+            if truecolor {
+                output.push_str(&format!(
+                    "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}",
+                    top[0], top[1], top[2], bottom[0], bottom[1], bottom[2]
+                ));
+            } else {
+                output.push(match (luminance(top) >= 0x80, luminance(bottom) >= 0x80) {
+                    (true, true) => '\u{2588}',
+                    (true, false) => '\u{2580}',
+                    (false, true) => '\u{2584}',
+                    (false, false) => ' ',
+                });
+            }
+        }
+        if truecolor {
+            output.push_str("\x1b[0m");
+        }
+        output.push('\n');
+    }
+    output
+}
+
+fn terminal_supports_truecolor() -> bool {
+    match std::env::var("COLORTERM") {
+        Ok(value) => value == "truecolor" || value == "24bit",
+        Err(_) => false,
+    }
+}
+
+/// Print a preview of `text` rendered using the font and size from
+/// `ls-fonts --rasterize`, or the configured font if they are not
+/// specified
+pub fn run_rasterize(
+    config: &ConfigHandle,
+    text: &str,
+    family: Option<&str>,
+    size: Option<f64>,
+) -> anyhow::Result<()> {
+    let font_config = FontConfiguration::new(Some(config.clone()))?;
+    if let Some(size) = size {
+        let dpi = config.dpi.unwrap_or_else(|| default_dpi()) as usize;
+        font_config.change_scaling(size / config.font_size, dpi);
+    }
+
+    let style = match family {
+        Some(family) => TextStyle {
+            font: vec![FontAttributes::new(family)],
+            foreground: None,
+        },
+        None => config.font.clone(),
+    };
+    let font = font_config.resolve_font(&style)?;
+    println!("{}", ParsedFont::lua_fallback(&font.clone_handles()));
+
+    let image = render_text(&font, text)?;
+    let factor = (image.height + MAX_PREVIEW_ROWS * 2 - 1) / (MAX_PREVIEW_ROWS * 2);
+    let image = image.downscale(factor);
+    print!("{}", to_half_blocks(&image, terminal_supports_truecolor()));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn half_blocks() {
+        let mut image = Image::new(3, 2);
+        // left column fully lit, middle column lit at the top only
+        for &(x, y) in &[(0, 0), (0, 1), (1, 0)] {
+            let offset = (y * 3 + x) * 4;
+            image.data[offset..offset + 4].copy_from_slice(&[0xff; 4]);
+        }
+        assert_eq!(to_half_blocks(&image, false), "\u{2588}\u{2580} \n");
+
+        let scaled = image.downscale(2);
+        assert_eq!((scaled.width, scaled.height), (2, 1));
+        assert_eq!(scaled.pixel(0, 0), [0xbf; 4]);
+        assert_eq!(scaled.pixel(1, 0), [0; 4]);
+    }
+
+    #[test]
+    fn rasterize_text() {
+        config::use_test_configuration();
+
+        let fonts = FontConfiguration::new(None).unwrap();
+        let style = TextStyle {
+            font: vec![FontAttributes::new("Fira Code")],
+            foreground: None,
+        };
+        let font = fonts.resolve_font(&style).unwrap();
+        let image = render_text(&font, "ab").unwrap();
+        let cell_width = font.metrics().cell_width.get();
+        assert_eq!(image.width, (cell_width * 2.).ceil() as usize);
+
+        let inked = |range: std::ops::Range<usize>| {
+            range
+                .into_iter()
+                .any(|x| (0..image.height).any(|y| image.pixel(x, y)[3] != 0))
+        };
+        let half = (cell_width.floor() as usize).min(image.width);
+        assert!(inked(0..half));
+        assert!(inked(half..image.width));
+    }
+}
@@ -9,7 +9,7 @@ use ::window::glium;
 use ::window::glium::backend::Context as GliumContext;
 use ::window::glium::texture::SrgbTexture2d;
 use ::window::{Point, Rect};
-use config::{AllowSquareGlyphOverflow, TextStyle};
+use config::{AllowSquareGlyphOverflow, FontAttributes, TextStyle, WideGlyphPolicy};
 use euclid::num::Zero;
 use lru::LruCache;
 use std::collections::HashMap;
@@ -19,15 +19,23 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use termwiz::image::ImageData;
 use wezterm_font::units::*;
-use wezterm_font::{FontConfiguration, GlyphInfo};
+use wezterm_font::{CoverageFormat, FontConfiguration, FontSynthesis, GlyphInfo};
 use wezterm_term::Underline;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct GlyphKey {
     pub font_idx: usize,
     pub glyph_pos: u32,
-    pub style: TextStyle,
+    /// Only the fonts of the style are part of the key; its foreground
+    /// color is applied when the glyph is composited, so that changing
+    /// the color of some text doesn't render its glyphs again.
+    pub font: Vec<FontAttributes>,
     pub followed_by_space: bool,
+    pub synthesis: FontSynthesis,
+    /// When the glyph is fitted to its cells, the number of cells.
+    /// The scale that fitting applies is determined by the size of
+    /// the box that the outline is fitted to.
+    pub fit_cells: Option<u8>,
 }
 
 /// We'd like to avoid allocating when resolving from the cache
@@ -39,8 +47,10 @@ pub struct GlyphKey {
 pub struct BorrowedGlyphKey<'a> {
     pub font_idx: usize,
     pub glyph_pos: u32,
-    pub style: &'a TextStyle,
+    pub font: &'a [FontAttributes],
     pub followed_by_space: bool,
+    pub synthesis: FontSynthesis,
+    pub fit_cells: Option<u8>,
 }
 
 impl<'a> BorrowedGlyphKey<'a> {
@@ -48,8 +58,10 @@ impl<'a> BorrowedGlyphKey<'a> {
         GlyphKey {
             font_idx: self.font_idx,
             glyph_pos: self.glyph_pos,
-            style: self.style.clone(),
+            font: self.font.to_vec(),
             followed_by_space: self.followed_by_space,
+            synthesis: self.synthesis,
+            fit_cells: self.fit_cells,
         }
     }
 }
@@ -63,8 +75,10 @@ impl GlyphKeyTrait for GlyphKey {
         BorrowedGlyphKey {
             font_idx: self.font_idx,
             glyph_pos: self.glyph_pos,
-            style: &self.style,
+            font: &self.font,
             followed_by_space: self.followed_by_space,
+            synthesis: self.synthesis,
+            fit_cells: self.fit_cells,
         }
     }
 }
@@ -98,7 +112,14 @@ impl<'a> std::hash::Hash for (dyn GlyphKeyTrait + 'a) {
 /// Caches a rendered glyph.
 /// The image data may be None for whitespace glyphs.
 pub struct CachedGlyph<T: Texture2d> {
+    /// Color glyphs, such as emoji, have their colors baked into the
+    /// texture and are drawn as-is.  Otherwise the texture holds only
+    /// coverage, which is tinted with the foreground color of the text
+    /// when it is composited.
     pub has_color: bool,
+    /// What the channels of the texture hold; subpixel glyphs carry
+    /// separate coverage for each color channel
+    pub coverage: CoverageFormat,
     pub x_offset: PixelLength,
     pub y_offset: PixelLength,
     pub bearing_x: PixelLength,
@@ -111,6 +132,7 @@ impl<T: Texture2d> std::fmt::Debug for CachedGlyph<T> {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
         fmt.debug_struct("CachedGlyph")
             .field("has_color", &self.has_color)
+            .field("coverage", &self.coverage)
             .field("x_offset", &self.x_offset)
             .field("y_offset", &self.y_offset)
             .field("bearing_x", &self.bearing_x)
@@ -397,11 +419,26 @@ impl<T: Texture2d> GlyphCache<T> {
         style: &TextStyle,
         followed_by_space: bool,
     ) -> anyhow::Result<Rc<CachedGlyph<T>>> {
+        // The same face can be used both with and without synthetic
+        // styling, so that needs to be part of the key
+        let synthesis = self
+            .fonts
+            .resolve_font(style)?
+            .synthesis_for_idx(info.font_idx);
+        // Glyphs that are fitted to their cells are rendered at a scale
+        // that depends on the number of cells
+        let fit_cells = if self.fonts.config().wide_glyph_policy == WideGlyphPolicy::FitToCells {
+            Some(info.num_cells)
+        } else {
+            None
+        };
         let key = BorrowedGlyphKey {
             font_idx: info.font_idx,
             glyph_pos: info.glyph_pos,
-            style,
+            font: &style.font,
             followed_by_space,
+            synthesis,
+            fit_cells,
         };
 
         if let Some(entry) = self.glyph_cache.get(&key as &dyn GlyphKeyTrait) {
@@ -432,6 +469,7 @@ impl<T: Texture2d> GlyphCache<T> {
                 );
                 Rc::new(CachedGlyph {
                     has_color: false,
+                    coverage: CoverageFormat::Gray,
                     texture: None,
                     x_offset: PixelLength::zero(),
                     y_offset: PixelLength::zero(),
@@ -453,17 +491,10 @@ impl<T: Texture2d> GlyphCache<T> {
         style: &TextStyle,
         followed_by_space: bool,
     ) -> anyhow::Result<Rc<CachedGlyph<T>>> {
-        let base_metrics;
-        let idx_metrics;
-        let glyph;
-
-        {
-            let font = self.fonts.resolve_font(style)?;
-            base_metrics = font.metrics();
-            glyph = font.rasterize_glyph(info.glyph_pos, info.font_idx)?;
-
-            idx_metrics = font.metrics_for_idx(info.font_idx)?;
-        }
+        let font = self.fonts.resolve_font(style)?;
+        let base_metrics = font.metrics();
+        let idx_metrics = font.metrics_for_idx(info.font_idx)?;
+        let mut glyph;
 
         let aspect = (idx_metrics.cell_width / idx_metrics.cell_height).get();
 
@@ -482,34 +513,15 @@ impl<T: Texture2d> GlyphCache<T> {
             false
         };
 
-        // Maximum width allowed for this glyph based on its unicode width and
-        // the dimensions of a cell
-        let max_pixel_width = base_metrics.cell_width.get() * (info.num_cells as f64 + 0.25);
-
-        let scale;
-        if info.font_idx == 0 {
+        let y_scale = if info.font_idx == 0 {
             // We are the base font
-            scale = if allow_width_overflow || glyph.width as f64 <= max_pixel_width {
-                1.0
-            } else {
-                // Scale the glyph to fit in its number of cells
-                1.0 / info.num_cells as f64
-            };
+            1.0
         } else if !idx_metrics.is_scaled {
             // A bitmap font that isn't scaled to the requested height.
-            let y_scale = base_metrics.cell_height.get() / idx_metrics.cell_height.get();
-            let y_scaled_width = y_scale * glyph.width as f64;
-
-            if allow_width_overflow || y_scaled_width <= max_pixel_width {
-                // prefer height-wise scaling
-                scale = y_scale;
-            } else {
-                // otherwise just make it fit the width
-                scale = max_pixel_width / glyph.width as f64;
-            }
+            base_metrics.cell_height.get() / idx_metrics.cell_height.get()
         } else {
             // a scalable fallback font
-            let y_scale = match (
+            match (
                 self.fonts.config().use_cap_height_to_scale_fallback_fonts,
                 base_metrics.cap_height_ratio,
                 idx_metrics.cap_height_ratio,
@@ -525,40 +537,63 @@ impl<T: Texture2d> GlyphCache<T> {
                     // any additional scaling
                     1.0
                 }
-            };
-
-            // How wide the glyph would be using the y_scale we produced
-            let y_scaled_width = y_scale * glyph.width as f64;
-
-            if allow_width_overflow || y_scaled_width <= max_pixel_width {
-                scale = y_scale;
-            } else {
-                scale = max_pixel_width / glyph.width as f64;
             }
+        };
 
-            #[cfg(debug_assertions)]
-            {
-                log::debug!(
-                    "{} allow_width_overflow={} is_square_or_wide={} aspect={} \
-                       y_scaled_width={} max_pixel_width={} glyph.width={} -> scale={}",
-                    info.text,
-                    allow_width_overflow,
-                    is_square_or_wide,
-                    aspect,
-                    y_scaled_width,
-                    max_pixel_width,
-                    glyph.width,
-                    scale
-                );
-            }
+        let wide_glyph_policy = self.fonts.config().wide_glyph_policy;
+        let scale = if allow_width_overflow {
+            glyph = font.rasterize_glyph(info.glyph_pos, info.font_idx)?;
+            y_scale
+        } else if wide_glyph_policy == WideGlyphPolicy::FitToCells {
+            // Fit the outline to its cells, expressed in the pixels of
+            // the glyph prior to applying y_scale, before rendering it
+            let cell_width = base_metrics.cell_width * info.num_cells as f64 / y_scale;
+            let cell_height = base_metrics.cell_height / y_scale;
+            let (fitted, bitmap_scale) = font.rasterize_fitted_glyph(
+                info.glyph_pos,
+                info.font_idx,
+                cell_width.get(),
+                cell_height.get(),
+            )?;
+            glyph = fitted;
+            y_scale * bitmap_scale
+        } else {
+            glyph = font.rasterize_glyph(info.glyph_pos, info.font_idx)?;
+            // Classify the glyph against the cell width expressed in
+            // the pixels of the glyph prior to applying y_scale, and
+            // deal with it according to the configured policy if it
+            // is too wide for its cells
+            let cell_width = base_metrics.cell_width / y_scale;
+            y_scale
+                * glyph.apply_wide_glyph_policy(
+                    wide_glyph_policy,
+                    cell_width,
+                    info.num_cells as usize,
+                )
         };
 
+        #[cfg(debug_assertions)]
+        {
+            log::debug!(
+                "{} allow_width_overflow={} is_square_or_wide={} aspect={} \
+                   y_scale={} glyph.width={} -> scale={}",
+                info.text,
+                allow_width_overflow,
+                is_square_or_wide,
+                aspect,
+                y_scale,
+                glyph.width,
+                scale
+            );
+        }
+
         let (cell_width, cell_height) = (base_metrics.cell_width, base_metrics.cell_height);
 
         let glyph = if glyph.width == 0 || glyph.height == 0 {
             // a whitespace glyph
             CachedGlyph {
                 has_color: glyph.has_color,
+                coverage: glyph.coverage,
                 texture: None,
                 x_offset: info.x_offset * scale,
                 y_offset: info.y_offset * scale,
@@ -599,6 +634,7 @@ impl<T: Texture2d> GlyphCache<T> {
 
             let g = CachedGlyph {
                 has_color: glyph.has_color,
+                coverage: glyph.coverage,
                 texture: Some(tex),
                 x_offset,
                 y_offset,
@@ -1049,3 +1085,187 @@ impl<T: Texture2d> GlyphCache<T> {
         self.line_sprite(key)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use termwiz::color::RgbColor;
+    use wezterm_font::RasterizedGlyph;
+
+    fn test_fonts() -> Rc<FontConfiguration> {
+        config::use_test_configuration();
+        Rc::new(FontConfiguration::new(None).unwrap())
+    }
+
+    /// Fira Code is only bundled in its Regular weight
+    fn fira_code() -> TextStyle {
+        TextStyle {
+            font: vec![FontAttributes::new("Fira Code")],
+            foreground: None,
+        }
+    }
+
+    #[test]
+    fn synthesized_styles_are_cached_separately() {
+        let fonts = test_fonts();
+        let render_metrics = RenderMetrics::new(&fonts).unwrap();
+        let mut glyph_cache = GlyphCache::new_in_memory(&fonts, 128, &render_metrics).unwrap();
+
+        // Fira Code is only bundled in its Regular weight, so asking
+        // for bold will synthesize it from that same face
+        let regular = TextStyle {
+            font: vec![FontAttributes::new("Fira Code")],
+            foreground: None,
+        };
+        let bold = regular.make_bold();
+
+        let regular_font = fonts.resolve_font(&regular).unwrap();
+        let bold_font = fonts.resolve_font(&bold).unwrap();
+        assert!(regular_font.synthesis_for_idx(0).is_none());
+        assert!(bold_font.synthesis_for_idx(0).bold);
+
+        // The same glyph of the same face is used for both
+        assert!(regular_font.clone_handles()[0] == bold_font.clone_handles()[0]);
+        let info = regular_font.shape("a", || {}).unwrap().remove(0);
+        let bold_info = bold_font.shape("a", || {}).unwrap().remove(0);
+        assert_eq!(info.font_idx, bold_info.font_idx);
+        assert_eq!(info.glyph_pos, bold_info.glyph_pos);
+
+        let regular_cached = glyph_cache.cached_glyph(&info, &regular, false).unwrap();
+        let bold_cached = glyph_cache.cached_glyph(&bold_info, &bold, false).unwrap();
+        assert_eq!(glyph_cache.glyph_cache.len(), 2);
+        assert!(!Rc::ptr_eq(&regular_cached, &bold_cached));
+        let mut synthesis = glyph_cache
+            .glyph_cache
+            .keys()
+            .map(|key| key.synthesis.bold)
+            .collect::<Vec<_>>();
+        synthesis.sort();
+        assert_eq!(synthesis, vec![false, true]);
+
+        let regular_glyph = regular_font
+            .rasterize_glyph(info.glyph_pos, info.font_idx)
+            .unwrap();
+        let bold_glyph = bold_font
+            .rasterize_glyph(bold_info.glyph_pos, bold_info.font_idx)
+            .unwrap();
+        assert_ne!(regular_glyph.data, bold_glyph.data);
+    }
+
+    #[test]
+    fn foreground_is_not_part_of_the_key() {
+        let fonts = test_fonts();
+        let render_metrics = RenderMetrics::new(&fonts).unwrap();
+        let mut glyph_cache = GlyphCache::new_in_memory(&fonts, 128, &render_metrics).unwrap();
+
+        let plain = fira_code();
+        let red = TextStyle {
+            foreground: Some(RgbColor::new(0xff, 0, 0)),
+            ..plain.clone()
+        };
+
+        let info = fonts
+            .resolve_font(&plain)
+            .unwrap()
+            .shape("a", || {})
+            .unwrap()
+            .remove(0);
+        let plain_glyph = glyph_cache.cached_glyph(&info, &plain, false).unwrap();
+        let red_glyph = glyph_cache.cached_glyph(&info, &red, false).unwrap();
+        assert!(Rc::ptr_eq(&plain_glyph, &red_glyph));
+        assert!(!plain_glyph.has_color);
+        assert_eq!(glyph_cache.glyph_cache.len(), 1);
+    }
+
+    #[test]
+    fn linear_coverage() {
+        let fonts = test_fonts();
+        let style = fira_code();
+        let font = fonts.resolve_font(&style).unwrap();
+        let info = font.shape("a", || {}).unwrap().remove(0);
+
+        let srgb = font.rasterize_glyph(info.glyph_pos, info.font_idx).unwrap();
+        let linear = font
+            .rasterize_glyph_in_color_space(
+                info.glyph_pos,
+                info.font_idx,
+                wezterm_font::ColorSpace::Linear,
+            )
+            .unwrap();
+        assert_eq!(srgb.coverage, wezterm_font::CoverageFormat::Gray);
+        assert_eq!(linear.coverage, wezterm_font::CoverageFormat::Gray);
+        assert_eq!(srgb.color_space, wezterm_font::ColorSpace::Srgb);
+        assert_eq!(linear.color_space, wezterm_font::ColorSpace::Linear);
+        assert_eq!(srgb.width, linear.width);
+
+        // Only the color channels are gamma adjusted; for linear
+        // coverage they are the same as the alpha channel
+        for (s, l) in srgb.data.chunks(4).zip(linear.data.chunks(4)) {
+            assert_eq!(s[3], l[3]);
+            assert_eq!(l[0], l[3]);
+        }
+        assert_ne!(srgb.data, linear.data);
+    }
+
+    #[test]
+    fn load_glyphs_can_be_cancelled() {
+        let fonts = test_fonts();
+        let style = fira_code();
+        let font = fonts.resolve_font(&style).unwrap();
+        let glyphs = font
+            .shape("hello", || {})
+            .unwrap()
+            .iter()
+            .map(|info| (info.glyph_pos, info.font_idx))
+            .collect::<Vec<_>>();
+
+        let cancel = std::sync::atomic::AtomicBool::new(false);
+        let loaded = font.load_glyphs(&glyphs, Some(&cancel)).unwrap();
+        assert!(!loaded.cancelled);
+        assert_eq!(loaded.glyphs.len(), glyphs.len());
+
+        cancel.store(true, std::sync::atomic::Ordering::Relaxed);
+        let loaded = font.load_glyphs(&glyphs, Some(&cancel)).unwrap();
+        assert!(loaded.cancelled);
+        assert!(loaded.glyphs.is_empty());
+    }
+
+    #[test]
+    fn synthesized_bold_preserves_advance() {
+        let fonts = test_fonts();
+        let regular = fira_code();
+        let bold = regular.make_bold();
+
+        let regular_font = fonts.resolve_font(&regular).unwrap();
+        let bold_font = fonts.resolve_font(&bold).unwrap();
+        assert!(bold_font.synthesis_for_idx(0).preserve_advance);
+
+        let text = "wezterm";
+        let regular_infos = regular_font.shape(text, || {}).unwrap();
+        let bold_infos = bold_font.shape(text, || {}).unwrap();
+        assert_eq!(regular_infos.len(), bold_infos.len());
+
+        let width =
+            |infos: &[GlyphInfo]| infos.iter().map(|info| info.x_advance.get()).sum::<f64>();
+        assert_eq!(width(&regular_infos), width(&bold_infos));
+
+        for (info, bold_info) in regular_infos.iter().zip(bold_infos.iter()) {
+            let regular_glyph = regular_font
+                .rasterize_glyph(info.glyph_pos, info.font_idx)
+                .unwrap();
+            let bold_glyph = bold_font
+                .rasterize_glyph(bold_info.glyph_pos, bold_info.font_idx)
+                .unwrap();
+
+            // The extra ink is split either side of the regular glyph,
+            // so its center must not drift across the cell
+            let center = |g: &RasterizedGlyph| g.bearing_x.get() + g.width as f64 / 2.;
+            assert!(
+                (center(&regular_glyph) - center(&bold_glyph)).abs() <= 1.0,
+                "bold {:?} drifted from regular {:?}",
+                bold_info,
+                info
+            );
+        }
+    }
+}
@@ -18,6 +18,7 @@ use wezterm_gui_subcommands::*;
 use wezterm_ssh::*;
 use wezterm_toast_notification::*;
 
+mod fontpreview;
 mod frontend;
 mod glyphcache;
 mod markdown;
@@ -87,6 +88,13 @@ enum SubCommand {
 
     #[structopt(name = "ls-fonts", about = "Display information about fonts")]
     LsFonts(LsFontsCommand),
+
+    #[structopt(
+        name = "font-digest",
+        about = "Compute a digest of rendered glyphs for regression testing",
+        setting = structopt::clap::AppSettings::Hidden
+    )]
+    FontDigest(FontDigestCommand),
 }
 
 async fn async_run_ssh(opts: SshCommand) -> anyhow::Result<()> {
@@ -413,21 +421,63 @@ fn maybe_show_configuration_error_window() {
     }
 }
 
-pub fn run_ls_fonts(config: config::ConfigHandle, _cmd: &LsFontsCommand) -> anyhow::Result<()> {
+pub fn run_ls_fonts(config: config::ConfigHandle, cmd: &LsFontsCommand) -> anyhow::Result<()> {
     use wezterm_font::parser::ParsedFont;
 
     // Disable the normal config error UI window, as we don't have
     // a fully baked GUI environment running
     config::assign_error_callback(|err| eprintln!("{}", err));
 
+    if let Some(text) = &cmd.rasterize {
+        return fontpreview::run_rasterize(&config, text, cmd.font.as_deref(), cmd.size);
+    }
+
     let font_config = wezterm_font::FontConfiguration::new(Some(config.clone()))?;
 
+    if let Some(text) = &cmd.text {
+        let mut attrs = wezterm_term::CellAttributes::default();
+        if cmd.bold {
+            attrs.set_intensity(wezterm_term::Intensity::Bold);
+        }
+        attrs.set_italic(cmd.italic);
+        let font = font_config.resolve_font(font_config.match_style(&config, &attrs))?;
+        return print_text_trace(&config, &font, text);
+    }
+
+    if let Some(family) = &cmd.coverage {
+        return print_coverage(&font_config, family, cmd.full, cmd.page);
+    }
+
+    if let [family_a, family_b] = cmd.coverage_diff.as_slice() {
+        return print_coverage_diff(&font_config, family_a, family_b, cmd.full, cmd.page);
+    }
+
+    if cmd.all {
+        return print_all_fonts(&config);
+    }
+
+    if cmd.explain {
+        let lib = wezterm_font::ftwrap::Library::new_metrics_only()?;
+        println!("{}", lib.capabilities());
+        println!();
+    }
+
     println!("Primary font:");
     let default_font = font_config.default_font()?;
     println!(
         "{}",
         ParsedFont::lua_fallback(&default_font.clone_handles())
     );
+    print_strikes(&config, &default_font.clone_handles());
+    print_metrics_divergence(&config, &default_font);
+    if cmd.explain {
+        print_rejected_candidates(&default_font.clone_handles());
+        print_kerning(&default_font.clone_handles());
+        print_color_formats(&default_font.clone_handles());
+        print_lowest_rec_ppem(&config, &default_font.clone_handles());
+        print_baseline_offsets(&default_font);
+        print_cell_size(&config, &default_font);
+    }
     println!();
 
     for rule in &config.font_rules {
@@ -459,12 +509,496 @@ pub fn run_ls_fonts(config: config::ConfigHandle, _cmd: &LsFontsCommand) -> anyh
         println!("{}:", condition);
         let font = font_config.resolve_font(&rule.font)?;
         println!("{}", ParsedFont::lua_fallback(&font.clone_handles()));
+        print_strikes(&config, &font.clone_handles());
+        print_metrics_divergence(&config, &font);
+        if cmd.explain {
+            print_rejected_candidates(&font.clone_handles());
+            print_kerning(&font.clone_handles());
+            print_color_formats(&font.clone_handles());
+            print_lowest_rec_ppem(&config, &font.clone_handles());
+            print_baseline_offsets(&font);
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+pub fn run_font_digest(
+    config: config::ConfigHandle,
+    cmd: &FontDigestCommand,
+) -> anyhow::Result<()> {
+    use wezterm_font::digest::{render_digest, DigestOptions};
+
+    // Disable the normal config error UI window, as we don't have
+    // a fully baked GUI environment running
+    config::assign_error_callback(|err| eprintln!("{}", err));
+
+    let font_config = wezterm_font::FontConfiguration::new(Some(config.clone()))?;
+    let default_font = font_config.default_font()?;
+
+    for parsed in default_font.clone_handles() {
+        println!("{}", parsed.names().full_name);
+        println!("  {}", parsed.handle.diagnostic_string());
+        for &size in &cmd.sizes {
+            let mut options = DigestOptions::new(size, cmd.dpi);
+            if let Some(text) = &cmd.text {
+                options.text = text.clone();
+            }
+            let digest = render_digest(&parsed.handle, &options)?;
+            println!("  size={} dpi={}: {}", size, cmd.dpi, digest);
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the candidates that lost out to each of the fonts in `handles`
+fn print_rejected_candidates(handles: &[wezterm_font::parser::ParsedFont]) {
+    for parsed in handles {
+        let rejected = parsed.rejected_candidates();
+        if rejected.is_empty() {
+            continue;
+        }
+        println!("-- {} was selected over:", parsed.lua_name());
+        for candidate in rejected {
+            println!("--   {}", candidate.lua_name);
+            println!("--     {}", candidate.diagnostic);
+            if let Some(revision) = candidate.revision {
+                println!("--     revision {:.3}", revision);
+            }
+            if let Some(version) = &candidate.version {
+                println!("--     {}", version);
+            }
+            println!(
+                "--     eliminated by {}: {}",
+                candidate.eliminated_by, candidate.score
+            );
+        }
+    }
+}
+
+/// Print the color glyph formats of any color fonts in `handles`
+fn print_color_formats(handles: &[wezterm_font::parser::ParsedFont]) {
+    for parsed in handles {
+        let formats = parsed.color_formats();
+        if !formats.is_empty() {
+            println!(
+                "-- Color formats for {}: {}",
+                parsed.names().full_name,
+                formats
+            );
+        }
+    }
+}
+
+/// Print where each of the fonts in `handles` defines its kerning
+fn print_kerning(handles: &[wezterm_font::parser::ParsedFont]) {
+    for parsed in handles {
+        println!(
+            "-- Kerning for {}: {}",
+            parsed.names().full_name,
+            parsed.kerning_source()
+        );
+    }
+}
+
+/// Print the number of pixels by which the baseline_offset of each
+/// font moves its glyphs at the current size and dpi
+fn print_baseline_offsets(font: &wezterm_font::LoadedFont) {
+    for (idx, parsed) in font.clone_handles().iter().enumerate() {
+        if let Some(offset) = parsed.baseline_offset() {
+            println!(
+                "-- {}: baseline_offset={} moves its glyphs {}px up",
+                parsed.names().full_name,
+                offset,
+                font.baseline_offset(idx).get()
+            );
+        }
+    }
+}
+
+/// Print the fractional cell dimensions of `font` and the whole
+/// pixel size that they are rounded to by `cell_dimension_rounding`
+fn print_cell_size(config: &config::ConfigHandle, font: &wezterm_font::LoadedFont) {
+    let metrics = font.metrics();
+    let (width, height) =
+        metrics.cell_pixel_size(config.line_height, config.cell_dimension_rounding);
+    println!(
+        "-- Cell size {}x{} from {}x{} (line_height={}, cell_dimension_rounding={})",
+        width,
+        height,
+        metrics.cell_width.get(),
+        metrics.cell_height.get(),
+        config.line_height,
+        config.cell_dimension_rounding
+    );
+}
+
+/// Print the fonts from font_dirs and the built-in fonts, for
+/// `ls-fonts --all`, along with the reason that any of them won't
+/// be used as a fallback
+fn print_all_fonts(config: &config::ConfigHandle) -> anyhow::Result<()> {
+    use wezterm_font::db::FontDatabase;
+
+    for (label, db) in &[
+        ("font_dirs", FontDatabase::with_font_dirs(config)?),
+        ("built-in fonts", FontDatabase::with_built_in()?),
+    ] {
+        println!("Fonts from {}:", label);
+        for parsed in db.fonts() {
+            println!("{}", parsed.lua_name());
+            println!("  -- {}", parsed.handle.diagnostic_string());
+            if !config::FontFormat::is_allowed(
+                parsed.format(),
+                config.font_allowed_formats.as_deref(),
+            ) {
+                if let Some(format) = parsed.format() {
+                    println!(
+                        "  -- not used for fallback: font_allowed_formats excludes {}",
+                        format
+                    );
+                }
+            }
+        }
         println!();
     }
+    Ok(())
+}
+
+/// Lists with more ranges than this are split into pages by
+/// `ls-fonts --coverage` and `--coverage-diff` unless `--full` is used
+const MAX_COVERAGE_RANGES: usize = 64;
+
+/// Resolve `family` to the font whose coverage is to be shown,
+/// warning if some other font was selected for it
+fn resolve_coverage_font(
+    font_config: &wezterm_font::FontConfiguration,
+    family: &str,
+) -> anyhow::Result<wezterm_font::parser::ParsedFont> {
+    let style = config::TextStyle {
+        font: vec![config::FontAttributes::new(family)],
+        foreground: None,
+    };
+    let font = font_config.resolve_font(&style)?;
+    let parsed = font
+        .clone_handles()
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("no fonts were resolved for {}", family))?;
+    if !parsed.names().family.eq_ignore_ascii_case(family) {
+        log::warn!(
+            "{} was not found; showing the coverage of {} instead",
+            family,
+            parsed.names().full_name
+        );
+    }
+    Ok(parsed)
+}
+
+/// Returns every codepoint that `parsed` covers.  The coverage is
+/// cached by the font, so repeated queries don't rescan its cmap.
+fn full_coverage(
+    parsed: &wezterm_font::parser::ParsedFont,
+) -> anyhow::Result<rangeset::RangeSet<u32>> {
+    let mut all = rangeset::RangeSet::new();
+    all.add_range(0..0x110000);
+    parsed.coverage_intersection(&all)
+}
+
+/// Print the ranges of `set` in the form accepted by unicode_range_rules.
+/// Unless `full` is true, long lists are split into pages of
+/// MAX_COVERAGE_RANGES ranges, and only the 1-based `page` is printed.
+fn print_unicode_ranges(set: &rangeset::RangeSet<u32>, full: bool, page: Option<usize>) {
+    if set.is_empty() {
+        println!("(none)");
+        return;
+    }
+    let formatted = config::format_unicode_ranges(set);
+    let ranges: Vec<&str> = formatted.split(", ").collect();
+    if full || ranges.len() <= MAX_COVERAGE_RANGES {
+        println!("{}", formatted);
+        return;
+    }
+
+    let num_pages = (ranges.len() + MAX_COVERAGE_RANGES - 1) / MAX_COVERAGE_RANGES;
+    let page = page.unwrap_or(1).max(1).min(num_pages);
+    let start = (page - 1) * MAX_COVERAGE_RANGES;
+    let end = (start + MAX_COVERAGE_RANGES).min(ranges.len());
+    println!("{}", ranges[start..end].join(", "));
+    println!(
+        "(ranges {}-{} of {}, page {} of {}; use --page N to show another \
+         page or --full to show them all)",
+        start + 1,
+        end,
+        ranges.len(),
+        page,
+        num_pages
+    );
+}
+
+/// Print the codepoints that are covered by the font that
+/// `family` resolves to
+fn print_coverage(
+    font_config: &wezterm_font::FontConfiguration,
+    family: &str,
+    full: bool,
+    page: Option<usize>,
+) -> anyhow::Result<()> {
+    let parsed = resolve_coverage_font(font_config, family)?;
+    let coverage = full_coverage(&parsed)?;
+
+    println!("Coverage of {}:", parsed.lua_name());
+    print_unicode_ranges(&coverage, full, page);
+    Ok(())
+}
+
+/// Print the codepoints that are covered by only one of the fonts
+/// that `family_a` and `family_b` resolve to, followed by a summary
+/// of the differences in each script
+fn print_coverage_diff(
+    font_config: &wezterm_font::FontConfiguration,
+    family_a: &str,
+    family_b: &str,
+    full: bool,
+    page: Option<usize>,
+) -> anyhow::Result<()> {
+    use wezterm_font::coverage::CoverageDiff;
+
+    let a = resolve_coverage_font(font_config, family_a)?;
+    let b = resolve_coverage_font(font_config, family_b)?;
+    let diff = CoverageDiff::new(&full_coverage(&a)?, &full_coverage(&b)?);
+    let (name_a, name_b) = (a.names().full_name.clone(), b.names().full_name.clone());
+
+    println!("Only in {} ({} codepoints):", name_a, diff.only_in_a.len());
+    print_unicode_ranges(&diff.only_in_a, full, page);
+    println!();
+    println!("Only in {} ({} codepoints):", name_b, diff.only_in_b.len());
+    print_unicode_ranges(&diff.only_in_b, full, page);
+    println!();
+    println!("In both: {} codepoints", diff.common.len());
+    println!();
+
+    println!("By script, where A is {} and B is {}:", name_a, name_b);
+    println!(
+        "{:<16} {:>10} {:>10} {:>10}",
+        "Script", "Only in A", "Only in B", "Both"
+    );
+    for (script, only_a, only_b, common) in diff.summarize_by_script() {
+        println!(
+            "{:<16} {:>10} {:>10} {:>10}",
+            script, only_a, only_b, common
+        );
+    }
+    Ok(())
+}
 
+/// Print the font used to render each glyph of `text`, along with
+/// any glyph_substitutions that apply to it, and any fonts that were
+/// skipped because they don't allow a style to be synthesized
+fn print_text_trace(
+    config: &config::ConfigHandle,
+    font: &wezterm_font::LoadedFont,
+    text: &str,
+) -> anyhow::Result<()> {
+    for declined in font.declined_synthesis() {
+        println!("-- {}", declined);
+    }
+    let infos = fontpreview::shape(font, text)?;
+    // Shaping may have added fallback fonts
+    let handles = font.clone_handles();
+    for (idx, info) in infos.iter().enumerate() {
+        let start = info.cluster as usize;
+        let end = infos[idx + 1..]
+            .iter()
+            .map(|next| next.cluster as usize)
+            .find(|&next| next > start)
+            .unwrap_or(text.len());
+        let cluster = text.get(start..end).unwrap_or("");
+
+        let codepoints = cluster
+            .chars()
+            .map(|c| {
+                let mut desc = format!("U+{:04X}", c as u32);
+                match config.glyph_substitution_map.lookup(c) {
+                    Some(config::GlyphSubstitutionTarget::Codepoint(replacement)) => {
+                        desc.push_str(&format!(
+                            " (replaced by U+{:04X} via glyph_substitutions)",
+                            replacement as u32
+                        ));
+                    }
+                    Some(config::GlyphSubstitutionTarget::Font(rule)) => {
+                        desc.push_str(&format!(" (font from glyph_substitutions[{}])", rule));
+                    }
+                    None => {}
+                }
+                desc
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let font_name = handles
+            .get(info.font_idx)
+            .map(|parsed| parsed.lua_name())
+            .unwrap_or_else(|| format!("font_idx {}", info.font_idx));
+        println!(
+            "{:?} {} -> glyph {} of {}",
+            cluster, codepoints, info.glyph_pos, font_name
+        );
+
+        let missing = info.glyph_pos == 0
+            || handles
+                .get(info.font_idx)
+                .map(wezterm_font::parser::ParsedFont::is_last_resort)
+                .unwrap_or(true);
+        if missing {
+            print_charmap_scan(&handles, cluster);
+        }
+    }
     Ok(())
 }
 
+/// When none of the fonts appear to have a glyph for `text`, check
+/// whether any of them has one in a charmap that isn't normally used,
+/// to help explain why another application might be able to render it
+fn print_charmap_scan(handles: &[wezterm_font::parser::ParsedFont], text: &str) {
+    let lib = match wezterm_font::ftwrap::Library::new_metrics_only() {
+        Ok(lib) => lib,
+        Err(err) => {
+            log::error!("Unable to scan charmaps: {:#}", err);
+            return;
+        }
+    };
+    for parsed in handles.iter().filter(|parsed| !parsed.is_last_resort()) {
+        let face = match lib.face_from_locator(&parsed.handle) {
+            Ok(face) => face,
+            Err(_) => continue,
+        };
+        for c in text.chars() {
+            if let Some(glyph) = face.find_glyph_by_unicode_scan(c) {
+                println!(
+                    "--   {} has glyph {} for U+{:04X} when all of its charmaps are searched",
+                    parsed.lua_name(),
+                    glyph,
+                    c as u32
+                );
+            }
+        }
+    }
+}
+
+/// Print the bitmap strikes of any bitmap fonts in `handles`, and
+/// the font_size values that would use the nearest strikes exactly
+/// when the configured size falls between them
+fn print_strikes(config: &config::ConfigHandle, handles: &[wezterm_font::parser::ParsedFont]) {
+    let dpi = config.dpi.unwrap_or_else(|| ::window::default_dpi()) as u32;
+    let lib = match wezterm_font::ftwrap::Library::new_metrics_only() {
+        Ok(lib) => lib,
+        Err(err) => {
+            log::error!("Unable to list strikes: {:#}", err);
+            return;
+        }
+    };
+    for parsed in handles {
+        let mut face = match lib.face_from_locator(&parsed.handle) {
+            Ok(face) => face,
+            Err(_) => continue,
+        };
+        let strikes = face.strikes();
+        if strikes.is_empty() {
+            continue;
+        }
+        println!("-- Bitmap strikes for {}:", parsed.names().full_name);
+        for strike in strikes {
+            let format = match strike.format {
+                Some(format) => format!("{:?}", format),
+                None => "unknown format".to_string(),
+            };
+            println!("--   {}x{} {}", strike.width, strike.height, format);
+        }
+        if let Some(advice) = parsed.strike_advice(config.font_size, dpi) {
+            println!(
+                "-- font_size={} at {}dpi is {:.1}px, so the {}px strike is scaled to fit",
+                config.font_size, dpi, advice.pixel_size, advice.selected
+            );
+            for suggestion in &advice.nearest {
+                println!(
+                    "--   font_size={:.2} would use the {}px strike exactly",
+                    suggestion.point_size, suggestion.ppem
+                );
+            }
+        }
+    }
+}
+
+/// Print the fallbacks of `font` whose cell metrics diverge from those
+/// of its primary font by more than fallback_metrics_warning_ratio
+fn print_metrics_divergence(config: &config::ConfigHandle, font: &wezterm_font::LoadedFont) {
+    let handles = font.clone_handles();
+    for (idx, parsed) in handles.iter().enumerate().skip(1) {
+        if parsed.is_last_resort() {
+            continue;
+        }
+        match font.fallback_metrics_divergence(idx, config.fallback_metrics_warning_ratio) {
+            Ok(Some(divergence)) => println!(
+                "-- The cells of {} are {} of those of {}, so text that mixes them will look uneven",
+                parsed.names().full_name,
+                divergence,
+                handles[0].names().full_name
+            ),
+            Ok(None) => {}
+            Err(err) => log::debug!(
+                "Unable to compare the metrics of {}: {:#}",
+                parsed.names().full_name,
+                err
+            ),
+        }
+    }
+}
+
+/// Print the lowest recommended size of each of the fonts in `handles`,
+/// and whether the configured font_size is smaller than it
+fn print_lowest_rec_ppem(
+    config: &config::ConfigHandle,
+    handles: &[wezterm_font::parser::ParsedFont],
+) {
+    use wezterm_font::ftwrap::{pixels_to_point_size, point_size_to_pixels};
+
+    let dpi = config.dpi.unwrap_or_else(|| ::window::default_dpi()) as u32;
+    let lib = match wezterm_font::ftwrap::Library::new_metrics_only() {
+        Ok(lib) => lib,
+        Err(err) => {
+            log::error!("Unable to check lowest_rec_ppem: {:#}", err);
+            return;
+        }
+    };
+    for parsed in handles {
+        let lowest = match lib
+            .face_from_locator(&parsed.handle)
+            .ok()
+            .and_then(|face| face.lowest_rec_ppem())
+        {
+            Some(lowest) => lowest,
+            None => continue,
+        };
+        let mut line = format!(
+            "-- Lowest recommended size for {}: {}ppem (font_size={:.2} at {}dpi)",
+            parsed.names().full_name,
+            lowest,
+            pixels_to_point_size(lowest as f64, dpi),
+            dpi
+        );
+        if point_size_to_pixels(config.font_size, dpi) < lowest as f64 {
+            line.push_str(if config.freetype_unhinted_below_lowest_rec_ppem {
+                "; font_size is smaller, so hinting is disabled"
+            } else {
+                "; font_size is smaller, consider freetype_unhinted_below_lowest_rec_ppem"
+            });
+        }
+        println!("{}", line);
+    }
+}
+
 #[cfg(windows)]
 mod win_bindings {
     ::windows::include_bindings!();
@@ -540,5 +1074,6 @@ fn run() -> anyhow::Result<()> {
         SubCommand::Serial(serial) => run_serial(config, &serial),
         SubCommand::Connect(connect) => run_mux_client(config, &connect),
         SubCommand::LsFonts(cmd) => run_ls_fonts(config, &cmd),
+        SubCommand::FontDigest(cmd) => run_font_digest(config, &cmd),
     }
 }
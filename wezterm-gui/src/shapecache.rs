@@ -6,11 +6,14 @@ use std::rc::Rc;
 use termwiz::cellcluster::CellCluster;
 use wezterm_font::shaper::GlyphInfo;
 use wezterm_font::units::*;
+use wezterm_font::FontSynthesis;
 
 #[derive(PartialEq, Eq, Hash)]
 pub struct ShapeCacheKey {
     pub style: TextStyle,
     pub text: String,
+    /// The synthesis applied to the primary font for `style`
+    pub synthesis: FontSynthesis,
 }
 
 #[derive(Debug, PartialEq)]
@@ -198,6 +201,7 @@ where
 pub struct BorrowedShapeCacheKey<'a> {
     pub style: &'a TextStyle,
     pub text: &'a str,
+    pub synthesis: FontSynthesis,
 }
 
 impl<'a> BorrowedShapeCacheKey<'a> {
@@ -205,6 +209,7 @@ impl<'a> BorrowedShapeCacheKey<'a> {
         ShapeCacheKey {
             style: self.style.clone(),
             text: self.text.to_owned(),
+            synthesis: self.synthesis,
         }
     }
 }
@@ -218,6 +223,7 @@ impl ShapeCacheKeyTrait for ShapeCacheKey {
         BorrowedShapeCacheKey {
             style: &self.style,
             text: &self.text,
+            synthesis: self.synthesis,
         }
     }
 }
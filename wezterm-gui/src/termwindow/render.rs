@@ -534,12 +534,16 @@ impl super::TermWindow {
 
         let style = self.fonts.match_style(&config, &CellAttributes::default());
         let glyph_info = {
-            let key = BorrowedShapeCacheKey { style, text };
+            let font = self.fonts.resolve_font(style)?;
+            let key = BorrowedShapeCacheKey {
+                style,
+                text,
+                synthesis: font.synthesis_for_idx(0),
+            };
             match self.lookup_cached_shape(&key) {
                 Some(Ok(info)) => info,
                 Some(Err(err)) => return Err(err),
                 None => {
-                    let font = self.fonts.resolve_font(style)?;
                     let window = self.window.as_ref().unwrap().clone();
                     match font.shape(text, move || {
                         window.notify(TermWindowNotif::InvalidateShapeCache)
@@ -823,15 +827,16 @@ impl super::TermWindow {
 
             let shape_resolve_start = Instant::now();
             let glyph_info = {
+                let font = self.fonts.resolve_font(style)?;
                 let key = BorrowedShapeCacheKey {
                     style,
                     text: &cluster.text,
+                    synthesis: font.synthesis_for_idx(0),
                 };
                 match self.lookup_cached_shape(&key) {
                     Some(Ok(info)) => info,
                     Some(Err(err)) => return Err(err),
                     None => {
-                        let font = self.fonts.resolve_font(style)?;
                         let window = self.window.as_ref().unwrap().clone();
                         match font.shape(&cluster.text, move || {
                             window.notify(TermWindowNotif::InvalidateShapeCache)
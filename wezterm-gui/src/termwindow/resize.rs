@@ -4,7 +4,7 @@ use config::ConfigHandle;
 use mux::Mux;
 use portable_pty::PtySize;
 use std::rc::Rc;
-use wezterm_font::FontConfiguration;
+use wezterm_font::{DisplayInfo, FontConfiguration, SubpixelOrder};
 
 #[derive(Debug, Clone, Copy)]
 pub struct RowsAndCols {
@@ -30,6 +30,16 @@ impl super::TermWindow {
         }
 
         let (prior_font, prior_dpi) = self.fonts.change_scaling(font_scale, dimensions.dpi);
+        // A change in dpi is how we learn that the window has moved to
+        // a different display.  The window layer doesn't report the
+        // subpixel geometry of the display, so it is left unknown and
+        // the subpixel order provider, if any, is consulted instead.
+        // The texture atlas is recreated below, which discards glyphs
+        // rasterized with the flags of the prior display.
+        self.fonts.set_display_info(DisplayInfo {
+            dpi: dimensions.dpi as u32,
+            subpixel_order: SubpixelOrder::Unknown,
+        });
         match RenderMetrics::new(&self.fonts) {
             Ok(metrics) => {
                 self.render_metrics = metrics;
@@ -25,16 +25,25 @@ impl RenderMetrics {
             .default_font_metrics()
             .context("failed to get font metrics!?")?;
 
-        let line_height = fonts.config().line_height;
-
-        let (cell_height, cell_width) = (
-            (metrics.cell_height.get() * line_height).ceil() as usize,
-            metrics.cell_width.get().ceil() as usize,
+        let config = fonts.config();
+        let line_height = config.line_height;
+        let rounding = config.cell_dimension_rounding;
+
+        let (cell_width, cell_height) = metrics.cell_pixel_size(line_height, rounding);
+        let (_, unscaled_cell_height) = metrics.cell_pixel_size(1.0, rounding);
+        log::debug!(
+            "cell size {}x{} from {}x{} (line_height={}, cell_dimension_rounding={})",
+            cell_width,
+            cell_height,
+            metrics.cell_width.get(),
+            metrics.cell_height.get(),
+            line_height,
+            rounding
         );
 
         // When line_height != 1.0, we want to adjust the baseline position
         // such that we are horizontally centered.
-        let line_height_y_adjust = (cell_height as f64 - metrics.cell_height.get().ceil()) / 2.;
+        let line_height_y_adjust = (cell_height as f64 - unscaled_cell_height as f64) / 2.;
 
         let underline_height = metrics.underline_thickness.get().round().max(1.) as isize;
 
@@ -67,6 +67,13 @@ enum SubCommand {
     #[structopt(name = "ls-fonts", about = "Display information about fonts")]
     LsFonts(LsFontsCommand),
 
+    #[structopt(
+        name = "font-digest",
+        about = "Compute a digest of rendered glyphs for regression testing",
+        setting = structopt::clap::AppSettings::Hidden
+    )]
+    FontDigest(FontDigestCommand),
+
     #[structopt(name = "cli", about = "Interact with experimental mux server")]
     Cli(CliCommand),
 
@@ -299,6 +306,7 @@ fn run() -> anyhow::Result<()> {
     {
         SubCommand::Start(_)
         | SubCommand::LsFonts(_)
+        | SubCommand::FontDigest(_)
         | SubCommand::Ssh(_)
         | SubCommand::Serial(_)
         | SubCommand::Connect(_) => delegate_to_gui(saver),